@@ -211,6 +211,23 @@ pub mod efi {
     pub use crate::system::MEMORY_ATTRIBUTES_TABLE_GUID;
     pub use crate::system::MEMORY_ATTRIBUTES_TABLE_VERSION;
     pub use crate::system::MemoryAttributesTable;
+    pub use crate::system::RT_PROPERTIES_TABLE_GUID;
+    pub use crate::system::RT_PROPERTIES_TABLE_VERSION;
+    pub use crate::system::RT_SUPPORTED_GET_TIME;
+    pub use crate::system::RT_SUPPORTED_SET_TIME;
+    pub use crate::system::RT_SUPPORTED_GET_WAKEUP_TIME;
+    pub use crate::system::RT_SUPPORTED_SET_WAKEUP_TIME;
+    pub use crate::system::RT_SUPPORTED_GET_VARIABLE;
+    pub use crate::system::RT_SUPPORTED_GET_NEXT_VARIABLE_NAME;
+    pub use crate::system::RT_SUPPORTED_SET_VARIABLE;
+    pub use crate::system::RT_SUPPORTED_SET_VIRTUAL_ADDRESS_MAP;
+    pub use crate::system::RT_SUPPORTED_CONVERT_POINTER;
+    pub use crate::system::RT_SUPPORTED_GET_NEXT_HIGH_MONOTONIC_COUNT;
+    pub use crate::system::RT_SUPPORTED_RESET_SYSTEM;
+    pub use crate::system::RT_SUPPORTED_UPDATE_CAPSULE;
+    pub use crate::system::RT_SUPPORTED_QUERY_CAPSULE_CAPABILITIES;
+    pub use crate::system::RT_SUPPORTED_QUERY_VARIABLE_INFO;
+    pub use crate::system::RtPropertiesTable;
 
     pub use crate::system::SPECIFICATION_REVISION;
     pub use crate::system::TableHeader;
@@ -0,0 +1,19 @@
+//! Load File Protocol
+//!
+//! The load file protocol is used to obtain files, primarily boot options, from arbitrary
+//! devices, such as network boot servers.
+
+pub const PROTOCOL_GUID: crate::base::Guid = crate::base::Guid::from_fields(
+    0x56ec3091, 0x954c, 0x11d2, 0x8e, 0x3f, &[0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b]
+);
+
+#[repr(C)]
+pub struct Protocol {
+    pub load_file: eficall!{fn(
+        *mut Protocol,
+        *mut crate::protocols::device_path::Protocol,
+        crate::base::Boolean,
+        *mut usize,
+        *mut core::ffi::c_void,
+    ) -> crate::base::Status},
+}
@@ -9,6 +9,8 @@ pub mod device_path;
 pub mod device_path_utilities;
 pub mod file;
 pub mod graphics_output;
+pub mod load_file;
+pub mod load_file2;
 pub mod loaded_image;
 pub mod loaded_image_device_path;
 pub mod simple_file_system;
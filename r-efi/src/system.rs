@@ -397,6 +397,35 @@ pub struct MemoryAttributesTable {
     pub entry: [MemoryDescriptor],
 }
 
+pub const RT_PROPERTIES_TABLE_GUID: crate::base::Guid = crate::base::Guid::from_fields(
+    0xeb66918a, 0x7eef, 0x402a, 0x84, 0x2e, &[0x93, 0x1d, 0x21, 0xc3, 0x8a, 0xe9]
+);
+
+pub const RT_PROPERTIES_TABLE_VERSION: u16 = 0x0001u16;
+
+pub const RT_SUPPORTED_GET_TIME: u32 = 0x00000001u32;
+pub const RT_SUPPORTED_SET_TIME: u32 = 0x00000002u32;
+pub const RT_SUPPORTED_GET_WAKEUP_TIME: u32 = 0x00000004u32;
+pub const RT_SUPPORTED_SET_WAKEUP_TIME: u32 = 0x00000008u32;
+pub const RT_SUPPORTED_GET_VARIABLE: u32 = 0x00000010u32;
+pub const RT_SUPPORTED_GET_NEXT_VARIABLE_NAME: u32 = 0x00000020u32;
+pub const RT_SUPPORTED_SET_VARIABLE: u32 = 0x00000040u32;
+pub const RT_SUPPORTED_SET_VIRTUAL_ADDRESS_MAP: u32 = 0x00000080u32;
+pub const RT_SUPPORTED_CONVERT_POINTER: u32 = 0x00000100u32;
+pub const RT_SUPPORTED_GET_NEXT_HIGH_MONOTONIC_COUNT: u32 = 0x00000200u32;
+pub const RT_SUPPORTED_RESET_SYSTEM: u32 = 0x00000400u32;
+pub const RT_SUPPORTED_UPDATE_CAPSULE: u32 = 0x00000800u32;
+pub const RT_SUPPORTED_QUERY_CAPSULE_CAPABILITIES: u32 = 0x00001000u32;
+pub const RT_SUPPORTED_QUERY_VARIABLE_INFO: u32 = 0x00002000u32;
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct RtPropertiesTable {
+    pub version: u16,
+    pub length: u16,
+    pub runtime_services_supported: u32,
+}
+
 //
 // Global Tables
 //
@@ -545,7 +574,7 @@ pub struct BootServices {
     pub create_event: eficall!{fn(
         u32,
         crate::base::Tpl,
-        EventNotify,
+        Option<EventNotify>,
         *mut core::ffi::c_void,
         *mut crate::base::Event,
     ) -> crate::base::Status},
@@ -742,7 +771,7 @@ pub struct BootServices {
     pub create_event_ex: eficall!{fn(
         u32,
         crate::base::Tpl,
-        EventNotify,
+        Option<EventNotify>,
         *const core::ffi::c_void,
         *const crate::base::Guid,
         *mut crate::base::Event,
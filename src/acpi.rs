@@ -0,0 +1,350 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use core::sync::atomic::{AtomicU8, AtomicU16, Ordering};
+
+use cpuio::Port;
+
+use crate::mem::MemoryRegion;
+
+const RSDP_SIGNATURE: &[u8] = b"RSD PTR ";
+const RSDP_CHECKSUM_LENGTH: u64 = 20;
+const RSDP_REVISION: u64 = 15;
+const RSDP_RSDT_ADDRESS: u64 = 16;
+const RSDP_XSDT_ADDRESS: u64 = 24;
+
+// The BDA holds the segment of the EBDA, the RSDP is in its first KiB.
+const BDA_EBDA_SEGMENT: u64 = 0x40e;
+const EBDA_SEARCH_LENGTH: u64 = 0x400;
+// Cloud Hypervisor places the RSDP at the start of the (unannounced) EBDA.
+const CLOUD_HYPERVISOR_RSDP_BASE: u64 = 0xa0000;
+const BIOS_AREA_BASE: u64 = 0xe0000;
+const BIOS_AREA_LENGTH: u64 = 0x20000;
+
+const TABLE_HEADER_LENGTH: u64 = 36;
+const TABLE_LENGTH: u64 = 4;
+
+const FADT_SIGNATURE: &[u8] = b"FACP";
+const FADT_DSDT: u64 = 40;
+const FADT_PM1A_CONTROL_BLOCK: u64 = 64;
+const FADT_PM1B_CONTROL_BLOCK: u64 = 68;
+const FADT_DAY_ALARM: u64 = 106;
+const FADT_MONTH_ALARM: u64 = 107;
+const FADT_CENTURY: u64 = 108;
+const FADT_FLAGS: u64 = 112;
+const FADT_X_DSDT: u64 = 140;
+const FADT_X_PM1A_CONTROL_BLOCK: u64 = 172;
+const FADT_X_PM1B_CONTROL_BLOCK: u64 = 184;
+const FADT_SLEEP_CONTROL_REGISTER: u64 = 244;
+const FADT_FLAGS_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+// Generic Address Structure
+const GAS_LENGTH: u64 = 12;
+const GAS_ADDRESS: u64 = 4;
+const GAS_SPACE_SYSTEM_IO: u8 = 1;
+
+const PM1_CONTROL_SLP_TYP_SHIFT: u16 = 10;
+const PM1_CONTROL_SLP_TYP_MASK: u16 = 0x7 << PM1_CONTROL_SLP_TYP_SHIFT;
+const PM1_CONTROL_SLP_EN: u16 = 1 << 13;
+const SLEEP_CONTROL_SLP_TYP_SHIFT: u8 = 2;
+const SLEEP_CONTROL_SLP_TYP_MASK: u8 = 0x7 << SLEEP_CONTROL_SLP_TYP_SHIFT;
+const SLEEP_CONTROL_SLP_EN: u8 = 1 << 5;
+
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ROOT_CHAR: u8 = b'\\';
+
+// Found in the FADT at boot, as the tables are not mapped once the OS runs.
+static PM1A_CONTROL: AtomicU16 = AtomicU16::new(0);
+static PM1B_CONTROL: AtomicU16 = AtomicU16::new(0);
+static SLEEP_CONTROL: AtomicU16 = AtomicU16::new(0);
+static SLP_TYP_A: AtomicU8 = AtomicU8::new(0);
+static SLP_TYP_B: AtomicU8 = AtomicU8::new(0);
+// The CMOS indexes of the RTC date alarm and century registers, 0 without one
+static RTC_DAY_ALARM: AtomicU8 = AtomicU8::new(0);
+static RTC_MONTH_ALARM: AtomicU8 = AtomicU8::new(0);
+static RTC_CENTURY: AtomicU8 = AtomicU8::new(0);
+
+fn is_checksum_valid(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+// Decode a ZeroOp, OneOp or ByteConst package element.
+fn parse_aml_byte(aml: &[u8], offset: usize) -> Option<(u8, usize)> {
+    match *aml.get(offset)? {
+        AML_ZERO_OP => Some((0, offset + 1)),
+        AML_ONE_OP => Some((1, offset + 1)),
+        AML_BYTE_PREFIX => Some((*aml.get(offset + 1)?, offset + 2)),
+        _ => None,
+    }
+}
+
+/// Get the SLP_TYPa and SLP_TYPb values from the \_S5 package of the AML
+fn parse_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    for position in 1..aml.len().saturating_sub(3) {
+        if &aml[position..position + 4] != b"_S5_" {
+            continue;
+        }
+        let is_definition = aml[position - 1] == AML_NAME_OP
+            || (position >= 2 && aml[position - 1] == AML_ROOT_CHAR && aml[position - 2] == AML_NAME_OP);
+        if !is_definition || aml.get(position + 4) != Some(&AML_PACKAGE_OP) {
+            continue;
+        }
+
+        // The top two bits of the PkgLength lead byte count its extra bytes,
+        // it is followed by NumElements.
+        let offset = position + 5;
+        let offset = offset + ((*aml.get(offset)? >> 6) + 1) as usize + 1;
+        let (slp_typ_a, offset) = parse_aml_byte(aml, offset)?;
+        // Cloud Hypervisor only provides SLP_TYPa
+        let slp_typ_b = parse_aml_byte(aml, offset).map_or(0, |(value, _)| value);
+        return Some((slp_typ_a, slp_typ_b));
+    }
+    None
+}
+
+#[cfg(not(test))]
+fn find_rsdp_in(base: u64, length: u64) -> Option<MemoryRegion> {
+    let mut offset = 0;
+    while offset + RSDP_CHECKSUM_LENGTH <= length {
+        let region = MemoryRegion::new(base + offset, RSDP_CHECKSUM_LENGTH + 16);
+        if region.as_slice::<u8>(0, 8) == RSDP_SIGNATURE
+            && is_checksum_valid(region.as_slice::<u8>(0, RSDP_CHECKSUM_LENGTH)) {
+            return Some(region);
+        }
+        offset += 16;
+    }
+    None
+}
+
+#[cfg(not(test))]
+fn find_rsdp() -> Option<MemoryRegion> {
+    let ebda = (MemoryRegion::new(0, 0x1000).read_u16(BDA_EBDA_SEGMENT) as u64) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = find_rsdp_in(ebda, EBDA_SEARCH_LENGTH) {
+            return Some(rsdp);
+        }
+    }
+    find_rsdp_in(CLOUD_HYPERVISOR_RSDP_BASE, EBDA_SEARCH_LENGTH)
+        .or_else(|| find_rsdp_in(BIOS_AREA_BASE, BIOS_AREA_LENGTH))
+}
+
+#[cfg(not(test))]
+fn get_table(address: u64) -> Option<MemoryRegion> {
+    if address == 0 {
+        return None;
+    }
+    let length = MemoryRegion::new(address, TABLE_HEADER_LENGTH).read_u32(TABLE_LENGTH) as u64;
+    if length < TABLE_HEADER_LENGTH {
+        return None;
+    }
+    let table = MemoryRegion::new(address, length);
+    if !is_checksum_valid(table.as_slice::<u8>(0, length)) {
+        return None;
+    }
+    Some(table)
+}
+
+#[cfg(not(test))]
+fn find_table(signature: &[u8]) -> Option<MemoryRegion> {
+    let rsdp = find_rsdp()?;
+
+    let xsdt = if rsdp.read_u8(RSDP_REVISION) >= 2 {
+        get_table(rsdp.read_u64(RSDP_XSDT_ADDRESS))
+    } else {
+        None
+    };
+    let (sdt, entry_size) = match xsdt {
+        Some(xsdt) => (xsdt, 8),
+        None => (get_table(rsdp.read_u32(RSDP_RSDT_ADDRESS) as u64)?, 4),
+    };
+
+    let length = sdt.read_u32(TABLE_LENGTH) as u64;
+    let mut offset = TABLE_HEADER_LENGTH;
+    while offset + entry_size <= length {
+        let address = if entry_size == 8 {
+            sdt.read_u64(offset)
+        } else {
+            sdt.read_u32(offset) as u64
+        };
+        if let Some(table) = get_table(address) {
+            if table.as_slice::<u8>(0, 4) == signature {
+                return Some(table);
+            }
+        }
+        offset += entry_size;
+    }
+    None
+}
+
+#[cfg(not(test))]
+// Get an I/O port from the legacy 32-bit block or the extended GAS of the FADT.
+fn get_fadt_io_port(fadt: &MemoryRegion, length: u64, block: u64, gas: u64) -> u16 {
+    let port = fadt.read_u32(block);
+    if port != 0 {
+        return port as u16;
+    }
+    if length >= gas + GAS_LENGTH && fadt.read_u8(gas) == GAS_SPACE_SYSTEM_IO {
+        return fadt.read_u64(gas + GAS_ADDRESS) as u16;
+    }
+    0
+}
+
+/// The CMOS index of the RTC day of month alarm, 0 if there is none
+pub fn rtc_day_alarm() -> u8 {
+    RTC_DAY_ALARM.load(Ordering::SeqCst)
+}
+
+/// The CMOS index of the RTC month alarm, 0 if there is none
+pub fn rtc_month_alarm() -> u8 {
+    RTC_MONTH_ALARM.load(Ordering::SeqCst)
+}
+
+/// The CMOS index of the RTC century, 0 if there is none
+pub fn rtc_century() -> u8 {
+    RTC_CENTURY.load(Ordering::SeqCst)
+}
+
+#[cfg(not(test))]
+/// Find the RTC registers, and the registers and values needed to enter S5,
+/// in the FADT and DSDT
+pub fn init() {
+    let fadt = match find_table(FADT_SIGNATURE) {
+        Some(fadt) => fadt,
+        None => {
+            log!("ACPI: no FADT found\n");
+            return;
+        }
+    };
+    let length = fadt.read_u32(TABLE_LENGTH) as u64;
+
+    if length >= FADT_CENTURY + 1 {
+        RTC_DAY_ALARM.store(fadt.read_u8(FADT_DAY_ALARM), Ordering::SeqCst);
+        RTC_MONTH_ALARM.store(fadt.read_u8(FADT_MONTH_ALARM), Ordering::SeqCst);
+        RTC_CENTURY.store(fadt.read_u8(FADT_CENTURY), Ordering::SeqCst);
+    }
+    log!(
+        "ACPI: RTC day alarm {:#x} month alarm {:#x} century {:#x}\n",
+        rtc_day_alarm(),
+        rtc_month_alarm(),
+        rtc_century()
+    );
+
+    let mut dsdt_address = fadt.read_u32(FADT_DSDT) as u64;
+    if length >= FADT_X_DSDT + 8 && fadt.read_u64(FADT_X_DSDT) != 0 {
+        dsdt_address = fadt.read_u64(FADT_X_DSDT);
+    }
+    let s5 = get_table(dsdt_address).and_then(|dsdt| {
+        let length = dsdt.read_u32(TABLE_LENGTH) as u64;
+        parse_s5(dsdt.as_slice::<u8>(TABLE_HEADER_LENGTH, length - TABLE_HEADER_LENGTH))
+    });
+    let (slp_typ_a, slp_typ_b) = match s5 {
+        Some(s5) => s5,
+        None => {
+            log!("ACPI: no \\_S5 package found\n");
+            return;
+        }
+    };
+    SLP_TYP_A.store(slp_typ_a, Ordering::SeqCst);
+    SLP_TYP_B.store(slp_typ_b, Ordering::SeqCst);
+
+    if length >= FADT_FLAGS + 4 && (fadt.read_u32(FADT_FLAGS) & FADT_FLAGS_HW_REDUCED_ACPI) != 0 {
+        if length >= FADT_SLEEP_CONTROL_REGISTER + GAS_LENGTH
+            && fadt.read_u8(FADT_SLEEP_CONTROL_REGISTER) == GAS_SPACE_SYSTEM_IO {
+            let port = fadt.read_u64(FADT_SLEEP_CONTROL_REGISTER + GAS_ADDRESS) as u16;
+            SLEEP_CONTROL.store(port, Ordering::SeqCst);
+        }
+    } else {
+        let port = get_fadt_io_port(&fadt, length, FADT_PM1A_CONTROL_BLOCK, FADT_X_PM1A_CONTROL_BLOCK);
+        PM1A_CONTROL.store(port, Ordering::SeqCst);
+        let port = get_fadt_io_port(&fadt, length, FADT_PM1B_CONTROL_BLOCK, FADT_X_PM1B_CONTROL_BLOCK);
+        PM1B_CONTROL.store(port, Ordering::SeqCst);
+    }
+
+    log!(
+        "ACPI: S5 SLP_TYP {}/{} PM1a_CNT {:#x} PM1b_CNT {:#x} SLEEP_CONTROL {:#x}\n",
+        slp_typ_a,
+        slp_typ_b,
+        PM1A_CONTROL.load(Ordering::SeqCst),
+        PM1B_CONTROL.load(Ordering::SeqCst),
+        SLEEP_CONTROL.load(Ordering::SeqCst)
+    );
+}
+
+fn enter_s5_pm1(port: u16, slp_typ: u8) {
+    let mut control: Port<u16> = unsafe { Port::new(port) };
+    let value = control.read() & !(PM1_CONTROL_SLP_TYP_MASK | PM1_CONTROL_SLP_EN);
+    control.write(value | ((slp_typ as u16) << PM1_CONTROL_SLP_TYP_SHIFT) | PM1_CONTROL_SLP_EN);
+}
+
+/// Enter S5 through the registers found in the FADT, returns if there are none
+/// or the write had no effect.
+pub fn shutdown() {
+    let sleep_control = SLEEP_CONTROL.load(Ordering::SeqCst);
+    let pm1a_control = PM1A_CONTROL.load(Ordering::SeqCst);
+    let pm1b_control = PM1B_CONTROL.load(Ordering::SeqCst);
+    let slp_typ_a = SLP_TYP_A.load(Ordering::SeqCst);
+    let slp_typ_b = SLP_TYP_B.load(Ordering::SeqCst);
+
+    if sleep_control != 0 {
+        let mut control: Port<u8> = unsafe { Port::new(sleep_control) };
+        control.write(((slp_typ_a << SLEEP_CONTROL_SLP_TYP_SHIFT) & SLEEP_CONTROL_SLP_TYP_MASK) | SLEEP_CONTROL_SLP_EN);
+    }
+
+    // SLP_TYPb is written first, the system sleeps once SLP_EN is set in PM1a.
+    if pm1b_control != 0 {
+        enter_s5_pm1(pm1b_control, slp_typ_b);
+    }
+    if pm1a_control != 0 {
+        enter_s5_pm1(pm1a_control, slp_typ_a);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_checksum_valid, parse_s5};
+
+    #[test]
+    fn test_checksum() {
+        assert!(is_checksum_valid(&[0x01, 0xff]));
+        assert!(is_checksum_valid(&[]));
+        assert!(!is_checksum_valid(&[0x01, 0xfe]));
+    }
+
+    #[test]
+    fn test_parse_s5() {
+        // QEMU: Name (_S5, Package (0x04) { Zero, Zero, Zero, Zero })
+        let qemu = [0x10, 0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(parse_s5(&qemu), Some((0, 0)));
+
+        // Cloud Hypervisor: Name (_S5, Package (0x01) { 0x05 })
+        let cloud_hypervisor = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x04, 0x01, 0x0a, 0x05];
+        assert_eq!(parse_s5(&cloud_hypervisor), Some((5, 0)));
+
+        // Name (\_S5, Package (0x02) { 0x07, One })
+        let root = [0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x05, 0x02, 0x0a, 0x07, 0x01, 0x00];
+        assert_eq!(parse_s5(&root), Some((7, 1)));
+
+        // A reference to \_S5 before its definition is skipped
+        let reference = [0x70, b'_', b'S', b'5', b'_', 0x60, 0x08, b'_', b'S', b'5', b'_', 0x12, 0x04, 0x01, 0x01, 0x00];
+        assert_eq!(parse_s5(&reference), Some((1, 0)));
+
+        assert_eq!(parse_s5(&[0x08, b'_', b'S', b'4', b'_', 0x12, 0x04, 0x01, 0x01]), None);
+    }
+}
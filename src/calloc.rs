@@ -35,7 +35,7 @@ pub fn malloc<T>() -> Result<*mut T, Status> {
 }
 
 pub fn free<T>(ptr: *mut T) {
-    ALLOCATOR.lock().free_pages(&ptr as *const _ as u64);
+    ALLOCATOR.lock().free_pages(ptr as u64, ((core::mem::size_of::<T>() + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize) as u64);
 }
 
 pub fn duplicate<T>(d: &T) -> Result<*mut T, Status> {
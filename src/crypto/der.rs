@@ -0,0 +1,156 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+pub const TAG_INTEGER: u8 = 0x02;
+pub const TAG_BIT_STRING: u8 = 0x03;
+pub const TAG_OCTET_STRING: u8 = 0x04;
+pub const TAG_NULL: u8 = 0x05;
+pub const TAG_OID: u8 = 0x06;
+pub const TAG_SEQUENCE: u8 = 0x30;
+pub const TAG_SET: u8 = 0x31;
+
+/// Tag of a constructed, context specific element: [number]
+pub const fn tag_context(number: u8) -> u8 {
+    0xa0 | number
+}
+
+// Contents of the object identifiers in use
+pub const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+pub const OID_SHA256_WITH_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+pub const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+pub const OID_PKCS7_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01];
+pub const OID_PKCS7_SIGNED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02];
+pub const OID_MESSAGE_DIGEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x04];
+
+#[derive(Clone, Copy)]
+pub struct Element<'a> {
+    pub tag: u8,
+    /// The value, without the tag and the length
+    pub content: &'a [u8],
+    /// The whole encoding, with the tag and the length
+    pub raw: &'a [u8],
+}
+
+/// Reads the elements of a DER encoding one after the other. Only the low
+/// tag numbers and the definite lengths of DER are supported.
+#[derive(Clone, Copy)]
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn peek_tag(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    pub fn read(&mut self) -> Option<Element<'a>> {
+        if self.data.len() < 2 || self.data[0] & 0x1f == 0x1f {
+            return None;
+        }
+        let tag = self.data[0];
+        let (length, header) = match self.data[1] {
+            length @ 0..=0x7f => (length as usize, 2),
+            0x81..=0x84 => {
+                let count = (self.data[1] & 0x7f) as usize;
+                if self.data.len() < 2 + count {
+                    return None;
+                }
+                let mut length = 0usize;
+                for byte in &self.data[2..2 + count] {
+                    length = (length << 8) | *byte as usize;
+                }
+                (length, 2 + count)
+            }
+            _ => return None,
+        };
+        if self.data.len() - header < length {
+            return None;
+        }
+        let element = Element {
+            tag,
+            content: &self.data[header..header + length],
+            raw: &self.data[..header + length],
+        };
+        self.data = &self.data[header + length..];
+        Some(element)
+    }
+
+    /// Read the next element, which must have the given tag
+    pub fn read_tag(&mut self, tag: u8) -> Option<Element<'a>> {
+        match self.read() {
+            Some(element) if element.tag == tag => Some(element),
+            _ => None,
+        }
+    }
+
+    /// Read the next element only if it has the given tag
+    pub fn read_optional(&mut self, tag: u8) -> Option<Element<'a>> {
+        if self.peek_tag() == Some(tag) {
+            self.read()
+        } else {
+            None
+        }
+    }
+}
+
+/// The content of an INTEGER without its leading zeros, as a big endian number
+pub fn unsigned_integer<'a>(element: &Element<'a>) -> &'a [u8] {
+    let start = element.content.iter().position(|b| *b != 0).unwrap_or(element.content.len());
+    &element.content[start..]
+}
+
+/// The object identifier of an AlgorithmIdentifier
+pub fn algorithm<'a>(element: &Element<'a>) -> Option<&'a [u8]> {
+    let mut reader = Reader::new(element.content);
+    Some(reader.read_tag(TAG_OID)?.content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reader() {
+        // SEQUENCE { INTEGER 0x00ff, OCTET STRING (200 bytes) }
+        let mut data = vec![0x30, 0x81, 0xcf, 0x02, 0x02, 0x00, 0xff, 0x04, 0x81, 0xc8];
+        data.extend_from_slice(&[0x55; 200]);
+
+        let mut reader = Reader::new(&data);
+        let sequence = reader.read_tag(TAG_SEQUENCE).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(sequence.raw.len(), data.len());
+
+        let mut reader = Reader::new(sequence.content);
+        assert!(reader.read_optional(tag_context(0)).is_none());
+        assert_eq!(unsigned_integer(&reader.read_tag(TAG_INTEGER).unwrap()), &[0xff]);
+        let octets = reader.read().unwrap();
+        assert_eq!(octets.tag, TAG_OCTET_STRING);
+        assert_eq!(octets.content.len(), 200);
+        assert!(reader.read().is_none());
+
+        // Truncated, or with an indefinite length
+        assert!(Reader::new(&data[..100]).read().is_none());
+        assert!(Reader::new(&[0x30, 0x80, 0x00, 0x00]).read().is_none());
+    }
+}
@@ -0,0 +1,22 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Only what is needed to check signatures: SHA-256, RSA PKCS#1 v1.5
+// verification, and enough DER to read X.509 certificates and PKCS#7.
+
+pub mod sha256;
+pub mod rsa;
+pub mod der;
+pub mod x509;
+pub mod pkcs7;
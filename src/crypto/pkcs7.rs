@@ -0,0 +1,197 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use super::der::*;
+use super::sha256;
+use super::x509::Certificate;
+
+// Longest chain followed from the signer to a trusted certificate
+const MAX_CHAIN_LENGTH: usize = 8;
+
+/// A PKCS#7 SignedData with SHA-256 digests and RSA signatures, from its first
+/// signer only.
+pub struct SignedData<'a> {
+    content_type: &'a [u8],
+    content: Option<Element<'a>>,
+    certificates: &'a [u8],
+    issuer: &'a [u8],
+    serial: &'a [u8],
+    authenticated_attributes: Option<Element<'a>>,
+    signature: &'a [u8],
+}
+
+impl<'a> SignedData<'a> {
+    /// Parse a SignedData, or a ContentInfo holding one
+    pub fn parse(data: &'a [u8]) -> Option<SignedData<'a>> {
+        let mut reader = Reader::new(Reader::new(data).read_tag(TAG_SEQUENCE)?.content);
+        if reader.peek_tag() == Some(TAG_OID) {
+            if reader.read_tag(TAG_OID)?.content != OID_PKCS7_SIGNED_DATA {
+                return None;
+            }
+            let content = reader.read_tag(tag_context(0))?;
+            reader = Reader::new(Reader::new(content.content).read_tag(TAG_SEQUENCE)?.content);
+        }
+
+        reader.read_tag(TAG_INTEGER)?; // version
+        reader.read_tag(TAG_SET)?; // digestAlgorithms
+        let mut content_info = Reader::new(reader.read_tag(TAG_SEQUENCE)?.content);
+        let content_type = content_info.read_tag(TAG_OID)?.content;
+        let content = match content_info.read_optional(tag_context(0)) {
+            Some(content) => Some(Reader::new(content.content).read()?),
+            None => None,
+        };
+        let certificates = match reader.read_optional(tag_context(0)) {
+            Some(certificates) => certificates.content,
+            None => &[],
+        };
+        reader.read_optional(tag_context(1)); // crls
+        let signer_infos = reader.read_tag(TAG_SET)?;
+
+        let mut reader = Reader::new(Reader::new(signer_infos.content).read_tag(TAG_SEQUENCE)?.content);
+        reader.read_tag(TAG_INTEGER)?; // version
+        let mut issuer_and_serial = Reader::new(reader.read_tag(TAG_SEQUENCE)?.content);
+        let issuer = issuer_and_serial.read_tag(TAG_SEQUENCE)?.raw;
+        let serial = issuer_and_serial.read_tag(TAG_INTEGER)?.content;
+        if algorithm(&reader.read_tag(TAG_SEQUENCE)?)? != OID_SHA256 {
+            return None;
+        }
+        let authenticated_attributes = reader.read_optional(tag_context(0));
+        let signature_algorithm = algorithm(&reader.read_tag(TAG_SEQUENCE)?)?;
+        if signature_algorithm != OID_RSA_ENCRYPTION && signature_algorithm != OID_SHA256_WITH_RSA_ENCRYPTION {
+            return None;
+        }
+        let signature = reader.read_tag(TAG_OCTET_STRING)?.content;
+
+        Some(SignedData { content_type, content, certificates, issuer, serial, authenticated_attributes, signature })
+    }
+
+    /// The object identifier of the type of the content that was signed
+    pub fn content_type(&self) -> &'a [u8] {
+        self.content_type
+    }
+
+    /// The content that was signed, when it is not detached
+    pub fn content(&self) -> Option<Element<'a>> {
+        self.content
+    }
+
+    fn find_certificate(&self, matches: &dyn Fn(&Certificate) -> bool) -> Option<Certificate<'a>> {
+        let mut reader = Reader::new(self.certificates);
+        while let Some(element) = reader.read() {
+            if let Some(certificate) = Certificate::parse(element.raw) {
+                if matches(&certificate) {
+                    return Some(certificate);
+                }
+            }
+        }
+        None
+    }
+
+    // The digest that was signed, given the digest of the content
+    fn signed_digest(&self, digest: &[u8; sha256::DIGEST_SIZE]) -> Option<[u8; sha256::DIGEST_SIZE]> {
+        let attributes = match self.authenticated_attributes {
+            Some(attributes) => attributes,
+            None => return Some(*digest),
+        };
+
+        // The content digest is one of the attributes, which are signed instead
+        let mut reader = Reader::new(attributes.content);
+        let mut found = false;
+        while let Some(attribute) = reader.read() {
+            let mut attribute = Reader::new(attribute.content);
+            if attribute.read_tag(TAG_OID)?.content != OID_MESSAGE_DIGEST {
+                continue;
+            }
+            let mut values = Reader::new(attribute.read_tag(TAG_SET)?.content);
+            if values.read_tag(TAG_OCTET_STRING)?.content != digest {
+                return None;
+            }
+            found = true;
+        }
+        if !found {
+            return None;
+        }
+
+        // They are signed as a SET OF, not with their implicit tag
+        let mut sha256 = sha256::Sha256::new();
+        sha256.update(&[TAG_SET]);
+        sha256.update(&attributes.raw[1..]);
+        Some(sha256.finish())
+    }
+
+    /// Check that the signer signed content with the given digest, and that its
+    /// certificate is trusted or chains up to a trusted one through the
+    /// certificates included. Returns the certificate of the signer.
+    pub fn verify(
+        &self,
+        digest: &[u8; sha256::DIGEST_SIZE],
+        is_trusted: &dyn Fn(&Certificate) -> bool,
+    ) -> Option<Certificate<'a>> {
+        let signer = self.find_certificate(&|certificate| {
+            certificate.issuer == self.issuer && certificate.serial == self.serial
+        })?;
+        if !signer.verify(&self.signed_digest(digest)?, self.signature) {
+            return None;
+        }
+
+        let mut certificate = signer;
+        for _ in 0..MAX_CHAIN_LENGTH {
+            if is_trusted(&certificate) {
+                return Some(signer);
+            }
+            let issuer = self.find_certificate(&|issuer| {
+                issuer.raw != certificate.raw && certificate.is_signed_by(issuer)
+            })?;
+            certificate = issuer;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The trust of the variable services: a certificate that is trusted, or signed by one
+    fn trusted_by<'a>(anchor: &'a Certificate<'a>) -> impl Fn(&Certificate) -> bool + 'a {
+        move |certificate| certificate.raw == anchor.raw || certificate.is_signed_by(anchor)
+    }
+
+    #[test]
+    fn test_signed_data() {
+        let root = Certificate::parse(include_bytes!("testdata/root.der")).unwrap();
+        let leaf = Certificate::parse(include_bytes!("testdata/leaf.der")).unwrap();
+        let content = b"signed content";
+        let digest = sha256::digest(content);
+
+        // Signed by the leaf without attributes, then with them
+        for data in [&include_bytes!("testdata/leaf_noattr.p7")[..], &include_bytes!("testdata/leaf_attr.p7")[..]].iter() {
+            let signed_data = SignedData::parse(data).unwrap();
+            assert_eq!(signed_data.content_type(), OID_PKCS7_DATA);
+            assert!(signed_data.content().is_none());
+            let signer = signed_data.verify(&digest, &trusted_by(&root)).unwrap();
+            assert!(signer.raw == leaf.raw);
+            assert!(signed_data.verify(&digest, &trusted_by(&leaf)).is_some());
+            assert!(signed_data.verify(&digest, &|_| false).is_none());
+            assert!(signed_data.verify(&sha256::digest(b"other content"), &trusted_by(&root)).is_none());
+        }
+
+        // The leaf is signed by an intermediate, only included in the SignedData
+        let signed_data = SignedData::parse(include_bytes!("testdata/chain.p7")).unwrap();
+        assert!(signed_data.verify(&digest, &trusted_by(&root)).is_some());
+        assert!(signed_data.verify(&digest, &trusted_by(&leaf)).is_none());
+    }
+}
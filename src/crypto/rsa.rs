@@ -0,0 +1,223 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use core::cmp::Ordering;
+
+use super::sha256::DIGEST_SIZE;
+
+pub const MAX_MODULUS_BITS: usize = 4096;
+
+// The cost of pow_mod grows with the exponent, keys use 3 or 65537
+const MAX_EXPONENT_BITS: usize = 32;
+
+// One limb more than the modulus, so doubling a residue cannot overflow
+const LIMBS: usize = MAX_MODULUS_BITS / 32 + 1;
+
+// DER of the DigestInfo of a SHA-256 digest, with and without the NULL parameters
+const SHA256_DIGEST_INFO: [u8; 19] = [
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00,
+    0x04, 0x20,
+];
+const SHA256_DIGEST_INFO_NO_NULL: [u8; 17] = [
+    0x30, 0x2f, 0x30, 0x0b, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x04, 0x20,
+];
+
+// An unsigned integer of up to MAX_MODULUS_BITS, with its least significant limb first.
+// Only verification is done, so nothing here needs to run in constant time.
+#[derive(Clone, Copy)]
+struct Number {
+    limbs: [u32; LIMBS],
+}
+
+impl Number {
+    fn zero() -> Number {
+        Number { limbs: [0; LIMBS] }
+    }
+
+    fn from_be_bytes(bytes: &[u8]) -> Option<Number> {
+        let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+        let bytes = &bytes[start..];
+        if bytes.len() > MAX_MODULUS_BITS / 8 {
+            return None;
+        }
+        let mut number = Number::zero();
+        for (i, byte) in bytes.iter().rev().enumerate() {
+            number.limbs[i / 4] |= u32::from(*byte) << ((i % 4) * 8);
+        }
+        Some(number)
+    }
+
+    fn to_be_bytes(&self, bytes: &mut [u8]) {
+        let length = bytes.len();
+        for (i, byte) in bytes.iter_mut().rev().enumerate() {
+            *byte = if i / 4 < LIMBS { (self.limbs[i / 4] >> ((i % 4) * 8)) as u8 } else { 0 };
+        }
+    }
+
+    fn bits(&self) -> usize {
+        for i in (0..LIMBS).rev() {
+            if self.limbs[i] != 0 {
+                return i * 32 + 32 - self.limbs[i].leading_zeros() as usize;
+            }
+        }
+        0
+    }
+
+    fn bit(&self, bit: usize) -> bool {
+        (self.limbs[bit / 32] >> (bit % 32)) & 1 != 0
+    }
+
+    fn compare(&self, other: &Number) -> Ordering {
+        for i in (0..LIMBS).rev() {
+            match self.limbs[i].cmp(&other.limbs[i]) {
+                Ordering::Equal => {}
+                ordering => return ordering,
+            }
+        }
+        Ordering::Equal
+    }
+
+    fn add(&mut self, other: &Number) {
+        let mut carry = 0u64;
+        for i in 0..LIMBS {
+            let sum = u64::from(self.limbs[i]) + u64::from(other.limbs[i]) + carry;
+            self.limbs[i] = sum as u32;
+            carry = sum >> 32;
+        }
+    }
+
+    fn sub(&mut self, other: &Number) {
+        let mut borrow = 0i64;
+        for i in 0..LIMBS {
+            let difference = i64::from(self.limbs[i]) - i64::from(other.limbs[i]) - borrow;
+            self.limbs[i] = difference as u32;
+            borrow = if difference < 0 { 1 } else { 0 };
+        }
+    }
+
+    // self = (self + other) mod modulus, both being below modulus
+    fn add_mod(&mut self, other: &Number, modulus: &Number) {
+        self.add(other);
+        if self.compare(modulus) != Ordering::Less {
+            self.sub(modulus);
+        }
+    }
+
+    // (a * b) mod modulus, with a and b below modulus, by shifting and adding
+    fn mul_mod(a: &Number, b: &Number, modulus: &Number) -> Number {
+        let mut result = Number::zero();
+        for bit in (0..a.bits()).rev() {
+            let double = result;
+            result.add_mod(&double, modulus);
+            if a.bit(bit) {
+                result.add_mod(b, modulus);
+            }
+        }
+        result
+    }
+
+    // (base ^ exponent) mod modulus, with base below modulus
+    fn pow_mod(base: &Number, exponent: &Number, modulus: &Number) -> Number {
+        let mut result = Number::zero();
+        result.limbs[0] = 1;
+        for bit in (0..exponent.bits()).rev() {
+            result = Number::mul_mod(&result, &result, modulus);
+            if exponent.bit(bit) {
+                result = Number::mul_mod(&result, base, modulus);
+            }
+        }
+        result
+    }
+}
+
+/// Check an RSASSA-PKCS1-v1_5 signature of a SHA-256 digest, given the big
+/// endian modulus and public exponent of the key.
+pub fn verify_sha256(modulus: &[u8], exponent: &[u8], signature: &[u8], digest: &[u8; DIGEST_SIZE]) -> bool {
+    let modulus = match Number::from_be_bytes(modulus) {
+        Some(modulus) => modulus,
+        None => return false,
+    };
+    let exponent = match Number::from_be_bytes(exponent) {
+        Some(exponent) => exponent,
+        None => return false,
+    };
+    let length = (modulus.bits() + 7) / 8;
+    if length < SHA256_DIGEST_INFO.len() + DIGEST_SIZE + 11
+        || signature.len() != length
+        || exponent.bits() == 0
+        || exponent.bits() > MAX_EXPONENT_BITS
+    {
+        return false;
+    }
+    let signature = match Number::from_be_bytes(signature) {
+        Some(signature) if signature.compare(&modulus) == Ordering::Less => signature,
+        _ => return false,
+    };
+
+    let mut encoded = [0u8; MAX_MODULUS_BITS / 8];
+    let encoded = &mut encoded[..length];
+    Number::pow_mod(&signature, &exponent, &modulus).to_be_bytes(encoded);
+
+    // 0x00 0x01 0xff... 0x00 DigestInfo
+    let digest_info: &[u8] = if encoded[length - DIGEST_SIZE - SHA256_DIGEST_INFO.len()..].starts_with(&SHA256_DIGEST_INFO) {
+        &SHA256_DIGEST_INFO
+    } else {
+        &SHA256_DIGEST_INFO_NO_NULL
+    };
+    let padding_end = length - DIGEST_SIZE - digest_info.len() - 1;
+    encoded[0] == 0
+        && encoded[1] == 1
+        && encoded[2..padding_end].iter().all(|b| *b == 0xff)
+        && encoded[padding_end] == 0
+        && &encoded[padding_end + 1..length - DIGEST_SIZE] == digest_info
+        && &encoded[length - DIGEST_SIZE..] == digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::sha256;
+
+    #[test]
+    fn test_pow_mod() {
+        let modulus = Number::from_be_bytes(&[0x01, 0x00, 0x01, 0x17]).unwrap(); // 16777495, prime
+        let base = Number::from_be_bytes(&[0x12, 0x34, 0x56]).unwrap();
+        let exponent = Number::from_be_bytes(&[0x01, 0x00, 0x01]).unwrap();
+        let mut expected = 1u64;
+        for _ in 0..65537 {
+            expected = expected * 0x123456 % 16777495;
+        }
+        let mut result = [0u8; 8];
+        Number::pow_mod(&base, &exponent, &modulus).to_be_bytes(&mut result);
+        assert_eq!(u64::from_be_bytes(result), expected);
+    }
+
+    #[test]
+    fn test_verify_sha256() {
+        // openssl dgst -sha256 -sign key.pem, of "abc" with a 2048 bit key
+        let modulus = include_bytes!("testdata/rsa2048.modulus");
+        let signature = include_bytes!("testdata/rsa2048_abc.sig");
+        let digest = sha256::digest(b"abc");
+        assert!(verify_sha256(modulus, &[0x01, 0x00, 0x01], signature, &digest));
+        assert!(!verify_sha256(modulus, &[0x01, 0x00, 0x01], signature, &sha256::digest(b"abd")));
+        assert!(!verify_sha256(modulus, &[0x03], signature, &digest));
+        assert!(!verify_sha256(modulus, &[0x01, 0x00, 0x00, 0x00, 0x01], signature, &digest));
+
+        let mut signature = *signature;
+        signature[100] ^= 1;
+        assert!(!verify_sha256(modulus, &[0x01, 0x00, 0x01], &signature, &digest));
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use super::der::*;
+use super::rsa;
+use super::sha256;
+
+/// An X.509 certificate with an RSA key. Validity periods are not kept: there
+/// is no trusted time in firmware to check them against.
+#[derive(Clone, Copy)]
+pub struct Certificate<'a> {
+    pub raw: &'a [u8],
+    tbs: &'a [u8],
+    pub serial: &'a [u8],
+    /// The DER encodings of the names, compared as they are
+    pub issuer: &'a [u8],
+    pub subject: &'a [u8],
+    modulus: &'a [u8],
+    exponent: &'a [u8],
+    signature_algorithm: &'a [u8],
+    signature: &'a [u8],
+}
+
+// The content of a BIT STRING made of whole bytes
+fn bit_string_bytes<'a>(element: &Element<'a>) -> Option<&'a [u8]> {
+    match element.content.split_first() {
+        Some((0, bytes)) => Some(bytes),
+        _ => None,
+    }
+}
+
+impl<'a> Certificate<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Certificate<'a>> {
+        let mut reader = Reader::new(data);
+        let certificate = reader.read_tag(TAG_SEQUENCE)?;
+
+        let mut reader = Reader::new(certificate.content);
+        let tbs = reader.read_tag(TAG_SEQUENCE)?;
+        let signature_algorithm = algorithm(&reader.read_tag(TAG_SEQUENCE)?)?;
+        let signature = bit_string_bytes(&reader.read_tag(TAG_BIT_STRING)?)?;
+
+        let mut reader = Reader::new(tbs.content);
+        reader.read_optional(tag_context(0)); // version
+        let serial = reader.read_tag(TAG_INTEGER)?.content;
+        reader.read_tag(TAG_SEQUENCE)?; // signature, repeated
+        let issuer = reader.read_tag(TAG_SEQUENCE)?.raw;
+        reader.read_tag(TAG_SEQUENCE)?; // validity
+        let subject = reader.read_tag(TAG_SEQUENCE)?.raw;
+        let public_key_info = reader.read_tag(TAG_SEQUENCE)?;
+
+        let mut reader = Reader::new(public_key_info.content);
+        if algorithm(&reader.read_tag(TAG_SEQUENCE)?)? != OID_RSA_ENCRYPTION {
+            return None;
+        }
+        let public_key = bit_string_bytes(&reader.read_tag(TAG_BIT_STRING)?)?;
+        let mut reader = Reader::new(Reader::new(public_key).read_tag(TAG_SEQUENCE)?.content);
+        let modulus = unsigned_integer(&reader.read_tag(TAG_INTEGER)?);
+        let exponent = unsigned_integer(&reader.read_tag(TAG_INTEGER)?);
+
+        Some(Certificate {
+            raw: certificate.raw,
+            tbs: tbs.raw,
+            serial,
+            issuer,
+            subject,
+            modulus,
+            exponent,
+            signature_algorithm,
+            signature,
+        })
+    }
+
+    /// Check a signature of a SHA-256 digest made with the key of the certificate
+    pub fn verify(&self, digest: &[u8; sha256::DIGEST_SIZE], signature: &[u8]) -> bool {
+        rsa::verify_sha256(self.modulus, self.exponent, signature, digest)
+    }
+
+    /// The SHA-256 digest of the TBSCertificate, which dbx revokes certificates by
+    pub fn tbs_digest(&self) -> [u8; sha256::DIGEST_SIZE] {
+        sha256::digest(self.tbs)
+    }
+
+    /// Check that the certificate was issued by another one
+    pub fn is_signed_by(&self, issuer: &Certificate) -> bool {
+        self.issuer == issuer.subject
+            && self.signature_algorithm == OID_SHA256_WITH_RSA_ENCRYPTION
+            && issuer.verify(&self.tbs_digest(), self.signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_certificate() {
+        // A self-signed root, and a leaf it issued
+        let root = Certificate::parse(include_bytes!("testdata/root.der")).unwrap();
+        let leaf = Certificate::parse(include_bytes!("testdata/leaf.der")).unwrap();
+        assert_eq!(root.issuer, root.subject);
+        assert_eq!(leaf.issuer, root.subject);
+        assert!(root.is_signed_by(&root));
+        assert!(leaf.is_signed_by(&root));
+        assert!(!root.is_signed_by(&leaf));
+        assert!(!leaf.is_signed_by(&leaf));
+
+        let mut tampered = *include_bytes!("testdata/leaf.der");
+        let position = tampered.len() / 3;
+        tampered[position] ^= 1;
+        match Certificate::parse(&tampered) {
+            Some(leaf) => assert!(!leaf.is_signed_by(&root)),
+            None => {}
+        }
+    }
+}
@@ -29,6 +29,28 @@ pub struct MemoryDescriptor {
     pub attribute: u64,
 }
 
+fn runtime_attribute(memory_type: MemoryType) -> u64 {
+    match memory_type {
+        MemoryType::RuntimeServicesCode | MemoryType::RuntimeServicesData => {
+            r_efi::efi::MEMORY_RUNTIME
+        }
+        _ => 0,
+    }
+}
+
+// The types of the memory AllocatePages gives, which FreePages takes back.
+// Reserved and MMIO ranges are only ever added by the firmware.
+fn is_allocatable(memory_type: u32) -> bool {
+    memory_type == MemoryType::LoaderCode as u32
+        || memory_type == MemoryType::LoaderData as u32
+        || memory_type == MemoryType::BootServicesCode as u32
+        || memory_type == MemoryType::BootServicesData as u32
+        || memory_type == MemoryType::RuntimeServicesCode as u32
+        || memory_type == MemoryType::RuntimeServicesData as u32
+        || memory_type == MemoryType::AcpiReclaimMemory as u32
+        || memory_type == MemoryType::AcpiMemoryNvs as u32
+}
+
 #[derive(Default, Clone, Copy)]
 struct Allocation {
     in_use: bool,
@@ -234,6 +256,7 @@ impl Allocator {
         // Identical special case
         if self.allocations[dest].descriptor.number_of_pages == page_count {
             self.allocations[dest].descriptor.r#type = memory_type as u32;
+            self.allocations[dest].descriptor.attribute |= runtime_attribute(memory_type);
             return (
                 Status::SUCCESS,
                 self.allocations[dest].descriptor.physical_start,
@@ -289,12 +312,7 @@ impl Allocator {
         }
 
         self.allocations[assigned].descriptor.r#type = memory_type as u32;
-        self.allocations[assigned].descriptor.attribute |= match memory_type {
-            MemoryType::RuntimeServicesCode | MemoryType::RuntimeServicesData => {
-                r_efi::efi::MEMORY_RUNTIME
-            }
-            _ => 0,
-        };
+        self.allocations[assigned].descriptor.attribute |= runtime_attribute(memory_type);
 
         (
             Status::SUCCESS,
@@ -337,22 +355,90 @@ impl Allocator {
         }
     }
 
-    pub fn free_pages(&mut self, address: u64) -> Status {
-        //log!("free_pages : 0x{:016x}\n", address);
+    // Find the descriptor containing the whole range
+    fn find_allocation(&self, page_count: u64, address: u64) -> Option<usize> {
         let mut cur = self.first_allocation;
-
         while cur != None {
-            let a = &mut self.allocations[cur.unwrap()];
+            let descriptor = &self.allocations[cur.unwrap()].descriptor;
+            let alloc_bottom = descriptor.physical_start;
+            let alloc_top = alloc_bottom + PAGE_SIZE * descriptor.number_of_pages;
 
-            if address == a.descriptor.physical_start {
-                a.descriptor.r#type = MemoryType::ConventionalMemory as u32;
-                self.merge_free_memory();
-                return Status::SUCCESS;
+            if address >= alloc_bottom && address < alloc_top {
+                if address + PAGE_SIZE * page_count <= alloc_top {
+                    return cur;
+                }
+                return None;
+            }
+            cur = self.allocations[cur.unwrap()].next_allocation;
+        }
+
+        None
+    }
+
+    // Any page-aligned range of an allocation can be freed, the rest of it
+    // stays allocated.
+    pub fn free_pages(&mut self, address: u64, page_count: u64) -> Status {
+        //log!("free_pages : 0x{:016x} ({})\n", address, page_count);
+        if address % PAGE_SIZE != 0 || page_count == 0 {
+            return Status::INVALID_PARAMETER;
+        }
+        let end = match page_count.checked_mul(PAGE_SIZE).and_then(|size| address.checked_add(size)) {
+            Some(end) => end,
+            None => return Status::INVALID_PARAMETER,
+        };
+
+        let dest = match self.find_allocation(page_count, address) {
+            Some(dest) => dest,
+            None => {
+                log!("free_pages: 0x{:016x}-0x{:016x} is not allocated\n", address, end - 1);
+                return Status::NOT_FOUND;
+            }
+        };
+        match self.allocations[dest].descriptor.r#type {
+            t if t == MemoryType::ConventionalMemory as u32 => {
+                log!("free_pages: 0x{:016x}-0x{:016x} is already free\n", address, end - 1);
+                return Status::NOT_FOUND;
+            }
+            t if !is_allocatable(t) => {
+                log!("free_pages: 0x{:016x}-0x{:016x} is not allocated memory\n", address, end - 1);
+                return Status::NOT_FOUND;
+            }
+            _ => {}
+        }
+
+        // Split off the pages before the range, then the ones after it
+        let mut freed = dest;
+        if address > self.allocations[dest].descriptor.physical_start {
+            let pages = (address - self.allocations[dest].descriptor.physical_start) / PAGE_SIZE;
+            match self.split_allocation(dest, pages) {
+                Some(split) => freed = split,
+                None => return Status::OUT_OF_RESOURCES,
+            }
+        }
+        if self.allocations[freed].descriptor.number_of_pages > page_count {
+            if self.split_allocation(freed, page_count) == None {
+                // Undo the first split, the range stays allocated
+                self.merge_allocation(dest);
+                return Status::OUT_OF_RESOURCES;
             }
-            cur = a.next_allocation;
         }
 
-        Status::NOT_FOUND
+        self.key += 1;
+        let a = &mut self.allocations[freed];
+        a.descriptor.r#type = MemoryType::ConventionalMemory as u32;
+        a.descriptor.attribute &= !r_efi::efi::MEMORY_RUNTIME;
+        self.merge_free_memory();
+        Status::SUCCESS
+    }
+
+    // Merge an allocation with the one after it, which it was split from
+    fn merge_allocation(&mut self, current: usize) {
+        if let Some(next) = self.allocations[current].next_allocation {
+            self.allocations[current].descriptor.number_of_pages +=
+                self.allocations[next].descriptor.number_of_pages;
+            self.allocations[current].next_allocation = self.allocations[next].next_allocation;
+            self.allocations[next].in_use = false;
+        }
     }
 
     pub fn get_descriptor_count(&self) -> usize {
@@ -382,31 +468,113 @@ impl Allocator {
         count
     }
 
-    #[cfg(not(test))]
+    // The regions the OS keeps mapped for the runtime services, None when
+    // there are more than out holds
+    pub fn get_runtime_descriptors(&self, out: &mut [MemoryDescriptor]) -> Option<usize> {
+        let mut count = 0;
+        let mut cur = self.first_allocation;
+
+        while cur != None {
+            let descriptor = &self.allocations[cur.unwrap()].descriptor;
+            if (descriptor.attribute & r_efi::efi::MEMORY_RUNTIME) != 0 {
+                *out.get_mut(count)? = *descriptor;
+                count += 1;
+            }
+            cur = self.allocations[cur.unwrap()].next_allocation;
+        }
+
+        Some(count)
+    }
+
+    // Whether descriptor names one of the runtime regions, as it is in the
+    // memory map.
+    pub fn is_runtime_region(&self, descriptor: &MemoryDescriptor) -> bool {
+        let mut cur = self.first_allocation;
+        while cur != None {
+            let allocation = &self.allocations[cur.unwrap()].descriptor;
+            if (allocation.attribute & r_efi::efi::MEMORY_RUNTIME) != 0
+                && allocation.physical_start == descriptor.physical_start
+                && allocation.number_of_pages == descriptor.number_of_pages
+            {
+                return true;
+            }
+            cur = self.allocations[cur.unwrap()].next_allocation;
+        }
+
+        false
+    }
+
+    pub fn runtime_region_count(&self) -> usize {
+        let mut count = 0;
+        let mut cur = self.first_allocation;
+        while cur != None {
+            if (self.allocations[cur.unwrap()].descriptor.attribute & r_efi::efi::MEMORY_RUNTIME) != 0 {
+                count += 1;
+            }
+            cur = self.allocations[cur.unwrap()].next_allocation;
+        }
+
+        count
+    }
+
+    // Set the virtual addresses of the runtime regions. Nothing is updated
+    // unless every descriptor names a runtime region.
     pub fn update_virtual_addresses(&mut self, descriptors: &[MemoryDescriptor]) -> Status {
-        let mut i = 0;
+        if !descriptors.iter().all(|descriptor| self.is_runtime_region(descriptor)) {
+            return Status::NOT_FOUND;
+        }
 
-        'outer: while i < descriptors.len() {
+        for descriptor in descriptors {
             let mut cur = self.first_allocation;
             while cur != None {
-                if self.allocations[cur.unwrap()].descriptor.physical_start
-                    == descriptors[i].physical_start
+                let allocation = &mut self.allocations[cur.unwrap()].descriptor;
+                if (allocation.attribute & r_efi::efi::MEMORY_RUNTIME) != 0
+                    && allocation.physical_start == descriptor.physical_start
                 {
-                    self.allocations[cur.unwrap()].descriptor.virtual_start =
-                        descriptors[i].virtual_start;
-                    i += 1;
-                    continue 'outer;
+                    allocation.virtual_start = descriptor.virtual_start;
+                    break;
                 }
-
                 cur = self.allocations[cur.unwrap()].next_allocation;
             }
-
-            return Status::NOT_FOUND;
         }
 
         Status::SUCCESS
     }
 
+    // Translate a physical address in a runtime region to the virtual address
+    // assigned by update_virtual_addresses().
+    pub fn convert_pointer(&self, address: u64) -> Option<u64> {
+        let mut cur = self.first_allocation;
+        while cur != None {
+            let descriptor = &self.allocations[cur.unwrap()].descriptor;
+            if (descriptor.attribute & r_efi::efi::MEMORY_RUNTIME) != 0
+                && address >= descriptor.physical_start
+                && address < descriptor.physical_start + descriptor.number_of_pages * PAGE_SIZE
+            {
+                return Some(address - descriptor.physical_start + descriptor.virtual_start);
+            }
+            cur = self.allocations[cur.unwrap()].next_allocation;
+        }
+
+        None
+    }
+
+    // The type of the memory an address is in
+    pub fn get_memory_type(&self, address: u64) -> Option<u32> {
+        let mut cur = self.first_allocation;
+        while cur != None {
+            let descriptor = &self.allocations[cur.unwrap()].descriptor;
+            if address >= descriptor.physical_start
+                && address < descriptor.physical_start + descriptor.number_of_pages * PAGE_SIZE
+            {
+                return Some(descriptor.r#type);
+            }
+            cur = self.allocations[cur.unwrap()].next_allocation;
+        }
+
+        None
+    }
+
     #[cfg(not(test))]
     pub fn get_map_key(&self) -> usize {
         self.key
@@ -423,7 +591,7 @@ impl Allocator {
 
 #[cfg(test)]
 mod tests {
-    use super::Allocator;
+    use super::{Allocator, MemoryDescriptor};
     use r_efi::efi::{AllocateType, MemoryType, Status};
 
     fn add_initial_allocations(allocator: &mut Allocator) {
@@ -835,10 +1003,217 @@ mod tests {
         );
         assert_eq!(descriptors[2].r#type, MemoryType::ConventionalMemory as u32);
 
-        assert_eq!(allocator.free_pages(0x1000), Status::SUCCESS);
+        assert_eq!(allocator.free_pages(0x1000, 1), Status::SUCCESS);
 
         let count = allocator.get_descriptors(&mut descriptors);
 
         assert_eq!(count, 4);
     }
+
+    #[test]
+    fn test_free_partial_pages() {
+        let mut allocator = Allocator::new();
+
+        add_initial_allocations(&mut allocator);
+
+        let mut descriptors: [super::MemoryDescriptor; super::MAX_ALLOCATIONS] =
+            unsafe { std::mem::zeroed() };
+
+        // 8 pages at 0x10000
+        assert_eq!(
+            allocator.allocate_pages(
+                AllocateType::AllocateAddress,
+                MemoryType::LoaderData,
+                8,
+                0x10000
+            ),
+            (Status::SUCCESS, 0x10000)
+        );
+        let key = allocator.key;
+
+        // Not page aligned, empty, or not within the allocation
+        assert_eq!(allocator.free_pages(0x10800, 1), Status::INVALID_PARAMETER);
+        assert_eq!(allocator.free_pages(0x10000, 0), Status::INVALID_PARAMETER);
+        assert_eq!(allocator.free_pages(0x17000, 2), Status::NOT_FOUND);
+        assert_eq!(allocator.free_pages(0xa0000, 1), Status::NOT_FOUND);
+        assert_eq!(allocator.free_pages(3584 * 1024 * 1024, 1), Status::NOT_FOUND);
+        assert_eq!(allocator.key, key);
+
+        // The first page, the last two, then one in the middle
+        assert_eq!(allocator.free_pages(0x10000, 1), Status::SUCCESS);
+        assert_eq!(allocator.free_pages(0x16000, 2), Status::SUCCESS);
+        assert_eq!(allocator.free_pages(0x13000, 1), Status::SUCCESS);
+        assert_ne!(allocator.key, key);
+
+        let count = allocator.get_descriptors(&mut descriptors);
+        assert_eq!(count, 8);
+
+        assert_eq!(descriptors[0].physical_start, 0);
+        assert_eq!(descriptors[0].number_of_pages, 0x11);
+        assert_eq!(descriptors[0].r#type, MemoryType::ConventionalMemory as u32);
+
+        assert_eq!(descriptors[1].physical_start, 0x11000);
+        assert_eq!(descriptors[1].number_of_pages, 2);
+        assert_eq!(descriptors[1].r#type, MemoryType::LoaderData as u32);
+
+        assert_eq!(descriptors[2].physical_start, 0x13000);
+        assert_eq!(descriptors[2].number_of_pages, 1);
+        assert_eq!(descriptors[2].r#type, MemoryType::ConventionalMemory as u32);
+
+        assert_eq!(descriptors[3].physical_start, 0x14000);
+        assert_eq!(descriptors[3].number_of_pages, 2);
+        assert_eq!(descriptors[3].r#type, MemoryType::LoaderData as u32);
+
+        assert_eq!(descriptors[4].physical_start, 0x16000);
+        assert_eq!(
+            descriptors[4].number_of_pages,
+            (0x9fc00 / super::PAGE_SIZE) - 0x16
+        );
+        assert_eq!(descriptors[4].r#type, MemoryType::ConventionalMemory as u32);
+
+        // Double free, and a range over allocated and free pages
+        assert_eq!(allocator.free_pages(0x13000, 1), Status::NOT_FOUND);
+        assert_eq!(allocator.free_pages(0x12000, 2), Status::NOT_FOUND);
+
+        assert_eq!(allocator.free_pages(0x11000, 2), Status::SUCCESS);
+        assert_eq!(allocator.free_pages(0x14000, 2), Status::SUCCESS);
+        assert_eq!(allocator.get_descriptors(&mut descriptors), 4);
+    }
+
+    #[test]
+    fn test_convert_pointer() {
+        let mut allocator = Allocator::new();
+
+        add_initial_allocations(&mut allocator);
+
+        let (status, address) = allocator.allocate_pages(
+            AllocateType::AllocateAnyPages,
+            MemoryType::RuntimeServicesData,
+            2,
+            0,
+        );
+        assert_eq!(status, Status::SUCCESS);
+
+        let (status, boot_address) = allocator.allocate_pages(
+            AllocateType::AllocateAnyPages,
+            MemoryType::BootServicesData,
+            1,
+            0,
+        );
+        assert_eq!(status, Status::SUCCESS);
+
+        let mut cur = allocator.first_allocation;
+        while cur != None {
+            let descriptor = &mut allocator.allocations[cur.unwrap()].descriptor;
+            if descriptor.physical_start == address {
+                assert_ne!(descriptor.attribute & r_efi::efi::MEMORY_RUNTIME, 0);
+                descriptor.virtual_start = 0xffff_8000_0000_0000;
+            }
+            cur = allocator.allocations[cur.unwrap()].next_allocation;
+        }
+
+        assert_eq!(
+            allocator.convert_pointer(address + 0x1010),
+            Some(0xffff_8000_0000_1010)
+        );
+        assert_eq!(allocator.convert_pointer(address + 0x2000), None);
+        assert_eq!(allocator.convert_pointer(boot_address), None);
+
+        // Freed runtime memory is no longer converted
+        assert_eq!(allocator.free_pages(address, 2), Status::SUCCESS);
+        assert_eq!(allocator.convert_pointer(address), None);
+    }
+
+    #[test]
+    fn test_update_virtual_addresses() {
+        let mut allocator = Allocator::new();
+
+        add_initial_allocations(&mut allocator);
+
+        let (status, code) = allocator.allocate_pages(
+            AllocateType::AllocateAnyPages,
+            MemoryType::RuntimeServicesCode,
+            1,
+            0,
+        );
+        assert_eq!(status, Status::SUCCESS);
+        let (status, data) = allocator.allocate_pages(
+            AllocateType::AllocateAnyPages,
+            MemoryType::RuntimeServicesData,
+            2,
+            0,
+        );
+        assert_eq!(status, Status::SUCCESS);
+        assert_eq!(allocator.runtime_region_count(), 2);
+
+        let mut descriptors = [MemoryDescriptor::default(); 2];
+        assert_eq!(allocator.get_runtime_descriptors(&mut descriptors), Some(2));
+        for descriptor in descriptors.iter_mut() {
+            descriptor.virtual_start = descriptor.physical_start + 0xffff_8000_0000_0000;
+        }
+
+        // A region that is not in the memory map leaves all of them alone
+        let mut unknown = descriptors;
+        unknown[1].number_of_pages = 1;
+        assert!(!allocator.is_runtime_region(&unknown[1]));
+        assert_eq!(allocator.update_virtual_addresses(&unknown), Status::NOT_FOUND);
+        assert_eq!(allocator.convert_pointer(code), Some(0));
+
+        assert_eq!(allocator.update_virtual_addresses(&descriptors), Status::SUCCESS);
+        assert_eq!(allocator.convert_pointer(code), Some(code + 0xffff_8000_0000_0000));
+        assert_eq!(allocator.convert_pointer(data + 0x1000), Some(data + 0xffff_8000_0000_1000));
+    }
+
+    #[test]
+    fn test_get_memory_type() {
+        let mut allocator = Allocator::new();
+
+        add_initial_allocations(&mut allocator);
+
+        assert_eq!(
+            allocator.allocate_pages(
+                AllocateType::AllocateAddress,
+                MemoryType::BootServicesData,
+                2,
+                0x1000
+            ),
+            (Status::SUCCESS, 0x1000)
+        );
+
+        assert_eq!(
+            allocator.get_memory_type(0x2fff),
+            Some(MemoryType::BootServicesData as u32)
+        );
+        assert_eq!(
+            allocator.get_memory_type(0x3000),
+            Some(MemoryType::ConventionalMemory as u32)
+        );
+        assert_eq!(
+            allocator.get_memory_type(3584 * 1024 * 1024),
+            Some(MemoryType::MemoryMappedIO as u32)
+        );
+        assert_eq!(allocator.get_memory_type(0xa0000), None);
+    }
+
+    #[test]
+    fn test_get_runtime_descriptors() {
+        let mut allocator = Allocator::new();
+
+        add_initial_allocations(&mut allocator);
+
+        let mut descriptors = [MemoryDescriptor::default(); 2];
+        assert_eq!(allocator.get_runtime_descriptors(&mut descriptors), Some(0));
+
+        allocator.allocate_pages(AllocateType::AllocateAddress, MemoryType::RuntimeServicesData, 1, 0x1000);
+        allocator.allocate_pages(AllocateType::AllocateAddress, MemoryType::BootServicesData, 1, 0x2000);
+        allocator.allocate_pages(AllocateType::AllocateAddress, MemoryType::RuntimeServicesCode, 2, 0x3000);
+        assert_eq!(allocator.get_runtime_descriptors(&mut descriptors), Some(2));
+        assert_eq!(descriptors[0].physical_start, 0x1000);
+        assert_eq!(descriptors[0].r#type, MemoryType::RuntimeServicesData as u32);
+        assert_eq!(descriptors[1].physical_start, 0x3000);
+        assert_eq!(descriptors[1].number_of_pages, 2);
+
+        allocator.allocate_pages(AllocateType::AllocateAddress, MemoryType::RuntimeServicesData, 1, 0x6000);
+        assert_eq!(allocator.get_runtime_descriptors(&mut descriptors), None);
+    }
 }
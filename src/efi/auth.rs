@@ -0,0 +1,751 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use r_efi::efi;
+use r_efi::efi::{Guid, Status};
+
+use core::sync::atomic::Ordering;
+
+use crate::crypto::pkcs7::SignedData;
+use crate::crypto::sha256;
+use crate::crypto::x509::Certificate;
+
+use super::variable::{AuthInfo, Variable, GLOBAL_VARIABLE_GUID};
+
+pub const IMAGE_SECURITY_DATABASE_GUID: Guid = Guid::from_fields(
+    0xd719b2cb, 0x3d3a, 0x4596, 0xa3, 0xbc, &[0xda, 0xd0, 0x0e, 0x67, 0x65, 0x6f]
+);
+
+pub const CERT_SHA256_GUID: Guid = Guid::from_fields(
+    0xc1c41626, 0x504c, 0x4092, 0xac, 0xa9, &[0x41, 0xf9, 0x36, 0x93, 0x43, 0x28]
+);
+
+pub const CERT_RSA2048_GUID: Guid = Guid::from_fields(
+    0x3c5766e8, 0x269c, 0x4e34, 0xaa, 0x14, &[0xed, 0x77, 0x6e, 0x85, 0xb3, 0xb6]
+);
+
+pub const CERT_X509_GUID: Guid = Guid::from_fields(
+    0xa5c059a1, 0x94e4, 0x4aa7, 0x87, 0xb5, &[0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72]
+);
+
+pub const CERT_X509_SHA256_GUID: Guid = Guid::from_fields(
+    0x3bd2a492, 0x96c0, 0x4079, 0xb4, 0x20, &[0xfc, 0xf9, 0x8e, 0xf1, 0x03, 0xed]
+);
+
+/// The vendor of the variable keeping the Secure Boot mode, which only the firmware writes
+pub const SECURE_BOOT_MODE_GUID: Guid = Guid::from_fields(
+    0x3f6c9a2e, 0x7d41, 0x4b85, 0x9e, 0x12, &[0x5a, 0xc8, 0x0d, 0x37, 0xe6, 0x94]
+);
+
+pub const CERT_TYPE_PKCS7_GUID: Guid = Guid::from_fields(
+    0x4aafd29d, 0x68df, 0x49ee, 0x8a, 0xa9, &[0x34, 0x7d, 0x37, 0x56, 0x65, 0xa7]
+);
+
+const WIN_CERT_REVISION: u16 = 0x0200;
+const WIN_CERT_TYPE_EFI_GUID: u16 = 0x0ef1;
+
+const TIME_SIZE: usize = 16;
+// EFI_SIGNATURE_LIST without its header and signatures
+pub const SIGNATURE_LIST_SIZE: usize = 28;
+// The owner GUID starting every EFI_SIGNATURE_DATA
+pub const SIGNATURE_OWNER_SIZE: usize = 16;
+
+// What PK, KEK and the signature databases must be set with
+const KEY_ATTRIBUTES: u32 = efi::VARIABLE_NON_VOLATILE
+    | efi::VARIABLE_BOOTSERVICE_ACCESS
+    | efi::VARIABLE_RUNTIME_ACCESS
+    | efi::VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS;
+const MODE_ATTRIBUTES: u32 = efi::VARIABLE_BOOTSERVICE_ACCESS | efi::VARIABLE_RUNTIME_ACCESS;
+const STORED_MODE_ATTRIBUTES: u32 = efi::VARIABLE_NON_VOLATILE | efi::VARIABLE_BOOTSERVICE_ACCESS;
+
+// The values of the SecureBootMode variable, for the modes PK alone does not tell
+const STORED_USER_MODE: u8 = 0;
+const STORED_AUDIT_MODE: u8 = 1;
+const STORED_DEPLOYED_MODE: u8 = 2;
+
+/// The Secure Boot modes. The volatile SetupMode, SecureBoot, AuditMode and
+/// DeployedMode are derived at boot from PK and the non-volatile SecureBootMode
+/// variable, so Audit and Deployed mode last across resets.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mode {
+    Setup,
+    User,
+    Audit,
+    Deployed,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum Protection {
+    PlatformKey,
+    KeyExchangeKey,
+    SignatureDatabase,
+    // Any other time based authenticated variable, which only its creator can update
+    Private,
+}
+
+// The longest of the variable names used here
+const MAX_NAME_LENGTH: usize = 16;
+
+// A variable name as the variable services keep them: UCS-2, without its terminator
+pub struct Name {
+    bytes: [u8; 2 * MAX_NAME_LENGTH],
+    size: usize,
+}
+
+impl Name {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[.. self.size]
+    }
+}
+
+pub fn variable_name(name: &str) -> Name {
+    let mut var_name = Name { bytes: [0; 2 * MAX_NAME_LENGTH], size: 2 * name.len() };
+    for (index, c) in name.bytes().enumerate() {
+        var_name.bytes[2 * index] = c;
+    }
+    var_name
+}
+
+fn is_variable(var_name: &[u8], var_guid: &[u8; 16], name: &str, guid: &Guid) -> bool {
+    var_name == variable_name(name).as_bytes() && var_guid == guid.as_bytes()
+}
+
+fn protection(var_name: &[u8], var_guid: &[u8; 16]) -> Protection {
+    if is_variable(var_name, var_guid, "PK", &GLOBAL_VARIABLE_GUID) {
+        Protection::PlatformKey
+    } else if is_variable(var_name, var_guid, "KEK", &GLOBAL_VARIABLE_GUID) {
+        Protection::KeyExchangeKey
+    } else if ["db", "dbx", "dbt", "dbr"].iter().any(|name| is_variable(var_name, var_guid, name, &IMAGE_SECURITY_DATABASE_GUID)) {
+        Protection::SignatureDatabase
+    } else {
+        Protection::Private
+    }
+}
+
+/// The data of a variable
+pub fn get_data<'a>(variable: &'a Variable, name: &str, guid: &Guid) -> Option<&'a [u8]> {
+    match variable.get_variable(variable_name(name).as_bytes(), guid.as_bytes()) {
+        (Status::SUCCESS, _, data) => Some(data),
+        _ => None,
+    }
+}
+
+fn set_u8(variable: &mut Variable, name: &str, value: u8) {
+    variable.set_variable(variable_name(name).as_bytes(), GLOBAL_VARIABLE_GUID.as_bytes(), MODE_ATTRIBUTES, &[value]);
+}
+
+pub fn get_mode(variable: &Variable) -> Mode {
+    let platform_key = get_data(variable, "PK", &GLOBAL_VARIABLE_GUID).is_some();
+    let stored = get_data(variable, "SecureBootMode", &SECURE_BOOT_MODE_GUID);
+    match (platform_key, stored) {
+        (false, Some([STORED_AUDIT_MODE])) => Mode::Audit,
+        (false, _) => Mode::Setup,
+        (true, Some([STORED_DEPLOYED_MODE])) => Mode::Deployed,
+        (true, _) => Mode::User,
+    }
+}
+
+// Keep a mode across resets, writing the store only when it changes
+fn store_mode(variable: &mut Variable, mode: Mode) {
+    let stored = match mode {
+        Mode::Audit => STORED_AUDIT_MODE,
+        Mode::Deployed => STORED_DEPLOYED_MODE,
+        Mode::Setup | Mode::User => STORED_USER_MODE,
+    };
+    if get_data(variable, "SecureBootMode", &SECURE_BOOT_MODE_GUID) == Some(&[stored]) {
+        return;
+    }
+    let status = variable.set_variable(variable_name("SecureBootMode").as_bytes(), SECURE_BOOT_MODE_GUID.as_bytes(),
+                                       STORED_MODE_ATTRIBUTES, &[stored]);
+    if status != Status::SUCCESS {
+        log!("auth: cannot keep the Secure Boot mode: {:?}\n", status);
+    }
+}
+
+// Keep a mode, and publish it through SetupMode, SecureBoot, AuditMode and DeployedMode
+fn set_mode(variable: &mut Variable, mode: Mode) {
+    store_mode(variable, mode);
+    set_u8(variable, "SetupMode", (mode == Mode::Setup || mode == Mode::Audit) as u8);
+    set_u8(variable, "SecureBoot", (mode == Mode::User || mode == Mode::Deployed) as u8);
+    set_u8(variable, "AuditMode", (mode == Mode::Audit) as u8);
+    set_u8(variable, "DeployedMode", (mode == Mode::Deployed) as u8);
+}
+
+/// Move to a mode from the firmware setup, where the user is physically present.
+/// Unlike through SetVariable, Deployed mode can be left, and PK is removed to go
+/// back to Setup or Audit mode.
+pub fn set_user_mode(variable: &mut Variable, new_mode: Mode) -> Status {
+    let platform_key = get_data(variable, "PK", &GLOBAL_VARIABLE_GUID).is_some();
+    match new_mode {
+        Mode::Setup | Mode::Audit if platform_key => {
+            let status = variable.set_variable(variable_name("PK").as_bytes(), GLOBAL_VARIABLE_GUID.as_bytes(), 0, &[]);
+            if status != Status::SUCCESS {
+                return status;
+            }
+        },
+        // There must be a PK to enroll first
+        Mode::User | Mode::Deployed if !platform_key => return Status::NOT_FOUND,
+        _ => {},
+    }
+    log!("auth: Secure Boot in {:?} mode\n", new_mode);
+    set_mode(variable, new_mode);
+    Status::SUCCESS
+}
+
+/// Derive the mode from PK and the stored mode, once the non-volatile variables are loaded
+pub fn initialize(variable: &mut Variable) {
+    let mode = get_mode(variable);
+    log!("auth: Secure Boot in {:?} mode\n", mode);
+    set_mode(variable, mode);
+}
+
+/// Walk the EFI_SIGNATURE_LISTs in data, passing the type, the signature size and the
+/// signatures of each list to found. Returns false if they are malformed.
+pub fn for_each_signature_list(data: &[u8], found: &mut dyn FnMut(&[u8; 16], usize, &[u8])) -> bool {
+    let read_u32 = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]) as usize;
+    let mut offset = 0;
+    while offset < data.len() {
+        if data.len() - offset < SIGNATURE_LIST_SIZE {
+            return false;
+        }
+        let mut signature_type = [0u8; 16];
+        signature_type.copy_from_slice(&data[offset .. offset + 16]);
+        let list_size = read_u32(offset + 16);
+        let header_size = read_u32(offset + 20);
+        let signature_size = read_u32(offset + 24);
+        if list_size > data.len() - offset || list_size < SIGNATURE_LIST_SIZE + header_size || signature_size <= SIGNATURE_OWNER_SIZE
+           || (list_size - SIGNATURE_LIST_SIZE - header_size) % signature_size != 0 {
+            return false;
+        }
+        let expected_size = if signature_type == *CERT_SHA256_GUID.as_bytes() {
+            Some(SIGNATURE_OWNER_SIZE + sha256::DIGEST_SIZE)
+        } else if signature_type == *CERT_RSA2048_GUID.as_bytes() {
+            Some(SIGNATURE_OWNER_SIZE + 256)
+        } else if signature_type == *CERT_X509_SHA256_GUID.as_bytes() {
+            Some(SIGNATURE_OWNER_SIZE + sha256::DIGEST_SIZE + TIME_SIZE)
+        } else {
+            None
+        };
+        if expected_size.is_some() && expected_size != Some(signature_size) {
+            return false;
+        }
+        found(&signature_type, signature_size, &data[offset + SIGNATURE_LIST_SIZE + header_size .. offset + list_size]);
+        offset += list_size;
+    }
+    true
+}
+
+/// Call found with the certificate of every X.509 signature in the lists
+pub fn for_each_certificate(data: &[u8], found: &mut dyn FnMut(&[u8])) {
+    for_each_signature_list(data, &mut |signature_type, signature_size, signatures| {
+        if *signature_type == *CERT_X509_GUID.as_bytes() {
+            for signature in signatures.chunks(signature_size) {
+                found(&signature[SIGNATURE_OWNER_SIZE ..]);
+            }
+        }
+    });
+}
+
+// PK holds a single X.509 certificate, KEK certificates or RSA keys, and every
+// certificate of either must be usable to check signatures.
+fn is_valid_payload(protection: Protection, payload: &[u8]) -> bool {
+    let mut lists = 0;
+    let mut signatures = 0;
+    let mut usable = true;
+    let valid = for_each_signature_list(payload, &mut |signature_type, signature_size, data| {
+        lists += 1;
+        signatures += data.len() / signature_size;
+        let is_x509 = *signature_type == *CERT_X509_GUID.as_bytes();
+        let is_rsa2048 = *signature_type == *CERT_RSA2048_GUID.as_bytes();
+        match protection {
+            Protection::PlatformKey => usable &= is_x509,
+            Protection::KeyExchangeKey => usable &= is_x509 || is_rsa2048,
+            _ => {},
+        }
+    });
+    if !valid || !usable {
+        return false;
+    }
+
+    let mut parsed = true;
+    if protection == Protection::PlatformKey || protection == Protection::KeyExchangeKey {
+        for_each_certificate(payload, &mut |certificate| parsed &= Certificate::parse(certificate).is_some());
+    }
+    parsed && (protection != Protection::PlatformKey || (lists == 1 && signatures == 1))
+}
+
+/// Whether a certificate is one of the X.509 certificates of the lists, or was issued by one
+pub fn is_trusted_by(certificate: &Certificate, lists: &[u8]) -> bool {
+    let mut trusted = false;
+    for_each_certificate(lists, &mut |anchor| {
+        if trusted {
+            return;
+        }
+        if let Some(anchor) = Certificate::parse(anchor) {
+            trusted = certificate.raw == anchor.raw || certificate.is_signed_by(&anchor);
+        }
+    });
+    trusted
+}
+
+// Write the signatures of new lists that are not in the existing ones into data,
+// which follows the existing lists. Returns the size written.
+fn append_signature_lists(existing: &[u8], new: &[u8], data: &mut [u8]) -> Option<usize> {
+    let is_present = |signature_type: &[u8; 16], signature: &[u8]| {
+        let mut present = false;
+        for_each_signature_list(existing, &mut |existing_type, signature_size, signatures| {
+            present |= existing_type == signature_type && signature_size == signature.len()
+                && signatures.chunks(signature_size).any(|existing| existing[SIGNATURE_OWNER_SIZE ..] == signature[SIGNATURE_OWNER_SIZE ..]);
+        });
+        present
+    };
+
+    let mut size = 0;
+    let mut fits = true;
+    let valid = for_each_signature_list(new, &mut |signature_type, signature_size, signatures| {
+        // Only the signatures are kept, with an empty header
+        let list_start = size;
+        size += SIGNATURE_LIST_SIZE;
+        for signature in signatures.chunks(signature_size) {
+            if is_present(signature_type, signature) {
+                continue;
+            }
+            if size + signature_size > data.len() {
+                fits = false;
+                return;
+            }
+            data[size .. size + signature_size].copy_from_slice(signature);
+            size += signature_size;
+        }
+        if !fits || size == list_start + SIGNATURE_LIST_SIZE {
+            size = list_start;
+            return;
+        }
+        data[list_start .. list_start + 16].copy_from_slice(signature_type);
+        data[list_start + 16 .. list_start + 20].copy_from_slice(&((size - list_start) as u32).to_le_bytes());
+        data[list_start + 20 .. list_start + 24].copy_from_slice(&0u32.to_le_bytes());
+        data[list_start + 24 .. list_start + 28].copy_from_slice(&(signature_size as u32).to_le_bytes());
+    });
+    if valid && fits {
+        Some(size)
+    } else {
+        None
+    }
+}
+
+// The EFI_VARIABLE_AUTHENTICATION_2 starting the data of an authenticated write
+struct Authentication<'a> {
+    timestamp: [u8; TIME_SIZE],
+    signature: &'a [u8],
+    payload: &'a [u8],
+}
+
+fn parse_authentication(data: &[u8]) -> Option<Authentication> {
+    // EFI_TIME, then a WIN_CERTIFICATE_UEFI_GUID
+    let header_size = TIME_SIZE + 8 + 16;
+    if data.len() < header_size {
+        return None;
+    }
+    let length = u32::from_le_bytes([data[16], data[17], data[18], data[19]]) as usize;
+    let revision = u16::from_le_bytes([data[20], data[21]]);
+    let certificate_type = u16::from_le_bytes([data[22], data[23]]);
+    if revision != WIN_CERT_REVISION || certificate_type != WIN_CERT_TYPE_EFI_GUID
+       || &data[24 .. 40] != CERT_TYPE_PKCS7_GUID.as_bytes()
+       || length < header_size - TIME_SIZE || length > data.len() - TIME_SIZE {
+        return None;
+    }
+
+    let mut timestamp = [0u8; TIME_SIZE];
+    timestamp.copy_from_slice(&data[.. TIME_SIZE]);
+    Some(Authentication {
+        timestamp,
+        signature: &data[header_size .. TIME_SIZE + length],
+        payload: &data[TIME_SIZE + length ..],
+    })
+}
+
+// Only the date and time are set in the time stamps of authenticated writes
+fn is_valid_timestamp(timestamp: &[u8; TIME_SIZE]) -> bool {
+    timestamp[7 ..].iter().all(|b| *b == 0)
+}
+
+fn is_later(timestamp: &[u8; TIME_SIZE], than: &[u8; TIME_SIZE]) -> bool {
+    let key = |t: &[u8; TIME_SIZE]| (u16::from_le_bytes([t[0], t[1]]), t[2], t[3], t[4], t[5], t[6]);
+    key(timestamp) > key(than)
+}
+
+// The digest signed for an authenticated write
+fn authentication_digest(var_name: &[u8], var_guid: &[u8; 16], attributes: u32, authentication: &Authentication) -> [u8; sha256::DIGEST_SIZE] {
+    let mut sha256 = sha256::Sha256::new();
+    sha256.update(var_name);
+    sha256.update(var_guid);
+    sha256.update(&attributes.to_le_bytes());
+    sha256.update(&authentication.timestamp);
+    sha256.update(authentication.payload);
+    sha256.finish()
+}
+
+// AuditMode and DeployedMode can be set to move between modes, before ExitBootServices
+fn set_mode_variable(variable: &mut Variable, var_name: &[u8], attributes: u32, data: &[u8]) -> Status {
+    if attributes != MODE_ATTRIBUTES || data != [1] {
+        return Status::WRITE_PROTECTED;
+    }
+    if crate::efi::EXIT_BOOT_SERVICES.load(Ordering::SeqCst) {
+        return Status::WRITE_PROTECTED;
+    }
+
+    let mode = get_mode(variable);
+    let new_mode = if var_name == variable_name("AuditMode").as_bytes() {
+        match mode {
+            Mode::Setup | Mode::User | Mode::Audit => Mode::Audit,
+            Mode::Deployed => return Status::WRITE_PROTECTED,
+        }
+    } else if var_name == variable_name("DeployedMode").as_bytes() {
+        match mode {
+            Mode::User | Mode::Deployed => Mode::Deployed,
+            _ => return Status::WRITE_PROTECTED,
+        }
+    } else {
+        return Status::WRITE_PROTECTED;
+    };
+
+    if mode == Mode::User && new_mode == Mode::Audit {
+        // Audit mode has no platform key
+        let status = variable.set_variable(variable_name("PK").as_bytes(), GLOBAL_VARIABLE_GUID.as_bytes(), 0, &[]);
+        if status != Status::SUCCESS {
+            return status;
+        }
+    }
+    log!("auth: Secure Boot in {:?} mode\n", new_mode);
+    set_mode(variable, new_mode);
+    Status::SUCCESS
+}
+
+fn set_authenticated_variable(
+    variable: &mut Variable,
+    var_name: &[u8],
+    var_guid: &[u8; 16],
+    attributes: u32,
+    data: &[u8],
+    protection: Protection,
+) -> Status {
+    let authentication = match parse_authentication(data) {
+        Some(authentication) if is_valid_timestamp(&authentication.timestamp) => authentication,
+        _ => return Status::SECURITY_VIOLATION,
+    };
+    let payload = authentication.payload;
+    let append = (attributes & efi::VARIABLE_APPEND_WRITE) != 0;
+    let stored_attributes = attributes & !efi::VARIABLE_APPEND_WRITE;
+    if protection != Protection::Private {
+        if stored_attributes != KEY_ATTRIBUTES || (append && protection == Protection::PlatformKey) {
+            return Status::INVALID_PARAMETER;
+        }
+        if !payload.is_empty() && !is_valid_payload(protection, payload) {
+            return Status::INVALID_PARAMETER;
+        }
+    }
+
+    let (status, existing_attributes, _) = variable.get_variable(var_name, var_guid);
+    let exists = status == Status::SUCCESS;
+    if exists && existing_attributes != stored_attributes {
+        return Status::INVALID_PARAMETER;
+    }
+    let existing_auth = variable.get_auth_info(var_name, var_guid).unwrap_or_default();
+    if exists && !append && !is_later(&authentication.timestamp, &existing_auth.timestamp) {
+        return Status::SECURITY_VIOLATION;
+    }
+
+    // Anyone holding the keys in Setup and Audit modes, so only private variables are checked then
+    let mode = get_mode(variable);
+    let verify = protection == Protection::Private || mode == Mode::User || mode == Mode::Deployed;
+    let mut auth = AuthInfo { timestamp: authentication.timestamp, signer: [0; 32] };
+    if verify {
+        let signed_data = match SignedData::parse(authentication.signature) {
+            Some(signed_data) => signed_data,
+            None => return Status::SECURITY_VIOLATION,
+        };
+        let digest = authentication_digest(var_name, var_guid, attributes, &authentication);
+        let platform_key = get_data(variable, "PK", &GLOBAL_VARIABLE_GUID).unwrap_or(&[]);
+        let key_exchange_key = get_data(variable, "KEK", &GLOBAL_VARIABLE_GUID).unwrap_or(&[]);
+
+        let signer = match protection {
+            Protection::PlatformKey | Protection::KeyExchangeKey => {
+                signed_data.verify(&digest, &|certificate| is_trusted_by(certificate, platform_key))
+            },
+            Protection::SignatureDatabase => {
+                signed_data.verify(&digest, &|certificate| {
+                    is_trusted_by(certificate, key_exchange_key) || is_trusted_by(certificate, platform_key)
+                })
+            },
+            Protection::Private => {
+                // The certificate creating the variable is the only one allowed to update it
+                signed_data.verify(&digest, &|certificate| {
+                    !exists || sha256::digest(certificate.raw) == existing_auth.signer
+                })
+            },
+        };
+        match signer {
+            Some(signer) if protection == Protection::Private => auth.signer = sha256::digest(signer.raw),
+            Some(_) => {},
+            None => {
+                log!("auth: signature check failed\n");
+                return Status::SECURITY_VIOLATION;
+            },
+        }
+    }
+
+    let status = if append && exists {
+        if is_later(&existing_auth.timestamp, &auth.timestamp) {
+            auth.timestamp = existing_auth.timestamp;
+        }
+        variable.append_variable(var_name, var_guid, payload.len(), &auth, &mut |existing, data| {
+            if protection == Protection::Private {
+                data.copy_from_slice(payload);
+                payload.len()
+            } else {
+                // The payload was checked, and its signatures take no more room once appended
+                append_signature_lists(existing, payload, data).unwrap_or(0)
+            }
+        })
+    } else if payload.is_empty() {
+        variable.set_variable(var_name, var_guid, 0, &[])
+    } else {
+        variable.set_authenticated_variable(var_name, var_guid, stored_attributes, payload, &auth)
+    };
+    if status == Status::SUCCESS && protection == Protection::PlatformKey {
+        let new_mode = match (payload.is_empty(), mode) {
+            (true, _) => Mode::Setup,
+            (false, Mode::Setup) => Mode::User,
+            (false, Mode::Audit) => Mode::Deployed,
+            (false, mode) => mode,
+        };
+        if new_mode != mode {
+            log!("auth: Secure Boot in {:?} mode\n", new_mode);
+        }
+        set_mode(variable, new_mode);
+    }
+    status
+}
+
+/// SetVariable, enforcing time based authenticated writes and the Secure Boot
+/// key hierarchy: PK signs KEK, and KEK or PK sign the signature databases.
+pub fn set_variable(
+    variable: &mut Variable,
+    var_name: &[u8],
+    var_guid: &[u8; 16],
+    attributes: u32,
+    data: &[u8],
+) -> Status {
+    if ["SetupMode", "SecureBoot", "AuditMode", "DeployedMode"].iter().any(|name| is_variable(var_name, var_guid, name, &GLOBAL_VARIABLE_GUID)) {
+        return set_mode_variable(variable, var_name, attributes, data);
+    }
+    if var_guid == SECURE_BOOT_MODE_GUID.as_bytes() {
+        return Status::WRITE_PROTECTED;
+    }
+
+    let protection = protection(var_name, var_guid);
+    if (attributes & efi::VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS) != 0 {
+        return set_authenticated_variable(variable, var_name, var_guid, attributes, data, protection);
+    }
+
+    if protection != Protection::Private {
+        return Status::INVALID_PARAMETER;
+    }
+    let (status, existing_attributes, _) = variable.get_variable(var_name, var_guid);
+    if status == Status::SUCCESS && (existing_attributes & efi::VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS) != 0 {
+        return Status::SECURITY_VIOLATION;
+    }
+    variable.set_variable(var_name, var_guid, attributes, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::variable::{NvStore, MAX_VARIABLE_SIZE, VARIABLE_STORE_SIZE};
+
+    const AUTHENTICATED: u32 = KEY_ATTRIBUTES;
+
+    fn new_variable() -> Variable {
+        let mut variable = Variable::new();
+        variable.set_storage(Box::leak(vec![0u8; VARIABLE_STORE_SIZE].into_boxed_slice()), MAX_VARIABLE_SIZE);
+        variable
+    }
+
+    fn set(variable: &mut Variable, name: &str, guid: &Guid, data: &[u8]) -> Status {
+        set_variable(variable, variable_name(name).as_bytes(), guid.as_bytes(), AUTHENTICATED, data)
+    }
+
+    fn get(variable: &Variable, name: &str) -> Option<Vec<u8>> {
+        get_data(variable, name, &GLOBAL_VARIABLE_GUID).map(|data| data.to_vec())
+    }
+
+    // The non-volatile variables, as a store keeps them across resets
+    #[derive(Default)]
+    struct RamStore {
+        variables: Vec<(Vec<u8>, [u8; 16], u32, Vec<u8>)>,
+    }
+
+    impl NvStore for RamStore {
+        fn load(&mut self, _: &mut [u8], restore: &mut dyn FnMut(&[u8], &[u8; 16], u32, &[u8])) {
+            for (name, guid, attributes, data) in self.variables.iter() {
+                restore(name, guid, *attributes, data);
+            }
+        }
+        fn write_variable(&mut self, name: &[u8], guid: &[u8; 16], attributes: u32, data: &[&[u8]]) -> Status {
+            self.variables.retain(|(n, g, _, _)| n != name || g != guid);
+            let data = data.concat();
+            if !data.is_empty() {
+                self.variables.push((name.to_vec(), *guid, attributes, data));
+            }
+            Status::SUCCESS
+        }
+        fn size(&self) -> u64 {
+            VARIABLE_STORE_SIZE as u64
+        }
+        fn free_space(&self) -> u64 {
+            VARIABLE_STORE_SIZE as u64
+        }
+    }
+
+    // See testdata/make_auth_variables.py for how the writes are signed
+    #[test]
+    fn test_key_hierarchy() {
+        let mut variable = new_variable();
+        initialize(&mut variable);
+        assert_eq!(get_mode(&variable), Mode::Setup);
+        assert_eq!(get(&variable, "SetupMode"), Some(vec![1]));
+        assert_eq!(get(&variable, "SecureBoot"), Some(vec![0]));
+
+        // Without the authentication, or read-only
+        let pk = include_bytes!("testdata/pk.auth");
+        assert_eq!(
+            set_variable(&mut variable, variable_name("PK").as_bytes(), GLOBAL_VARIABLE_GUID.as_bytes(),
+                         AUTHENTICATED & !efi::VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS, &pk[..]),
+            Status::INVALID_PARAMETER
+        );
+        assert_eq!(set_variable(&mut variable, variable_name("SetupMode").as_bytes(), GLOBAL_VARIABLE_GUID.as_bytes(),
+                                MODE_ATTRIBUTES, &[0]), Status::WRITE_PROTECTED);
+
+        // Enrolling PK moves to user mode, then KEK must be signed by PK
+        assert_eq!(set(&mut variable, "PK", &GLOBAL_VARIABLE_GUID, pk), Status::SUCCESS);
+        assert_eq!(get_mode(&variable), Mode::User);
+        assert_eq!(get(&variable, "SecureBoot"), Some(vec![1]));
+        assert_eq!(set(&mut variable, "KEK", &GLOBAL_VARIABLE_GUID, include_bytes!("testdata/kek_by_other.auth")), Status::SECURITY_VIOLATION);
+        assert_eq!(set(&mut variable, "KEK", &GLOBAL_VARIABLE_GUID, include_bytes!("testdata/kek.auth")), Status::SUCCESS);
+        assert!(get(&variable, "KEK").is_some());
+        // No replay
+        assert_eq!(set(&mut variable, "KEK", &GLOBAL_VARIABLE_GUID, include_bytes!("testdata/kek.auth")), Status::SECURITY_VIOLATION);
+
+        // db is signed by KEK, and appending a hash already there changes nothing
+        assert_eq!(set(&mut variable, "db", &IMAGE_SECURITY_DATABASE_GUID, include_bytes!("testdata/db.auth")), Status::SUCCESS);
+        let db = get_data(&variable, "db", &IMAGE_SECURITY_DATABASE_GUID).unwrap().to_vec();
+        assert_eq!(
+            set_variable(&mut variable, variable_name("db").as_bytes(), IMAGE_SECURITY_DATABASE_GUID.as_bytes(),
+                         AUTHENTICATED | efi::VARIABLE_APPEND_WRITE, include_bytes!("testdata/db_append.auth")),
+            Status::SUCCESS
+        );
+        let appended = get_data(&variable, "db", &IMAGE_SECURITY_DATABASE_GUID).unwrap().to_vec();
+        assert_eq!(appended.len(), db.len() + SIGNATURE_LIST_SIZE + SIGNATURE_OWNER_SIZE + sha256::DIGEST_SIZE);
+        assert_eq!(&appended[.. db.len()], &db[..]);
+
+        // Deleting PK, signed by itself, goes back to setup mode
+        assert_eq!(set(&mut variable, "PK", &GLOBAL_VARIABLE_GUID, include_bytes!("testdata/pk_delete.auth")), Status::SUCCESS);
+        assert!(get(&variable, "PK").is_none());
+        assert_eq!(get_mode(&variable), Mode::Setup);
+
+        // Audit mode, then deployed mode once PK is enrolled
+        assert_eq!(set_variable(&mut variable, variable_name("DeployedMode").as_bytes(), GLOBAL_VARIABLE_GUID.as_bytes(),
+                                MODE_ATTRIBUTES, &[1]), Status::WRITE_PROTECTED);
+        assert_eq!(set_variable(&mut variable, variable_name("AuditMode").as_bytes(), GLOBAL_VARIABLE_GUID.as_bytes(),
+                                MODE_ATTRIBUTES, &[1]), Status::SUCCESS);
+        assert_eq!(get_mode(&variable), Mode::Audit);
+        assert_eq!(get(&variable, "SetupMode"), Some(vec![1]));
+        assert_eq!(set(&mut variable, "PK", &GLOBAL_VARIABLE_GUID, include_bytes!("testdata/pk_later.auth")), Status::SUCCESS);
+        assert_eq!(get_mode(&variable), Mode::Deployed);
+        assert_eq!(get(&variable, "DeployedMode"), Some(vec![1]));
+    }
+
+    #[test]
+    fn test_user_mode() {
+        let mut variable = new_variable();
+        initialize(&mut variable);
+        assert_eq!(set_user_mode(&mut variable, Mode::User), Status::NOT_FOUND);
+        assert_eq!(set_user_mode(&mut variable, Mode::Audit), Status::SUCCESS);
+        assert_eq!(get_mode(&variable), Mode::Audit);
+
+        // Deployed mode is only left by the user
+        assert_eq!(set(&mut variable, "PK", &GLOBAL_VARIABLE_GUID, include_bytes!("testdata/pk.auth")), Status::SUCCESS);
+        assert_eq!(set_user_mode(&mut variable, Mode::Deployed), Status::SUCCESS);
+        assert_eq!(set_variable(&mut variable, variable_name("AuditMode").as_bytes(), GLOBAL_VARIABLE_GUID.as_bytes(),
+                                MODE_ATTRIBUTES, &[1]), Status::WRITE_PROTECTED);
+        assert_eq!(set_user_mode(&mut variable, Mode::User), Status::SUCCESS);
+        assert_eq!(get_mode(&variable), Mode::User);
+        assert_eq!(get(&variable, "DeployedMode"), Some(vec![0]));
+
+        // Setup mode removes PK
+        assert_eq!(set_user_mode(&mut variable, Mode::Setup), Status::SUCCESS);
+        assert!(get(&variable, "PK").is_none());
+        assert_eq!(get_mode(&variable), Mode::Setup);
+        assert_eq!(get(&variable, "SecureBoot"), Some(vec![0]));
+    }
+
+    #[test]
+    fn test_mode_after_reset() {
+        let store = Box::into_raw(Box::new(RamStore::default()));
+        let mut variable = new_variable();
+        variable.set_nv_store(unsafe { &mut *store });
+        initialize(&mut variable);
+        assert_eq!(set(&mut variable, "PK", &GLOBAL_VARIABLE_GUID, include_bytes!("testdata/pk.auth")), Status::SUCCESS);
+        assert_eq!(set_variable(&mut variable, variable_name("DeployedMode").as_bytes(), GLOBAL_VARIABLE_GUID.as_bytes(),
+                                MODE_ATTRIBUTES, &[1]), Status::SUCCESS);
+        assert_eq!(get_mode(&variable), Mode::Deployed);
+        // Only the firmware keeps the mode
+        assert_eq!(set_variable(&mut variable, variable_name("SecureBootMode").as_bytes(), SECURE_BOOT_MODE_GUID.as_bytes(),
+                                STORED_MODE_ATTRIBUTES, &[STORED_USER_MODE]), Status::WRITE_PROTECTED);
+        drop(variable);
+
+        // Deployed mode is still there after a reset, so PK cannot be removed through AuditMode
+        let mut variable = new_variable();
+        variable.set_nv_store(unsafe { &mut *store });
+        initialize(&mut variable);
+        assert_eq!(get_mode(&variable), Mode::Deployed);
+        assert_eq!(get(&variable, "DeployedMode"), Some(vec![1]));
+        assert_eq!(get(&variable, "AuditMode"), Some(vec![0]));
+        assert_eq!(set_variable(&mut variable, variable_name("AuditMode").as_bytes(), GLOBAL_VARIABLE_GUID.as_bytes(),
+                                MODE_ATTRIBUTES, &[1]), Status::WRITE_PROTECTED);
+        assert!(get(&variable, "PK").is_some());
+    }
+
+    #[test]
+    fn test_private_variable() {
+        let guid = Guid::from_fields(0x12345678, 0x1234, 0x1234, 0x12, 0x34, &[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+        let mut variable = new_variable();
+        initialize(&mut variable);
+
+        // Created by the KEK certificate, which is the only one able to update it
+        assert_eq!(set(&mut variable, "Private", &guid, include_bytes!("testdata/private.auth")), Status::SUCCESS);
+        assert_eq!(set(&mut variable, "Private", &guid, include_bytes!("testdata/private_by_pk.auth")), Status::SECURITY_VIOLATION);
+        assert_eq!(set(&mut variable, "Private", &guid, include_bytes!("testdata/private_update.auth")), Status::SUCCESS);
+        assert_eq!(get_data(&variable, "Private", &guid), Some(&b"updated"[..]));
+
+        // Unauthenticated writes are refused
+        assert_eq!(
+            set_variable(&mut variable, variable_name("Private").as_bytes(), guid.as_bytes(),
+                         efi::VARIABLE_BOOTSERVICE_ACCESS, b"data"),
+            Status::SECURITY_VIOLATION
+        );
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use crate::crypto::der::*;
+use crate::crypto::pkcs7::SignedData;
+use crate::crypto::sha256;
+use crate::crypto::x509::Certificate;
+
+use super::peloader::{
+    IMAGE_DIRECTORY_ENTRY_SECURITY, IMAGE_DOS_SIGNATURE, IMAGE_NT_OPTIONAL_HDR64_MAGIC, IMAGE_PE_SIGNATURE,
+};
+
+const WIN_CERT_REVISION: u16 = 0x0200;
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+
+// SPC_INDIRECT_DATA_OBJID, the content type of Authenticode signatures
+const OID_SPC_INDIRECT_DATA: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x01, 0x04];
+
+// Offsets in the headers, the optional header being the PE32+ one
+const DOS_LFANEW: usize = 0x3c;
+const FILE_HEADER_NUMBER_OF_SECTIONS: usize = 4 + 2;
+const FILE_HEADER_SIZE_OF_OPTIONAL_HEADER: usize = 4 + 16;
+const OPTIONAL_HEADER: usize = 4 + 20;
+const OPTIONAL_HEADER_SIZE_OF_HEADERS: usize = 60;
+const OPTIONAL_HEADER_CHECKSUM: usize = 64;
+const OPTIONAL_HEADER_NUMBER_OF_RVA_AND_SIZES: usize = 108;
+const OPTIONAL_HEADER_DATA_DIRECTORY: usize = 112;
+const SECTION_HEADER_SIZE: usize = 40;
+const SECTION_SIZE_OF_RAW_DATA: usize = 16;
+const SECTION_POINTER_TO_RAW_DATA: usize = 20;
+
+// The PE/COFF limit
+const MAX_SECTIONS: usize = 96;
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset .. offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset .. offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// A PE32+ image as a file, with its Authenticode hash
+pub struct Image<'a> {
+    hash: [u8; sha256::DIGEST_SIZE],
+    certificates: &'a [u8],
+}
+
+impl<'a> Image<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Image<'a>> {
+        if read_u16(data, 0)? != IMAGE_DOS_SIGNATURE {
+            return None;
+        }
+        let nt_header = read_u32(data, DOS_LFANEW)? as usize;
+        if read_u32(data, nt_header)? != IMAGE_PE_SIGNATURE {
+            return None;
+        }
+        let optional_header = nt_header + OPTIONAL_HEADER;
+        if read_u16(data, optional_header)? != IMAGE_NT_OPTIONAL_HDR64_MAGIC {
+            return None;
+        }
+        let size_of_headers = read_u32(data, optional_header + OPTIONAL_HEADER_SIZE_OF_HEADERS)? as usize;
+        let checksum = optional_header + OPTIONAL_HEADER_CHECKSUM;
+        let number_of_rva_and_sizes = read_u32(data, optional_header + OPTIONAL_HEADER_NUMBER_OF_RVA_AND_SIZES)? as usize;
+        if size_of_headers > data.len() || checksum + 4 > size_of_headers {
+            return None;
+        }
+
+        // The certificate table is not part of the image, it follows it in the file
+        let security = if number_of_rva_and_sizes > IMAGE_DIRECTORY_ENTRY_SECURITY {
+            let security = optional_header + OPTIONAL_HEADER_DATA_DIRECTORY + IMAGE_DIRECTORY_ENTRY_SECURITY * 8;
+            if security + 8 > size_of_headers {
+                return None;
+            }
+            Some(security)
+        } else {
+            None
+        };
+        let certificates = match security {
+            Some(security) => {
+                let offset = read_u32(data, security)? as usize;
+                let size = read_u32(data, security + 4)? as usize;
+                if size != 0 && (offset < size_of_headers || offset + size != data.len()) {
+                    return None;
+                }
+                &data[data.len() - size ..]
+            },
+            None => &[],
+        };
+
+        // The headers, without the checksum and the certificate table entry
+        let mut sha256 = sha256::Sha256::new();
+        sha256.update(&data[.. checksum]);
+        match security {
+            Some(security) => {
+                sha256.update(&data[checksum + 4 .. security]);
+                sha256.update(&data[security + 8 .. size_of_headers]);
+            },
+            None => sha256.update(&data[checksum + 4 .. size_of_headers]),
+        }
+
+        // Then the sections, in the order they are in the file
+        let number_of_sections = read_u16(data, nt_header + FILE_HEADER_NUMBER_OF_SECTIONS)? as usize;
+        let size_of_optional_header = read_u16(data, nt_header + FILE_HEADER_SIZE_OF_OPTIONAL_HEADER)? as usize;
+        if number_of_sections > MAX_SECTIONS {
+            return None;
+        }
+        let mut sections = [(0usize, 0usize); MAX_SECTIONS];
+        let mut count = 0;
+        for index in 0 .. number_of_sections {
+            let section = optional_header + size_of_optional_header + index * SECTION_HEADER_SIZE;
+            let size = read_u32(data, section + SECTION_SIZE_OF_RAW_DATA)? as usize;
+            let pointer = read_u32(data, section + SECTION_POINTER_TO_RAW_DATA)? as usize;
+            if size == 0 {
+                continue;
+            }
+            if pointer + size > data.len() - certificates.len() {
+                return None;
+            }
+            let mut position = count;
+            while position > 0 && sections[position - 1].0 > pointer {
+                sections[position] = sections[position - 1];
+                position -= 1;
+            }
+            sections[position] = (pointer, size);
+            count += 1;
+        }
+        let mut hashed = size_of_headers;
+        for (pointer, size) in sections[.. count].iter() {
+            sha256.update(&data[*pointer .. *pointer + *size]);
+            hashed += *size;
+        }
+
+        // And whatever else is in the file
+        let end = data.len() - certificates.len();
+        if end > hashed {
+            sha256.update(&data[hashed .. end]);
+        }
+
+        Some(Image { hash: sha256.finish(), certificates })
+    }
+
+    pub fn hash(&self) -> &[u8; sha256::DIGEST_SIZE] {
+        &self.hash
+    }
+
+    /// Call found with every PKCS#7 signature in the certificate table
+    pub fn for_each_signature(&self, found: &mut dyn FnMut(&'a [u8])) {
+        let certificates = self.certificates;
+        let mut offset = 0;
+        while offset + 8 <= certificates.len() {
+            let length = read_u32(certificates, offset).unwrap() as usize;
+            let revision = read_u16(certificates, offset + 4).unwrap();
+            let certificate_type = read_u16(certificates, offset + 6).unwrap();
+            if length < 8 || length > certificates.len() - offset {
+                return;
+            }
+            if revision == WIN_CERT_REVISION && certificate_type == WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+                found(&certificates[offset + 8 .. offset + length]);
+            }
+            // Each entry is 8 byte aligned
+            offset += (length + 7) & !7;
+        }
+    }
+}
+
+/// An Authenticode signature: a PKCS#7 SignedData of an SpcIndirectDataContent
+/// holding the hash of the image.
+pub struct Signature<'a> {
+    signed_data: SignedData<'a>,
+    image_hash: &'a [u8],
+    // Of the content of the SpcIndirectDataContent, which is what was signed
+    digest: [u8; sha256::DIGEST_SIZE],
+}
+
+impl<'a> Signature<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Signature<'a>> {
+        let signed_data = SignedData::parse(data)?;
+        if signed_data.content_type() != OID_SPC_INDIRECT_DATA {
+            return None;
+        }
+        let content = signed_data.content()?;
+        if content.tag != TAG_SEQUENCE {
+            return None;
+        }
+
+        // SEQUENCE { data SpcAttributeTypeAndOptionalValue, messageDigest DigestInfo }
+        let mut reader = Reader::new(content.content);
+        reader.read_tag(TAG_SEQUENCE)?;
+        let mut digest_info = Reader::new(reader.read_tag(TAG_SEQUENCE)?.content);
+        if algorithm(&digest_info.read_tag(TAG_SEQUENCE)?)? != OID_SHA256 {
+            return None;
+        }
+        let image_hash = digest_info.read_tag(TAG_OCTET_STRING)?.content;
+
+        Some(Signature { signed_data, image_hash, digest: sha256::digest(content.content) })
+    }
+
+    /// Check that the signature is of an image with that hash, by a certificate
+    /// that is trusted or issued by a trusted one. Returns the certificate.
+    pub fn verify(&self, hash: &[u8; sha256::DIGEST_SIZE], is_trusted: &dyn Fn(&Certificate) -> bool) -> Option<Certificate<'a>> {
+        if self.image_hash != hash {
+            return None;
+        }
+        self.signed_data.verify(&self.digest, is_trusted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // See testdata/make_signed_images.py
+    #[test]
+    fn test_authenticode() {
+        let unsigned = Image::parse(include_bytes!("testdata/unsigned.efi")).unwrap();
+        let signed = Image::parse(include_bytes!("testdata/signed.efi")).unwrap();
+        // Neither the certificate table nor its entry are hashed
+        assert_eq!(unsigned.hash(), signed.hash());
+        let mut count = 0;
+        unsigned.for_each_signature(&mut |_| count += 1);
+        assert_eq!(count, 0);
+
+        let root = Certificate::parse(include_bytes!("testdata/image_root.der")).unwrap();
+        let other = Certificate::parse(include_bytes!("testdata/image_other.der")).unwrap();
+        // The root is not in the signature, only the certificate it issued
+        let trusted_by = |anchor: &Certificate, certificate: &Certificate| certificate.is_signed_by(anchor);
+        let mut signatures = 0;
+        signed.for_each_signature(&mut |data| {
+            let signature = Signature::parse(data).unwrap();
+            assert!(signature.verify(signed.hash(), &|certificate| trusted_by(&root, certificate)).is_some());
+            assert!(signature.verify(signed.hash(), &|certificate| trusted_by(&other, certificate)).is_none());
+            assert!(signature.verify(&[0; sha256::DIGEST_SIZE], &|certificate| trusted_by(&root, certificate)).is_none());
+            signatures += 1;
+        });
+        assert_eq!(signatures, 1);
+
+        // A change in a section changes the hash
+        let mut tampered = *include_bytes!("testdata/signed.efi");
+        tampered[0x300] ^= 1;
+        assert_ne!(Image::parse(&tampered).unwrap().hash(), signed.hash());
+        // And the certificate table must end the file
+        let mut truncated = include_bytes!("testdata/signed.efi").to_vec();
+        truncated.pop();
+        assert!(Image::parse(&truncated).is_none());
+    }
+}
@@ -383,6 +383,24 @@ pub fn populate_block_wrappers(
     efi_part_id
 }
 
+#[cfg(not(test))]
+/// Reset the devices behind the wrappers, so no request is in flight when the OS takes over
+pub fn shutdown_block_wrappers(wrappers: &mut BlockWrappers) {
+    let mut last_block: *const crate::block::VirtioBlockDevice = core::ptr::null();
+    for i in 0..wrappers.count {
+        let wrapper = wrappers.wrappers[i];
+        if wrapper.is_null() {
+            continue;
+        }
+        // All the partitions of a disk share its device
+        let block = unsafe { (*wrapper).block };
+        if !block.is_null() && block != last_block {
+            unsafe { (*block).reset() };
+            last_block = block;
+        }
+    }
+}
+
 #[cfg(not(test))]
 #[allow(clippy::transmute_ptr_to_ptr)]
 pub fn get_block_io_media_str(wrappers: &mut BlockWrappers, index: usize) -> (*mut c_void) {
@@ -0,0 +1,607 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use r_efi::efi::{Boolean, Char16, Guid, Handle, MemoryType, Status};
+use r_efi::protocols::device_path::Protocol as DevicePathProtocol;
+use r_efi::system::{VARIABLE_BOOTSERVICE_ACCESS, VARIABLE_RUNTIME_ACCESS};
+
+use core::ffi::c_void;
+
+use super::auth::{self, variable_name};
+use super::setup::{MAX_COMMAND_LINE_LENGTH, SETUP_GUID};
+use super::variable::GLOBAL_VARIABLE_GUID;
+
+const SIMPLE_FILE_SYSTEM_GUID: Guid = r_efi::protocols::simple_file_system::PROTOCOL_GUID;
+const DEVICE_PATH_GUID: Guid = r_efi::protocols::device_path::PROTOCOL_GUID;
+
+// EFI_LOAD_OPTION attributes
+pub const LOAD_OPTION_ACTIVE: u32 = 0x0000_0001;
+pub const LOAD_OPTION_FORCE_RECONNECT: u32 = 0x0000_0002;
+pub const LOAD_OPTION_HIDDEN: u32 = 0x0000_0008;
+pub const LOAD_OPTION_CATEGORY: u32 = 0x0000_1f00;
+pub const LOAD_OPTION_CATEGORY_BOOT: u32 = 0x0000_0000;
+pub const LOAD_OPTION_CATEGORY_APP: u32 = 0x0000_0100;
+
+const DEVICE_PATH_NODE_SIZE: usize = 4;
+const TYPE_MEDIA: u8 = 0x04;
+const TYPE_END: u8 = 0x7f;
+const SUBTYPE_HARD_DRIVE: u8 = 0x01;
+const SUBTYPE_FILE_PATH: u8 = 0x04;
+const SUBTYPE_END_ENTIRE: u8 = 0xff;
+
+// HARDDRIVE_DEVICE_PATH, the partition signature being followed by the
+// partition format and the signature type
+const HARD_DRIVE_SIZE: usize = 42;
+const HARD_DRIVE_SIGNATURE: usize = 24;
+const HARD_DRIVE_SIGNATURE_TYPE: usize = 41;
+
+// The removable media boot path of x86_64
+const DEFAULT_BOOT_FILE: &str = "\\EFI\\BOOT\\BOOTX64.EFI";
+const MAX_FILE_PATH_SIZE: usize = 64;
+
+// BootCurrent is not kept across boots
+const BOOT_CURRENT_ATTRIBUTES: u32 = VARIABLE_BOOTSERVICE_ACCESS | VARIABLE_RUNTIME_ACCESS;
+
+// How often a key is looked for while the timeout counts down, in microseconds
+const TIMEOUT_POLL_INTERVAL: usize = 100_000;
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset .. offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset .. offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// The type, subtype and size of the device path node at offset
+fn node(path: &[u8], offset: usize) -> Option<(u8, u8, usize)> {
+    let header = path.get(offset .. offset.checked_add(DEVICE_PATH_NODE_SIZE)?)?;
+    let size = u16::from_le_bytes([header[2], header[3]]) as usize;
+    if size < DEVICE_PATH_NODE_SIZE || offset + size > path.len() {
+        return None;
+    }
+    Some((header[0], header[1], size))
+}
+
+/// The size of the device path path starts with, its end node included
+pub fn device_path_size(path: &[u8]) -> Option<usize> {
+    let mut offset = 0;
+    loop {
+        let (node_type, sub_type, size) = node(path, offset)?;
+        offset += size;
+        if node_type == TYPE_END && sub_type == SUBTYPE_END_ENTIRE {
+            return Some(offset);
+        }
+    }
+}
+
+/// An EFI_LOAD_OPTION, the data of a Boot#### variable
+pub struct LoadOption<'a> {
+    pub attributes: u32,
+    // UCS-2, without its terminator
+    pub description: &'a [u8],
+    // The first device path of the list, the one to boot
+    pub file_path: &'a [u8],
+    pub optional_data: &'a [u8],
+}
+
+impl<'a> LoadOption<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<LoadOption<'a>> {
+        let attributes = read_u32(data, 0)?;
+        let file_path_list_length = read_u16(data, 4)? as usize;
+        let description_length = 2 * data.get(6 ..)?.chunks_exact(2).position(|c| c[0] == 0 && c[1] == 0)?;
+        let file_path_list = 6 + description_length + 2;
+        let optional_data = file_path_list + file_path_list_length;
+        if optional_data > data.len() {
+            return None;
+        }
+        let file_path_size = device_path_size(&data[file_path_list .. optional_data])?;
+        Some(LoadOption {
+            attributes,
+            description: &data[6 .. 6 + description_length],
+            file_path: &data[file_path_list .. file_path_list + file_path_size],
+            optional_data: &data[optional_data ..],
+        })
+    }
+
+    /// Whether the option is active, and one to boot rather than an application
+    pub fn is_bootable(&self) -> bool {
+        self.attributes & LOAD_OPTION_ACTIVE != 0 && self.attributes & LOAD_OPTION_CATEGORY == LOAD_OPTION_CATEGORY_BOOT
+    }
+}
+
+/// The name of the Boot#### variable of an option, as the variable services keep it
+pub fn boot_option_name(number: u16) -> [u8; 16] {
+    let hex = b"0123456789ABCDEF";
+    let mut name = [0u8; 16];
+    for (index, c) in b"Boot".iter().enumerate() {
+        name[2 * index] = *c;
+    }
+    for index in 0 .. 4 {
+        name[8 + 2 * index] = hex[(number >> (12 - 4 * index)) as usize & 0xf];
+    }
+    name
+}
+
+/// The option number of a Boot#### variable name
+pub fn boot_option_number(name: &[u8]) -> Option<u16> {
+    if name.len() != 16 || name[.. 8] != boot_option_name(0)[.. 8] {
+        return None;
+    }
+    let mut number = 0;
+    for c in name[8 ..].chunks_exact(2) {
+        let digit = match (c[0], c[1]) {
+            (b'0' ..= b'9', 0) => c[0] - b'0',
+            (b'A' ..= b'F', 0) => c[0] - b'A' + 10,
+            _ => return None,
+        };
+        number = (number << 4) | digit as u16;
+    }
+    Some(number)
+}
+
+/// The option numbers of BootOrder
+pub fn boot_order(data: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]]))
+}
+
+/// Write the load options for a command line: UCS-2, with its terminator.
+/// Returns their size.
+pub fn command_line_load_options(command_line: &[u8], load_options: &mut [u8]) -> usize {
+    let length = core::cmp::min(command_line.len(), load_options.len() / 2 - 1);
+    for (index, c) in command_line[.. length].iter().chain(core::iter::once(&0)).enumerate() {
+        load_options[2 * index .. 2 * index + 2].copy_from_slice(&[*c, 0]);
+    }
+    2 * (length + 1)
+}
+
+/// If path is a short-form one, starting with a hard drive node, the size of
+/// the part of device_path that comes before the same partition. The full
+/// device path is that part, then path.
+pub fn hard_drive_prefix(path: &[u8], device_path: &[u8]) -> Option<usize> {
+    match node(path, 0)? {
+        (TYPE_MEDIA, SUBTYPE_HARD_DRIVE, HARD_DRIVE_SIZE) => {},
+        _ => return None,
+    }
+    // The partition is known by its signature, without one it cannot be found
+    if path[HARD_DRIVE_SIGNATURE_TYPE] == 0 {
+        return None;
+    }
+    let partition = &path[HARD_DRIVE_SIGNATURE .. HARD_DRIVE_SIZE];
+    let mut offset = 0;
+    loop {
+        match node(device_path, offset)? {
+            (TYPE_MEDIA, SUBTYPE_HARD_DRIVE, HARD_DRIVE_SIZE)
+                if device_path[offset + HARD_DRIVE_SIGNATURE .. offset + HARD_DRIVE_SIZE] == *partition => return Some(offset),
+            (TYPE_END, SUBTYPE_END_ENTIRE, _) => return None,
+            (_, _, size) => offset += size,
+        }
+    }
+}
+
+/// Whether path is a short-form one, starting with a file path node
+pub fn is_file_path(path: &[u8]) -> bool {
+    match node(path, 0) {
+        Some((TYPE_MEDIA, SUBTYPE_FILE_PATH, _)) => true,
+        _ => false,
+    }
+}
+
+/// Write a file path node for name, then an end node. Returns their size.
+pub fn file_path(name: &str, path: &mut [u8]) -> usize {
+    let node_size = DEVICE_PATH_NODE_SIZE + 2 * (name.len() + 1);
+    path[0 .. 4].copy_from_slice(&[TYPE_MEDIA, SUBTYPE_FILE_PATH, node_size as u8, (node_size >> 8) as u8]);
+    for (index, c) in name.bytes().chain(core::iter::once(0)).enumerate() {
+        path[4 + 2 * index .. 6 + 2 * index].copy_from_slice(&[c, 0]);
+    }
+    path[node_size .. node_size + 4].copy_from_slice(&[TYPE_END, SUBTYPE_END_ENTIRE, 4, 0]);
+    node_size + 4
+}
+
+#[cfg(not(test))]
+fn allocate(size: usize) -> Option<&'static mut [u8]> {
+    let mut address = core::ptr::null_mut();
+    if super::allocate_pool(MemoryType::BootServicesData, size, &mut address) != Status::SUCCESS {
+        log!("boot_manager: fail on allocate pool\n");
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts_mut(address as *mut u8, size) })
+}
+
+#[cfg(not(test))]
+fn free(buffer: &mut [u8]) {
+    super::free_pool(buffer.as_mut_ptr() as *mut c_void);
+}
+
+// A copy of a global variable: the variable lock cannot be held while booting,
+// images are verified against db and dbx
+#[cfg(not(test))]
+fn get_variable(name: &[u8]) -> Option<&'static mut [u8]> {
+    let variable = super::VARIABLE.lock();
+    let data = match variable.get_variable(name, GLOBAL_VARIABLE_GUID.as_bytes()) {
+        (Status::SUCCESS, _, data) if !data.is_empty() => data,
+        _ => return None,
+    };
+    let copy = allocate(data.len())?;
+    copy.copy_from_slice(data);
+    Some(copy)
+}
+
+#[cfg(not(test))]
+fn get_u16(name: &str) -> Option<u16> {
+    let variable = super::VARIABLE.lock();
+    match variable.get_variable(variable_name(name).as_bytes(), GLOBAL_VARIABLE_GUID.as_bytes()) {
+        (Status::SUCCESS, _, data) => read_u16(data, 0),
+        _ => None,
+    }
+}
+
+#[cfg(not(test))]
+fn set_variable(name: &str, attributes: u32, data: &[u8]) {
+    let status = super::VARIABLE.lock().set_variable(variable_name(name).as_bytes(), GLOBAL_VARIABLE_GUID.as_bytes(), attributes, data);
+    if status != Status::SUCCESS && status != Status::NOT_FOUND {
+        log!("boot_manager: cannot set {}: {:?}\n", name, status);
+    }
+}
+
+// The handles of the file systems, to be freed with free_pool
+#[cfg(not(test))]
+pub fn file_system_handles() -> &'static [Handle] {
+    let (status, count, buffer) = super::HANDLE_DATABASE.lock().locate_handle_buffer(
+        &mut SIMPLE_FILE_SYSTEM_GUID.clone());
+    if status != Status::SUCCESS {
+        return &[];
+    }
+    unsafe { core::slice::from_raw_parts(buffer, count) }
+}
+
+#[cfg(not(test))]
+fn device_path(handle: Handle) -> Option<&'static [u8]> {
+    let (status, interface) = super::HANDLE_DATABASE.lock().handle_protocol(handle,
+        &mut DEVICE_PATH_GUID.clone());
+    if status != Status::SUCCESS {
+        return None;
+    }
+    let size = super::device_path::get_device_path_size(interface as *mut DevicePathProtocol);
+    Some(unsafe { core::slice::from_raw_parts(interface as *const u8, size) })
+}
+
+// The firmware command line, for the images booted without load options of their own
+#[cfg(not(test))]
+fn firmware_load_options(load_options: &mut [u8]) -> usize {
+    let variable = super::VARIABLE.lock();
+    match auth::get_data(&variable, "CommandLine", &SETUP_GUID) {
+        Some(command_line) if !command_line.is_empty() => command_line_load_options(command_line, load_options),
+        _ => 0,
+    }
+}
+
+// Load the file a full device path names, and start it
+#[cfg(not(test))]
+fn boot_device_path(path: &[u8], number: Option<u16>, load_options: &[u8]) -> Status {
+    let mut image_handle: Handle = core::ptr::null_mut();
+    let status = super::load_image(
+                   Boolean::TRUE,
+                   core::ptr::null_mut(),
+                   path.as_ptr() as *mut c_void,
+                   core::ptr::null_mut(),
+                   0,
+                   &mut image_handle);
+    if status != Status::SUCCESS {
+        log!("boot_manager: load image fails {:?}\n", status);
+        return status;
+    }
+    if !load_options.is_empty() {
+        let status = super::image::Image::new().set_load_options(image_handle, load_options);
+        if status != Status::SUCCESS {
+            log!("boot_manager: cannot set the load options {:?}\n", status);
+        }
+    }
+
+    if let Some(number) = number {
+        set_variable("BootCurrent", BOOT_CURRENT_ATTRIBUTES, &number.to_le_bytes());
+    }
+    super::initrd::prepare();
+    let mut exit_data_size: usize = 0;
+    let mut exit_data: *mut Char16 = core::ptr::null_mut();
+    let status = super::start_image(image_handle, &mut exit_data_size, &mut exit_data);
+    log!("boot_manager: image returned {:?}\n", status);
+    if number.is_some() {
+        set_variable("BootCurrent", BOOT_CURRENT_ATTRIBUTES, &[]);
+    }
+    status
+}
+
+// Boot prefix, a device path without its end node, then path
+#[cfg(not(test))]
+fn boot_expanded(prefix: &[u8], path: &[u8], number: Option<u16>, load_options: &[u8]) -> Status {
+    let full = match allocate(prefix.len() + path.len()) {
+        Some(full) => full,
+        None => return Status::OUT_OF_RESOURCES,
+    };
+    full[.. prefix.len()].copy_from_slice(prefix);
+    full[prefix.len() ..].copy_from_slice(path);
+    let status = boot_device_path(full, number, load_options);
+    free(full);
+    status
+}
+
+// Short-form device paths are completed with the device path of the file
+// system they are on
+#[cfg(not(test))]
+fn boot_load_option(number: u16, option: &LoadOption) -> Status {
+    let mut command_line = [0u8; 2 * (MAX_COMMAND_LINE_LENGTH + 1)];
+    let load_options = if option.optional_data.is_empty() {
+        let size = firmware_load_options(&mut command_line);
+        &command_line[.. size]
+    } else {
+        option.optional_data
+    };
+
+    let path = option.file_path;
+    let is_hard_drive = match node(path, 0) {
+        Some((TYPE_MEDIA, SUBTYPE_HARD_DRIVE, _)) => true,
+        _ => false,
+    };
+    if !is_hard_drive && !is_file_path(path) {
+        return boot_device_path(path, Some(number), load_options);
+    }
+
+    let handles = file_system_handles();
+    let mut status = Status::NOT_FOUND;
+    for handle in handles.iter() {
+        let device_path = match device_path(*handle) {
+            Some(device_path) => device_path,
+            None => continue,
+        };
+        let prefix_size = if is_hard_drive {
+            match hard_drive_prefix(path, device_path) {
+                Some(size) => size,
+                None => continue,
+            }
+        } else {
+            device_path.len() - DEVICE_PATH_NODE_SIZE
+        };
+        // The first file system with the file is the one
+        status = boot_expanded(&device_path[.. prefix_size], path, Some(number), load_options);
+        if status != Status::NOT_FOUND {
+            break;
+        }
+    }
+    super::free_pool(handles.as_ptr() as *mut c_void);
+    status
+}
+
+/// Boot a Boot#### option, if it is active and in the boot category unless
+/// booted as BootNext is
+#[cfg(not(test))]
+pub fn boot_option(number: u16, boot_next: bool) {
+    let data = match get_variable(&boot_option_name(number)) {
+        Some(data) => data,
+        None => {
+            log!("boot_manager: no Boot{:04X}\n", number);
+            return;
+        },
+    };
+    match LoadOption::parse(data) {
+        // BootNext is booted whatever its attributes
+        Some(option) if boot_next || option.is_bootable() => {
+            log!("boot_manager: booting Boot{:04X}\n", number);
+            let status = boot_load_option(number, &option);
+            log!("boot_manager: Boot{:04X} fails {:?}\n", number, status);
+        },
+        Some(_) => {},
+        None => log!("boot_manager: invalid Boot{:04X}\n", number),
+    }
+    free(data);
+}
+
+// The default boot file of every file system, for removable media and for
+// disks installed without boot options
+#[cfg(not(test))]
+fn boot_default() {
+    let mut path = [0u8; MAX_FILE_PATH_SIZE];
+    let size = file_path(DEFAULT_BOOT_FILE, &mut path);
+    let mut command_line = [0u8; 2 * (MAX_COMMAND_LINE_LENGTH + 1)];
+    let load_options_size = firmware_load_options(&mut command_line);
+    let handles = file_system_handles();
+    for handle in handles.iter() {
+        if let Some(device_path) = device_path(*handle) {
+            let prefix = &device_path[.. device_path.len() - DEVICE_PATH_NODE_SIZE];
+            let status = boot_expanded(prefix, &path[.. size], None, &command_line[.. load_options_size]);
+            log!("boot_manager: {} fails {:?}\n", DEFAULT_BOOT_FILE, status);
+        }
+    }
+    super::free_pool(handles.as_ptr() as *mut c_void);
+}
+
+// Count Timeout down, in seconds. Returns whether to show the setup: a key
+// was pressed, even before, or Timeout is 0xffff to wait for the user there.
+#[cfg(not(test))]
+fn wait_timeout() -> bool {
+    let timeout = get_u16("Timeout").unwrap_or(0);
+    if timeout == 0xffff {
+        return true;
+    }
+    if timeout != 0 {
+        super::setup::print(format_args!("Press any key for the setup, booting in {} seconds\n", timeout));
+    }
+    let mut remaining = timeout as usize * 1_000_000;
+    loop {
+        if super::CONIN.lock().read_byte() != 0 {
+            return true;
+        }
+        if remaining == 0 {
+            return false;
+        }
+        remaining -= TIMEOUT_POLL_INTERVAL;
+        super::stall(TIMEOUT_POLL_INTERVAL);
+    }
+}
+
+/// Boot BootNext, then the options of BootOrder, then the default boot file
+/// of every file system. Returns if none of them could be started, or all
+/// of them returned.
+#[cfg(not(test))]
+pub fn boot() {
+    if wait_timeout() {
+        super::setup::run();
+    }
+
+    // BootNext is for one boot only, whether it succeeds or not
+    if let Some(number) = get_u16("BootNext") {
+        set_variable("BootNext", 0, &[]);
+        boot_option(number, true);
+    }
+
+    if let Some(order) = get_variable(variable_name("BootOrder").as_bytes()) {
+        for number in boot_order(order) {
+            boot_option(number, false);
+        }
+        free(order);
+    }
+
+    boot_default();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ucs2(name: &str) -> Vec<u8> {
+        name.bytes().flat_map(|c| vec![c, 0]).collect()
+    }
+
+    fn hard_drive(partition_number: u32, signature: u8) -> Vec<u8> {
+        let mut node = vec![TYPE_MEDIA, SUBTYPE_HARD_DRIVE, HARD_DRIVE_SIZE as u8, 0];
+        node.extend_from_slice(&partition_number.to_le_bytes());
+        node.extend_from_slice(&0x800u64.to_le_bytes());
+        node.extend_from_slice(&0x1000u64.to_le_bytes());
+        node.extend_from_slice(&[signature; 16]);
+        node.extend_from_slice(&[0x02, 0x02]);
+        node
+    }
+
+    fn end() -> Vec<u8> {
+        vec![TYPE_END, SUBTYPE_END_ENTIRE, 4, 0]
+    }
+
+    fn load_option(attributes: u32, description: &str, file_path: &[u8], optional_data: &[u8]) -> Vec<u8> {
+        let mut data = attributes.to_le_bytes().to_vec();
+        data.extend_from_slice(&(file_path.len() as u16).to_le_bytes());
+        data.extend(ucs2(description));
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(file_path);
+        data.extend_from_slice(optional_data);
+        data
+    }
+
+    #[test]
+    fn test_load_option() {
+        let mut file = [0u8; MAX_FILE_PATH_SIZE];
+        let size = file_path("\\EFI\\debian\\shimx64.efi", &mut file);
+        let mut path = hard_drive(1, 0xaa);
+        path.extend_from_slice(&file[.. size]);
+
+        let data = load_option(LOAD_OPTION_ACTIVE, "debian", &path, b"root=/dev/vda2");
+        let option = LoadOption::parse(&data).unwrap();
+        assert_eq!(option.attributes, LOAD_OPTION_ACTIVE);
+        assert_eq!(option.description, &ucs2("debian")[..]);
+        assert_eq!(option.file_path, &path[..]);
+        assert_eq!(option.optional_data, b"root=/dev/vda2");
+        assert!(option.is_bootable());
+
+        // Only the first device path of the list is booted
+        let mut list = path.clone();
+        list.extend(end());
+        let data = load_option(LOAD_OPTION_ACTIVE, "", &list, &[]);
+        let option = LoadOption::parse(&data).unwrap();
+        assert_eq!(option.description, &[]);
+        assert_eq!(option.file_path, &path[..]);
+        assert_eq!(option.optional_data, &[]);
+
+        // Inactive options and applications are not booted
+        let data = load_option(0, "debian", &path, &[]);
+        assert!(!LoadOption::parse(&data).unwrap().is_bootable());
+        let data = load_option(LOAD_OPTION_ACTIVE | LOAD_OPTION_CATEGORY_APP, "shell", &path, &[]);
+        assert!(!LoadOption::parse(&data).unwrap().is_bootable());
+
+        // Truncated, or without an end node
+        let data = load_option(LOAD_OPTION_ACTIVE, "debian", &path, &[]);
+        assert!(LoadOption::parse(&data[.. data.len() - 1]).is_none());
+        assert!(LoadOption::parse(&data[.. 10]).is_none());
+        let data = load_option(LOAD_OPTION_ACTIVE, "debian", &path[.. path.len() - 4], &[]);
+        assert!(LoadOption::parse(&data).is_none());
+        let mut bad = path.clone();
+        bad[2] = 2;
+        let data = load_option(LOAD_OPTION_ACTIVE, "debian", &bad, &[]);
+        assert!(LoadOption::parse(&data).is_none());
+    }
+
+    #[test]
+    fn test_boot_order() {
+        assert_eq!(&boot_option_name(0x0001), &ucs2("Boot0001")[..]);
+        assert_eq!(&boot_option_name(0xbeef), &ucs2("BootBEEF")[..]);
+        assert_eq!(boot_option_number(&boot_option_name(0xbeef)), Some(0xbeef));
+        assert_eq!(boot_option_number(&ucs2("Boot00a1")), None);
+        assert_eq!(boot_option_number(&ucs2("BootOrder")), None);
+        assert_eq!(boot_option_number(&ucs2("BootNext")), None);
+        assert_eq!(boot_order(&[0x01, 0x00, 0x00, 0x10, 0x02]).collect::<Vec<u16>>(), vec![0x0001, 0x1000]);
+    }
+
+    #[test]
+    fn test_command_line() {
+        let mut load_options = [0xffu8; 32];
+        assert_eq!(command_line_load_options(b"console=ttyS0", &mut load_options), 28);
+        assert_eq!(&load_options[.. 28], &[ucs2("console=ttyS0"), vec![0, 0]].concat()[..]);
+        // Cut to the room there is, with the terminator
+        let mut load_options = [0xffu8; 8];
+        assert_eq!(command_line_load_options(b"console=ttyS0", &mut load_options), 8);
+        assert_eq!(&load_options, &[b'c', 0, b'o', 0, b'n', 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_short_form() {
+        let pci = vec![0x01, 0x01, 6, 0, 0, 3];
+        let mut device_path = pci.clone();
+        device_path.extend(hard_drive(1, 0xaa));
+        device_path.extend(end());
+
+        let mut file = [0u8; MAX_FILE_PATH_SIZE];
+        let size = file_path(DEFAULT_BOOT_FILE, &mut file);
+        assert_eq!(device_path_size(&file), Some(size));
+        assert!(is_file_path(&file[.. size]));
+        assert!(!is_file_path(&device_path));
+
+        let mut short_form = hard_drive(1, 0xaa);
+        short_form.extend_from_slice(&file[.. size]);
+        assert_eq!(hard_drive_prefix(&short_form, &device_path), Some(pci.len()));
+        // The partition number, start and size may differ, the signature may not
+        let mut other = hard_drive(2, 0xaa);
+        other.extend(end());
+        assert_eq!(hard_drive_prefix(&short_form, &other), Some(0));
+        let mut other = hard_drive(1, 0xbb);
+        other.extend(end());
+        assert_eq!(hard_drive_prefix(&short_form, &other), None);
+        // Without a signature
+        short_form[HARD_DRIVE_SIGNATURE_TYPE] = 0;
+        assert_eq!(hard_drive_prefix(&short_form, &device_path), None);
+        // Full device paths are not short-form ones
+        assert_eq!(hard_drive_prefix(&device_path, &device_path), None);
+    }
+}
@@ -41,6 +41,10 @@ impl ConIn {
         byte
     }
 
+    pub fn has_byte(&mut self) -> bool {
+        (self.lsr_port.read() & LSR_RXDA) != 0
+    }
+
     pub fn new() -> ConIn {
         ConIn {
             port: unsafe { Port::new(0x3f8) },
@@ -20,6 +20,12 @@ use r_efi::efi::{
     LocateSearchType, MemoryDescriptor, MemoryType, OpenProtocolInformationEntry, PhysicalAddress,
     ResetType, Status, Time, TimeCapabilities, TimerDelay, Tpl, MEMORY_WB
 };
+use r_efi::efi::{
+    EVT_TIMER, EVT_NOTIFY_WAIT, EVT_NOTIFY_SIGNAL,
+    EVT_SIGNAL_EXIT_BOOT_SERVICES, EVT_SIGNAL_VIRTUAL_ADDRESS_CHANGE,
+    EVENT_GROUP_EXIT_BOOT_SERVICES, EVENT_GROUP_VIRTUAL_ADDRESS_CHANGE,
+    TPL_APPLICATION, TPL_CALLBACK, TPL_NOTIFY, TPL_HIGH_LEVEL
+};
 
 use core::ffi::c_void;
 use core::mem::transmute;
@@ -27,31 +33,89 @@ use core::mem::size_of;
 
 const EVENT_STRUCT_SIGNATURE: u32 = 0x54564549; // 'I','E','V','T'
 
-#[derive(Default)]
+#[derive(Default, Copy, Clone)]
 struct EventStruct {
     signature: u32,
     r#type: u32,
     notify_tpl: Tpl,
     notify_function: usize,
     notify_context: usize,
+    // non-zero once signaled, cleared by check_event or by the notification
+    signal_count: usize,
+    // set while the notification function is waiting in the notify queue
+    notify_queued: bool,
+    // FIFO order among queued notifications of the same TPL
+    notify_sequence: u64,
+    // timer state, in 100ns units of the event engine system time
+    timer_active: bool,
+    trigger_time: u64,
+    period: u64,
+    // signaling any event of a group signals all of them
+    event_group: Option<Guid>,
 }
 
-const MAX_EVENT_STRUCT : usize = 16;
+const MAX_EVENT_STRUCT : usize = 128;
 
-#[derive(Default)]
 pub struct EventInfo {
-    event_count: usize,
+    current_tpl: Tpl,
+    system_time: u64,
+    notify_sequence: u64,
     event_struct: [EventStruct; MAX_EVENT_STRUCT],
 }
 
+impl Default for EventInfo {
+  fn default() -> EventInfo {
+    EventInfo {
+      current_tpl: TPL_APPLICATION,
+      system_time: 0,
+      notify_sequence: 0,
+      event_struct: [EventStruct::default(); MAX_EVENT_STRUCT],
+    }
+  }
+}
+
 impl EventInfo {
     pub fn create_event (
         &mut self,
         r#type: u32,
         notify_tpl: Tpl,
-        notify_function: EventNotify,
+        notify_function: usize,
         notify_context: *mut c_void,
     ) -> (Status, Event) {
+        self.create_event_ex (r#type, notify_tpl, notify_function, notify_context, None)
+    }
+
+    pub fn create_event_ex (
+        &mut self,
+        r#type: u32,
+        notify_tpl: Tpl,
+        notify_function: usize,
+        notify_context: *mut c_void,
+        event_group: Option<Guid>,
+    ) -> (Status, Event) {
+        // These event types are the legacy way to join their event group.
+        let event_group = match r#type {
+          EVT_SIGNAL_EXIT_BOOT_SERVICES | EVT_SIGNAL_VIRTUAL_ADDRESS_CHANGE if event_group.is_some() => {
+            return (Status::INVALID_PARAMETER, core::ptr::null_mut());
+          },
+          EVT_SIGNAL_EXIT_BOOT_SERVICES => Some(EVENT_GROUP_EXIT_BOOT_SERVICES),
+          EVT_SIGNAL_VIRTUAL_ADDRESS_CHANGE => Some(EVENT_GROUP_VIRTUAL_ADDRESS_CHANGE),
+          _ => event_group,
+        };
+
+        if (r#type & EVT_NOTIFY_WAIT) != 0 && (r#type & EVT_NOTIFY_SIGNAL) != 0 {
+          return (Status::INVALID_PARAMETER, core::ptr::null_mut());
+        }
+        if (r#type & (EVT_NOTIFY_WAIT | EVT_NOTIFY_SIGNAL)) != 0 {
+          if notify_function == 0 {
+            return (Status::INVALID_PARAMETER, core::ptr::null_mut());
+          }
+          match notify_tpl {
+            TPL_CALLBACK | TPL_NOTIFY | TPL_HIGH_LEVEL => {},
+            _ => { return (Status::INVALID_PARAMETER, core::ptr::null_mut()); },
+          }
+        }
+
         let (status, new_event) = self.get_new_event ();
         if status != Status::SUCCESS {
           return (status, core::ptr::null_mut());
@@ -61,36 +125,567 @@ impl EventInfo {
         event_struct.signature = EVENT_STRUCT_SIGNATURE;
         event_struct.r#type = r#type;
         event_struct.notify_tpl = notify_tpl;
-        event_struct.notify_function = notify_function as usize;
+        event_struct.notify_function = notify_function;
         event_struct.notify_context = notify_context as usize;
+        event_struct.event_group = event_group;
 
         (Status::SUCCESS, new_event)
     }
+
     pub fn close_event (
         &mut self,
         event: Event
     ) -> (Status) {
-        (Status::UNSUPPORTED)
+        match self.get_event_index (event) {
+          Some(index) => {
+            self.event_struct[index] = EventStruct::default();
+            (Status::SUCCESS)
+          },
+          None => (Status::INVALID_PARAMETER),
+        }
+    }
+
+    pub fn signal_event (
+        &mut self,
+        event: Event
+    ) -> (Status) {
+        let index = match self.get_event_index (event) {
+          Some(index) => index,
+          None => { return (Status::INVALID_PARAMETER); },
+        };
+
+        match self.event_struct[index].event_group {
+          Some(event_group) => self.signal_event_group (&event_group),
+          None => self.signal_event_index (index),
+        }
+
+        (Status::SUCCESS)
+    }
+
+    pub fn signal_event_group (
+        &mut self,
+        event_group: &Guid,
+    ) {
+        for index in 0 .. MAX_EVENT_STRUCT {
+          if self.event_struct[index].signature == EVENT_STRUCT_SIGNATURE &&
+             self.event_struct[index].event_group == Some(*event_group) {
+            self.signal_event_index (index);
+          }
+        }
+    }
+
+    fn signal_event_index (
+        &mut self,
+        index: usize,
+    ) {
+        if self.event_struct[index].signal_count == 0 {
+          self.event_struct[index].signal_count = 1;
+          if (self.event_struct[index].r#type & EVT_NOTIFY_SIGNAL) != 0 {
+            self.queue_notify (index);
+          }
+        }
+    }
+
+    // Queue the notification function of a wait event that is not signaled yet,
+    // so it gets a chance to signal the event before check_event looks at it.
+    pub fn queue_wait_notify (
+        &mut self,
+        event: Event
+    ) -> (Status) {
+        let index = match self.get_event_index (event) {
+          Some(index) => index,
+          None => { return (Status::INVALID_PARAMETER); },
+        };
+
+        if (self.event_struct[index].r#type & EVT_NOTIFY_SIGNAL) != 0 {
+          return (Status::INVALID_PARAMETER);
+        }
+
+        if self.event_struct[index].signal_count == 0 &&
+           (self.event_struct[index].r#type & EVT_NOTIFY_WAIT) != 0 {
+          self.queue_notify (index);
+        }
+
+        (Status::SUCCESS)
+    }
+
+    pub fn check_event (
+        &mut self,
+        event: Event
+    ) -> (Status) {
+        let index = match self.get_event_index (event) {
+          Some(index) => index,
+          None => { return (Status::INVALID_PARAMETER); },
+        };
+
+        if (self.event_struct[index].r#type & EVT_NOTIFY_SIGNAL) != 0 {
+          return (Status::INVALID_PARAMETER);
+        }
+
+        if self.event_struct[index].signal_count != 0 {
+          self.event_struct[index].signal_count = 0;
+          return (Status::SUCCESS);
+        }
+
+        (Status::NOT_READY)
+    }
+
+    pub fn set_timer (
+        &mut self,
+        event: Event,
+        timer_type: TimerDelay,
+        trigger_time: u64,
+    ) -> (Status) {
+        let index = match self.get_event_index (event) {
+          Some(index) => index,
+          None => { return (Status::INVALID_PARAMETER); },
+        };
+
+        if (self.event_struct[index].r#type & EVT_TIMER) == 0 {
+          return (Status::INVALID_PARAMETER);
+        }
+
+        let system_time = self.system_time;
+        let event_struct = &mut self.event_struct[index];
+        match timer_type {
+          TimerDelay::TimerCancel => {
+            event_struct.timer_active = false;
+            event_struct.trigger_time = 0;
+            event_struct.period = 0;
+          },
+          TimerDelay::TimerRelative => {
+            event_struct.timer_active = true;
+            event_struct.trigger_time = system_time + trigger_time;
+            event_struct.period = 0;
+          },
+          TimerDelay::TimerPeriodic => {
+            event_struct.timer_active = true;
+            event_struct.trigger_time = system_time + trigger_time;
+            event_struct.period = trigger_time;
+          },
+        }
+
+        (Status::SUCCESS)
+    }
+
+    // Advance the system time by elapsed (in 100ns units) and signal every
+    // timer event whose trigger time has passed.
+    pub fn timer_tick (
+        &mut self,
+        elapsed: u64,
+    ) {
+        self.system_time = self.system_time + elapsed;
+
+        for index in 0 .. MAX_EVENT_STRUCT {
+          if self.event_struct[index].signature != EVENT_STRUCT_SIGNATURE ||
+             !self.event_struct[index].timer_active ||
+             self.event_struct[index].trigger_time > self.system_time {
+            continue;
+          }
+
+          let event = &mut self.event_struct[index] as *mut EventStruct as Event;
+          self.signal_event (event);
+
+          let event_struct = &mut self.event_struct[index];
+          if event_struct.period == 0 {
+            event_struct.timer_active = false;
+          } else {
+            event_struct.trigger_time = event_struct.trigger_time + event_struct.period;
+            if event_struct.trigger_time <= self.system_time {
+              event_struct.trigger_time = self.system_time + event_struct.period;
+            }
+          }
+        }
+    }
+
+    // Remove the next notification that may run above the current TPL.
+    // The current TPL is raised to the notification TPL, the caller restores
+    // the returned previous TPL once the notification function returns.
+    pub fn pop_notify (
+        &mut self,
+    ) -> Option<(usize, Event, usize, Tpl)> {
+        let mut found : Option<usize> = None;
+        for index in 0 .. MAX_EVENT_STRUCT {
+          let event_struct = &self.event_struct[index];
+          if event_struct.signature != EVENT_STRUCT_SIGNATURE ||
+             !event_struct.notify_queued ||
+             event_struct.notify_tpl <= self.current_tpl {
+            continue;
+          }
+          match found {
+            Some(best) => {
+              let best_struct = &self.event_struct[best];
+              if event_struct.notify_tpl > best_struct.notify_tpl ||
+                 (event_struct.notify_tpl == best_struct.notify_tpl &&
+                  event_struct.notify_sequence < best_struct.notify_sequence) {
+                found = Some(index);
+              }
+            },
+            None => { found = Some(index); },
+          }
+        }
+
+        let index = found?;
+        let previous_tpl = self.current_tpl;
+        let event_struct = &mut self.event_struct[index];
+        event_struct.notify_queued = false;
+        if (event_struct.r#type & EVT_NOTIFY_SIGNAL) != 0 {
+          event_struct.signal_count = 0;
+        }
+        self.current_tpl = event_struct.notify_tpl;
+
+        Some((
+          event_struct.notify_function,
+          event_struct as *mut EventStruct as Event,
+          event_struct.notify_context,
+          previous_tpl,
+          ))
+    }
+
+    // Whether a queued notification may run above the current TPL.
+    pub fn notify_pending (
+        &self,
+    ) -> bool {
+        self.event_struct.iter().any(|event_struct|
+          event_struct.signature == EVENT_STRUCT_SIGNATURE &&
+          event_struct.notify_queued &&
+          event_struct.notify_tpl > self.current_tpl)
+    }
+
+    pub fn get_current_tpl (
+        &self,
+    ) -> Tpl {
+        self.current_tpl
+    }
+
+    pub fn set_current_tpl (
+        &mut self,
+        tpl: Tpl,
+    ) {
+        self.current_tpl = tpl;
+    }
+
+    // RaiseTPL may only keep or raise the current TPL.
+    pub fn raise_tpl (
+        &mut self,
+        new_tpl: Tpl,
+    ) -> (Status, Tpl) {
+        let old_tpl = self.current_tpl;
+        if new_tpl < old_tpl || new_tpl > efi::TPL_HIGH_LEVEL {
+          return (Status::INVALID_PARAMETER, old_tpl);
+        }
+        self.current_tpl = new_tpl;
+        (Status::SUCCESS, old_tpl)
+    }
+
+    // RestoreTPL may only keep or lower the current TPL.
+    pub fn restore_tpl (
+        &mut self,
+        old_tpl: Tpl,
+    ) -> Status {
+        if old_tpl > self.current_tpl {
+          return Status::INVALID_PARAMETER;
+        }
+        self.current_tpl = old_tpl;
+        Status::SUCCESS
     }
+
+    pub fn get_system_time (
+        &self,
+    ) -> u64 {
+        self.system_time
+    }
+
+    fn queue_notify (
+        &mut self,
+        index: usize,
+    ) {
+        if self.event_struct[index].notify_queued {
+          return;
+        }
+        self.event_struct[index].notify_queued = true;
+        self.event_struct[index].notify_sequence = self.notify_sequence;
+        self.notify_sequence = self.notify_sequence + 1;
+    }
+
+    fn get_event_index (
+        &self,
+        event: Event,
+    ) -> Option<usize> {
+        let base = &self.event_struct[0] as *const EventStruct as usize;
+        let address = event as usize;
+        if address < base || (address - base) % size_of::<EventStruct>() != 0 {
+          return None;
+        }
+        let index = (address - base) / size_of::<EventStruct>();
+        if index >= MAX_EVENT_STRUCT {
+          return None;
+        }
+        if self.event_struct[index].signature != EVENT_STRUCT_SIGNATURE {
+          return None;
+        }
+        Some(index)
+    }
+
     fn get_new_event (
         &mut self
     ) -> (Status, Event) {
-        if self.event_count >= MAX_EVENT_STRUCT {
-          return (Status::OUT_OF_RESOURCES, core::ptr::null_mut());
+        for index in 0 .. MAX_EVENT_STRUCT {
+          if self.event_struct[index].signature != EVENT_STRUCT_SIGNATURE {
+            let event_struct = &mut self.event_struct[index];
+            *event_struct = EventStruct::default();
+            return (Status::SUCCESS, event_struct as *mut EventStruct as Event);
+          }
         }
-        let event_struct = &mut self.event_struct[self.event_count];
-        self.event_count = self.event_count + 1;
 
-        event_struct.signature = 0;
-
-        (Status::SUCCESS, event_struct as *mut EventStruct as Event)
+        log!("{}:{} out of resource\n", file!(), line!());
+        (Status::OUT_OF_RESOURCES, core::ptr::null_mut())
     }
 
     pub fn new() -> EventInfo {
         EventInfo {
-            event_count: 0,
             ..EventInfo::default()
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::EventInfo;
+    use core::ffi::c_void;
+    use r_efi::efi::{
+        Event, Status, TimerDelay, EVT_TIMER, EVT_NOTIFY_WAIT, EVT_NOTIFY_SIGNAL,
+        TPL_APPLICATION, TPL_CALLBACK, TPL_NOTIFY, TPL_HIGH_LEVEL,
+        EVT_SIGNAL_EXIT_BOOT_SERVICES, EVENT_GROUP_EXIT_BOOT_SERVICES, EVENT_GROUP_READY_TO_BOOT,
+    };
+
+    extern "win64" fn test_notify(_: Event, _: *mut c_void) {}
+
+    fn notify_address() -> usize {
+        test_notify as usize
+    }
+
+    #[test]
+    fn test_create_close_event() {
+        let mut event_info = EventInfo::new();
+
+        let (status, event) = event_info.create_event(
+            EVT_NOTIFY_WAIT | EVT_NOTIFY_SIGNAL,
+            TPL_CALLBACK,
+            notify_address(),
+            core::ptr::null_mut(),
+        );
+        assert_eq!(status, Status::INVALID_PARAMETER);
+        assert!(event.is_null());
+
+        let (status, _) =
+            event_info.create_event(EVT_NOTIFY_SIGNAL, TPL_CALLBACK, 0, core::ptr::null_mut());
+        assert_eq!(status, Status::INVALID_PARAMETER);
+
+        let (status, _) = event_info.create_event(
+            EVT_NOTIFY_SIGNAL,
+            TPL_APPLICATION,
+            notify_address(),
+            core::ptr::null_mut(),
+        );
+        assert_eq!(status, Status::INVALID_PARAMETER);
+
+        let (status, event) =
+            event_info.create_event(EVT_TIMER, 0, 0, core::ptr::null_mut());
+        assert_eq!(status, Status::SUCCESS);
+
+        assert_eq!(event_info.close_event(event), Status::SUCCESS);
+        assert_eq!(event_info.close_event(event), Status::INVALID_PARAMETER);
+        assert_eq!(event_info.signal_event(event), Status::INVALID_PARAMETER);
+
+        // The slot is reused once closed
+        let (status, new_event) =
+            event_info.create_event(EVT_TIMER, 0, 0, core::ptr::null_mut());
+        assert_eq!(status, Status::SUCCESS);
+        assert_eq!(new_event, event);
+    }
+
+    #[test]
+    fn test_signal_check_event() {
+        let mut event_info = EventInfo::new();
+
+        let (_, event) = event_info.create_event(0, 0, 0, core::ptr::null_mut());
+        assert_eq!(event_info.check_event(event), Status::NOT_READY);
+        assert_eq!(event_info.signal_event(event), Status::SUCCESS);
+        assert_eq!(event_info.check_event(event), Status::SUCCESS);
+        assert_eq!(event_info.check_event(event), Status::NOT_READY);
+
+        let (_, event) = event_info.create_event(
+            EVT_NOTIFY_SIGNAL,
+            TPL_CALLBACK,
+            notify_address(),
+            core::ptr::null_mut(),
+        );
+        assert_eq!(event_info.check_event(event), Status::INVALID_PARAMETER);
+        assert_eq!(event_info.queue_wait_notify(event), Status::INVALID_PARAMETER);
+    }
+
+    #[test]
+    fn test_notify_order() {
+        let mut event_info = EventInfo::new();
+
+        let (_, callback1) = event_info.create_event(
+            EVT_NOTIFY_SIGNAL,
+            TPL_CALLBACK,
+            notify_address(),
+            core::ptr::null_mut(),
+        );
+        let (_, callback2) = event_info.create_event(
+            EVT_NOTIFY_SIGNAL,
+            TPL_CALLBACK,
+            notify_address(),
+            core::ptr::null_mut(),
+        );
+        let (_, notify) = event_info.create_event(
+            EVT_NOTIFY_SIGNAL,
+            TPL_NOTIFY,
+            notify_address(),
+            core::ptr::null_mut(),
+        );
+
+        event_info.signal_event(callback2);
+        event_info.signal_event(callback1);
+        event_info.signal_event(notify);
+        // Signaling twice does not queue the notification twice
+        event_info.signal_event(notify);
+
+        let (_, event, _, previous_tpl) = event_info.pop_notify().unwrap();
+        assert_eq!(event, notify);
+        assert_eq!(previous_tpl, TPL_APPLICATION);
+        assert_eq!(event_info.get_current_tpl(), TPL_NOTIFY);
+
+        // Nothing at or below TPL_NOTIFY may run while at TPL_NOTIFY
+        assert!(event_info.pop_notify().is_none());
+        event_info.set_current_tpl(previous_tpl);
+
+        let (_, event, _, previous_tpl) = event_info.pop_notify().unwrap();
+        assert_eq!(event, callback2);
+        event_info.set_current_tpl(previous_tpl);
+        let (_, event, _, previous_tpl) = event_info.pop_notify().unwrap();
+        assert_eq!(event, callback1);
+        event_info.set_current_tpl(previous_tpl);
+        assert!(event_info.pop_notify().is_none());
+    }
+
+    #[test]
+    fn test_event_group() {
+        let mut event_info = EventInfo::new();
+
+        let (status, _) = event_info.create_event_ex(
+            EVT_SIGNAL_EXIT_BOOT_SERVICES,
+            TPL_CALLBACK,
+            notify_address(),
+            core::ptr::null_mut(),
+            Some(EVENT_GROUP_READY_TO_BOOT),
+        );
+        assert_eq!(status, Status::INVALID_PARAMETER);
+
+        let (_, legacy) = event_info.create_event(
+            EVT_SIGNAL_EXIT_BOOT_SERVICES,
+            TPL_CALLBACK,
+            notify_address(),
+            core::ptr::null_mut(),
+        );
+        let (_, grouped) = event_info.create_event_ex(
+            EVT_NOTIFY_SIGNAL,
+            TPL_NOTIFY,
+            notify_address(),
+            core::ptr::null_mut(),
+            Some(EVENT_GROUP_EXIT_BOOT_SERVICES),
+        );
+        let (_, other) = event_info.create_event_ex(
+            EVT_NOTIFY_SIGNAL,
+            TPL_NOTIFY,
+            notify_address(),
+            core::ptr::null_mut(),
+            Some(EVENT_GROUP_READY_TO_BOOT),
+        );
+
+        // Signaling one member signals the whole group, and nothing else
+        assert_eq!(event_info.signal_event(legacy), Status::SUCCESS);
+        let (_, event, _, previous_tpl) = event_info.pop_notify().unwrap();
+        assert_eq!(event, grouped);
+        event_info.set_current_tpl(previous_tpl);
+        let (_, event, _, previous_tpl) = event_info.pop_notify().unwrap();
+        assert_eq!(event, legacy);
+        event_info.set_current_tpl(previous_tpl);
+        assert!(event_info.pop_notify().is_none());
+
+        event_info.signal_event_group(&EVENT_GROUP_READY_TO_BOOT);
+        let (_, event, _, _) = event_info.pop_notify().unwrap();
+        assert_eq!(event, other);
+    }
+
+    #[test]
+    fn test_raise_restore_tpl() {
+        let mut event_info = EventInfo::new();
+
+        let (_, event) = event_info.create_event(
+            EVT_NOTIFY_SIGNAL,
+            TPL_CALLBACK,
+            notify_address(),
+            core::ptr::null_mut(),
+        );
+
+        assert_eq!(event_info.raise_tpl(TPL_NOTIFY), (Status::SUCCESS, TPL_APPLICATION));
+        assert_eq!(event_info.raise_tpl(TPL_CALLBACK), (Status::INVALID_PARAMETER, TPL_NOTIFY));
+        assert_eq!(event_info.raise_tpl(TPL_HIGH_LEVEL + 1), (Status::INVALID_PARAMETER, TPL_NOTIFY));
+        assert_eq!(event_info.get_current_tpl(), TPL_NOTIFY);
+
+        // The notification is held back until the TPL drops below TPL_CALLBACK
+        event_info.signal_event(event);
+        assert!(event_info.pop_notify().is_none());
+        assert_eq!(event_info.restore_tpl(TPL_HIGH_LEVEL), Status::INVALID_PARAMETER);
+        assert_eq!(event_info.restore_tpl(TPL_CALLBACK), Status::SUCCESS);
+        assert!(event_info.pop_notify().is_none());
+        assert_eq!(event_info.restore_tpl(TPL_APPLICATION), Status::SUCCESS);
+        let (_, notified, _, previous_tpl) = event_info.pop_notify().unwrap();
+        assert_eq!(notified, event);
+        assert_eq!(previous_tpl, TPL_APPLICATION);
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut event_info = EventInfo::new();
+
+        let (_, event) = event_info.create_event(0, 0, 0, core::ptr::null_mut());
+        assert_eq!(
+            event_info.set_timer(event, TimerDelay::TimerRelative, 100),
+            Status::INVALID_PARAMETER
+        );
+
+        let (_, one_shot) = event_info.create_event(EVT_TIMER, 0, 0, core::ptr::null_mut());
+        assert_eq!(
+            event_info.set_timer(one_shot, TimerDelay::TimerRelative, 100),
+            Status::SUCCESS
+        );
+        event_info.timer_tick(50);
+        assert_eq!(event_info.check_event(one_shot), Status::NOT_READY);
+        event_info.timer_tick(50);
+        assert_eq!(event_info.check_event(one_shot), Status::SUCCESS);
+        event_info.timer_tick(100);
+        assert_eq!(event_info.check_event(one_shot), Status::NOT_READY);
+
+        let (_, periodic) = event_info.create_event(EVT_TIMER, 0, 0, core::ptr::null_mut());
+        assert_eq!(
+            event_info.set_timer(periodic, TimerDelay::TimerPeriodic, 100),
+            Status::SUCCESS
+        );
+        for _ in 0..3 {
+            event_info.timer_tick(100);
+            assert_eq!(event_info.check_event(periodic), Status::SUCCESS);
+        }
+
+        assert_eq!(
+            event_info.set_timer(periodic, TimerDelay::TimerCancel, 0),
+            Status::SUCCESS
+        );
+        event_info.timer_tick(100);
+        assert_eq!(event_info.check_event(periodic), Status::NOT_READY);
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use core::sync::atomic::Ordering;
+
+use crate::block::SectorWrite;
+use crate::efi::variable::relocate_trait_object;
+use crate::pflash::{Error, FlashDevice};
+
+pub const FILE_FLASH_SIZE: usize = 64 * 1024;
+const SECTOR_SIZE: usize = 512;
+pub const FILE_FLASH_SECTORS: usize = FILE_FLASH_SIZE / SECTOR_SIZE;
+// Nothing is erased on a disk, this only lays out the banks of the store
+const BLOCK_SIZE: u64 = 4096;
+
+/// Flash emulated on top of a file, whose content is kept in memory. Changes
+/// are written through to the sectors of the file until boot services are
+/// exited: the disk belongs to the OS after that, so later changes last only
+/// until the next reset, as with OVMF on QEMU without flash.
+pub struct FileFlash {
+    device: *const dyn SectorWrite,
+    sectors: [u64; FILE_FLASH_SECTORS],
+    data: &'static mut [u8; FILE_FLASH_SIZE],
+}
+
+// Firmware code only runs on the boot processor
+unsafe impl Send for FileFlash {}
+
+impl FileFlash {
+    /// The file is made of the given sectors of the device, and its current
+    /// content is in data.
+    pub fn new(
+        device: *const dyn SectorWrite,
+        sectors: [u64; FILE_FLASH_SECTORS],
+        data: &'static mut [u8; FILE_FLASH_SIZE],
+    ) -> FileFlash {
+        FileFlash { device, sectors, data }
+    }
+
+    fn write_sectors(&mut self, offset: u64, length: u64) -> bool {
+        if crate::efi::EXIT_BOOT_SERVICES.load(Ordering::SeqCst) {
+            return true;
+        }
+
+        let first = offset as usize / SECTOR_SIZE;
+        let last = (offset + length - 1) as usize / SECTOR_SIZE;
+        let mut buffer = [0u8; SECTOR_SIZE];
+        for sector in first..=last {
+            buffer.copy_from_slice(&self.data[sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE]);
+            if unsafe { &*self.device }.write(self.sectors[sector], &mut buffer).is_err() {
+                log!("file_flash: failed to write sector 0x{:x}\n", self.sectors[sector]);
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl FlashDevice for FileFlash {
+    fn size(&self) -> u64 {
+        FILE_FLASH_SIZE as u64
+    }
+
+    fn block_size(&self) -> u64 {
+        BLOCK_SIZE
+    }
+
+    fn read(&self, offset: u64, data: &mut [u8]) {
+        let offset = offset as usize;
+        data.copy_from_slice(&self.data[offset..offset + data.len()]);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        assert!(offset + data.len() as u64 <= FILE_FLASH_SIZE as u64);
+        if data.is_empty() {
+            return Ok(());
+        }
+        // Like flash, a write only clears bits
+        for (i, byte) in data.iter().enumerate() {
+            self.data[offset as usize + i] &= *byte;
+        }
+        if self.write_sectors(offset, data.len() as u64) {
+            Ok(())
+        } else {
+            Err(Error::ProgramFailed)
+        }
+    }
+
+    fn erase_block(&mut self, offset: u64) -> Result<(), Error> {
+        assert!(offset < FILE_FLASH_SIZE as u64);
+        let offset = offset - offset % BLOCK_SIZE;
+        for byte in self.data[offset as usize..(offset + BLOCK_SIZE) as usize].iter_mut() {
+            *byte = 0xff;
+        }
+        if self.write_sectors(offset, BLOCK_SIZE) {
+            Ok(())
+        } else {
+            Err(Error::EraseFailed)
+        }
+    }
+
+    fn relocate(&mut self, convert: &dyn Fn(u64) -> Option<u64>) {
+        let mut device = self.device as *mut dyn SectorWrite;
+        relocate_trait_object(&mut device, convert);
+        self.device = device;
+        if let Some(data) = convert(self.data.as_mut_ptr() as u64) {
+            self.data = unsafe { &mut *(data as *mut [u8; FILE_FLASH_SIZE]) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::efi::flash_store::FlashStore;
+    use crate::efi::variable::NvStore;
+    use core::cell::RefCell;
+    use r_efi::efi::Status;
+
+    struct FakeDisk {
+        data: RefCell<Vec<u8>>,
+    }
+
+    impl SectorWrite for FakeDisk {
+        fn write(&self, sector: u64, data: &mut [u8]) -> Result<(), crate::block::Error> {
+            let offset = sector as usize * SECTOR_SIZE;
+            self.data.borrow_mut()[offset..offset + SECTOR_SIZE].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), crate::block::Error> {
+            Ok(())
+        }
+    }
+
+    // The file is scattered over the disk, backwards
+    fn file_flash(disk: &'static FakeDisk) -> FileFlash {
+        let mut sectors = [0u64; FILE_FLASH_SECTORS];
+        let mut data = Box::new([0u8; FILE_FLASH_SIZE]);
+        for i in 0..FILE_FLASH_SECTORS {
+            sectors[i] = (2 * (FILE_FLASH_SECTORS - i)) as u64;
+            let offset = sectors[i] as usize * SECTOR_SIZE;
+            data[i * SECTOR_SIZE..(i + 1) * SECTOR_SIZE]
+                .copy_from_slice(&disk.data.borrow()[offset..offset + SECTOR_SIZE]);
+        }
+        FileFlash::new(disk, sectors, Box::leak(data))
+    }
+
+    #[test]
+    fn test_file_flash() {
+        let disk: &'static FakeDisk = Box::leak(Box::new(FakeDisk {
+            data: RefCell::new(vec![0u8; (2 * FILE_FLASH_SECTORS + 1) * SECTOR_SIZE]),
+        }));
+        let guid = [3u8; 16];
+
+        let mut store = FlashStore::new(file_flash(disk), 0).unwrap();
+        assert_eq!(store.write_variable(b"Boot0000", &guid, 7, &[&[1, 2, 3]]), Status::SUCCESS);
+        assert_eq!(store.write_variable(b"BootOrder", &guid, 7, &[&[0, 0]]), Status::SUCCESS);
+        assert_eq!(store.write_variable(b"Boot0000", &guid, 7, &[&[4, 5]]), Status::SUCCESS);
+        // Sectors not in the file are left alone
+        assert!(disk.data.borrow()[..SECTOR_SIZE].iter().all(|b| *b == 0));
+        assert!(disk.data.borrow()[3 * SECTOR_SIZE..4 * SECTOR_SIZE].iter().all(|b| *b == 0));
+
+        // The content is read again from the disk
+        let mut store = FlashStore::new(file_flash(disk), 0).unwrap();
+        let mut variables = Vec::new();
+        store.load(&mut [0; 0x100], &mut |name, _, _, data| variables.push((name.to_vec(), data.to_vec())));
+        assert_eq!(
+            variables,
+            vec![(b"BootOrder".to_vec(), vec![0, 0]), (b"Boot0000".to_vec(), vec![4, 5])]
+        );
+    }
+}
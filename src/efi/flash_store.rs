@@ -0,0 +1,591 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use r_efi::efi::Status;
+
+use core::mem::size_of;
+
+use crate::pflash::FlashDevice;
+
+use super::variable::NvStore;
+
+// The store is split in two banks of whole blocks. Variables are appended to
+// the active bank as records, and a record is made obsolete by clearing bits
+// in its state, so nothing is erased until the bank is full. Then the live
+// records are copied to the other (spare) bank, whose header is written last:
+// until then the old bank stays the active one, so a power failure at any
+// point leaves either the old or the new content.
+
+const BANK_SIGNATURE: u32 = 0x474c_564e; // 'N','V','L','G'
+const BANK_HEADER_SIZE: u64 = 16;
+
+const RECORD_MAGIC: u16 = 0x55aa;
+const RECORD_ALIGNMENT: u64 = 8;
+const RECORD_HEADER_SIZE: u64 = 32;
+
+// Bits are cleared one after the other: the header is written with the state
+// erased, the name and data follow, then the record is made valid.
+const RECORD_STATE_IN_PROGRESS: u8 = 0xff;
+const RECORD_STATE_VALID: u8 = 0x7f;
+const RECORD_STATE_DELETED: u8 = 0x3f;
+
+const RECORD_STATE_OFFSET: u64 = 2;
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct BankHeader {
+    signature: u32,
+    sequence: u32,
+    reserved: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct RecordHeader {
+    magic: u16,
+    state: u8,
+    reserved: u8,
+    attributes: u32,
+    name_size: u32,
+    data_size: u32,
+    guid: [u8; 16],
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+fn as_bytes_mut<T>(value: &mut T) -> &mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(value as *mut T as *mut u8, size_of::<T>()) }
+}
+
+fn align_up(value: u64) -> u64 {
+    (value + RECORD_ALIGNMENT - 1) & !(RECORD_ALIGNMENT - 1)
+}
+
+fn record_size(name_size: u64, data_size: u64) -> u64 {
+    align_up(RECORD_HEADER_SIZE + name_size + data_size)
+}
+
+pub struct FlashStore<F: FlashDevice> {
+    flash: F,
+    // Offset of the first bank in the flash, the bank size, and the active bank
+    offset: u64,
+    bank_size: u64,
+    bank: u64,
+    sequence: u32,
+    // Offset of the next record in the active bank
+    write_offset: u64,
+}
+
+impl<F: FlashDevice> FlashStore<F> {
+    fn bank_offset(&self, bank: u64) -> u64 {
+        self.offset + bank * self.bank_size
+    }
+
+    fn read_bank_header(&self, bank: u64) -> BankHeader {
+        let mut header = BankHeader::default();
+        self.flash.read(self.bank_offset(bank), as_bytes_mut(&mut header));
+        header
+    }
+
+    fn read_record_header(&self, offset: u64) -> RecordHeader {
+        let mut header = RecordHeader::default();
+        self.flash.read(self.bank_offset(self.bank) + offset, as_bytes_mut(&mut header));
+        header
+    }
+
+    // Get the record at offset in the active bank, with the offset of the next one.
+    // None at the end of the log, or when it is corrupted.
+    fn next_record(&self, offset: u64) -> Option<(RecordHeader, u64)> {
+        if offset + RECORD_HEADER_SIZE > self.bank_size {
+            return None;
+        }
+        let header = self.read_record_header(offset);
+        if header.magic != RECORD_MAGIC {
+            return None;
+        }
+        let next = offset + record_size(header.name_size as u64, header.data_size as u64);
+        if header.name_size as u64 > self.bank_size || header.data_size as u64 > self.bank_size || next > self.bank_size {
+            return None;
+        }
+        Some((header, next))
+    }
+
+    // Find the end of the log in the active bank. If the log is damaged
+    // beyond that point, the bank is considered full so it gets reclaimed.
+    fn find_write_offset(&self) -> u64 {
+        let mut offset = BANK_HEADER_SIZE;
+        while let Some((_, next)) = self.next_record(offset) {
+            offset = next;
+        }
+        if offset + 2 <= self.bank_size {
+            let mut magic = [0u8; 2];
+            self.flash.read(self.bank_offset(self.bank) + offset, &mut magic);
+            if magic != [0xff, 0xff] {
+                return self.bank_size;
+            }
+        }
+        offset
+    }
+
+    // Whether the name of the record at offset in the active bank is name, read a piece at a time
+    fn is_record_name(&self, offset: u64, name: &[u8]) -> bool {
+        let mut buffer = [0u8; 64];
+        let base = self.bank_offset(self.bank) + offset + RECORD_HEADER_SIZE;
+        for (index, piece) in name.chunks(buffer.len()).enumerate() {
+            self.flash.read(base + (index * buffer.len()) as u64, &mut buffer[..piece.len()]);
+            if &buffer[..piece.len()] != piece {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Whether the records at offset and other in the active bank have the same name of size bytes
+    fn is_same_name(&self, offset: u64, other: u64, size: u64) -> bool {
+        let mut buffer = [0u8; 64];
+        let mut other_buffer = [0u8; 64];
+        let base = self.bank_offset(self.bank) + RECORD_HEADER_SIZE;
+        let mut compared = 0;
+        while compared < size {
+            let length = core::cmp::min(size - compared, buffer.len() as u64) as usize;
+            self.flash.read(base + offset + compared, &mut buffer[..length]);
+            self.flash.read(base + other + compared, &mut other_buffer[..length]);
+            if buffer[..length] != other_buffer[..length] {
+                return false;
+            }
+            compared += length as u64;
+        }
+        true
+    }
+
+    // Whether a valid record after the one at offset, which ends at next, is of
+    // the same variable. That happens when an update was interrupted before the
+    // old record was deleted, and only the last record counts.
+    fn is_superseded(&self, offset: u64, header: &RecordHeader, next: u64) -> bool {
+        let mut later = next;
+        while let Some((later_header, after)) = self.next_record(later) {
+            if later_header.state == RECORD_STATE_VALID && later_header.name_size == header.name_size
+                && later_header.guid == header.guid && self.is_same_name(offset, later, header.name_size as u64) {
+                return true;
+            }
+            later = after;
+        }
+        false
+    }
+
+    fn erase_bank(&mut self, bank: u64) -> Status {
+        let block_size = self.flash.block_size();
+        let base = self.bank_offset(bank);
+        let mut offset = 0;
+        while offset < self.bank_size {
+            if self.flash.erase_block(base + offset).is_err() {
+                log!("flash_store: erase failed at 0x{:x}\n", base + offset);
+                return Status::DEVICE_ERROR;
+            }
+            offset += block_size;
+        }
+        Status::SUCCESS
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Status {
+        match self.flash.write(offset, data) {
+            Ok(()) => Status::SUCCESS,
+            Err(e) => {
+                log!("flash_store: write failed at 0x{:x}: {:?}\n", offset, e);
+                Status::DEVICE_ERROR
+            }
+        }
+    }
+
+    fn write_bank_header(&mut self, bank: u64, sequence: u32) -> Status {
+        let header = BankHeader { signature: BANK_SIGNATURE, sequence, reserved: !0 };
+        let offset = self.bank_offset(bank);
+        self.write(offset, as_bytes(&header))
+    }
+
+    // Copy the valid records to the spare bank, then make it the active one.
+    fn reclaim(&mut self) -> Status {
+        let spare = 1 - self.bank;
+        let status = self.erase_bank(spare);
+        if status != Status::SUCCESS {
+            return status;
+        }
+
+        let mut buffer = [0u8; 256];
+        let mut offset = BANK_HEADER_SIZE;
+        let mut spare_offset = BANK_HEADER_SIZE;
+        while let Some((header, next)) = self.next_record(offset) {
+            if header.state == RECORD_STATE_VALID && !self.is_superseded(offset, &header, next) {
+                let mut copied = 0;
+                let size = next - offset;
+                while copied < size {
+                    let length = core::cmp::min(size - copied, buffer.len() as u64) as usize;
+                    self.flash.read(self.bank_offset(self.bank) + offset + copied, &mut buffer[..length]);
+                    let target = self.bank_offset(spare) + spare_offset + copied;
+                    let status = self.write(target, &buffer[..length]);
+                    if status != Status::SUCCESS {
+                        return status;
+                    }
+                    copied += length as u64;
+                }
+                spare_offset += size;
+            }
+            offset = next;
+        }
+
+        let sequence = self.sequence.wrapping_add(1);
+        let status = self.write_bank_header(spare, sequence);
+        if status != Status::SUCCESS {
+            return status;
+        }
+
+        log!("flash_store: reclaimed 0x{:x} of 0x{:x} bytes\n", self.write_offset - spare_offset, self.bank_size);
+        self.bank = spare;
+        self.sequence = sequence;
+        self.write_offset = spare_offset;
+        Status::SUCCESS
+    }
+
+    fn append_record(&mut self, name: &[u8], guid: &[u8; 16], attributes: u32, data: &[&[u8]], data_size: u64) -> Status {
+        let header = RecordHeader {
+            magic: RECORD_MAGIC,
+            state: RECORD_STATE_IN_PROGRESS,
+            reserved: 0xff,
+            attributes,
+            name_size: name.len() as u32,
+            data_size: data_size as u32,
+            guid: *guid,
+        };
+        let size = record_size(name.len() as u64, data_size);
+        let offset = self.bank_offset(self.bank) + self.write_offset;
+        // Whatever happens next, this space is used
+        self.write_offset += size;
+
+        let status = self.write(offset, as_bytes(&header));
+        if status != Status::SUCCESS {
+            return status;
+        }
+        let status = self.write(offset + RECORD_HEADER_SIZE, name);
+        if status != Status::SUCCESS {
+            return status;
+        }
+        let mut data_offset = offset + RECORD_HEADER_SIZE + name.len() as u64;
+        for piece in data.iter() {
+            let status = self.write(data_offset, piece);
+            if status != Status::SUCCESS {
+                return status;
+            }
+            data_offset += piece.len() as u64;
+        }
+        self.write(offset + RECORD_STATE_OFFSET, &[RECORD_STATE_VALID])
+    }
+
+    fn delete_record(&mut self, offset: u64) -> Status {
+        let offset = self.bank_offset(self.bank) + offset + RECORD_STATE_OFFSET;
+        self.write(offset, &[RECORD_STATE_DELETED])
+    }
+
+    // Delete every valid record of a variable before the end offset, as there
+    // is more than one after an interrupted update.
+    fn delete_records(&mut self, name: &[u8], guid: &[u8; 16], end: u64) -> Status {
+        let mut offset = BANK_HEADER_SIZE;
+        while let Some((header, next)) = self.next_record(offset) {
+            if offset >= end {
+                break;
+            }
+            if header.state == RECORD_STATE_VALID && header.name_size as usize == name.len() && header.guid == *guid
+                && self.is_record_name(offset, name) {
+                let status = self.delete_record(offset);
+                if status != Status::SUCCESS {
+                    return status;
+                }
+            }
+            offset = next;
+        }
+        Status::SUCCESS
+    }
+
+    /// Use the flash from offset to its end as the store, formatting it
+    /// if it holds no valid bank.
+    pub fn new(flash: F, offset: u64) -> Option<FlashStore<F>> {
+        let block_size = flash.block_size();
+        let bank_size = (flash.size() - offset) / 2 / block_size * block_size;
+        if offset % block_size != 0 || bank_size == 0 {
+            return None;
+        }
+
+        let mut store = FlashStore { flash, offset, bank_size, bank: 0, sequence: 0, write_offset: 0 };
+
+        let headers = [store.read_bank_header(0), store.read_bank_header(1)];
+        let valid = [headers[0].signature == BANK_SIGNATURE, headers[1].signature == BANK_SIGNATURE];
+        let bank = match valid {
+            [true, true] => {
+                if (headers[1].sequence.wrapping_sub(headers[0].sequence) as i32) > 0 { 1 } else { 0 }
+            },
+            [true, false] => 0,
+            [false, true] => 1,
+            [false, false] => {
+                log!("flash_store: formatting 0x{:x} bytes\n", store.bank_size * 2);
+                if store.erase_bank(0) != Status::SUCCESS || store.write_bank_header(0, 1) != Status::SUCCESS {
+                    return None;
+                }
+                store.sequence = 1;
+                store.write_offset = BANK_HEADER_SIZE;
+                return Some(store);
+            }
+        };
+
+        store.bank = bank;
+        store.sequence = headers[bank as usize].sequence;
+        store.write_offset = store.find_write_offset();
+        Some(store)
+    }
+}
+
+impl<F: FlashDevice> NvStore for FlashStore<F> {
+    fn load(&mut self, buffer: &mut [u8], restore: &mut dyn FnMut(&[u8], &[u8; 16], u32, &[u8])) {
+        let mut offset = BANK_HEADER_SIZE;
+        while let Some((header, next)) = self.next_record(offset) {
+            let name_size = header.name_size as usize;
+            let size = name_size + header.data_size as usize;
+            if header.state != RECORD_STATE_VALID || self.is_superseded(offset, &header, next) {
+                // Deleted, or left by an interrupted update
+            } else if size <= buffer.len() {
+                let base = self.bank_offset(self.bank) + offset + RECORD_HEADER_SIZE;
+                self.flash.read(base, &mut buffer[..size]);
+                let (name, data) = buffer[..size].split_at(name_size);
+                restore(name, &header.guid, header.attributes, data);
+            } else {
+                log!("flash_store: record too large at 0x{:x}\n", offset);
+            }
+            offset = next;
+        }
+    }
+
+    fn write_variable(&mut self, name: &[u8], guid: &[u8; 16], attributes: u32, data: &[&[u8]]) -> Status {
+        let data_size = data.iter().map(|piece| piece.len() as u64).sum();
+        if data_size == 0 {
+            let end = self.write_offset;
+            return self.delete_records(name, guid, end);
+        }
+
+        let size = record_size(name.len() as u64, data_size);
+        if self.write_offset + size > self.bank_size {
+            let status = self.reclaim();
+            if status != Status::SUCCESS {
+                return status;
+            }
+            if self.write_offset + size > self.bank_size {
+                return Status::OUT_OF_RESOURCES;
+            }
+        }
+
+        // The new record is valid before the old ones are deleted
+        let new_offset = self.write_offset;
+        let status = self.append_record(name, guid, attributes, data, data_size);
+        if status != Status::SUCCESS {
+            return status;
+        }
+        self.delete_records(name, guid, new_offset)
+    }
+
+    fn size(&self) -> u64 {
+        self.bank_size
+    }
+
+    fn free_space(&self) -> u64 {
+        // Deleted records are given back by a reclaim
+        let mut live = BANK_HEADER_SIZE;
+        let mut offset = BANK_HEADER_SIZE;
+        while let Some((header, next)) = self.next_record(offset) {
+            if header.state == RECORD_STATE_VALID && !self.is_superseded(offset, &header, next) {
+                live += next - offset;
+            }
+            offset = next;
+        }
+        self.bank_size - live
+    }
+
+    fn relocate(&mut self, convert: &dyn Fn(u64) -> Option<u64>) {
+        self.flash.relocate(convert);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlashStore, BANK_HEADER_SIZE, RECORD_HEADER_SIZE, RECORD_STATE_OFFSET};
+    use crate::efi::variable::NvStore;
+    use crate::pflash::{Error, FlashDevice};
+    use r_efi::efi::Status;
+
+    const BLOCK_SIZE: u64 = 0x100;
+    const FLASH_SIZE: usize = 0x500;
+
+    struct RamFlash {
+        data: [u8; FLASH_SIZE],
+        // Number of byte writes left before a simulated power failure
+        writes_left: Option<usize>,
+    }
+
+    impl FlashDevice for RamFlash {
+        fn size(&self) -> u64 {
+            FLASH_SIZE as u64
+        }
+        fn block_size(&self) -> u64 {
+            BLOCK_SIZE
+        }
+        fn read(&self, offset: u64, data: &mut [u8]) {
+            data.copy_from_slice(&self.data[offset as usize..offset as usize + data.len()]);
+        }
+        fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+            for (i, byte) in data.iter().enumerate() {
+                if let Some(left) = self.writes_left {
+                    if left == 0 {
+                        return Err(Error::Timeout);
+                    }
+                    self.writes_left = Some(left - 1);
+                }
+                // Writes can only clear bits
+                self.data[offset as usize + i] &= *byte;
+            }
+            Ok(())
+        }
+        fn erase_block(&mut self, offset: u64) -> Result<(), Error> {
+            let start = (offset - offset % BLOCK_SIZE) as usize;
+            for byte in self.data[start..start + BLOCK_SIZE as usize].iter_mut() {
+                *byte = 0xff;
+            }
+            Ok(())
+        }
+    }
+
+    fn load(store: &mut FlashStore<RamFlash>) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut variables: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        store.load(&mut [0; 0x400], &mut |name, _, _, data| {
+            variables.retain(|(n, _)| n != name);
+            variables.push((name.to_vec(), data.to_vec()));
+        });
+        variables
+    }
+
+    fn reopen(store: FlashStore<RamFlash>) -> FlashStore<RamFlash> {
+        let mut flash = store.flash;
+        flash.writes_left = None;
+        FlashStore::new(flash, BLOCK_SIZE).unwrap()
+    }
+
+    #[test]
+    fn test_flash_store() {
+        let flash = RamFlash { data: [0; FLASH_SIZE], writes_left: None };
+        let mut store = FlashStore::new(flash, BLOCK_SIZE).unwrap();
+        assert_eq!(store.bank_size, 2 * BLOCK_SIZE);
+        assert!(load(&mut store).is_empty());
+
+        let guid = [1; 16];
+        assert_eq!(store.write_variable(b"a\0", &guid, 7, &[&[1, 2, 3]]), Status::SUCCESS);
+        assert_eq!(store.write_variable(b"b\0", &guid, 7, &[&[4]]), Status::SUCCESS);
+        assert_eq!(store.write_variable(b"a\0", &guid, 7, &[&[5]]), Status::SUCCESS);
+        assert_eq!(store.write_variable(b"b\0", &guid, 7, &[&[]]), Status::SUCCESS);
+
+        let mut store = reopen(store);
+        assert_eq!(load(&mut store), vec![(b"a\0".to_vec(), vec![5])]);
+
+        // Names are compared a piece at a time
+        let long_name = [b'n'; 100];
+        assert_eq!(store.write_variable(&long_name, &guid, 7, &[&[6], &[7]]), Status::SUCCESS);
+        assert_eq!(store.write_variable(&long_name, &guid, 7, &[&[8]]), Status::SUCCESS);
+        assert_eq!(load(&mut store), vec![(b"a\0".to_vec(), vec![5]), (long_name.to_vec(), vec![8])]);
+        assert_eq!(store.write_variable(&long_name, &guid, 7, &[]), Status::SUCCESS);
+
+        // Updates beyond the size of a bank go through reclaims
+        for i in 0..100u8 {
+            assert_eq!(store.write_variable(b"c\0", &guid, 7, &[&[i; 20]]), Status::SUCCESS);
+        }
+        let mut store = reopen(store);
+        assert_eq!(load(&mut store), vec![(b"a\0".to_vec(), vec![5]), (b"c\0".to_vec(), vec![99; 20])]);
+        assert_eq!(store.write_variable(b"d\0", &guid, 7, &[&[0; 0x200]]), Status::OUT_OF_RESOURCES);
+    }
+
+    #[test]
+    fn test_flash_store_power_failure() {
+        let guid = [1; 16];
+        // Fail every possible write of an update, then of an update needing a reclaim
+        for writes in 0..200 {
+            let flash = RamFlash { data: [0; FLASH_SIZE], writes_left: None };
+            let mut store = FlashStore::new(flash, BLOCK_SIZE).unwrap();
+            for i in 0..6u8 {
+                assert_eq!(store.write_variable(b"a\0", &guid, 7, &[&[i; 40]]), Status::SUCCESS);
+            }
+            let before = store.write_offset;
+            store.flash.writes_left = Some(writes);
+            let status = store.write_variable(b"a\0", &guid, 7, &[&[0xaa; 40]]);
+            let status = if status == Status::SUCCESS {
+                store.write_variable(b"a\0", &guid, 7, &[&[0xbb; 40]])
+            } else {
+                status
+            };
+
+            let mut store = reopen(store);
+            let variables = load(&mut store);
+            assert_eq!(variables.len(), 1);
+            let data = &variables[0].1;
+            assert!(data == &vec![5; 40] || data == &vec![0xaa; 40] || data == &vec![0xbb; 40]);
+            if status == Status::SUCCESS {
+                assert_eq!(data, &vec![0xbb; 40]);
+            }
+
+            // The store is still usable
+            assert_eq!(store.write_variable(b"a\0", &guid, 7, &[&[0xcc; 40]]), Status::SUCCESS);
+            let mut store = reopen(store);
+            assert_eq!(load(&mut store), vec![(b"a\0".to_vec(), vec![0xcc; 40])]);
+        }
+    }
+
+    #[test]
+    fn test_flash_store_interrupted_update() {
+        let guid = [1; 16];
+        let flash = RamFlash { data: [0; FLASH_SIZE], writes_left: None };
+        let mut store = FlashStore::new(flash, BLOCK_SIZE).unwrap();
+        assert_eq!(store.write_variable(b"a\0", &guid, 7, &[&[1]]), Status::SUCCESS);
+        assert_eq!(store.write_variable(b"b\0", &guid, 7, &[&[2]]), Status::SUCCESS);
+
+        // The new record is written, but the power fails before the old one is deleted
+        store.flash.writes_left = Some(RECORD_HEADER_SIZE as usize + 2 + 1 + 1);
+        assert_eq!(store.write_variable(b"a\0", &guid, 7, &[&[3]]), Status::DEVICE_ERROR);
+        let mut store = reopen(store);
+        let mut restored = Vec::new();
+        store.load(&mut [0; 0x400], &mut |name, _, _, data| restored.push((name.to_vec(), data.to_vec())));
+        assert_eq!(restored, vec![(b"b\0".to_vec(), vec![2]), (b"a\0".to_vec(), vec![3])]);
+
+        // Deleting it then deletes both records
+        assert_eq!(store.write_variable(b"a\0", &guid, 7, &[]), Status::SUCCESS);
+        let mut store = reopen(store);
+        assert_eq!(load(&mut store), vec![(b"b\0".to_vec(), vec![2])]);
+
+        // A reclaim keeps only the last record
+        assert_eq!(store.write_variable(b"c\0", &guid, 7, &[&[4]]), Status::SUCCESS);
+        store.flash.writes_left = Some(RECORD_HEADER_SIZE as usize + 2 + 1 + 1);
+        assert_eq!(store.write_variable(b"c\0", &guid, 7, &[&[5]]), Status::DEVICE_ERROR);
+        let mut store = reopen(store);
+        assert_eq!(store.reclaim(), Status::SUCCESS);
+        let mut store = reopen(store);
+        let mut restored = Vec::new();
+        store.load(&mut [0; 0x400], &mut |name, _, _, data| restored.push((name.to_vec(), data.to_vec())));
+        assert_eq!(restored, vec![(b"b\0".to_vec(), vec![2]), (b"c\0".to_vec(), vec![5])]);
+    }
+}
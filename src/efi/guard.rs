@@ -0,0 +1,367 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use r_efi::efi::{AllocateType, MemoryType, PhysicalAddress, Status};
+
+use super::pool::PageAllocator;
+
+const PAGE_SIZE: u64 = 4096;
+
+// AllocatePool gives 8 bytes aligned buffers
+const POOL_ALIGNMENT: u64 = 8;
+
+const MAX_GUARDED: usize = 4096;
+
+/// An allocation between two guard pages
+#[derive(Clone, Copy, Debug)]
+pub struct Guarded {
+    // The first guard page, the allocated pages follow it
+    pub base: u64,
+    pub page_count: u64,
+    // What the caller got, a pool buffer ends right at the second guard page
+    pub address: u64,
+    pub size: u64,
+    pub memory_type: u32,
+    pub pool: bool,
+    // The return address of the AllocatePages or AllocatePool call, 0 for the firmware
+    pub caller: u64,
+}
+
+impl Guarded {
+    pub fn tail_guard(&self) -> u64 {
+        self.base + (self.page_count + 1) * PAGE_SIZE
+    }
+
+    // Whether any of the pages, guard pages included, is in the range
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        start < self.tail_guard() + PAGE_SIZE && end > self.base
+    }
+}
+
+// The table of the guarded allocations, too large to be built on the stack
+#[cfg(all(not(test), feature = "guard-pages"))]
+pub static mut GUARDED: [Option<Guarded>; MAX_GUARDED] = [None; MAX_GUARDED];
+
+/// The guarded allocations, to free them with their guard pages and to
+/// tell whose guard page a fault hit.
+pub struct Guards {
+    allocations: &'static mut [Option<Guarded>],
+}
+
+impl Guards {
+    pub fn new(allocations: &'static mut [Option<Guarded>]) -> Guards {
+        Guards { allocations }
+    }
+
+    fn add<A: PageAllocator>(
+        &mut self,
+        pages: &mut A,
+        memory_type: MemoryType,
+        page_count: u64,
+        size: u64,
+        pool: bool,
+        caller: u64,
+    ) -> Option<Guarded> {
+        let slot = self.allocations.iter().position(|allocation| allocation.is_none())?;
+        let base = pages.allocate_pages(memory_type, page_count.checked_add(2)?)?;
+        let address = if pool {
+            base + PAGE_SIZE + page_count * PAGE_SIZE - (size + POOL_ALIGNMENT - 1) / POOL_ALIGNMENT * POOL_ALIGNMENT
+        } else {
+            base + PAGE_SIZE
+        };
+        let guarded = Guarded {
+            base,
+            page_count,
+            address,
+            size,
+            memory_type: memory_type as u32,
+            pool,
+            caller,
+        };
+        self.allocations[slot] = Some(guarded);
+        Some(guarded)
+    }
+
+    pub fn allocate_pages<A: PageAllocator>(
+        &mut self,
+        pages: &mut A,
+        memory_type: MemoryType,
+        page_count: u64,
+        caller: u64,
+    ) -> Option<Guarded> {
+        let size = page_count.checked_mul(PAGE_SIZE)?;
+        self.add(pages, memory_type, page_count, size, false, caller)
+    }
+
+    pub fn allocate_pool<A: PageAllocator>(
+        &mut self,
+        pages: &mut A,
+        memory_type: MemoryType,
+        size: u64,
+        caller: u64,
+    ) -> Option<Guarded> {
+        let page_count = core::cmp::max(size.checked_add(PAGE_SIZE - 1)? / PAGE_SIZE, 1);
+        self.add(pages, memory_type, page_count, size, true, caller)
+    }
+
+    // Free an allocation with its guard pages, None when the range is not
+    // guarded. A range that is only part of a guarded allocation, or not
+    // allocated the same way, is rejected.
+    fn remove<A: PageAllocator>(&mut self, pages: &mut A, pool: bool, address: u64, page_count: Option<u64>) -> Option<Status> {
+        let end = address.saturating_add(page_count.unwrap_or(1).saturating_mul(PAGE_SIZE));
+        let slot = self.allocations.iter().position(|allocation| match allocation {
+            Some(guarded) => guarded.overlaps(address, end),
+            None => false,
+        })?;
+        let guarded = self.allocations[slot].unwrap();
+        if guarded.pool != pool
+            || guarded.address != address
+            || page_count.map_or(false, |page_count| page_count != guarded.page_count)
+        {
+            log!(
+                "guard pages - 0x{:x} is not the {} allocation 0x{:x} pages 0x{:x}\n",
+                address, if guarded.pool { "pool" } else { "pages" }, guarded.address, guarded.page_count
+            );
+            return Some(Status::INVALID_PARAMETER);
+        }
+        self.allocations[slot] = None;
+        pages.free_pages(guarded.base, guarded.page_count + 2);
+        Some(Status::SUCCESS)
+    }
+
+    /// Free pages allocated whole with AllocatePages. Those outside of the
+    /// guarded allocations are left to the allocator.
+    pub fn free_pages<A: PageAllocator>(&mut self, pages: &mut A, address: u64, page_count: u64) -> Option<Status> {
+        self.remove(pages, false, address, Some(page_count))
+    }
+
+    pub fn free_pool<A: PageAllocator>(&mut self, pages: &mut A, address: u64) -> Option<Status> {
+        self.remove(pages, true, address, None)
+    }
+
+    /// The allocation one of whose guard pages has the address
+    pub fn find(&self, address: u64) -> Option<Guarded> {
+        self.allocations.iter().filter_map(|allocation| *allocation).find(|guarded| {
+            (address >= guarded.base && address < guarded.base + PAGE_SIZE)
+                || (address >= guarded.tail_guard() && address < guarded.tail_guard() + PAGE_SIZE)
+        })
+    }
+}
+
+// The boot services tables call AllocatePages and AllocatePool through these,
+// which keep the return address to the caller for the allocation.
+#[cfg(all(not(test), feature = "guard-pages"))]
+global_asm!(r#"
+.global efi_guard_allocate_pages
+efi_guard_allocate_pages:
+    mov (%rsp), %rax
+    mov %rax, GUARD_CALLER(%rip)
+    jmp guard_allocate_pages
+
+.global efi_guard_allocate_pool
+efi_guard_allocate_pool:
+    mov (%rsp), %rax
+    mov %rax, GUARD_CALLER(%rip)
+    jmp guard_allocate_pool
+"#);
+
+#[cfg(all(not(test), feature = "guard-pages"))]
+#[no_mangle]
+static mut GUARD_CALLER: u64 = 0;
+
+#[cfg(all(not(test), feature = "guard-pages"))]
+#[no_mangle]
+extern "win64" fn guard_allocate_pages(
+    allocate_type: AllocateType,
+    memory_type: MemoryType,
+    pages: usize,
+    address: *mut PhysicalAddress,
+) -> Status {
+    crate::efi::allocate_pages(allocate_type, memory_type, pages, address)
+}
+
+#[cfg(all(not(test), feature = "guard-pages"))]
+#[no_mangle]
+extern "win64" fn guard_allocate_pool(
+    memory_type: MemoryType,
+    size: usize,
+    address: *mut *mut core::ffi::c_void,
+) -> Status {
+    crate::efi::allocate_pool(memory_type, size, address)
+}
+
+#[cfg(all(not(test), feature = "guard-pages"))]
+extern "win64" {
+    fn efi_guard_allocate_pages();
+    fn efi_guard_allocate_pool();
+}
+
+#[cfg(all(not(test), feature = "guard-pages"))]
+pub fn initialize(bs: &mut r_efi::efi::BootServices) {
+    bs.allocate_pages = unsafe { core::mem::transmute(efi_guard_allocate_pages as *const ()) };
+    bs.allocate_pool = unsafe { core::mem::transmute(efi_guard_allocate_pool as *const ()) };
+    log!("guard pages - every allocation is between non-present pages\n");
+}
+
+// The caller of the allocation in progress, the firmware itself calls the
+// services directly.
+#[cfg(all(not(test), feature = "guard-pages"))]
+fn take_caller() -> u64 {
+    unsafe {
+        let caller = core::ptr::read_volatile(&GUARD_CALLER);
+        core::ptr::write_volatile(&mut GUARD_CALLER, 0);
+        caller
+    }
+}
+
+#[cfg(all(not(test), feature = "guard-pages"))]
+fn protect(allocator: &mut super::alloc::Allocator, guarded: &Guarded) {
+    super::paging::set_no_access(allocator, guarded.base, 1);
+    super::paging::set_no_access(allocator, guarded.tail_guard(), 1);
+}
+
+/// Guard pages only work with the page tables of the firmware, allocations
+/// made before those are not guarded.
+#[cfg(all(not(test), feature = "guard-pages"))]
+pub fn allocate_pages(
+    allocator: &mut super::alloc::Allocator,
+    allocate_type: AllocateType,
+    memory_type: MemoryType,
+    page_count: u64,
+) -> Option<u64> {
+    let caller = take_caller();
+    match allocate_type {
+        AllocateType::AllocateAnyPages if super::paging::is_enabled() => {}
+        _ => return None,
+    }
+    let guarded = crate::efi::GUARDS.lock().allocate_pages(allocator, memory_type, page_count, caller)?;
+    protect(allocator, &guarded);
+    Some(guarded.address)
+}
+
+#[cfg(all(not(test), feature = "guard-pages"))]
+pub fn allocate_pool(allocator: &mut super::alloc::Allocator, memory_type: MemoryType, size: u64) -> Option<u64> {
+    let caller = take_caller();
+    if !super::paging::is_enabled() {
+        return None;
+    }
+    let guarded = crate::efi::GUARDS.lock().allocate_pool(allocator, memory_type, size, caller)?;
+    protect(allocator, &guarded);
+    Some(guarded.address)
+}
+
+#[cfg(all(not(test), feature = "guard-pages"))]
+pub fn free_pages(allocator: &mut super::alloc::Allocator, address: u64, page_count: u64) -> Option<Status> {
+    crate::efi::GUARDS.lock().free_pages(allocator, address, page_count)
+}
+
+#[cfg(all(not(test), feature = "guard-pages"))]
+pub fn free_pool(allocator: &mut super::alloc::Allocator, address: u64) -> Option<Status> {
+    crate::efi::GUARDS.lock().free_pool(allocator, address)
+}
+
+/// Tell which allocation a fault on a guard page over- or underran
+pub fn report(guards: &Guards, address: u64) {
+    let guarded = match guards.find(address) {
+        Some(guarded) => guarded,
+        None => return,
+    };
+    let side = if address < guarded.address { "before the start" } else { "past the end" };
+    let kind = if guarded.pool { "pool" } else { "pages" };
+    log!(
+        "GUARD PAGE: 0x{:x} is {} of the {} allocation 0x{:x} size 0x{:x} memory type {}\n",
+        address, side, kind, guarded.address, guarded.size, guarded.memory_type
+    );
+    if guarded.caller == 0 {
+        log!("GUARD PAGE: allocated by the firmware\n");
+    } else {
+        log!("GUARD PAGE: allocated by the caller at 0x{:x}\n", guarded.caller);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::efi::pool::TestPages;
+
+    fn new_guards() -> Guards {
+        Guards::new(Box::leak(vec![None; 16].into_boxed_slice()))
+    }
+
+    #[test]
+    fn test_allocate_pages() {
+        let mut pages = TestPages::new(8);
+        let mut guards = new_guards();
+        let base = pages.next;
+
+        let guarded = guards.allocate_pages(&mut pages, MemoryType::BootServicesData, 2, 0x1234).unwrap();
+        assert_eq!(pages.allocations, vec![(base, 4, MemoryType::BootServicesData as u32)]);
+        assert_eq!(guarded.address, base + 0x1000);
+        assert_eq!(guarded.size, 0x2000);
+        assert_eq!(guarded.tail_guard(), base + 0x3000);
+
+        // Only the whole allocation frees the guard pages, parts of it are rejected
+        assert_eq!(guards.free_pages(&mut pages, base + 0x1000, 1), Some(Status::INVALID_PARAMETER));
+        assert_eq!(guards.free_pages(&mut pages, base + 0x2000, 1), Some(Status::INVALID_PARAMETER));
+        assert_eq!(guards.free_pages(&mut pages, base, 4), Some(Status::INVALID_PARAMETER));
+        assert_eq!(guards.free_pool(&mut pages, base + 0x1000), Some(Status::INVALID_PARAMETER));
+        assert_eq!(pages.allocations.len(), 1);
+        assert_eq!(guards.free_pages(&mut pages, base + 0x1000, 2), Some(Status::SUCCESS));
+        assert!(pages.allocations.is_empty());
+        assert_eq!(guards.free_pages(&mut pages, base + 0x1000, 2), None);
+    }
+
+    #[test]
+    fn test_allocate_pool() {
+        let mut pages = TestPages::new(16);
+        let mut guards = new_guards();
+        let base = pages.next;
+
+        // The buffer ends at the tail guard, 8 bytes aligned
+        let guarded = guards.allocate_pool(&mut pages, MemoryType::LoaderData, 20, 0).unwrap();
+        assert_eq!(pages.allocations, vec![(base, 3, MemoryType::LoaderData as u32)]);
+        assert_eq!(guarded.address, base + 0x2000 - 24);
+        let guarded = guards.allocate_pool(&mut pages, MemoryType::LoaderData, 0x1001, 0).unwrap();
+        assert_eq!(pages.allocations[1], (base + 0x3000, 4, MemoryType::LoaderData as u32));
+        assert_eq!(guarded.address, base + 0x6000 - 0x1008);
+        let guarded = guards.allocate_pool(&mut pages, MemoryType::LoaderData, 0, 0).unwrap();
+        assert_eq!(guarded.address, base + 0x9000);
+
+        assert_eq!(guards.free_pages(&mut pages, base + 0x1000, 1), Some(Status::INVALID_PARAMETER));
+        assert_eq!(guards.free_pool(&mut pages, base + 0x2000 - 16), Some(Status::INVALID_PARAMETER));
+        assert_eq!(guards.free_pool(&mut pages, base + 0x2000 - 24), Some(Status::SUCCESS));
+        assert_eq!(pages.allocations.len(), 2);
+        assert_eq!(guards.allocate_pool(&mut pages, MemoryType::LoaderData, u64::MAX, 0).map(|g| g.address), None);
+    }
+
+    #[test]
+    fn test_find() {
+        let mut pages = TestPages::new(8);
+        let mut guards = new_guards();
+        let base = pages.next;
+        guards.allocate_pool(&mut pages, MemoryType::BootServicesData, 16, 0x1111).unwrap();
+        guards.allocate_pages(&mut pages, MemoryType::BootServicesCode, 1, 0x2222).unwrap();
+
+        assert_eq!(guards.find(base).unwrap().caller, 0x1111);
+        assert_eq!(guards.find(base + 0x2000).unwrap().caller, 0x1111);
+        assert!(guards.find(base + 0x1ff8).is_none());
+        // The guard pages of neighbours are next to each other
+        assert_eq!(guards.find(base + 0x3000).unwrap().caller, 0x2222);
+        assert_eq!(guards.find(base + 0x5fff).unwrap().memory_type, MemoryType::BootServicesCode as u32);
+        assert!(guards.find(base + 0x6000).is_none());
+    }
+}
@@ -164,6 +164,39 @@ impl HandleDatabase {
         (Status::SUCCESS, cur_handle)
     }
 
+    pub fn uninstall_protocol (
+        &mut self,
+        handle: Handle,
+        guid : *mut Guid,
+        interface : *mut c_void,
+    ) -> Status {
+        let (status, cur_handle) = self.get_handle (handle);
+        if status != Status::SUCCESS {
+          return Status::INVALID_PARAMETER;
+        }
+        let protocol_handle = unsafe {transmute::<Handle, &mut ProtocolHandle>(cur_handle)};
+
+        let (status, protocol_struct) = self.get_protocol (protocol_handle, guid);
+        if status != Status::SUCCESS || unsafe {(*protocol_struct).interface} != interface as usize {
+          return Status::NOT_FOUND;
+        }
+
+        let count = protocol_handle.protocol_count;
+        let index = (protocol_struct as usize - &protocol_handle.protocol_struct[0] as *const ProtocolStruct as usize) / size_of::<ProtocolStruct>();
+        for cur_index in index .. count - 1 {
+          protocol_handle.protocol_struct[cur_index] = protocol_handle.protocol_struct[cur_index + 1].clone();
+        }
+        protocol_handle.protocol_struct[count - 1] = ProtocolStruct::default();
+        protocol_handle.protocol_count = count - 1;
+
+        // The handle goes away with its last protocol, the slot may be reused.
+        if protocol_handle.protocol_count == 0 {
+          protocol_handle.signature = 0;
+        }
+
+        Status::SUCCESS
+    }
+
     fn locate_handle_count (
         &mut self,
         guid : *mut Guid,
@@ -309,7 +342,10 @@ impl HandleDatabase {
         guid : *mut Guid,
         ) -> (Status, *mut ProtocolStruct) {
         unsafe {
-          assert!((*protocol_handle).signature == HANDLE_SIGNATURE);
+          // A freed handle slot has no protocol.
+          if (*protocol_handle).signature != HANDLE_SIGNATURE {
+            return (Status::NOT_FOUND, core::ptr::null_mut());
+          }
           for index in 0 .. (*protocol_handle).protocol_count {
             let mut guid_data = (*protocol_handle).protocol_struct[index].guid;
             if *guid == guid_data {
@@ -323,12 +359,21 @@ impl HandleDatabase {
     fn get_new_handle (
         &mut self
         ) -> (Status, Handle) {
-        if (self.protocol_handle_count >= MAX_HANDLE_STRUCT) {
+        let mut index = self.protocol_handle_count;
+        for cur_index in 0 .. self.protocol_handle_count {
+          if self.protocol_handle[cur_index].signature != HANDLE_SIGNATURE {
+            index = cur_index;
+            break;
+          }
+        }
+        if (index >= MAX_HANDLE_STRUCT) {
           log!("{}:{} out of resource\n", file!(), line!());
           return (Status::OUT_OF_RESOURCES, core::ptr::null_mut());
         }
-        let protocol_handle = &mut self.protocol_handle[self.protocol_handle_count];
-        self.protocol_handle_count = self.protocol_handle_count + 1;
+        if index == self.protocol_handle_count {
+          self.protocol_handle_count = self.protocol_handle_count + 1;
+        }
+        let protocol_handle = &mut self.protocol_handle[index];
 
         protocol_handle.signature = HANDLE_SIGNATURE;
         protocol_handle.protocol_count = 0;
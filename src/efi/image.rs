@@ -31,9 +31,83 @@ use r_efi::protocols::device_path::End as EndDevicePath;
 use core::ffi::c_void;
 use core::mem::transmute;
 use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::efi::peloader::*;
 
+// efi_call_image() calls the entry point of an image after saving the
+// callee-saved registers of the win64 ABI on its stack, and records that
+// stack pointer in the jump context. efi_exit_image() switches back to a
+// recorded stack and returns from efi_call_image() with the given status,
+// which is how Exit() unwinds an image back to its StartImage() caller.
+#[cfg(not(test))]
+global_asm!(r#"
+.global efi_call_image
+efi_call_image:
+    push %rbp
+    push %rbx
+    push %rdi
+    push %rsi
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    sub $0xc8, %rsp
+    movdqu %xmm6, 0x20(%rsp)
+    movdqu %xmm7, 0x30(%rsp)
+    movdqu %xmm8, 0x40(%rsp)
+    movdqu %xmm9, 0x50(%rsp)
+    movdqu %xmm10, 0x60(%rsp)
+    movdqu %xmm11, 0x70(%rsp)
+    movdqu %xmm12, 0x80(%rsp)
+    movdqu %xmm13, 0x90(%rsp)
+    movdqu %xmm14, 0xa0(%rsp)
+    movdqu %xmm15, 0xb0(%rsp)
+    stmxcsr 0xc0(%rsp)
+    mov %rsp, (%r9)
+    mov %rcx, %rax
+    mov %rdx, %rcx
+    mov %r8, %rdx
+    call *%rax
+efi_call_image_return:
+    ldmxcsr 0xc0(%rsp)
+    movdqu 0x20(%rsp), %xmm6
+    movdqu 0x30(%rsp), %xmm7
+    movdqu 0x40(%rsp), %xmm8
+    movdqu 0x50(%rsp), %xmm9
+    movdqu 0x60(%rsp), %xmm10
+    movdqu 0x70(%rsp), %xmm11
+    movdqu 0x80(%rsp), %xmm12
+    movdqu 0x90(%rsp), %xmm13
+    movdqu 0xa0(%rsp), %xmm14
+    movdqu 0xb0(%rsp), %xmm15
+    add $0xc8, %rsp
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rsi
+    pop %rdi
+    pop %rbx
+    pop %rbp
+    ret
+
+.global efi_exit_image
+efi_exit_image:
+    mov %rcx, %rsp
+    mov %rdx, %rax
+    jmp efi_call_image_return
+"#);
+
+#[cfg(not(test))]
+extern "win64" {
+    fn efi_call_image(entry_point: usize, image_handle: Handle, system_table: *mut efi::SystemTable, jump_context: *mut usize) -> Status;
+    fn efi_exit_image(jump_context: usize, status: Status) -> !;
+}
+
+// The image whose entry point is running, Exit() is only valid for it.
+static CURRENT_IMAGE: AtomicUsize = AtomicUsize::new(0);
+
 // HACK: Until r-util/r-efi#11 gets merged
 #[cfg(not(test))]
 #[repr(C)]
@@ -91,6 +165,12 @@ struct ImageInfo {
     source_buffer: usize,
     source_size: usize,
     entry_point: usize,
+    started: bool,
+    jump_context: usize,
+    exit_data_size: usize,
+    exit_data: usize,
+    // The copy of the load options given by the firmware, freed with the image
+    load_options: usize,
     loaded_image: LoadedImageProtocol,
 }
 
@@ -108,6 +188,32 @@ fn dup_device_path(device_path: *mut c_void) -> *mut core::ffi::c_void{
   device_path_buffer
 }
 
+fn image_pages(image_size: usize) -> usize {
+  (image_size + crate::efi::PAGE_SIZE as usize - 1) / crate::efi::PAGE_SIZE as usize
+}
+
+fn get_image_info(image_handle: Handle) -> Option<&'static mut ImageInfo> {
+  if image_handle == core::ptr::null_mut() {
+    return None;
+  }
+
+  let mut handle_address: *mut c_void = core::ptr::null_mut();
+  let status = crate::efi::handle_protocol (
+                 image_handle,
+                 &mut IMAGE_INFO_GUID,
+                 &mut handle_address
+                 );
+  if status != Status::SUCCESS {
+    return None;
+  }
+
+  let handle = unsafe {transmute::<*mut c_void, &mut ImageInfo>(handle_address)};
+  if handle.signature != IMAGE_INFO_SIGNATURE {
+    return None;
+  }
+  Some(handle)
+}
+
 impl Image {
     pub fn load_image (
         &mut self,
@@ -116,6 +222,12 @@ impl Image {
         source_buffer: *mut c_void,
         source_size: usize,
     ) -> (Status, Handle) {
+        let status = crate::efi::secure_boot::verify_image (source_buffer, source_size, device_path);
+        if status != Status::SUCCESS {
+          log!("load_image - image not allowed by Secure Boot\n");
+          return (status, core::ptr::null_mut())
+        }
+
         let mut handle_address: *mut c_void = core::ptr::null_mut();
 
         let device_path_size = crate::efi::device_path::get_device_path_size (device_path as *mut DevicePathProtocol);
@@ -128,6 +240,7 @@ impl Image {
         let device_path_buffer : *mut c_void = (handle_address as usize + size_of::<ImageInfo>()) as *mut c_void;
         unsafe {core::ptr::copy_nonoverlapping (device_path, device_path_buffer, device_path_size);}
 
+        unsafe {core::ptr::write (handle_address as *mut ImageInfo, ImageInfo::default());}
         let handle = unsafe {transmute::<*mut c_void, &mut ImageInfo>(handle_address)};
         handle.signature = IMAGE_INFO_SIGNATURE;
         handle.source_buffer = source_buffer as usize;
@@ -138,12 +251,20 @@ impl Image {
         if image_size == 0 {
           return (Status::SECURITY_VIOLATION, core::ptr::null_mut())
         }
-        let mut image_address : *mut c_void = core::ptr::null_mut();
-        let status = crate::efi::allocate_pool (MemoryType::BootServicesData, image_size, &mut image_address);
+        // Applications are loader memory, drivers the memory of the services they provide
+        let (image_code_type, image_data_type) = match peloader_get_subsystem (source_buffer) {
+          IMAGE_SUBSYSTEM_EFI_BOOT_SERVICE_DRIVER => (MemoryType::BootServicesCode, MemoryType::BootServicesData),
+          IMAGE_SUBSYSTEM_EFI_RUNTIME_DRIVER => (MemoryType::RuntimeServicesCode, MemoryType::RuntimeServicesData),
+          _ => (MemoryType::LoaderCode, MemoryType::LoaderData),
+        };
+        // The sections are page aligned, pool allocations are not
+        let mut image_page : PhysicalAddress = 0;
+        let status = crate::efi::allocate_pages (AllocateType::AllocateAnyPages, image_code_type, image_pages(image_size), &mut image_page);
         if status != Status::SUCCESS {
-          log!("load_image - fail on allocate pool\n");
+          log!("load_image - fail on allocate pages\n");
           return (Status::OUT_OF_RESOURCES, core::ptr::null_mut())
         }
+        let image_address = image_page as *mut c_void;
         log!("image_address - {:p}\n", image_address);
 
         handle.entry_point = peloader_load_image (image_address, image_size, source_buffer, source_size);
@@ -151,6 +272,7 @@ impl Image {
         if handle.entry_point == 0 {
           return (Status::SECURITY_VIOLATION, core::ptr::null_mut())
         }
+        crate::efi::paging::protect_image (image_address, image_size);
 
         let mut image_handle : Handle = core::ptr::null_mut();
         let status = crate::efi::install_protocol_interface (
@@ -172,8 +294,8 @@ impl Image {
         loaded_image.load_options = core::ptr::null_mut();
         loaded_image.image_base = image_address as *mut c_void;
         loaded_image.image_size = image_size as u64;
-        loaded_image.image_code_type = MemoryType::LoaderCode;
-        loaded_image.image_data_type = MemoryType::BootServicesData;
+        loaded_image.image_code_type = image_code_type;
+        loaded_image.image_data_type = image_data_type;
         loaded_image.unload = crate::efi::image_unload;
 
 
@@ -195,34 +317,169 @@ impl Image {
 
         (status, image_handle)
     }
-    pub fn start_image (
+    // The image sees a copy of the load options in LoadedImage, the same way
+    // the callers of LoadImage() set them.
+    pub fn set_load_options (
         &mut self,
         image_handle: Handle,
-    ) -> (Status, usize, *mut Char16) {
+        load_options: &[u8],
+    ) -> Status {
+        let handle = match get_image_info (image_handle) {
+          Some(handle) => handle,
+          None => {return Status::INVALID_PARAMETER},
+        };
+        if handle.started || load_options.len() > u32::MAX as usize {
+          return Status::INVALID_PARAMETER;
+        }
 
-        let mut handle_address: *mut c_void = core::ptr::null_mut();
-        let status = crate::efi::handle_protocol (
-                       image_handle,
-                       &mut IMAGE_INFO_GUID,
-                       &mut handle_address
-                       );
-        if status != Status::SUCCESS {
-          return (Status::INVALID_PARAMETER, 0, core::ptr::null_mut())
+        let mut address: *mut c_void = core::ptr::null_mut();
+        if !load_options.is_empty() {
+          let status = crate::efi::allocate_pool (MemoryType::BootServicesData, load_options.len(), &mut address);
+          if status != Status::SUCCESS {
+            log!("set_load_options - fail on allocate pool\n");
+            return status;
+          }
+          unsafe {core::ptr::copy_nonoverlapping (load_options.as_ptr(), address as *mut u8, load_options.len());}
+        }
+        if handle.load_options != 0 {
+          crate::efi::free_pool (handle.load_options as *mut c_void);
         }
+        handle.load_options = address as usize;
+        handle.loaded_image.load_options_size = load_options.len() as u32;
+        handle.loaded_image.load_options = address;
+        Status::SUCCESS
+    }
 
-        let handle = unsafe {transmute::<*mut c_void, &mut ImageInfo>(handle_address)};
-        if handle.signature != IMAGE_INFO_SIGNATURE {
+    pub fn start_image (
+        &mut self,
+        image_handle: Handle,
+    ) -> (Status, usize, *mut Char16) {
+        let handle = match get_image_info (image_handle) {
+          Some(handle) => handle,
+          None => {return (Status::INVALID_PARAMETER, 0, core::ptr::null_mut())},
+        };
+        if handle.started {
           return (Status::INVALID_PARAMETER, 0, core::ptr::null_mut())
         }
 
         log!("start_image - entry_point 0x{:x}\n", handle.entry_point);
 
-        let ptr = handle.entry_point as *const ();
-        let code: extern "win64" fn(Handle, *mut efi::SystemTable) -> Status = unsafe { core::mem::transmute(ptr) };
+        handle.started = true;
+        let previous_image = CURRENT_IMAGE.swap(image_handle as usize, Ordering::SeqCst);
+        let status = unsafe {
+          efi_call_image (handle.entry_point, image_handle, &mut crate::efi::ST, &mut handle.jump_context)
+        };
+        CURRENT_IMAGE.store(previous_image, Ordering::SeqCst);
+        handle.jump_context = 0;
+
+        let exit_data_size = handle.exit_data_size;
+        let exit_data = handle.exit_data as *mut Char16;
+        log!("start_image - status 0x{:x}, exit_data_size 0x{:x}\n", status.value(), exit_data_size);
+
+        // An application, or a driver that failed, is gone once it returns.
+        if status.is_error() || peloader_get_subsystem (handle.loaded_image.image_base) == IMAGE_SUBSYSTEM_EFI_APPLICATION {
+          self.free_image (image_handle, handle);
+        }
+
+        (status, exit_data_size, exit_data)
+    }
 
-        let status = unsafe { (code)(image_handle, &mut crate::efi::ST) };
+    pub fn exit (
+        &mut self,
+        image_handle: Handle,
+        exit_status: Status,
+        exit_data_size: usize,
+        exit_data: *mut Char16,
+    ) -> Status {
+        let handle = match get_image_info (image_handle) {
+          Some(handle) => handle,
+          None => {return Status::INVALID_PARAMETER},
+        };
+
+        // Exit() on a loaded but not started image just unloads it.
+        if !handle.started {
+          self.free_image (image_handle, handle);
+          return Status::SUCCESS;
+        }
+
+        if CURRENT_IMAGE.load(Ordering::SeqCst) != image_handle as usize {
+          log!("exit - image {:?} is not running\n", image_handle);
+          return Status::INVALID_PARAMETER;
+        }
 
-        (status, 0, core::ptr::null_mut())
+        handle.exit_data_size = exit_data_size;
+        handle.exit_data = exit_data as usize;
+        unsafe { efi_exit_image (handle.jump_context, exit_status) }
+    }
+
+    pub fn unload_image (
+        &mut self,
+        image_handle: Handle,
+    ) -> Status {
+        let handle = match get_image_info (image_handle) {
+          Some(handle) => handle,
+          None => {return Status::INVALID_PARAMETER},
+        };
+
+        if handle.started {
+          // A started image must agree to be unloaded. The image may have
+          // cleared the field, so read it as an address.
+          let unload = unsafe {core::ptr::read_volatile (&handle.loaded_image.unload as *const _ as *const usize)};
+          if unload == 0 || unload == crate::efi::image_unload as usize {
+            return Status::UNSUPPORTED;
+          }
+          let unload = unsafe {transmute::<usize, extern "win64" fn(Handle) -> Status>(unload)};
+          let status = (unload)(image_handle);
+          if status != Status::SUCCESS {
+            return status;
+          }
+        }
+
+        self.free_image (image_handle, handle);
+        Status::SUCCESS
+    }
+
+    // Remove the protocols installed by load_image() and free the image memory.
+    fn free_image (
+        &mut self,
+        image_handle: Handle,
+        handle: &mut ImageInfo,
+    ) {
+        let loaded_image_address: *mut c_void = &mut handle.loaded_image as *mut LoadedImageProtocol as *mut c_void;
+
+        let device_handle = handle.loaded_image.device_handle;
+        let mut device_path: *mut c_void = core::ptr::null_mut();
+        if device_handle != core::ptr::null_mut() &&
+           crate::efi::handle_protocol (
+             device_handle,
+             &mut r_efi::protocols::device_path::PROTOCOL_GUID as *mut Guid,
+             &mut device_path
+             ) == Status::SUCCESS {
+          crate::efi::uninstall_protocol_interface (
+            device_handle,
+            &mut r_efi::protocols::device_path::PROTOCOL_GUID as *mut Guid,
+            device_path
+            );
+          crate::efi::free_pool (device_path);
+        }
+
+        crate::efi::uninstall_protocol_interface (
+          image_handle,
+          &mut r_efi::protocols::loaded_image::PROTOCOL_GUID as *mut Guid,
+          loaded_image_address
+          );
+        crate::efi::uninstall_protocol_interface (
+          image_handle,
+          &mut IMAGE_INFO_GUID as *mut Guid,
+          handle as *mut ImageInfo as *mut c_void
+          );
+
+        if handle.load_options != 0 {
+          crate::efi::free_pool (handle.load_options as *mut c_void);
+        }
+        crate::efi::free_pages (handle.loaded_image.image_base as PhysicalAddress, image_pages(handle.loaded_image.image_size as usize));
+        handle.signature = 0;
+        crate::efi::free_pool (handle as *mut ImageInfo as *mut c_void);
     }
 
     pub fn new() -> Image {
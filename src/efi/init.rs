@@ -40,6 +40,11 @@ use crate::pci;
 use crate::part;
 use crate::fat;
 use crate::efi::file;
+use crate::efi::flash_store::FlashStore;
+use crate::efi::file_store::{FileFlash, FILE_FLASH_SECTORS, FILE_FLASH_SIZE};
+use crate::efi::variable::{MAX_VARIABLE_SIZE, VARIABLE_STORE_SIZE};
+use crate::pflash::{FlashDevice, Pflash};
+use crate::pi::fv::*;
 use r_efi::protocols::device_path::Protocol as DevicePathProtocol;
 use r_efi::protocols::device_path::End as EndDevicePath;
 
@@ -63,6 +68,15 @@ pub struct HardDriveDevicePath {
 
 
 
+// The base and size of the stack, when the SEC describes it in a HOB
+#[cfg(not(test))]
+static mut STACK: Option<(u64, u64)> = None;
+
+#[cfg(not(test))]
+pub fn stack_region() -> Option<(u64, u64)> {
+  unsafe { STACK }
+}
+
 #[cfg(not(test))]
 pub fn initialize_memory(hob: *const c_void) {
 
@@ -97,6 +111,11 @@ pub fn initialize_memory(hob: *const c_void) {
     match header.r#type {
       HOB_TYPE_MEMORY_ALLOCATION => {
         let allocation_hob = unsafe {transmute::<*const Header, &MemoryAllocation>(hob_header)};
+        if allocation_hob.alloc_descriptor.name == MEMORY_ALLOCATION_STACK_GUID {
+          unsafe {
+            STACK = Some((allocation_hob.alloc_descriptor.memory_base_address, allocation_hob.alloc_descriptor.memory_length));
+          }
+        }
         ALLOCATOR.lock().allocate_pages(
             AllocateType::AllocateAddress,
             allocation_hob.alloc_descriptor.memory_type,
@@ -112,6 +131,48 @@ pub fn initialize_memory(hob: *const c_void) {
     let addr = hob_header as usize + header.length as usize;
     hob_header = addr as *const Header;
   }
+
+  initialize_runtime_memory();
+}
+
+#[cfg(not(test))]
+extern "C" {
+  static start_of_text: u8;
+  static end_of_rodata: u8;
+  static end_of_bss: u8;
+}
+
+// The runtime services, the RT table and the state of the variable services all
+// live in the firmware image. Its text and read-only data are one
+// EfiRuntimeServicesCode region, its data and bss one EfiRuntimeServicesData
+// region right after it. The code is not relocated, so SetVirtualAddressMap()
+// requires the OS to keep the offset between the two.
+#[cfg(not(test))]
+fn initialize_runtime_memory() {
+  let image_start = unsafe { &start_of_text as *const u8 as u64 };
+  let data_start = unsafe { &end_of_rodata as *const u8 as u64 };
+  let image_end = unsafe { &end_of_bss as *const u8 as u64 };
+
+  for (memory_type, start, end) in [
+      (MemoryType::RuntimeServicesCode, image_start, data_start),
+      (MemoryType::RuntimeServicesData, data_start, image_end),
+      ].iter() {
+    let (status, _) = ALLOCATOR.lock().allocate_pages(
+        AllocateType::AllocateAddress,
+        *memory_type,
+        (end - start) / PAGE_SIZE,
+        *start,
+        );
+    if status != Status::SUCCESS {
+      log!("initialize_runtime_memory - firmware image 0x{:x}-0x{:x} is not free memory\n", start, end);
+    }
+  }
+}
+
+/// The code and data regions of the firmware image
+#[cfg(not(test))]
+pub fn firmware_image_regions() -> (u64, u64) {
+  unsafe { (&start_of_text as *const u8 as u64, &end_of_rodata as *const u8 as u64) }
 }
 
 #[cfg(not(test))]
@@ -121,7 +182,205 @@ pub fn find_loader(hob: *const c_void) -> (*const c_void, usize) {
 }
 
 #[cfg(not(test))]
-pub fn initialize_variable() {
+pub fn find_initrd(hob: *const c_void) -> &'static [u8] {
+  let (initrd, size) = find_file_in_fv (hob, &crate::efi::initrd::INITRD_FILE_GUID, SECTION_RAW);
+  if initrd.is_null() {
+    return &[];
+  }
+  log!("find_initrd - initrd in the firmware volume at 0x{:x}, size 0x{:x}\n", initrd as usize, size);
+  unsafe { core::slice::from_raw_parts(initrd as *const u8, size) }
+}
+
+#[cfg(not(test))]
+static mut FLASH_STORE: Option<FlashStore<Pflash>> = None;
+
+#[cfg(not(test))]
+pub fn variables_in_flash() -> bool {
+  unsafe { FLASH_STORE.is_some() }
+}
+
+// Keep the non-volatile variables in the firmware volume meant for them, when it is writable flash.
+#[cfg(not(test))]
+fn initialize_flash_store(hob: *const c_void) -> bool {
+  let mut hob_header : *const Header = hob as *const Header;
+  loop {
+    let header = unsafe {transmute::<*const Header, &Header>(hob_header)};
+    match header.r#type {
+      HOB_TYPE_FV => {
+        let fv_hob = unsafe {transmute::<*const Header, &FirmwareVolume>(hob_header)};
+        let fv_header = unsafe {transmute::<u64, &FirmwareVolumeHeader>(fv_hob.base_address)};
+        if fv_header.signature == FVH_SIGNATURE && fv_header.file_system_guid == SYSTEM_NV_DATA_FV_GUID {
+          let block_map = unsafe {transmute::<u64, &FvBlockMap>(fv_hob.base_address + size_of::<FirmwareVolumeHeader>() as u64)};
+          let block_size = block_map.length as u64;
+          match Pflash::probe(fv_hob.base_address, fv_hob.length, block_size) {
+            Some(pflash) => {
+              // The firmware volume header is left alone
+              let offset = (fv_header.header_length as u64 + block_size - 1) / block_size * block_size;
+              match FlashStore::new(pflash, offset) {
+                Some(store) => {
+                  log!("initialize_flash_store - variables in flash at 0x{:x}-0x{:x}\n", fv_hob.base_address, fv_hob.base_address + fv_hob.length - 1);
+                  // Runtime services keep writing to the flash through the OS mapping
+                  ALLOCATOR.lock().add_initial_allocation(
+                      MemoryType::MemoryMappedIO,
+                      (fv_hob.length + PAGE_SIZE - 1) / PAGE_SIZE,
+                      fv_hob.base_address,
+                      efi::MEMORY_UC | efi::MEMORY_RUNTIME,
+                      );
+                  unsafe {
+                    FLASH_STORE = Some(store);
+                    crate::efi::VARIABLE.lock().set_nv_store(FLASH_STORE.as_mut().unwrap());
+                  }
+                  return true;
+                },
+                None => {
+                  log!("initialize_flash_store - flash at 0x{:x} is too small\n", fv_hob.base_address);
+                },
+              }
+            },
+            None => {
+              log!("initialize_flash_store - 0x{:x} is not writable flash\n", fv_hob.base_address);
+            },
+          }
+        }
+      }
+      HOB_TYPE_END_OF_HOB_LIST => {
+        break;
+      }
+      _ => {}
+    }
+    let addr = hob_header as usize + header.length as usize;
+    hob_header = addr as *const Header;
+  }
+  false
+}
+
+#[cfg(not(test))]
+static mut FILE_STORE: Option<FlashStore<FileFlash>> = None;
+#[cfg(not(test))]
+static mut FILE_STORE_DATA: [u8; FILE_FLASH_SIZE] = [0; FILE_FLASH_SIZE];
+
+const NV_VARS_DIRECTORY: &str = "\\EFI";
+const NV_VARS_FILE: &str = "NVVARS";
+const NV_VARS_SHORT_NAME: [u8; fat::EFI_FAT_SHORT_NAME_LEN] = *b"NVVARS     ";
+
+// Without writable flash, keep the non-volatile variables in \EFI\NVVARS on the
+// EFI system partition, creating it if needed, and load them from there.
+#[cfg(not(test))]
+pub fn initialize_file_store(device: &crate::block::VirtioBlockDevice, filesystem: &fat::Filesystem) {
+  use crate::block::SectorWrite;
+  use crate::fat::Read;
+
+  if unsafe { FLASH_STORE.is_some() || FILE_STORE.is_some() } {
+    return;
+  }
+
+  let root = match filesystem.root() {
+    Ok(root) => root,
+    Err(_) => return,
+  };
+  let directory = match filesystem.open(&root, NV_VARS_DIRECTORY) {
+    Ok(directory) => directory,
+    Err(e) => {
+      log!("initialize_file_store - no {} directory: {:?}\n", NV_VARS_DIRECTORY, e);
+      return;
+    }
+  };
+
+  let mut created = false;
+  let nv_vars = match filesystem.open(&directory, NV_VARS_FILE) {
+    Err(fat::Error::NotFound) => {
+      if let Err(e) = filesystem.create_file(device, &directory, &NV_VARS_SHORT_NAME, FILE_FLASH_SIZE as u32) {
+        log!("initialize_file_store - failed to create {}: {:?}\n", NV_VARS_FILE, e);
+        return;
+      }
+      created = true;
+      filesystem.open(&directory, NV_VARS_FILE)
+    },
+    result => result,
+  };
+  let mut file = match nv_vars {
+    Ok(nv_vars) if nv_vars.file.is_some() && nv_vars.dir_ent.size as usize >= FILE_FLASH_SIZE => nv_vars.file.unwrap(),
+    _ => {
+      log!("initialize_file_store - {} is not usable\n", NV_VARS_FILE);
+      return;
+    },
+  };
+
+  let mut sectors = [0u64; FILE_FLASH_SECTORS];
+  match file.get_sectors(&mut sectors) {
+    Ok(count) if count == FILE_FLASH_SECTORS => {},
+    _ => {
+      log!("initialize_file_store - failed to find the sectors of {}\n", NV_VARS_FILE);
+      return;
+    },
+  }
+  let data = unsafe { &mut FILE_STORE_DATA };
+  for chunk in data.chunks_mut(512) {
+    if file.read(chunk).is_err() {
+      log!("initialize_file_store - failed to read {}\n", NV_VARS_FILE);
+      return;
+    }
+  }
+
+  // The device lives as long as the firmware, enter_uefi never returns
+  let device = unsafe { transmute::<&dyn SectorWrite, &'static dyn SectorWrite>(device) };
+  let mut flash = FileFlash::new(device, sectors, data);
+  if created {
+    // Whatever the clusters held before is not a store
+    let mut offset = 0;
+    while offset < flash.size() {
+      if flash.erase_block(offset).is_err() {
+        return;
+      }
+      offset += flash.block_size();
+    }
+  }
+
+  match FlashStore::new(flash, 0) {
+    Some(store) => {
+      log!("initialize_file_store - variables in {}\\{}\n", NV_VARS_DIRECTORY, NV_VARS_FILE);
+      unsafe {
+        FILE_STORE = Some(store);
+        crate::efi::VARIABLE.lock().set_nv_store(FILE_STORE.as_mut().unwrap());
+      }
+      // PK may have been stored there
+      crate::efi::auth::initialize(&mut crate::efi::VARIABLE.lock());
+      crate::efi::setup::initialize();
+      // The count set up at boot could not see the stored one
+      initialize_monotonic_count();
+    },
+    None => {
+      log!("initialize_file_store - failed to format {}\n", NV_VARS_FILE);
+    },
+  }
+}
+
+// The variables live in runtime memory of their own, which is all the room they have
+#[cfg(not(test))]
+fn initialize_variable_storage() {
+  let (status, storage) = ALLOCATOR.lock().allocate_pages(
+      AllocateType::AllocateAnyPages,
+      MemoryType::RuntimeServicesData,
+      VARIABLE_STORE_SIZE as u64 / PAGE_SIZE,
+      0,
+      );
+  if status != Status::SUCCESS {
+    log!("initialize_variable_storage - no memory for the variables\n");
+    return;
+  }
+  let storage = unsafe { core::slice::from_raw_parts_mut(storage as *mut u8, VARIABLE_STORE_SIZE) };
+  crate::efi::VARIABLE.lock().set_storage(storage, MAX_VARIABLE_SIZE);
+}
+
+#[cfg(not(test))]
+pub fn initialize_variable(hob: *const c_void) {
+  initialize_variable_storage ();
+  if !initialize_flash_store (hob) {
+    log!("initialize_variable - no flash, non-volatile variables go to the EFI system partition\n");
+  }
+  crate::efi::auth::initialize(&mut crate::efi::VARIABLE.lock());
+  crate::efi::setup::initialize();
+
   let mut var_name: [Char16; 13] = [0x50, 0x6c, 0x61, 0x74, 0x66, 0x6F, 0x72, 0x6d, 0x4c, 0x61, 0x6e, 0x67, 0x00]; // L"PlatformLang"
   let mut var_data: [u8; 3] = [0x65, 0x6e, 0x00]; // "en"
   crate::efi::set_variable(
@@ -133,6 +392,49 @@ pub fn initialize_variable() {
     );
 }
 
+#[cfg(not(test))]
+pub fn save_monotonic_count_high(high: u32) {
+  let mut var_name: [Char16; 4] = [0x4d, 0x54, 0x43, 0x00]; // L"MTC"
+  let mut var_data: u32 = high;
+  crate::efi::set_variable(
+    &mut var_name as *mut [Char16; 4] as *mut Char16,
+    &mut crate::efi::variable::MTC_GUID as *mut Guid,
+    VARIABLE_NON_VOLATILE | VARIABLE_BOOTSERVICE_ACCESS | VARIABLE_RUNTIME_ACCESS,
+    size_of::<u32>(),
+    &mut var_data as *mut u32 as *mut c_void
+    );
+}
+
+#[cfg(not(test))]
+pub fn initialize_monotonic_count() {
+  let mut var_name: [Char16; 4] = [0x4d, 0x54, 0x43, 0x00]; // L"MTC"
+  let mut var_data: u32 = 0;
+  let mut var_size: usize = size_of::<u32>();
+  let status = crate::efi::get_variable(
+    &mut var_name as *mut [Char16; 4] as *mut Char16,
+    &mut crate::efi::variable::MTC_GUID as *mut Guid,
+    core::ptr::null_mut(),
+    &mut var_size as *mut usize,
+    &mut var_data as *mut u32 as *mut c_void
+    );
+  if status != Status::SUCCESS || var_size != size_of::<u32>() {
+    var_data = 0;
+  }
+
+  // Every boot starts a new high 32 bits, so the count never goes backwards.
+  // Once they are used up, the count stays at its end.
+  let high = match var_data.checked_add(1) {
+    Some(high) => high,
+    None => {
+      log!("initialize_monotonic_count - the monotonic count is used up\n");
+      crate::efi::MONOTONIC_COUNT.store(core::u64::MAX, core::sync::atomic::Ordering::SeqCst);
+      return;
+    },
+  };
+  save_monotonic_count_high (high);
+  crate::efi::MONOTONIC_COUNT.store((high as u64) << 32, core::sync::atomic::Ordering::SeqCst);
+}
+
 pub fn initialize_console(system_table: *mut efi::SystemTable, con_in_ex: *mut c_void) {
   unsafe {
     let status = crate::efi::install_protocol_interface (
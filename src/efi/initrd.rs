@@ -0,0 +1,275 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use r_efi::efi::{Boolean, Guid, Handle, InterfaceType, Status};
+use r_efi::protocols::device_path::Protocol as DevicePathProtocol;
+use r_efi::protocols::load_file2::Protocol as LoadFile2Protocol;
+
+use core::ffi::c_void;
+
+use super::auth;
+use super::setup::SETUP_GUID;
+
+/// The vendor of the media device path Linux EFI stubs load their initrd from
+pub const LINUX_EFI_INITRD_MEDIA_GUID: Guid = Guid::from_fields(
+    0x5568e427, 0x68fc, 0x4f3d, 0xac, 0x74, &[0xca, 0x55, 0x52, 0x31, 0xcc, 0x68]
+);
+
+/// The freeform file of the firmware volume with the initrd in a raw section
+pub const INITRD_FILE_GUID: Guid = Guid::from_fields(
+    0x8a3f5c1e, 0x2b7d, 0x4e96, 0xb1, 0x4c, &[0x6e, 0x0a, 0x93, 0xd2, 0x57, 0xf8]
+);
+
+const TYPE_MEDIA: u8 = 0x04;
+const TYPE_END: u8 = 0x7f;
+const SUBTYPE_VENDOR: u8 = 0x03;
+const SUBTYPE_END_ENTIRE: u8 = 0xff;
+
+// VenMedia(LINUX_EFI_INITRD_MEDIA_GUID), then the end node
+pub const DEVICE_PATH_SIZE: usize = 20 + 4;
+
+// The longest path of an initrd on a file system
+const MAX_PATH_LENGTH: usize = 256;
+
+static mut DEVICE_PATH: [u8; DEVICE_PATH_SIZE] = [0; DEVICE_PATH_SIZE];
+
+// The handle of the initrd device path and LoadFile2, while they are installed
+static mut HANDLE: Handle = core::ptr::null_mut();
+
+#[cfg(not(test))]
+static mut LOAD_FILE2: LoadFile2Protocol = LoadFile2Protocol {
+    load_file: load_file,
+};
+
+// The initrd found in the firmware volume, and the last one read from a file system
+static mut FV_INITRD: &[u8] = &[];
+static mut FILE_INITRD: Option<&'static mut [u8]> = None;
+
+/// The device path the initrd is loaded from
+pub fn device_path() -> [u8; DEVICE_PATH_SIZE] {
+    let mut path = [0u8; DEVICE_PATH_SIZE];
+    path[0 .. 4].copy_from_slice(&[TYPE_MEDIA, SUBTYPE_VENDOR, 20, 0]);
+    path[4 .. 20].copy_from_slice(LINUX_EFI_INITRD_MEDIA_GUID.as_bytes());
+    path[20 .. 24].copy_from_slice(&[TYPE_END, SUBTYPE_END_ENTIRE, 4, 0]);
+    path
+}
+
+/// LoadFile2 of the initrd into buffer, with the size of buffer in
+/// buffer_size. A buffer too small, or none, gets the size of the initrd.
+pub fn copy_initrd(initrd: &[u8], boot_policy: Boolean, buffer_size: &mut usize, buffer: Option<&mut [u8]>) -> Status {
+    // The initrd is no boot option
+    if boot_policy != Boolean::FALSE {
+        return Status::UNSUPPORTED;
+    }
+    if initrd.is_empty() {
+        return Status::NOT_FOUND;
+    }
+    match buffer {
+        Some(buffer) if buffer.len() >= initrd.len() => {
+            buffer[.. initrd.len()].copy_from_slice(initrd);
+            *buffer_size = initrd.len();
+            Status::SUCCESS
+        },
+        _ => {
+            *buffer_size = initrd.len();
+            Status::BUFFER_TOO_SMALL
+        },
+    }
+}
+
+// Read the file the Initrd setup variable names from the first file system
+// that has it
+#[cfg(not(test))]
+fn read_initrd_file() -> Option<&'static mut [u8]> {
+    let mut name = [0u8; MAX_PATH_LENGTH];
+    let name = {
+        let variable = super::VARIABLE.lock();
+        let data = auth::get_data(&variable, "Initrd", &SETUP_GUID)?;
+        if data.is_empty() || data.len() > MAX_PATH_LENGTH {
+            log!("initrd: invalid Initrd\n");
+            return None;
+        }
+        name[.. data.len()].copy_from_slice(data);
+        core::str::from_utf8(&name[.. data.len()]).ok()?
+    };
+
+    let mut path = [0u8; 2 * (MAX_PATH_LENGTH + 1) + 8];
+    super::boot_manager::file_path(name, &mut path);
+    let handles = super::boot_manager::file_system_handles();
+    let mut initrd = None;
+    for handle in handles.iter() {
+        let (status, buffer, size) = super::read_file_path(*handle, path.as_mut_ptr() as *mut DevicePathProtocol);
+        if status == Status::SUCCESS {
+            log!("initrd: {} is 0x{:x} bytes\n", name, size);
+            initrd = Some(unsafe { core::slice::from_raw_parts_mut(buffer as *mut u8, size) });
+            break;
+        }
+    }
+    super::free_pool(handles.as_ptr() as *mut c_void);
+    initrd
+}
+
+// The file named by the Initrd setup variable, else the one in the firmware
+// volume. The file is read again on every call, so the initrd may be changed
+// in the setup between boots, and a buffer passed without a size query gets
+// the same initrd as the query would have reported.
+#[cfg(not(test))]
+fn initrd() -> &'static [u8] {
+    let file_initrd = unsafe { &mut FILE_INITRD };
+    if let Some(initrd) = file_initrd.take() {
+        super::free_pool(initrd.as_mut_ptr() as *mut c_void);
+    }
+    *file_initrd = read_initrd_file();
+    match file_initrd {
+        Some(initrd) => initrd,
+        None => unsafe { FV_INITRD },
+    }
+}
+
+#[cfg(not(test))]
+pub extern "win64" fn load_file(
+    _: *mut LoadFile2Protocol,
+    file_path: *mut DevicePathProtocol,
+    boot_policy: Boolean,
+    buffer_size: *mut usize,
+    buffer: *mut c_void,
+) -> Status {
+    crate::log!("EFI_STUB: initrd load_file\n");
+    if file_path.is_null() || buffer_size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    // There are no files under the initrd device path
+    if !super::device_path::is_device_path_end(file_path) {
+        return Status::NOT_FOUND;
+    }
+    let buffer = if buffer.is_null() {
+        None
+    } else {
+        Some(unsafe { core::slice::from_raw_parts_mut(buffer as *mut u8, *buffer_size) })
+    };
+    let initrd = initrd();
+    copy_initrd(initrd, boot_policy, unsafe { &mut *buffer_size }, buffer)
+}
+
+/// Keep the initrd in the firmware volume, if any, for the Linux EFI stubs
+/// booted without an initrd= argument. fv_initrd is empty without one.
+#[cfg(not(test))]
+pub fn initialize(fv_initrd: &'static [u8]) {
+    unsafe {
+        FV_INITRD = fv_initrd;
+        DEVICE_PATH = device_path();
+    }
+}
+
+/// Install LoadFile2 on the initrd device path when there is an initrd in
+/// the firmware volume or an Initrd setting, and uninstall it otherwise.
+/// Linux EFI stubs fail the boot when the device path exists but serves
+/// nothing, and look at their initrd= argument only when it does not exist,
+/// so this runs before each image the boot manager starts.
+#[cfg(not(test))]
+pub fn prepare() {
+    let present = !initrd().is_empty();
+    let installed = unsafe { !HANDLE.is_null() };
+    if present && !installed {
+        install();
+    } else if !present && installed {
+        uninstall();
+    }
+}
+
+#[cfg(not(test))]
+fn install() {
+    let mut handle: Handle = core::ptr::null_mut();
+    let status = super::install_protocol_interface(
+                   &mut handle,
+                   &mut r_efi::protocols::device_path::PROTOCOL_GUID.clone(),
+                   InterfaceType::NativeInterface,
+                   unsafe { DEVICE_PATH.as_mut_ptr() } as *mut c_void);
+    if status != Status::SUCCESS {
+        log!("initrd: cannot install the device path: {:?}\n", status);
+        return;
+    }
+    let status = super::install_protocol_interface(
+                   &mut handle,
+                   &mut r_efi::protocols::load_file2::PROTOCOL_GUID.clone(),
+                   InterfaceType::NativeInterface,
+                   unsafe { &mut LOAD_FILE2 } as *mut LoadFile2Protocol as *mut c_void);
+    if status != Status::SUCCESS {
+        log!("initrd: cannot install LoadFile2: {:?}\n", status);
+        super::uninstall_protocol_interface(
+          handle,
+          &mut r_efi::protocols::device_path::PROTOCOL_GUID.clone(),
+          unsafe { DEVICE_PATH.as_mut_ptr() } as *mut c_void);
+        return;
+    }
+    unsafe { HANDLE = handle };
+}
+
+#[cfg(not(test))]
+fn uninstall() {
+    let handle = unsafe { HANDLE };
+    let status = super::uninstall_protocol_interface(
+                   handle,
+                   &mut r_efi::protocols::load_file2::PROTOCOL_GUID.clone(),
+                   unsafe { &mut LOAD_FILE2 } as *mut LoadFile2Protocol as *mut c_void);
+    if status != Status::SUCCESS {
+        log!("initrd: cannot uninstall LoadFile2: {:?}\n", status);
+        return;
+    }
+    let status = super::uninstall_protocol_interface(
+                   handle,
+                   &mut r_efi::protocols::device_path::PROTOCOL_GUID.clone(),
+                   unsafe { DEVICE_PATH.as_mut_ptr() } as *mut c_void);
+    if status != Status::SUCCESS {
+        log!("initrd: cannot uninstall the device path: {:?}\n", status);
+    }
+    unsafe { HANDLE = core::ptr::null_mut() };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_path() {
+        let path = device_path();
+        assert_eq!(&path[.. 4], &[0x04, 0x03, 20, 0]);
+        assert_eq!(&path[4 .. 20], &[
+            0x27, 0xe4, 0x68, 0x55, 0xfc, 0x68, 0x3d, 0x4f, 0xac, 0x74, 0xca, 0x55, 0x52, 0x31, 0xcc, 0x68,
+        ]);
+        assert_eq!(&path[20 ..], &[0x7f, 0xff, 4, 0]);
+    }
+
+    #[test]
+    fn test_copy_initrd() {
+        let initrd = [1u8, 2, 3, 4, 5];
+        let mut size = 0;
+        assert_eq!(copy_initrd(&initrd, Boolean::FALSE, &mut size, None), Status::BUFFER_TOO_SMALL);
+        assert_eq!(size, 5);
+
+        let mut buffer = [0u8; 8];
+        size = 4;
+        assert_eq!(copy_initrd(&initrd, Boolean::FALSE, &mut size, Some(&mut buffer[.. 4])), Status::BUFFER_TOO_SMALL);
+        assert_eq!((size, buffer), (5, [0; 8]));
+
+        size = 8;
+        assert_eq!(copy_initrd(&initrd, Boolean::FALSE, &mut size, Some(&mut buffer)), Status::SUCCESS);
+        assert_eq!((size, buffer), (5, [1, 2, 3, 4, 5, 0, 0, 0]));
+
+        assert_eq!(copy_initrd(&initrd, Boolean::TRUE, &mut size, Some(&mut buffer)), Status::UNSUPPORTED);
+        assert_eq!(copy_initrd(&[], Boolean::FALSE, &mut size, None), Status::NOT_FOUND);
+    }
+}
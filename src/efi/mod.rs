@@ -15,6 +15,10 @@
 #![allow(unused)]
 
 mod alloc;
+mod pool;
+mod paging;
+mod guard;
+mod runtime_tables;
 mod block;
 mod file;
 mod device_path;
@@ -22,8 +26,17 @@ mod image;
 mod event;
 mod handle_database;
 mod variable;
+mod auth;
+mod authenticode;
+mod secure_boot;
+mod boot_manager;
+mod setup;
+mod initrd;
+mod flash_store;
+mod file_store;
 mod conout;
 mod conin;
+mod rtc;
 mod peloader;
 mod init;
 
@@ -32,6 +45,7 @@ use spin::Mutex;
 use core::fmt;
 use cpuio::Port;
 use core::mem::transmute;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use r_efi::efi;
 use r_efi::efi::{
@@ -64,6 +78,8 @@ use crate::pi::fv_lib::*;
 use crate::mem::MemoryRegion;
 
 use crate::efi::alloc::Allocator;
+use crate::efi::pool::Pool;
+use crate::efi::guard::Guards;
 
 
 use crate::pci;
@@ -120,12 +136,11 @@ use crate::pi::hob::{
 
 use handle_database::HandleDatabase;
 use variable::Variable;
-use variable::MAX_VARIABLE_NAME;
-use variable::MAX_VARIABLE_DATA;
 use image::Image;
 use event::EventInfo;
 use conout::ConOut;
 use conin::ConIn;
+use rtc::Rtc;
 
 #[cfg(not(test))]
 #[repr(C,packed)]
@@ -154,6 +169,15 @@ lazy_static! {
     pub static ref ALLOCATOR: Mutex<Allocator> = Mutex::new(Allocator::new());
 }
 
+lazy_static! {
+    pub static ref POOL: Mutex<Pool> = Mutex::new(Pool::new());
+}
+
+#[cfg(all(not(test), feature = "guard-pages"))]
+lazy_static! {
+    pub static ref GUARDS: Mutex<Guards> = Mutex::new(Guards::new(unsafe { &mut guard::GUARDED }));
+}
+
 lazy_static! {
     pub static ref HANDLE_DATABASE: Mutex<HandleDatabase> = Mutex::new(HandleDatabase::new());
 }
@@ -178,6 +202,10 @@ lazy_static! {
     pub static ref CONIN: Mutex<ConIn> = Mutex::new(ConIn::new());
 }
 
+lazy_static! {
+    pub static ref RTC: Mutex<Rtc> = Mutex::new(Rtc::new());
+}
+
 #[cfg(not(test))]
 pub static mut BLOCK_WRAPPERS: block::BlockWrappers = block::BlockWrappers {
     wrappers: [core::ptr::null_mut(); 16],
@@ -234,6 +262,13 @@ pub fn get_char16_size (
     return i
 }
 
+// A variable name as the variable services keep it: its UCS-2 characters, without
+// the terminator. None if there is no terminator within max_length characters.
+fn variable_name<'a> (name: *const Char16, max_length: usize) -> Option<&'a [u8]> {
+    let length = (0 .. max_length).position(|i| unsafe { *name.add(i) } == 0)?;
+    Some(unsafe { core::slice::from_raw_parts(name as *const u8, length * size_of::<Char16>()) })
+}
+
 pub fn char16_to_char8 (
     in_message: *mut Char16,
     in_message_size: usize,
@@ -325,6 +360,13 @@ pub extern "win64" fn stdin_read_key_stroke(
     Status::SUCCESS
 }
 
+#[cfg(not(test))]
+pub extern "win64" fn stdin_wait_for_key(event: Event, _: *mut c_void) {
+    if CONIN.lock().has_byte() {
+        signal_event(event);
+    }
+}
+
 #[cfg(not(test))]
 pub extern "win64" fn stdin_reset_ex(_: *mut SimpleTextInputExProtocol, _: Boolean) -> Status {
     crate::log!("EFI_STUB: stdin_reset_ex\n");
@@ -472,27 +514,52 @@ pub extern "win64" fn stdout_enable_cursor(_: *mut SimpleTextOutputProtocol, vis
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn get_time(_: *mut Time, _: *mut TimeCapabilities) -> Status {
-    crate::log!("EFI_STUB: get_time - UNSUPPORTED\n");
-    Status::UNSUPPORTED
+pub extern "win64" fn get_time(time: *mut Time, capabilities: *mut TimeCapabilities) -> Status {
+    if time.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    let capabilities = if capabilities.is_null() {
+        None
+    } else {
+        Some(unsafe { &mut *capabilities })
+    };
+    RTC.lock().get_time(unsafe { &mut *time }, capabilities)
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn set_time(_: *mut Time) -> Status {
-    crate::log!("EFI_STUB: set_time - UNSUPPORTED\n");
-    Status::UNSUPPORTED
+pub extern "win64" fn set_time(time: *mut Time) -> Status {
+    if time.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    RTC.lock().set_time(unsafe { &*time })
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn get_wakeup_time(_: *mut Boolean, _: *mut Boolean, _: *mut Time) -> Status {
-    crate::log!("EFI_STUB: get_wakeup_time - UNSUPPORTED\n");
-    Status::UNSUPPORTED
+pub extern "win64" fn get_wakeup_time(enabled: *mut Boolean, pending: *mut Boolean, time: *mut Time) -> Status {
+    if enabled.is_null() || pending.is_null() || time.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    let (status, alarm_enabled, alarm_pending) = RTC.lock().get_wakeup_time(unsafe { &mut *time });
+    if status == Status::SUCCESS {
+        unsafe {
+            *enabled = alarm_enabled.into();
+            *pending = alarm_pending.into();
+        }
+    }
+    status
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn set_wakeup_time(_: Boolean, _: *mut Time) -> Status {
-    crate::log!("EFI_STUB: set_wakeup_time - UNSUPPORTED\n");
-    Status::UNSUPPORTED
+pub extern "win64" fn set_wakeup_time(enable: Boolean, time: *mut Time) -> Status {
+    let time = if time.is_null() {
+        None
+    } else {
+        Some(unsafe { &*time })
+    };
+    RTC.lock().set_wakeup_time(enable.into(), time)
 }
 
 #[cfg(not(test))]
@@ -502,24 +569,120 @@ pub extern "win64" fn set_virtual_address_map(
     version: u32,
     descriptors: *mut MemoryDescriptor,
 ) -> Status {
-    crate::log!("EFI_STUB: set_virtual_address_map - ???\n");
-    let count = map_size / descriptor_size;
+    crate::log!("EFI_STUB: set_virtual_address_map\n");
+
+    // Only once, and only after ExitBootServices()
+    if !EXIT_BOOT_SERVICES.load(Ordering::SeqCst) || VIRTUAL_MODE.load(Ordering::SeqCst) {
+        return Status::UNSUPPORTED;
+    }
 
-    if version != efi::MEMORY_DESCRIPTOR_VERSION {
+    if version != efi::MEMORY_DESCRIPTOR_VERSION || descriptor_size < size_of::<alloc::MemoryDescriptor>() {
         return Status::INVALID_PARAMETER;
     }
 
-    let descriptors = unsafe {
-        core::slice::from_raw_parts_mut(descriptors as *mut alloc::MemoryDescriptor, count)
-    };
+    // The descriptor size may be larger than the structure, walk by descriptor_size.
+    let count = map_size / descriptor_size;
+    let descriptor = |index: usize| unsafe { &*((descriptors as usize + index * descriptor_size) as *const alloc::MemoryDescriptor) };
+
+    // Check the whole map before changing anything: every runtime region
+    // gets a page aligned address, and the code and data of the firmware
+    // image keep their offset, as its code is not relocated.
+    let mut allocator = ALLOCATOR.lock();
+    let (image_code, image_data) = crate::efi::init::firmware_image_regions();
+    let mut image_code_offset = None;
+    let mut image_data_offset = None;
+    let mut runtime_count = 0;
+    for index in 0 .. count {
+        let descriptor = descriptor(index);
+        if (descriptor.attribute & efi::MEMORY_RUNTIME) == 0 {
+            continue;
+        }
+        if !allocator.is_runtime_region(descriptor) {
+            crate::log!("EFI_STUB: set_virtual_address_map - unknown runtime region 0x{:x}\n", descriptor.physical_start);
+            return Status::NOT_FOUND;
+        }
+        if descriptor.virtual_start % PAGE_SIZE != 0 {
+            return Status::INVALID_PARAMETER;
+        }
+        let offset = descriptor.virtual_start.wrapping_sub(descriptor.physical_start);
+        let end = descriptor.physical_start + descriptor.number_of_pages * PAGE_SIZE;
+        if descriptor.physical_start <= image_code && image_code < end {
+            image_code_offset = Some(offset);
+        }
+        if descriptor.physical_start <= image_data && image_data < end {
+            image_data_offset = Some(offset);
+        }
+        runtime_count += 1;
+    }
+    if runtime_count != allocator.runtime_region_count() {
+        crate::log!("EFI_STUB: set_virtual_address_map - runtime regions missing from the map\n");
+        return Status::INVALID_PARAMETER;
+    }
+    if image_code_offset != image_data_offset {
+        crate::log!("EFI_STUB: set_virtual_address_map - firmware code and data mapped apart\n");
+        return Status::INVALID_PARAMETER;
+    }
+
+    for index in 0 .. count {
+        let descriptor = descriptor(index);
+        if (descriptor.attribute & efi::MEMORY_RUNTIME) != 0 {
+            allocator.update_virtual_addresses(core::slice::from_ref(descriptor));
+        }
+    }
+    drop(allocator);
+
+    // Runtime drivers convert their pointers from the notification functions.
+    EVENT.lock().signal_event_group(&efi::EVENT_GROUP_VIRTUAL_ADDRESS_CHANGE);
+    dispatch_event_notifies();
+
+    VARIABLE.lock().relocate(&|address| ALLOCATOR.lock().convert_pointer(address));
 
-    ALLOCATOR.lock().update_virtual_addresses(descriptors)
+    // The image is not relocated, its code runs through the new mapping and
+    // reaches the lazy_static globals, kept inline in its data, relative to
+    // itself. The pointers stored in them, above, and those the OS follows are
+    // converted, but the absolute addresses linked into the image, vtables and
+    // the static data its globals point to, stay physical: the OS must keep
+    // the physical mapping of the runtime regions as well.
+    unsafe {
+        let rt_functions = (&mut RT as *mut efi::RuntimeServices as usize + size_of::<efi::TableHeader>()) as *mut *mut c_void;
+        let rt_function_count = (size_of::<efi::RuntimeServices>() - size_of::<efi::TableHeader>()) / size_of::<usize>();
+        for index in 0 .. rt_function_count {
+            convert_pointer(0, rt_functions.add(index));
+        }
+
+        for index in 0 .. ST.number_of_table_entries {
+            convert_pointer(0, &mut CT[index].vendor_table);
+        }
+        convert_pointer(efi::OPTIONAL_POINTER as usize, transmute::<&mut *mut efi::RuntimeServices, *mut *mut c_void>(&mut ST.runtime_services));
+        convert_pointer(efi::OPTIONAL_POINTER as usize, transmute::<&mut *mut efi::ConfigurationTable, *mut *mut c_void>(&mut ST.configuration_table));
+        convert_pointer(efi::OPTIONAL_POINTER as usize, transmute::<&mut *mut Char16, *mut *mut c_void>(&mut ST.firmware_vendor));
+    }
+
+    VIRTUAL_MODE.store(true, Ordering::SeqCst);
+    Status::SUCCESS
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn convert_pointer(_: usize, _: *mut *mut c_void) -> Status {
-    crate::log!("EFI_STUB: convert_pointer - UNSUPPORTED\n");
-    Status::UNSUPPORTED
+pub extern "win64" fn convert_pointer(debug_disposition: usize, address: *mut *mut c_void) -> Status {
+    if address == core::ptr::null_mut() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    let pointer = unsafe { *address };
+    if pointer == core::ptr::null_mut() {
+        if (debug_disposition & efi::OPTIONAL_POINTER as usize) != 0 {
+            return Status::SUCCESS;
+        }
+        return Status::INVALID_PARAMETER;
+    }
+
+    match ALLOCATOR.lock().convert_pointer(pointer as u64) {
+        Some(virtual_address) => {
+            unsafe { *address = virtual_address as *mut c_void; }
+            Status::SUCCESS
+        },
+        None => Status::NOT_FOUND,
+    }
 }
 
 #[cfg(not(test))]
@@ -530,6 +693,9 @@ pub extern "win64" fn get_variable(
     size: *mut usize,
     data: *mut core::ffi::c_void,
 ) -> Status {
+    if var_name.is_null() || var_guid.is_null() || size.is_null() {
+      return Status::INVALID_PARAMETER;
+    }
     let var_name_size = get_char16_size (var_name, core::usize::MAX);
     if false {
       crate::log!("EFI_STUB: get_variable ");
@@ -539,37 +705,32 @@ pub extern "win64" fn get_variable(
       crate::log!("\n");
     }
 
-    if var_name_size > MAX_VARIABLE_NAME {
-      crate::log!("name too long\n");
-      return Status::UNSUPPORTED;
-    }
-
-    let mut name_buffer: [u8; MAX_VARIABLE_NAME] = [0; MAX_VARIABLE_NAME];
-    char16_to_char8 (
-        var_name,
-        var_name_size,
-        &mut name_buffer as *mut [u8; MAX_VARIABLE_NAME] as *mut u8,
-        MAX_VARIABLE_NAME);
-
-    let guid_buffer : *mut [u8; 16] = unsafe { core::mem::transmute::<*mut Guid, *mut [u8; 16]>(var_guid) };
+    let name = variable_name (var_name, core::usize::MAX).unwrap();
+    let guid_buffer : &[u8; 16] = unsafe { &*(var_guid as *const [u8; 16]) };
 
-    let (status, var_attributes, var_size, var_data) = VARIABLE.lock().get_variable(
-                     &mut name_buffer as *mut [u8; MAX_VARIABLE_NAME],
-                     guid_buffer
-                     );
+    let variable = VARIABLE.lock();
+    let (status, var_attributes, var_data) = variable.get_variable(name, guid_buffer);
 
     if (status == Status::NOT_FOUND) {
       return status;
     }
 
-    if unsafe {*size} < var_size {
-      unsafe {*size = var_size;}
+    // Only runtime variables are visible after ExitBootServices
+    if EXIT_BOOT_SERVICES.load(Ordering::SeqCst) && (var_attributes & efi::VARIABLE_RUNTIME_ACCESS) == 0 {
+      return Status::NOT_FOUND;
+    }
+
+    if unsafe {*size} < var_data.len() {
+      unsafe {*size = var_data.len();}
       return Status::BUFFER_TOO_SMALL;
     }
 
-    unsafe {*size = var_size;}
-    let data_ptr : *mut c_void = unsafe { core::mem::transmute::<*mut [u8; MAX_VARIABLE_DATA], *mut c_void>(var_data) };
-    unsafe {core::ptr::copy_nonoverlapping (data_ptr, data, var_size);}
+    if data.is_null() {
+      return Status::INVALID_PARAMETER;
+    }
+
+    unsafe {*size = var_data.len();}
+    unsafe {core::ptr::copy_nonoverlapping (var_data.as_ptr(), data as *mut u8, var_data.len());}
 
     if attributes != core::ptr::null_mut() {
       unsafe {*attributes = var_attributes;}
@@ -580,12 +741,46 @@ pub extern "win64" fn get_variable(
 
 #[cfg(not(test))]
 pub extern "win64" fn get_next_variable_name(
-    _: *mut usize,
-    _: *mut Char16,
-    _: *mut Guid,
+    var_name_size: *mut usize,
+    var_name: *mut Char16,
+    var_guid: *mut Guid,
 ) -> Status {
-    crate::log!("EFI_STUB: get_next_variable - UNSUPPORTED, TODO\n");
-    Status::UNSUPPORTED
+    if var_name_size.is_null() || var_name.is_null() || var_guid.is_null() {
+      return Status::INVALID_PARAMETER;
+    }
+
+    // The name passed in must be terminated within the buffer
+    let max_name_length = unsafe { *var_name_size } / size_of::<Char16>();
+    let name = match variable_name (var_name, max_name_length) {
+      Some(name) => name,
+      None => return Status::INVALID_PARAMETER,
+    };
+    let guid_buffer : &mut [u8; 16] = unsafe { &mut *(var_guid as *mut [u8; 16]) };
+
+    let variable = VARIABLE.lock();
+    let (status, next_name, next_guid) = variable.get_next_variable_name(
+                     name,
+                     guid_buffer,
+                     EXIT_BOOT_SERVICES.load(Ordering::SeqCst)
+                     );
+    if status != Status::SUCCESS {
+      return status;
+    }
+
+    let next_name_size = next_name.len() + size_of::<Char16>();
+    if unsafe { *var_name_size } < next_name_size {
+      unsafe { *var_name_size = next_name_size; }
+      return Status::BUFFER_TOO_SMALL;
+    }
+
+    unsafe {
+      core::ptr::copy_nonoverlapping (next_name.as_ptr(), var_name as *mut u8, next_name.len());
+      *var_name.add(next_name.len() / size_of::<Char16>()) = 0;
+      *var_name_size = next_name_size;
+    }
+    *guid_buffer = next_guid;
+
+    Status::SUCCESS
 }
 
 #[cfg(not(test))]
@@ -597,6 +792,9 @@ pub extern "win64" fn set_variable(
     data: *mut c_void,
 ) -> Status {
     // log!("EFI_STUB - set_variable size: {}\n", size);
+    if var_name.is_null() || var_guid.is_null() || (size != 0 && data.is_null()) {
+      return Status::INVALID_PARAMETER;
+    }
     let var_name_size = get_char16_size (var_name, core::usize::MAX);
     if false {
       crate::log!("EFI_STUB: set_variable ");
@@ -606,48 +804,71 @@ pub extern "win64" fn set_variable(
       crate::log!("\n");
     }
 
-    if var_name_size > MAX_VARIABLE_NAME {
-      crate::log!("name too long\n");
-      return Status::UNSUPPORTED;
+    let name = variable_name (var_name, core::usize::MAX).unwrap();
+    if name.is_empty() {
+      return Status::INVALID_PARAMETER;
     }
+    let guid_buffer : &[u8; 16] = unsafe { &*(var_guid as *const [u8; 16]) };
 
-    let mut name_buffer: [u8; MAX_VARIABLE_NAME] = [0; MAX_VARIABLE_NAME];
-    char16_to_char8 (
-        var_name,
-        var_name_size,
-        &mut name_buffer as *mut [u8; MAX_VARIABLE_NAME] as *mut u8,
-        MAX_VARIABLE_NAME);
-
-    let guid_buffer : *mut [u8; 16] = unsafe { core::mem::transmute::<*mut Guid, *mut [u8; 16]>(var_guid) };
-
-    if size > MAX_VARIABLE_DATA {
-      crate::log!("data too long\n");
+    if (attributes & (efi::VARIABLE_HARDWARE_ERROR_RECORD
+                      | efi::VARIABLE_AUTHENTICATED_WRITE_ACCESS
+                      | efi::VARIABLE_ENHANCED_AUTHENTICATED_ACCESS)) != 0 {
       return Status::UNSUPPORTED;
     }
 
-    let data_buffer: *mut [u8; MAX_VARIABLE_DATA] = unsafe { core::mem::transmute::<*mut c_void, *mut [u8; MAX_VARIABLE_DATA]>(data) };
-
-    let (status) = VARIABLE.lock().set_variable(
-                     &mut name_buffer as *mut [u8; MAX_VARIABLE_NAME],
-                     guid_buffer,
-                     attributes,
-                     size,
-                     data_buffer
-                     );
+    let data: &[u8] = if size == 0 {
+      &[]
+    } else {
+      unsafe { core::slice::from_raw_parts(data as *const u8, size) }
+    };
 
-    status
+    auth::set_variable(
+        &mut VARIABLE.lock(),
+        name,
+        guid_buffer,
+        attributes,
+        data)
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn get_next_high_mono_count(_: *mut u32) -> Status {
-    crate::log!("EFI_STUB: get_next_high_mono_count - UNSUPPORTED\n");
-    Status::UNSUPPORTED
+pub extern "win64" fn get_next_high_mono_count(high_count: *mut u32) -> Status {
+    if high_count == core::ptr::null_mut() {
+      return Status::INVALID_PARAMETER;
+    }
+
+    // The high 32 bits must not wrap, the count would go backwards.
+    let mut value = MONOTONIC_COUNT.load(Ordering::SeqCst);
+    let high = loop {
+      let high = match ((value >> 32) as u32).checked_add(1) {
+        Some(high) => high,
+        None => return Status::DEVICE_ERROR,
+      };
+      match MONOTONIC_COUNT.compare_exchange(value, (high as u64) << 32, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => break high,
+        Err(current) => value = current,
+      }
+    };
+    crate::efi::init::save_monotonic_count_high (high);
+
+    unsafe { *high_count = high; }
+    Status::SUCCESS
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn reset_system(_: ResetType, _: Status, _: usize, _: *mut c_void) {
-    crate::log!("EFI_STUB: reset_system.\n");
-    crate::i8042_reset();
+pub extern "win64" fn reset_system(reset_type: ResetType, status: Status, data_size: usize, data: *mut c_void) {
+    crate::log!("EFI_STUB: reset_system - {:?} {:?}\n", reset_type, status);
+
+    let data = if data.is_null() {
+        &[]
+    } else {
+        unsafe { core::slice::from_raw_parts(data as *const u8, data_size) }
+    };
+    match reset_type {
+        ResetType::ResetCold => crate::reset::reset_cold(),
+        ResetType::ResetWarm => crate::reset::reset_warm(),
+        ResetType::ResetShutdown => crate::reset::shutdown(),
+        ResetType::ResetPlatformSpecific => crate::reset::reset_platform_specific(data),
+    }
 }
 
 #[cfg(not(test))]
@@ -672,20 +893,69 @@ pub extern "win64" fn query_capsule_capabilities(
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn query_variable_info(_: u32, _: *mut u64, _: *mut u64, _: *mut u64) -> Status {
-    crate::log!("EFI_STUB: query_variable_info - UNSUPPORTED\n");
-    Status::UNSUPPORTED
+pub extern "win64" fn query_variable_info(
+    attributes: u32,
+    max_storage_size: *mut u64,
+    remaining_storage_size: *mut u64,
+    max_variable_size: *mut u64,
+) -> Status {
+    if max_storage_size.is_null() || remaining_storage_size.is_null() || max_variable_size.is_null() {
+      return Status::INVALID_PARAMETER;
+    }
+
+    // Runtime access requires boot service access, and is the only one left after ExitBootServices
+    if (attributes & efi::VARIABLE_BOOTSERVICE_ACCESS) == 0 {
+      return Status::INVALID_PARAMETER;
+    }
+    if EXIT_BOOT_SERVICES.load(Ordering::SeqCst) && (attributes & efi::VARIABLE_RUNTIME_ACCESS) == 0 {
+      return Status::INVALID_PARAMETER;
+    }
+    if (attributes & (efi::VARIABLE_HARDWARE_ERROR_RECORD
+                      | efi::VARIABLE_AUTHENTICATED_WRITE_ACCESS
+                      | efi::VARIABLE_ENHANCED_AUTHENTICATED_ACCESS)) != 0 {
+      return Status::UNSUPPORTED;
+    }
+
+    let (max_storage, remaining_storage, max_size) = VARIABLE.lock().query_variable_info((attributes & efi::VARIABLE_NON_VOLATILE) != 0);
+    unsafe {
+      *max_storage_size = max_storage;
+      *remaining_storage_size = remaining_storage;
+      *max_variable_size = max_size;
+    }
+
+    Status::SUCCESS
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn raise_tpl(_: Tpl) -> Tpl {
-    crate::log!("EFI_STUB: raise_tpl\n");
-    0
+pub extern "win64" fn raise_tpl(new_tpl: Tpl) -> Tpl {
+    // Mask interrupts first, so the timer tick never sees TPL_HIGH_LEVEL with interrupts on.
+    if new_tpl >= efi::TPL_HIGH_LEVEL {
+      x86_64::instructions::interrupts::disable();
+    }
+
+    let (status, old_tpl) = EVENT.lock().raise_tpl(new_tpl);
+    if status != Status::SUCCESS {
+      crate::log!("EFI_STUB: raise_tpl - invalid TPL {} (current {})\n", new_tpl, old_tpl);
+      if old_tpl < efi::TPL_HIGH_LEVEL {
+        x86_64::instructions::interrupts::enable();
+      }
+    }
+    old_tpl
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn restore_tpl(_: Tpl) {
-    crate::log!("EFI_STUB: restore_tpl\n");
+pub extern "win64" fn restore_tpl(old_tpl: Tpl) {
+    let status = EVENT.lock().restore_tpl(old_tpl);
+    if status != Status::SUCCESS {
+      crate::log!("EFI_STUB: restore_tpl - invalid TPL {} (current {})\n", old_tpl, EVENT.lock().get_current_tpl());
+      return;
+    }
+
+    // Interrupts stay masked while the TPL_HIGH_LEVEL notifications run
+    dispatch_event_notifies();
+    if old_tpl < efi::TPL_HIGH_LEVEL {
+      x86_64::instructions::interrupts::enable();
+    }
 }
 
 #[cfg(not(test))]
@@ -695,9 +965,18 @@ pub extern "win64" fn allocate_pages(
     pages: usize,
     address: *mut PhysicalAddress,
 ) -> Status {
+    let mut allocator = ALLOCATOR.lock();
+    #[cfg(feature = "guard-pages")]
+    {
+        if let Some(new_address) = crate::efi::guard::allocate_pages(&mut allocator, allocate_type, memory_type, pages as u64) {
+            unsafe {
+                *address = new_address;
+            }
+            return Status::SUCCESS;
+        }
+    }
     let (status, new_address) =
-        ALLOCATOR
-            .lock()
+        allocator
             .allocate_pages(
                 allocate_type,
                 memory_type,
@@ -705,6 +984,7 @@ pub extern "win64" fn allocate_pages(
                 unsafe { *address } as u64,
             );
     if status == Status::SUCCESS {
+        crate::efi::paging::set_memory_type_access(&mut allocator, new_address, pages as u64, memory_type as u32);
         unsafe {
             *address = new_address;
         }
@@ -715,8 +995,19 @@ pub extern "win64" fn allocate_pages(
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn free_pages(address: PhysicalAddress, _: usize) -> Status {
-    ALLOCATOR.lock().free_pages(address)
+pub extern "win64" fn free_pages(address: PhysicalAddress, pages: usize) -> Status {
+    let mut allocator = ALLOCATOR.lock();
+    #[cfg(feature = "guard-pages")]
+    {
+        if let Some(status) = crate::efi::guard::free_pages(&mut allocator, address, pages as u64) {
+            return status;
+        }
+    }
+    let status = allocator.free_pages(address, pages as u64);
+    if status == Status::SUCCESS {
+        crate::efi::paging::set_memory_type_access(&mut allocator, address, pages as u64, MemoryType::ConventionalMemory as u32);
+    }
+    status
 }
 
 #[cfg(not(test))]
@@ -757,12 +1048,21 @@ pub extern "win64" fn allocate_pool(
     size: usize,
     address: *mut *mut c_void,
 ) -> Status {
-    let (status, new_address) = ALLOCATOR.lock().allocate_pages(
-        AllocateType::AllocateAnyPages,
-        memory_type,
-        ((size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize) as u64,
-        address as u64,
-    );
+    if address.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    #[cfg(feature = "guard-pages")]
+    {
+        if let Some(new_address) = crate::efi::guard::allocate_pool(&mut ALLOCATOR.lock(), memory_type, size as u64) {
+            unsafe {
+                *address = new_address as *mut c_void;
+            }
+            return Status::SUCCESS;
+        }
+    }
+
+    let (status, new_address) = POOL.lock().allocate(&mut *ALLOCATOR.lock(), memory_type, size);
 
     if status == Status::SUCCESS {
         unsafe {
@@ -777,17 +1077,32 @@ pub extern "win64" fn allocate_pool(
 
 #[cfg(not(test))]
 pub extern "win64" fn free_pool(ptr: *mut c_void) -> Status {
-    ALLOCATOR.lock().free_pages(ptr as u64)
+    #[cfg(feature = "guard-pages")]
+    {
+        if let Some(status) = crate::efi::guard::free_pool(&mut ALLOCATOR.lock(), ptr as u64) {
+            return status;
+        }
+    }
+    POOL.lock().free(&mut *ALLOCATOR.lock(), ptr as u64)
 }
 
 #[cfg(not(test))]
 pub extern "win64" fn create_event(
     r#type: u32,
     notify_tpl: Tpl,
-    notify_function: EventNotify,
+    notify_function: Option<EventNotify>,
     notify_context: *mut c_void,
     event: *mut Event,
 ) -> Status {
+    if event == core::ptr::null_mut() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    // Events without notification have a NULL notify function
+    let notify_function = match notify_function {
+        Some(notify_function) => notify_function as usize,
+        None => 0,
+    };
 
     let (status, new_event) = EVENT.lock().create_event(
             r#type,
@@ -804,33 +1119,174 @@ pub extern "win64" fn create_event(
     status
 }
 
+// Run the queued notification functions that are above the current TPL.
+// The EVENT lock is dropped while a notification function runs, so it can
+// call back into the event services. Interrupts follow the TPL a function
+// runs at: masked at TPL_HIGH_LEVEL, enabled below it, and enabled again
+// afterwards if the current TPL is below TPL_HIGH_LEVEL. After
+// ExitBootServices() the interrupt flag belongs to the OS and is left alone.
 #[cfg(not(test))]
-pub extern "win64" fn set_timer(_: Event, _: TimerDelay, _: u64) -> Status {
-    crate::log!("EFI_STUB: set_timer - UNSUPPORTED\n");
-    Status::UNSUPPORTED
+fn dispatch_event_notifies() {
+    let manage_interrupts = !EXIT_BOOT_SERVICES.load(Ordering::SeqCst);
+    let mut dispatched = false;
+    loop {
+        let (notify_function, event, notify_context, previous_tpl, notify_tpl) = {
+          let mut event_info = EVENT.lock();
+          match event_info.pop_notify() {
+            Some((notify_function, event, notify_context, previous_tpl)) =>
+              (notify_function, event, notify_context, previous_tpl, event_info.get_current_tpl()),
+            None => { break; },
+          }
+        };
+        dispatched = true;
+
+        if manage_interrupts {
+          if notify_tpl >= efi::TPL_HIGH_LEVEL {
+            x86_64::instructions::interrupts::disable();
+          } else {
+            x86_64::instructions::interrupts::enable();
+          }
+        }
+        let notify_function = unsafe { transmute::<usize, EventNotify>(notify_function) };
+        (notify_function)(event, notify_context as *mut c_void);
+
+        EVENT.lock().set_current_tpl(previous_tpl);
+    }
+
+    if manage_interrupts && dispatched && EVENT.lock().get_current_tpl() < efi::TPL_HIGH_LEVEL {
+      x86_64::instructions::interrupts::enable();
+    }
 }
 
+// Set once ExitBootServices() succeeded, only runtime services are left.
+pub static EXIT_BOOT_SERVICES: AtomicBool = AtomicBool::new(false);
+
+// Set once SetVirtualAddressMap() succeeded.
+pub static VIRTUAL_MODE: AtomicBool = AtomicBool::new(false);
+
+// The monotonic count. The high 32 bits are kept in the "MTC" variable and
+// bumped on every boot, see initialize_monotonic_count().
+pub static MONOTONIC_COUNT: AtomicU64 = AtomicU64::new(0);
+
+// Time (in 100ns units) that elapsed while the timer interrupt could not take
+// the EVENT lock. It is accounted on the next tick.
+static PENDING_TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Whether the interrupted code holds a firmware lock, which a notification
+// function calling back into the firmware would dead lock on.
 #[cfg(not(test))]
-pub extern "win64" fn wait_for_event(_: usize, _: *mut Event, _: *mut usize) -> Status {
-    Status::UNSUPPORTED
+fn firmware_lock_held() -> bool {
+    #[cfg(feature = "guard-pages")]
+    {
+      if GUARDS.try_lock().is_none() {
+        return true;
+      }
+    }
+    ALLOCATOR.try_lock().is_none() ||
+      POOL.try_lock().is_none() ||
+      HANDLE_DATABASE.try_lock().is_none() ||
+      VARIABLE.try_lock().is_none() ||
+      IMAGE.try_lock().is_none() ||
+      CONOUT.try_lock().is_none() ||
+      CONIN.try_lock().is_none() ||
+      RTC.try_lock().is_none() ||
+      crate::logger::is_locked()
+}
+
+// Called from the timer interrupt handler. Expired timer events are signaled
+// here. Like the CoreTimerTick() of EDK2, the notification functions above the
+// interrupted TPL are then dispatched through RestoreTPL() at TPL_HIGH_LEVEL,
+// unless the interrupted code holds a firmware lock. Those are dispatched by
+// the next event service or tick.
+#[cfg(not(test))]
+pub fn timer_tick(elapsed: u64) {
+    PENDING_TIMER_TICKS.fetch_add(elapsed, Ordering::SeqCst);
+    let notify_pending = match EVENT.try_lock() {
+      Some(mut event) => {
+        let elapsed = PENDING_TIMER_TICKS.swap(0, Ordering::SeqCst);
+        event.timer_tick(elapsed);
+        event.notify_pending()
+      },
+      None => false,
+    };
+
+    if notify_pending && !firmware_lock_held() {
+      let old_tpl = raise_tpl(efi::TPL_HIGH_LEVEL);
+      restore_tpl(old_tpl);
+    }
+}
+
+// Called from the page fault handler, which may have interrupted a guarded
+// allocation.
+#[cfg(all(not(test), feature = "guard-pages"))]
+pub fn report_guard_page_fault(address: u64) {
+    if let Some(guards) = GUARDS.try_lock() {
+        guard::report(&guards, address);
+    }
 }
 
+// Called from the page fault handler, which runs on a stack of its own
 #[cfg(not(test))]
-pub extern "win64" fn signal_event(_: Event) -> Status {
-    crate::log!("EFI_STUB: signal_event - UNSUPPORTED\n");
-    Status::UNSUPPORTED
+pub fn is_stack_overflow(address: u64) -> bool {
+    paging::is_stack_guard(address)
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn close_event(_: Event) -> Status {
-    crate::log!("EFI_STUB: close_event - UNSUPPORTED\n");
-    Status::UNSUPPORTED
+pub extern "win64" fn set_timer(event: Event, timer_type: TimerDelay, trigger_time: u64) -> Status {
+    EVENT.lock().set_timer(event, timer_type, trigger_time)
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn check_event(_: Event) -> Status {
-    crate::log!("EFI_STUB: check_event - UNSUPPORTED\n");
-    Status::UNSUPPORTED
+pub extern "win64" fn wait_for_event(number_of_events: usize, events: *mut Event, index: *mut usize) -> Status {
+    if number_of_events == 0 || events == core::ptr::null_mut() || index == core::ptr::null_mut() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    if EVENT.lock().get_current_tpl() != efi::TPL_APPLICATION {
+        return Status::UNSUPPORTED;
+    }
+
+    let events = unsafe { core::slice::from_raw_parts(events, number_of_events) };
+    loop {
+        for i in 0 .. number_of_events {
+            let status = check_event(events[i]);
+            if status != Status::NOT_READY {
+                unsafe { *index = i; }
+                return status;
+            }
+        }
+        if crate::timer::is_running() {
+            // Sleep until the next interrupt, a timer tick at the latest.
+            x86_64::instructions::interrupts::enable_interrupts_and_hlt();
+        } else {
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+}
+
+#[cfg(not(test))]
+pub extern "win64" fn signal_event(event: Event) -> Status {
+    let status = EVENT.lock().signal_event(event);
+    if status == Status::SUCCESS {
+        dispatch_event_notifies();
+    }
+    status
+}
+
+#[cfg(not(test))]
+pub extern "win64" fn close_event(event: Event) -> Status {
+    EVENT.lock().close_event(event)
+}
+
+#[cfg(not(test))]
+pub extern "win64" fn check_event(event: Event) -> Status {
+    let status = EVENT.lock().queue_wait_notify(event);
+    if status != Status::SUCCESS {
+        return status;
+    }
+    dispatch_event_notifies();
+
+    EVENT.lock().check_event(event)
 }
 
 #[cfg(not(test))]
@@ -868,12 +1324,17 @@ pub extern "win64" fn reinstall_protocol_interface(
 
 #[cfg(not(test))]
 pub extern "win64" fn uninstall_protocol_interface(
-    _: Handle,
-    _: *mut Guid,
-    _: *mut c_void,
+    handle: Handle,
+    guid: *mut Guid,
+    interface: *mut c_void,
 ) -> Status {
-    crate::log!("EFI_STUB: uninstall_protocol_interface - UNSUPPORTED, TODO\n");
-    Status::SUCCESS
+    if handle == core::ptr::null_mut() || guid == core::ptr::null_mut() {
+      return Status::INVALID_PARAMETER;
+    }
+
+    let status = HANDLE_DATABASE.lock().uninstall_protocol(handle, guid, interface);
+    crate::log!("EFI_STUB: uninstall_protocol_interface: {:?}, handle: {:?}, interface: {:?} - status: {:x}\n", unsafe{*guid}, handle, interface, status.value() as u64);
+    status
 }
 
 #[cfg(not(test))]
@@ -1035,10 +1496,151 @@ pub extern "win64" fn locate_device_path(protocol: *mut Guid, device_path: *mut
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn install_configuration_table(_: *mut Guid, _: *mut c_void) -> Status {
-    crate::log!("EFI_STUB: install_configuration_table - UNSUPPORTED\n");
+pub extern "win64" fn install_configuration_table(guid: *mut Guid, table: *mut c_void) -> Status {
+    if guid.is_null() {
+      return Status::INVALID_PARAMETER;
+    }
+    let guid = unsafe { *guid };
 
-    Status::UNSUPPORTED
+    unsafe {
+      let count = ST.number_of_table_entries;
+      match (0 .. count).find(|index| CT[*index].vendor_guid == guid) {
+        Some(index) if table.is_null() => {
+          // The entries in use are kept at the start of the table
+          for i in index .. count - 1 {
+            CT[i].vendor_guid = CT[i + 1].vendor_guid;
+            CT[i].vendor_table = CT[i + 1].vendor_table;
+          }
+          ST.number_of_table_entries = count - 1;
+        },
+        Some(index) => CT[index].vendor_table = table,
+        None if table.is_null() => return Status::NOT_FOUND,
+        None => {
+          if count == MAX_CONFIGURATION_TABLE {
+            return Status::OUT_OF_RESOURCES;
+          }
+          CT[count].vendor_guid = guid;
+          CT[count].vendor_table = table;
+          ST.number_of_table_entries = count + 1;
+        },
+      }
+    }
+
+    Status::SUCCESS
+}
+
+// Read the file named by the file path nodes at the end of device_path, from
+// the file system on handle
+#[cfg(not(test))]
+pub fn read_file_path(handle: Handle, device_path: *mut DevicePathProtocol) -> (Status, *mut c_void, usize) {
+    let mut fs = core::ptr::null_mut();
+    let status = handle_protocol(handle, &mut efi::protocols::simple_file_system::PROTOCOL_GUID.clone(), &mut fs);
+    if status != Status::SUCCESS {
+      return (status, core::ptr::null_mut(), 0);
+    }
+    let fs = fs as *mut SimpleFileSystemProtocol;
+
+    // Each node is a part of the path, relative to the one before
+    let mut file = core::ptr::null_mut() as *mut efi::protocols::file::Protocol;
+    let status = unsafe { ((*fs).open_volume)(fs, &mut file) };
+    if status.is_error() {
+      log!("EFI_STUB: load image open_volume error 0x{:x}\n", status.value());
+      return (status, core::ptr::null_mut(), 0);
+    }
+    let mut node = device_path;
+    while !crate::efi::device_path::is_device_path_end(node) {
+      if crate::efi::device_path::get_device_path_node_type(node) != r_efi::protocols::device_path::TYPE_MEDIA ||
+         crate::efi::device_path::get_device_path_node_sub_type(node) != r_efi::protocols::device_path::Media::SUBTYPE_FILE_PATH {
+        unsafe { ((*file).close)(file); }
+        return (Status::NOT_FOUND, core::ptr::null_mut(), 0);
+      }
+      let mut next = core::ptr::null_mut() as *mut efi::protocols::file::Protocol;
+      let file_name = (node as usize + size_of::<DevicePathProtocol>()) as *mut Char16;
+      let status = unsafe { ((*file).open)(file, &mut next, file_name, efi::protocols::file::MODE_READ, 0) };
+      unsafe { ((*file).close)(file); }
+      if status.is_error() {
+        log!("EFI_STUB: load image open error 0x{:x}\n", status.value());
+        return (status, core::ptr::null_mut(), 0);
+      }
+      file = next;
+      node = crate::efi::device_path::get_next_device_path_node(node);
+    }
+
+    let mut info = [0u64; (size_of::<efi::protocols::file::Info>() + 1024) / 8];
+    let mut info_size = core::mem::size_of_val(&info);
+    let status = unsafe { ((*file).get_info)(file, &mut efi::protocols::file::INFO_ID.clone(), &mut info_size, info.as_mut_ptr() as *mut c_void) };
+    if status.is_error() {
+      log!("EFI_STUB: load image get_info error 0x{:x}\n", status.value());
+      unsafe { ((*file).close)(file); }
+      return (status, core::ptr::null_mut(), 0);
+    }
+    let mut size = unsafe { (*(info.as_ptr() as *const efi::protocols::file::Info)).file_size } as usize;
+    log!("EFI_STUB: file size is: {:?}\n", size);
+
+    let mut buffer = core::ptr::null_mut();
+    let mut status = allocate_pool(MemoryType::BootServicesData, size, &mut buffer);
+    if status == Status::SUCCESS {
+      status = unsafe { ((*file).read)(file, &mut size, buffer) };
+      if status.is_error() {
+        log!("EFI_STUB: load image read error 0x{:x}\n", status.value());
+        free_pool(buffer);
+      }
+    }
+    unsafe { ((*file).close)(file); }
+    (status, buffer, size)
+}
+
+// Get the file named by the end of device_path from the LoadFile or LoadFile2 on handle
+#[cfg(not(test))]
+fn load_file(handle: Handle, guid: &Guid, boot_policy: Boolean, device_path: *mut DevicePathProtocol) -> (Status, *mut c_void, usize) {
+    let mut interface = core::ptr::null_mut();
+    let status = handle_protocol(handle, &mut guid.clone(), &mut interface);
+    if status != Status::SUCCESS {
+      return (status, core::ptr::null_mut(), 0);
+    }
+    // LoadFile2 has the interface of LoadFile
+    let load_file = interface as *mut efi::protocols::load_file::Protocol;
+
+    let mut size = 0;
+    let status = unsafe { ((*load_file).load_file)(load_file, device_path, boot_policy, &mut size, core::ptr::null_mut()) };
+    if status != Status::BUFFER_TOO_SMALL {
+      return (if status == Status::SUCCESS { Status::NOT_FOUND } else { status }, core::ptr::null_mut(), 0);
+    }
+    let mut buffer = core::ptr::null_mut();
+    let status = allocate_pool(MemoryType::BootServicesData, size, &mut buffer);
+    if status != Status::SUCCESS {
+      return (status, core::ptr::null_mut(), 0);
+    }
+    let status = unsafe { ((*load_file).load_file)(load_file, device_path, boot_policy, &mut size, buffer) };
+    if status != Status::SUCCESS {
+      free_pool(buffer);
+    }
+    (status, buffer, size)
+}
+
+// LoadImage without a source buffer gets the file from the device its path
+// starts with: a file system, or else a LoadFile2 or LoadFile. The buffer is
+// allocated from pool.
+#[cfg(not(test))]
+fn read_image_file(boot_policy: Boolean, device_path: *mut DevicePathProtocol) -> (Status, *mut c_void, usize) {
+    let mut remaining = device_path as *mut c_void;
+    let mut handle: Handle = core::ptr::null_mut();
+    let status = locate_device_path(&mut efi::protocols::simple_file_system::PROTOCOL_GUID.clone(), &mut remaining, &mut handle);
+    if status == Status::SUCCESS {
+      return read_file_path(handle, remaining as *mut DevicePathProtocol);
+    }
+
+    // LoadFile2 does not load boot options
+    let mut guids = [efi::protocols::load_file2::PROTOCOL_GUID, efi::protocols::load_file::PROTOCOL_GUID];
+    let guids = if boot_policy == Boolean::FALSE { &mut guids[..] } else { &mut guids[1 ..] };
+    for guid in guids.iter_mut() {
+      let mut remaining = device_path as *mut c_void;
+      let status = locate_device_path(guid, &mut remaining, &mut handle);
+      if status == Status::SUCCESS {
+        return load_file(handle, guid, boot_policy, remaining as *mut DevicePathProtocol);
+      }
+    }
+    (Status::NOT_FOUND, core::ptr::null_mut(), 0)
 }
 
 #[cfg(not(test))]
@@ -1051,62 +1653,25 @@ pub extern "win64" fn load_image(
     image_handle: *mut Handle,
 ) -> Status {
     crate::log!("EFI_STUB: load_image size is: {}, parent_image_handle: {:?}\n", source_size, parent_image_handle);
+    if image_handle.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+
     let mut source_buffer = source_buffer;
     let mut source_size = source_size;
-
-    let mut fs_interface = core::ptr::null_mut();
-    crate::log!("EFI_STUB: start locate_protocol\n");
-    let status = locate_protocol(&efi::protocols::simple_file_system::PROTOCOL_GUID as *const efi::Guid as *mut efi::Guid, fs_interface, &mut fs_interface);
-    crate::log!("EFI_STUB: simple_file_system protocol 0x{:p} status: {:x}\n", fs_interface, status.value());
-    if source_size == 0 {
-        //device_path::print_device_path(device_path as *mut efi::protocols::device_path::Protocol);
-        if let Some(filename) = crate::efi::device_path::get_file_path_media_device_path(device_path as *mut efi::protocols::device_path::Protocol) {
-            let mut name = [0u8;512];
-            char16_to_char8(filename, 256, &name[0] as *const u8 as *mut u8, 256);
-            log!("EFI_STUB: filename is {}\n", core::str::from_utf8(&name[..]).unwrap_or("error"));
-            //let status = handle_protocol(parent_image_handle, &efi::protocols::simple_file_system::PROTOCOL_GUID as *const efi::Guid as *mut efi::Guid, &mut fs_interface);
-            // let mut handle = core::ptr::null_mut();
-            let mut fs = fs_interface as *mut efi::protocols::simple_file_system::Protocol;
-            let mut rootfile = core::ptr::null_mut() as *mut efi::protocols::file::Protocol;
-            let mut desfile = core::ptr::null_mut() as *mut efi::protocols::file::Protocol;
-            let mut status: efi::Status = unsafe{((*fs).open_volume)(fs, &mut rootfile)};
-            if status.is_error() {
-                log!("EFI_STUB: load image open_volume error \n");
-            }
-
-            unsafe{
-                status = ((*rootfile).open)(rootfile, &mut desfile as *mut *mut efi::protocols::file::Protocol, filename as *mut efi::Char16, efi::protocols::file::MODE_READ, 0);
-                if status.is_error() {
-                    log!("EFI_STUB: load image open error \n");
-                }
-                let mut buffer = [0u8; core::mem::size_of::<efi::protocols::file::Info>() + 1024];
-                let mut buffer_size:usize = core::mem::size_of::<efi::protocols::file::Info>() + 1024;
-                status = ((*desfile).get_info)(desfile, &mut efi::protocols::file::INFO_ID as *mut efi::Guid, &mut buffer_size, &mut buffer[0] as *mut u8 as *mut core::ffi::c_void);
-                if status.is_error() {
-                    log!("EFI_STUB: load image get_info error 0x{:x}\n", status.value());
-                }
-                let desfile_info = &buffer as *const u8 as *mut efi::protocols::file::Info;
-                source_size = unsafe{(*desfile_info).file_size} as usize;
-                log!("EFI_STUB: file size is: {:?}\n", source_size);
-
-                source_buffer = core::ptr::null_mut();
-                status = crate::efi::allocate_pool (MemoryType::BootServicesData, source_size, &mut source_buffer);
-                if status != Status::SUCCESS {
-                  log!("load_image - fail on allocate pool\n");
-                  return Status::OUT_OF_RESOURCES;
-                }
-
-                status = ((*desfile).read)(desfile, &mut source_size, source_buffer);
-                if status.is_error() {
-                    log!("EFI_STUB: load image read error 0x{:x}\n", status.value());
-                }
-            }
-
-        } else {
-            log!("EFI_STUB: not found filename\n");
+    let mut allocated = false;
+    if source_buffer.is_null() {
+        if device_path.is_null() {
+            return Status::NOT_FOUND;
         }
-        // simple file system device path
-
+        let (status, buffer, size) = read_image_file(boot_policy, device_path as *mut DevicePathProtocol);
+        if status != Status::SUCCESS {
+            crate::log!("EFI_STUB: load_image cannot read the file: {:?}\n", status);
+            return status;
+        }
+        source_buffer = buffer;
+        source_size = size;
+        allocated = true;
     }
 
     //let (status, new_image_handle) = IMAGE.lock().load_image(
@@ -1119,27 +1684,16 @@ pub extern "win64" fn load_image(
 
     crate::log!("EFI_STUB: load_image done handle {:?} status 0x{:x}\n", new_image_handle, status.value());
 
-    let mut loaded_image = core::ptr::null_mut();
-    let status = handle_protocol(new_image_handle, &mut r_efi::protocols::loaded_image::PROTOCOL_GUID as *const efi::Guid as *mut efi::Guid, &mut loaded_image);
-    crate::log!("EFI_STUB: loaded_image protocol {:p} status: {:x}\n", loaded_image, status.value());
-
-    let mut loaded_image: &mut image::LoadedImageProtocol = unsafe {transmute::<*mut c_void, &mut image::LoadedImageProtocol>(loaded_image as *mut c_void)};
-    crate::log!("EFI_STUB: loaded_image.device_handle: {:p}\n", loaded_image.device_handle);
-
-    crate::log!("EFI_STUB: loaded_image fs_interface: {:p}\n", fs_interface);
-    // let status = crate::efi::install_protocol_interface (
-    //     &mut loadeEFI_STUB - open_protocol:d_image.device_handle as *mut *mut c_void,
-    //     &mut r_efi::efi::protocols::simple_file_system::PROTOCOL_GUID as *mut Guid,
-    //     InterfaceType::NativeInterface,
-    //     fs_interface
-    //     );
+    // The image is copied into pages of its own, the file read for it is not needed anymore.
+    if allocated {
+        free_pool(source_buffer);
+    }
 
-    if status == Status::SUCCESS {
-        if image_handle != core::ptr::null_mut() {
-          unsafe { *image_handle = new_image_handle };
-        };
+    if status != Status::SUCCESS {
+        return status;
     }
 
+    unsafe { *image_handle = new_image_handle };
     status
 }
 
@@ -1164,33 +1718,93 @@ pub extern "win64" fn start_image(
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn exit(_: Handle, _: Status, _: usize, _: *mut Char16) -> Status {
-    crate::log!("EFI_STUB: exit - UNSUPPORTED\n");
-    Status::UNSUPPORTED
+pub extern "win64" fn exit(
+    image_handle: Handle,
+    exit_status: Status,
+    exit_data_size: usize,
+    exit_data: *mut Char16
+) -> Status {
+    crate::log!("EFI_STUB: exit, handle: {:?}, status: {:x}\n", image_handle, exit_status.value());
+
+    Image::new().exit(image_handle, exit_status, exit_data_size, exit_data)
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn unload_image(_: Handle) -> Status {
-    crate::log!("EFI_STUB: unload_image - UNSUPPORTED\n");
-    Status::UNSUPPORTED
+pub extern "win64" fn unload_image(image_handle: Handle) -> Status {
+    crate::log!("EFI_STUB: unload_image, handle: {:?}\n", image_handle);
+
+    Image::new().unload_image(image_handle)
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn exit_boot_services(_: Handle, _: usize) -> Status {
-    crate::log!("EFI_STUB: exit_boot_services\n");
+pub extern "win64" fn exit_boot_services(_: Handle, map_key: usize) -> Status {
+    crate::log!("EFI_STUB: exit_boot_services - map_key: 0x{:x}\n", map_key);
+
+    // The caller must have the latest memory map, or get it again and retry.
+    if map_key != ALLOCATOR.lock().get_map_key() {
+      crate::log!("EFI_STUB: exit_boot_services - stale map key\n");
+      return Status::INVALID_PARAMETER;
+    }
+
+    // The last chance of the drivers to use boot services
+    EVENT.lock().signal_event_group(&efi::EVENT_GROUP_EXIT_BOOT_SERVICES);
+    dispatch_event_notifies();
+
+    // The runtime regions the OS maps are final now
+    crate::efi::runtime_tables::update_memory_attributes();
+
+    // The OS owns the interrupt controllers and the devices from now on.
+    x86_64::instructions::interrupts::disable();
+    crate::timer::stop();
+    unsafe { crate::efi::block::shutdown_block_wrappers(&mut BLOCK_WRAPPERS); }
+    crate::efi::paging::remove_null_guard();
+    crate::efi::paging::remove_stack_guard();
+
+    EXIT_BOOT_SERVICES.store(true, Ordering::SeqCst);
+    unsafe {
+      ST.boot_services = core::ptr::null_mut();
+      ST.console_in_handle = core::ptr::null_mut();
+      ST.con_in = core::ptr::null_mut();
+      ST.console_out_handle = core::ptr::null_mut();
+      ST.con_out = core::ptr::null_mut();
+      ST.standard_error_handle = core::ptr::null_mut();
+      ST.std_err = core::ptr::null_mut();
+      core::ptr::write_bytes(&mut BS as *mut efi::BootServices as *mut u8, 0, size_of::<efi::BootServices>());
+    }
+
     Status::SUCCESS
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn get_next_monotonic_count(_: *mut u64) -> Status {
-    crate::log!("EFI_STUB: get_next_monotonic_count - UNSUPPORTED\n");
-    Status::UNSUPPORTED
+pub extern "win64" fn get_next_monotonic_count(count: *mut u64) -> Status {
+    if count == core::ptr::null_mut() {
+      return Status::INVALID_PARAMETER;
+    }
+
+    // The high 32 bits must not wrap, the count would go backwards.
+    let mut value = MONOTONIC_COUNT.load(Ordering::SeqCst);
+    loop {
+      if value == u64::MAX {
+        return Status::DEVICE_ERROR;
+      }
+      match MONOTONIC_COUNT.compare_exchange(value, value + 1, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => break,
+        Err(current) => value = current,
+      }
+    }
+    if (value as u32) == 0xffff_ffff {
+      // The low 32 bits wrapped, persist the new high 32 bits.
+      crate::efi::init::save_monotonic_count_high ((value >> 32) as u32 + 1);
+    }
+
+    unsafe { *count = value; }
+    Status::SUCCESS
 }
 
 #[cfg(not(test))]
-pub extern "win64" fn stall(_: usize) -> Status {
-    crate::log!("EFI_STUB: stall - called\n");
-    Status::UNSUPPORTED
+pub extern "win64" fn stall(microseconds: usize) -> Status {
+    crate::timer::stall(microseconds as u64);
+    Status::SUCCESS
 }
 
 #[cfg(not(test))]
@@ -1495,24 +2109,38 @@ pub extern "win64" fn set_mem(buffer: *mut c_void, size: usize, val: u8) {
 
 #[cfg(not(test))]
 pub extern "win64" fn create_event_ex(
-    _: u32,
-    _: Tpl,
-    _: EventNotify,
-    _: *const c_void,
-    _: *const Guid,
+    r#type: u32,
+    notify_tpl: Tpl,
+    notify_function: Option<EventNotify>,
+    notify_context: *const c_void,
+    event_group: *const Guid,
     event: *mut Event,
 ) -> Status {
-    crate::log!("EFI_STUB: create_event_ex - UNSUPPORTED\n");
-
     if event == core::ptr::null_mut() {
         crate::log!("EFI_STUB: create_event_ex - NULL\n");
         return Status::INVALID_PARAMETER;
     }
 
-    unsafe {*event = core::ptr::null_mut();}
+    let notify_function = match notify_function {
+        Some(notify_function) => notify_function as usize,
+        None => 0,
+    };
+    let event_group = if event_group == core::ptr::null() { None } else { Some(unsafe {*event_group}) };
 
-    // TBD
-    Status::SUCCESS
+    let (status, new_event) = EVENT.lock().create_event_ex(
+            r#type,
+            notify_tpl,
+            notify_function,
+            notify_context as *mut c_void,
+            event_group
+            );
+    crate::log!("EFI_STUB: create_event_ex - type:0x{:x} tpl:0x{:x} - status: {:?}\n", r#type, notify_tpl as usize, status);
+    if status == Status::SUCCESS {
+        unsafe {
+            *event = new_event;
+        }
+    }
+    status
 }
 
 #[cfg(not(test))]
@@ -1643,21 +2271,34 @@ pub static mut BS : efi::BootServices = efi::BootServices {
         reserved: core::ptr::null_mut(),
       };
 
-const MAX_CONFIGURATION_TABLE : usize = 4;
+const MAX_CONFIGURATION_TABLE : usize = 8;
 
+// Only the first ST.number_of_table_entries are in use
 pub static mut CT : [efi::ConfigurationTable; MAX_CONFIGURATION_TABLE] =
         [
           efi::ConfigurationTable {
-            vendor_guid: Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]), // TODO
+            vendor_guid: Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]),
             vendor_table: core::ptr::null_mut(),},
           efi::ConfigurationTable {
-            vendor_guid: Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]), // TODO
+            vendor_guid: Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]),
             vendor_table: core::ptr::null_mut(),},
           efi::ConfigurationTable {
-            vendor_guid: Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]), // TODO
+            vendor_guid: Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]),
             vendor_table: core::ptr::null_mut(),},
           efi::ConfigurationTable {
-            vendor_guid: Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]), // TODO
+            vendor_guid: Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]),
+            vendor_table: core::ptr::null_mut(),},
+          efi::ConfigurationTable {
+            vendor_guid: Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]),
+            vendor_table: core::ptr::null_mut(),},
+          efi::ConfigurationTable {
+            vendor_guid: Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]),
+            vendor_table: core::ptr::null_mut(),},
+          efi::ConfigurationTable {
+            vendor_guid: Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]),
+            vendor_table: core::ptr::null_mut(),},
+          efi::ConfigurationTable {
+            vendor_guid: Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]),
             vendor_table: core::ptr::null_mut(),},
         ];
 
@@ -1710,8 +2351,10 @@ pub fn enter_uefi(hob: *const c_void) -> ! {
       unsafe {*func_addr_ptr = install_multiple_protocol_interfaces_real as usize;}
       let func_addr_ptr = unsafe {transmute::<&mut UninstallMultipleProtocolInterfacesFunc, *mut usize>(&mut BS.uninstall_multiple_protocol_interfaces)};
       unsafe {*func_addr_ptr = uninstall_multiple_protocol_interfaces_real as usize;}
+      #[cfg(feature = "guard-pages")]
+      crate::efi::guard::initialize(&mut BS);
 
-      ST.number_of_table_entries = MAX_CONFIGURATION_TABLE;
+      ST.number_of_table_entries = 0;
       ST.configuration_table = &mut CT as *mut [r_efi::system::ConfigurationTable; MAX_CONFIGURATION_TABLE] as *mut r_efi::system::ConfigurationTable;
     }
 
@@ -1719,16 +2362,25 @@ pub fn enter_uefi(hob: *const c_void) -> ! {
 
     crate::efi::init::initialize_memory(hob);
     let new_hob = crate::pi::hob_lib::relocate_hob (hob);
+    install_configuration_table (&mut crate::pi::hob::HOB_LIST_GUID, new_hob);
+
     unsafe {
-      CT[0].vendor_guid = crate::pi::hob::HOB_LIST_GUID;
-      CT[0].vendor_table = new_hob;
+      create_event (efi::EVT_NOTIFY_WAIT, efi::TPL_NOTIFY, Some(stdin_wait_for_key), core::ptr::null_mut(), &mut STDIN.wait_for_key);
+      create_event (efi::EVT_NOTIFY_WAIT, efi::TPL_NOTIFY, Some(stdin_wait_for_key), core::ptr::null_mut(), &mut STDIN_EX.wait_for_key_ex);
     }
 
     unsafe {
       crate::efi::init::initialize_console (&mut ST, &mut STDIN_EX as *mut SimpleTextInputExProtocol as *mut c_void);
     }
 
-    crate::efi::init::initialize_variable ();
+    crate::efi::init::initialize_variable (new_hob);
+    crate::efi::init::initialize_monotonic_count ();
+
+    crate::acpi::init();
+    // After the BIOS data area at 0x40e is read
+    crate::efi::paging::initialize();
+    crate::timer::start();
+    x86_64::instructions::interrupts::enable();
 
     //crate::efi::init::initialize_fs ();
 
@@ -1764,6 +2416,8 @@ pub fn enter_uefi(hob: *const c_void) -> ! {
                 f = fat::Filesystem::new(&device, start, end, part_id);
                 if f.init().is_err() {
                     log!("Failed to create filesystem\n");
+                } else {
+                    crate::efi::init::initialize_file_store(&device, &f);
                 }
                 partition_start = start;
                 partition_end = end;
@@ -1820,51 +2474,6 @@ pub fn enter_uefi(hob: *const c_void) -> ! {
                         InterfaceType::NativeInterface,
                         (&mut efi_part.disk_paths) as *const crate::efi::block::BlockDevicePath as *const c_void as *mut c_void
                         );
-                let (image, size) = crate::efi::init::find_loader (new_hob);
-
-                let mut image_path = FullMemoryMappedDevicePath {
-                    memory_map: MemoryMappedDevicePathProtocol {
-                        header: DevicePathProtocol {
-                        r#type: r_efi::protocols::device_path::TYPE_HARDWARE,
-                        sub_type: r_efi::protocols::device_path::Hardware::SUBTYPE_MMAP,
-                        length: [24, 0],
-                        },
-                        memory_type: MemoryType::BootServicesCode,
-                        start_address: image as u64,
-                        end_address: image as u64 + size as u64 - 1,
-                    },
-                    end: r_efi::protocols::device_path::End {
-                        header: DevicePathProtocol {
-                        r#type: r_efi::protocols::device_path::TYPE_END,
-                        sub_type: r_efi::protocols::device_path::End::SUBTYPE_ENTIRE,
-                        length: [4, 0],
-                        },
-                    },
-                };
-
-                let mut image_handle : Handle = core::ptr::null_mut();
-                let status = load_image (
-                                Boolean::FALSE,
-                                core::ptr::null_mut(), // parent handle
-                                &mut image_path.memory_map.header as *mut DevicePathProtocol as *mut c_void,
-                                image as *mut c_void,
-                                size,
-                                &mut image_handle
-                                );
-                match (status) {
-                    Status::SUCCESS => {
-                    let mut exit_data_size : usize = 0;
-                    let mut exit_data : *mut Char16 = core::ptr::null_mut();
-                    let status = start_image (
-                                    image_handle,
-                                    &mut exit_data_size as *mut usize,
-                                    &mut exit_data as *mut *mut Char16
-                                    );
-                    },
-                    _ => {
-                    log!("load image fails {:?}\n", status);
-                    },
-                }
             }
             Err(_) => {
                 log!("Failed to find EFI partition\n");
@@ -1875,6 +2484,58 @@ pub fn enter_uefi(hob: *const c_void) -> ! {
       }
     }
 
+    initrd::initialize(crate::efi::init::find_initrd (new_hob));
+    runtime_tables::install();
+
+    boot_manager::boot();
+
+    // Nothing to boot on the disks, the application in the firmware volume is the last resort
+    let (image, size) = crate::efi::init::find_loader (new_hob);
+
+    let mut image_path = FullMemoryMappedDevicePath {
+        memory_map: MemoryMappedDevicePathProtocol {
+            header: DevicePathProtocol {
+            r#type: r_efi::protocols::device_path::TYPE_HARDWARE,
+            sub_type: r_efi::protocols::device_path::Hardware::SUBTYPE_MMAP,
+            length: [24, 0],
+            },
+            memory_type: MemoryType::BootServicesCode,
+            start_address: image as u64,
+            end_address: image as u64 + size as u64 - 1,
+        },
+        end: r_efi::protocols::device_path::End {
+            header: DevicePathProtocol {
+            r#type: r_efi::protocols::device_path::TYPE_END,
+            sub_type: r_efi::protocols::device_path::End::SUBTYPE_ENTIRE,
+            length: [4, 0],
+            },
+        },
+    };
+
+    let mut image_handle : Handle = core::ptr::null_mut();
+    let status = load_image (
+                    Boolean::FALSE,
+                    core::ptr::null_mut(), // parent handle
+                    &mut image_path.memory_map.header as *mut DevicePathProtocol as *mut c_void,
+                    image as *mut c_void,
+                    size,
+                    &mut image_handle
+                    );
+    match (status) {
+        Status::SUCCESS => {
+        let mut exit_data_size : usize = 0;
+        let mut exit_data : *mut Char16 = core::ptr::null_mut();
+        let status = start_image (
+                        image_handle,
+                        &mut exit_data_size as *mut usize,
+                        &mut exit_data as *mut *mut Char16
+                        );
+        },
+        _ => {
+        log!("load image fails {:?}\n", status);
+        },
+    }
+
     log!("Core Init Done\n");
     loop {}
 }
@@ -0,0 +1,596 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use r_efi::efi::{AllocateType, MemoryType, Status};
+
+use core::ffi::c_void;
+
+use super::alloc::{Allocator, MemoryDescriptor};
+use super::pool::PageAllocator;
+
+const PAGE_SIZE: u64 = 0x1000;
+const SIZE_2M: u64 = 0x20_0000;
+const SIZE_1G: u64 = 0x4000_0000;
+
+const ENTRY_COUNT: usize = 512;
+
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+// A 1GiB or 2MiB page in a directory entry
+const HUGE_PAGE: u64 = 1 << 7;
+// The PAT bit moves when a large page is split into 4KiB ones
+const PAT_4K: u64 = 1 << 7;
+const PAT_LARGE: u64 = 1 << 12;
+const NO_EXECUTE: u64 = 1 << 63;
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const ACCESS_MASK: u64 = PRESENT | WRITABLE | NO_EXECUTE;
+
+// Physical address bits mapped without 1GiB pages, the 2MiB ones take 1MiB
+// of tables for 512GiB.
+const MAX_ADDRESS_BITS_2M: u32 = 39;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Access {
+    NoAccess,
+    ReadOnly,
+    ReadWrite,
+    ReadExecute,
+    ReadWriteExecute,
+}
+
+impl Access {
+    fn flags(self, nx: u64) -> u64 {
+        match self {
+            Access::NoAccess => nx,
+            Access::ReadOnly => PRESENT | nx,
+            Access::ReadWrite => PRESENT | WRITABLE | nx,
+            Access::ReadExecute => PRESENT,
+            Access::ReadWriteExecute => PRESENT | WRITABLE,
+        }
+    }
+}
+
+// Only LoaderCode, which loaders ask for to run code from, is executable as
+// allocated. The images loaded into the code types are made executable
+// section by section, see protect_image().
+pub fn memory_type_access(memory_type: u32) -> Access {
+    if memory_type == MemoryType::LoaderCode as u32 {
+        Access::ReadWriteExecute
+    } else {
+        Access::ReadWrite
+    }
+}
+
+fn table(address: u64) -> &'static mut [u64; ENTRY_COUNT] {
+    unsafe { &mut *(address as *mut [u64; ENTRY_COUNT]) }
+}
+
+fn index(address: u64, shift: u32) -> usize {
+    ((address >> shift) as usize) & (ENTRY_COUNT - 1)
+}
+
+fn new_table<A: PageAllocator>(pages: &mut A) -> Option<u64> {
+    let address = pages.allocate_pages(MemoryType::BootServicesData, 1)?;
+    table(address).iter_mut().for_each(|entry| *entry = 0);
+    Some(address)
+}
+
+/// Identity mapped 4-level page tables
+pub struct PageTables {
+    root: u64,
+    // NO_EXECUTE when the CPU has it, the bit is reserved otherwise
+    nx: u64,
+}
+
+impl PageTables {
+    /// Map [0, top) read-write and not executable, with 1GiB pages when
+    /// `huge_pages`, 2MiB ones otherwise. top is rounded up to 1GiB.
+    pub fn new<A: PageAllocator>(pages: &mut A, top: u64, nx: bool, huge_pages: bool) -> Option<PageTables> {
+        let tables = PageTables {
+            root: new_table(pages)?,
+            nx: if nx { NO_EXECUTE } else { 0 },
+        };
+        let flags = Access::ReadWrite.flags(tables.nx);
+
+        let mut address = 0;
+        while address < top {
+            let pml4_entry = &mut table(tables.root)[index(address, 39)];
+            if *pml4_entry == 0 {
+                *pml4_entry = new_table(pages)? | PRESENT | WRITABLE;
+            }
+            let pdpt_entry = &mut table(*pml4_entry & ADDRESS_MASK)[index(address, 30)];
+            if huge_pages {
+                *pdpt_entry = address | HUGE_PAGE | flags;
+            } else {
+                let directory = new_table(pages)?;
+                for (i, entry) in table(directory).iter_mut().enumerate() {
+                    *entry = (address + i as u64 * SIZE_2M) | HUGE_PAGE | flags;
+                }
+                *pdpt_entry = directory | PRESENT | WRITABLE;
+            }
+            address += SIZE_1G;
+        }
+
+        Some(tables)
+    }
+
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    // Turn a 1GiB or 2MiB page into a table of pages of the next size, with
+    // the same access and caching.
+    fn split<A: PageAllocator>(pages: &mut A, entry: &mut u64, shift: u32) -> Status {
+        let directory = match new_table(pages) {
+            Some(directory) => directory,
+            None => return Status::OUT_OF_RESOURCES,
+        };
+        let child_shift = shift - 9;
+        let base = *entry & ADDRESS_MASK & !((1u64 << shift) - 1);
+        let mut flags = *entry & !ADDRESS_MASK;
+        let pat = *entry & PAT_LARGE != 0;
+        if child_shift == 12 {
+            flags &= !HUGE_PAGE;
+            if pat {
+                flags |= PAT_4K;
+            }
+        } else if pat {
+            flags |= PAT_LARGE;
+        }
+        for (i, child) in table(directory).iter_mut().enumerate() {
+            *child = (base + ((i as u64) << child_shift)) | flags;
+        }
+        // The pages decide the access, the tables allow everything
+        *entry = directory | PRESENT | WRITABLE;
+        Status::SUCCESS
+    }
+
+    /// Set the access to the pages of [address, address + size), splitting
+    /// larger pages as needed.
+    pub fn set_access<A: PageAllocator>(&mut self, pages: &mut A, address: u64, size: u64, access: Access) -> Status {
+        if address % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return Status::INVALID_PARAMETER;
+        }
+        let end = match address.checked_add(size) {
+            Some(end) => end,
+            None => return Status::INVALID_PARAMETER,
+        };
+        let flags = access.flags(self.nx);
+
+        let mut current = address;
+        'next: while current < end {
+            let mut directory = self.root;
+            let mut shift = 39;
+            loop {
+                let entry = &mut table(directory)[index(current, shift)];
+                if *entry == 0 {
+                    return Status::NOT_FOUND;
+                }
+                if shift == 12 || (shift < 39 && *entry & HUGE_PAGE != 0) {
+                    let page_size = 1u64 << shift;
+                    if current % page_size == 0 && end - current >= page_size {
+                        *entry = (*entry & !ACCESS_MASK) | flags;
+                        current += page_size;
+                        continue 'next;
+                    }
+                    let status = PageTables::split(pages, entry, shift);
+                    if status != Status::SUCCESS {
+                        return status;
+                    }
+                }
+                directory = *entry & ADDRESS_MASK;
+                shift -= 9;
+            }
+        }
+
+        Status::SUCCESS
+    }
+
+    /// The access to the page of an address, None when it is not mapped
+    pub fn access(&self, address: u64) -> Option<Access> {
+        let mut directory = self.root;
+        let mut shift = 39;
+        loop {
+            let entry = table(directory)[index(address, shift)];
+            if entry == 0 {
+                return None;
+            }
+            if shift == 12 || (shift < 39 && entry & HUGE_PAGE != 0) {
+                let execute = self.nx == 0 || entry & NO_EXECUTE == 0;
+                return Some(match (entry & PRESENT != 0, entry & WRITABLE != 0, execute) {
+                    (false, _, _) => Access::NoAccess,
+                    (true, false, false) => Access::ReadOnly,
+                    (true, true, false) => Access::ReadWrite,
+                    (true, false, true) => Access::ReadExecute,
+                    (true, true, true) => Access::ReadWriteExecute,
+                });
+            }
+            directory = entry & ADDRESS_MASK;
+            shift -= 9;
+        }
+    }
+}
+
+// The pages of the tables come straight from the allocator, so that setting
+// the access of an allocation does not recurse into itself.
+struct TablePages<'a>(&'a mut Allocator);
+
+impl<'a> PageAllocator for TablePages<'a> {
+    fn allocate_pages(&mut self, memory_type: MemoryType, page_count: u64) -> Option<u64> {
+        match Allocator::allocate_pages(self.0, AllocateType::AllocateAnyPages, memory_type, page_count, 0) {
+            (Status::SUCCESS, address) => Some(address),
+            _ => None,
+        }
+    }
+
+    fn free_pages(&mut self, address: u64, page_count: u64) {
+        Allocator::free_pages(self.0, address, page_count);
+    }
+
+    fn memory_type(&self, address: u64) -> Option<u32> {
+        self.0.get_memory_type(address)
+    }
+}
+
+#[cfg(not(test))]
+static mut PAGE_TABLES: Option<PageTables> = None;
+
+// The non-present page at the bottom of the firmware stack, 0 without one
+#[cfg(not(test))]
+static mut STACK_GUARD: u64 = 0;
+
+#[cfg(not(test))]
+extern "C" {
+    static start_of_text: u8;
+    static end_of_text: u8;
+    static end_of_rodata: u8;
+    static end_of_bss: u8;
+}
+
+#[cfg(not(test))]
+fn flush() {
+    x86_64::instructions::tlb::flush_all();
+}
+
+// Physical address bits, NX and 1GiB pages support of the CPU
+#[cfg(not(test))]
+fn cpu_features() -> (u32, bool, bool) {
+    use core::arch::x86_64::__cpuid;
+
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    let features = unsafe { __cpuid(0x8000_0001) }.edx;
+    let address_bits = if max_extended >= 0x8000_0008 {
+        unsafe { __cpuid(0x8000_0008) }.eax & 0xff
+    } else {
+        36
+    };
+    (core::cmp::min(address_bits, 48), features & (1 << 20) != 0, features & (1 << 26) != 0)
+}
+
+// The lowest page of the stack the firmware, and the images it starts, run on.
+// The stack is the one of the stack HOB, else the BootServicesData region the
+// stack pointer is in.
+#[cfg(not(test))]
+fn stack_guard_page(descriptors: &[MemoryDescriptor]) -> Option<u64> {
+    let marker = 0u8;
+    let stack_pointer = &marker as *const u8 as u64;
+    let (base, size) = crate::efi::init::stack_region().or_else(|| {
+        descriptors.iter().find(|descriptor| {
+            descriptor.r#type == MemoryType::BootServicesData as u32
+                && descriptor.physical_start <= stack_pointer
+                && stack_pointer < descriptor.physical_start + descriptor.number_of_pages * PAGE_SIZE
+        }).map(|descriptor| (descriptor.physical_start, descriptor.number_of_pages * PAGE_SIZE))
+    })?;
+    // The stack grows down, and must not already be in its lowest page
+    if base % PAGE_SIZE != 0 || stack_pointer < base + PAGE_SIZE || stack_pointer >= base + size {
+        log!("paging - no guard page for the stack at 0x{:x}\n", stack_pointer);
+        return None;
+    }
+    Some(base)
+}
+
+/// Switch to page tables that give every region of the memory map the access
+/// of its type, keep the firmware code read-only, and leave the page at 0 and
+/// the lowest page of the stack out.
+#[cfg(not(test))]
+pub fn initialize() {
+    use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+    use x86_64::registers::model_specific::Msr;
+    use x86_64::structures::paging::PhysFrame;
+    use x86_64::PhysAddr;
+
+    const IA32_EFER: u32 = 0xC000_0080;
+    const EFER_NXE: u64 = 1 << 11;
+
+    let (address_bits, nx, huge_pages) = cpu_features();
+    let address_bits = if huge_pages { address_bits } else { core::cmp::min(address_bits, MAX_ADDRESS_BITS_2M) };
+
+    let mut allocator = crate::efi::ALLOCATOR.lock();
+    let mut pages = TablePages(&mut *allocator);
+
+    // The memory map changes with the tables, so work on a copy of it
+    let count = pages.0.get_descriptor_count() + 4;
+    let snapshot_pages = (count * core::mem::size_of::<MemoryDescriptor>()) as u64 / PAGE_SIZE + 1;
+    let snapshot = match pages.allocate_pages(MemoryType::BootServicesData, snapshot_pages) {
+        Some(snapshot) => snapshot,
+        None => {
+            log!("paging - no memory for the memory map\n");
+            return;
+        }
+    };
+    let descriptors = unsafe { core::slice::from_raw_parts_mut(snapshot as *mut MemoryDescriptor, count) };
+    let count = pages.0.get_descriptors(descriptors);
+
+    let mut tables = match PageTables::new(&mut pages, 1u64 << address_bits, nx, huge_pages) {
+        Some(tables) => tables,
+        None => {
+            log!("paging - no memory for the page tables\n");
+            pages.free_pages(snapshot, snapshot_pages);
+            return;
+        }
+    };
+
+    for descriptor in &descriptors[..count] {
+        let access = memory_type_access(descriptor.r#type);
+        if access != Access::ReadWrite {
+            tables.set_access(&mut pages, descriptor.physical_start, descriptor.number_of_pages * PAGE_SIZE, access);
+        }
+    }
+    let stack_guard = stack_guard_page(&descriptors[..count]);
+    pages.free_pages(snapshot, snapshot_pages);
+
+    let (text, rodata, data, end) = unsafe {
+        (
+            &start_of_text as *const u8 as u64,
+            &end_of_text as *const u8 as u64,
+            &end_of_rodata as *const u8 as u64,
+            &end_of_bss as *const u8 as u64,
+        )
+    };
+    tables.set_access(&mut pages, text, rodata - text, Access::ReadExecute);
+    tables.set_access(&mut pages, rodata, data - rodata, Access::ReadOnly);
+    tables.set_access(&mut pages, data, end - data, Access::ReadWrite);
+
+    // NULL pointers fault
+    tables.set_access(&mut pages, 0, PAGE_SIZE, Access::NoAccess);
+
+    // So do stack overflows, instead of running into what lies below the stack
+    if let Some(stack_guard) = stack_guard {
+        if tables.set_access(&mut pages, stack_guard, PAGE_SIZE, Access::NoAccess) == Status::SUCCESS {
+            log!("paging - stack guard page at 0x{:x}\n", stack_guard);
+            unsafe { STACK_GUARD = stack_guard };
+        }
+    }
+
+    log!(
+        "paging - page tables at 0x{:x} for {} address bits, nx {}, 1GiB pages {}\n",
+        tables.root(), address_bits, nx, huge_pages
+    );
+
+    unsafe {
+        if nx {
+            let mut efer = Msr::new(IA32_EFER);
+            let value = efer.read();
+            efer.write(value | EFER_NXE);
+        }
+        let (_, flags) = Cr3::read();
+        Cr3::write(PhysFrame::containing_address(PhysAddr::new(tables.root())), flags);
+        let mut cr0 = Cr0::read();
+        cr0.insert(Cr0Flags::WRITE_PROTECT);
+        Cr0::write(cr0);
+        PAGE_TABLES = Some(tables);
+    }
+}
+
+/// Give pages the access of the memory type they now have
+#[cfg(not(test))]
+pub fn set_memory_type_access(allocator: &mut Allocator, address: u64, page_count: u64, memory_type: u32) {
+    if let Some(tables) = unsafe { PAGE_TABLES.as_mut() } {
+        let access = memory_type_access(memory_type);
+        let status = tables.set_access(&mut TablePages(allocator), address, page_count * PAGE_SIZE, access);
+        if status != Status::SUCCESS {
+            log!("paging - fail to set {:?} on 0x{:x} - {:?}\n", access, address, status);
+        }
+        flush();
+    }
+}
+
+/// Whether an address is in the guard page of the stack
+#[cfg(not(test))]
+pub fn is_stack_guard(address: u64) -> bool {
+    let stack_guard = unsafe { STACK_GUARD };
+    stack_guard != 0 && address >= stack_guard && address < stack_guard + PAGE_SIZE
+}
+
+#[cfg(not(test))]
+pub fn is_enabled() -> bool {
+    unsafe { PAGE_TABLES.is_some() }
+}
+
+/// Make pages fault on any access
+#[cfg(not(test))]
+pub fn set_no_access(allocator: &mut Allocator, address: u64, page_count: u64) {
+    if let Some(tables) = unsafe { PAGE_TABLES.as_mut() } {
+        tables.set_access(&mut TablePages(allocator), address, page_count * PAGE_SIZE, Access::NoAccess);
+        flush();
+    }
+}
+
+/// Make the sections of a loaded image W^X: code read-only, data not
+/// executable, the headers read-only.
+#[cfg(not(test))]
+pub fn protect_image(image_base: *mut c_void, image_size: usize) {
+    use crate::efi::peloader::{
+        peloader_for_each_section, IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_WRITE,
+    };
+
+    let tables = match unsafe { PAGE_TABLES.as_mut() } {
+        Some(tables) => tables,
+        None => return,
+    };
+    let mut allocator = crate::efi::ALLOCATOR.lock();
+    let mut pages = TablePages(&mut *allocator);
+
+    let base = image_base as u64;
+    let size = (image_size as u64 + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    let section_alignment = peloader_for_each_section(image_base, &mut |_| {});
+    if section_alignment as u64 % PAGE_SIZE != 0 {
+        log!("protect_image - section alignment 0x{:x} is smaller than a page, image 0x{:x} stays writable\n", section_alignment, base);
+        tables.set_access(&mut pages, base, size, Access::ReadWriteExecute);
+        flush();
+        return;
+    }
+
+    tables.set_access(&mut pages, base, size, Access::ReadOnly);
+    peloader_for_each_section(image_base, &mut |section| {
+        let start = base + section.virtual_address as u64;
+        let section_size = (section.virtual_size as u64 + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let characteristics = section.characteristics;
+        let access = match (characteristics & IMAGE_SCN_MEM_EXECUTE != 0, characteristics & IMAGE_SCN_MEM_WRITE != 0) {
+            (true, true) => {
+                log!("protect_image - section at 0x{:x} is writable code\n", start);
+                Access::ReadWriteExecute
+            }
+            (true, false) => Access::ReadExecute,
+            (false, true) => Access::ReadWrite,
+            (false, false) => Access::ReadOnly,
+        };
+        if start + section_size <= base + size {
+            tables.set_access(&mut pages, start, section_size, access);
+        }
+    });
+    flush();
+}
+
+/// Give the page at 0 back to the OS
+#[cfg(not(test))]
+pub fn remove_null_guard() {
+    if let Some(tables) = unsafe { PAGE_TABLES.as_mut() } {
+        tables.set_access(&mut TablePages(&mut *crate::efi::ALLOCATOR.lock()), 0, PAGE_SIZE, Access::ReadWrite);
+        flush();
+    }
+}
+
+/// Give the guard page of the stack back to the OS, which may use that memory
+/// before it leaves the firmware page tables
+#[cfg(not(test))]
+pub fn remove_stack_guard() {
+    if let Some(tables) = unsafe { PAGE_TABLES.as_mut() } {
+        let stack_guard = unsafe { STACK_GUARD };
+        if stack_guard != 0 {
+            tables.set_access(&mut TablePages(&mut *crate::efi::ALLOCATOR.lock()), stack_guard, PAGE_SIZE, Access::ReadWrite);
+            flush();
+            unsafe { STACK_GUARD = 0 };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use r_efi::efi::MemoryType;
+
+    use crate::efi::pool::TestPages;
+
+    #[test]
+    fn test_new() {
+        let mut pages = TestPages::new(16);
+        let tables = PageTables::new(&mut pages, 0x1_0000_0001, true, false).unwrap();
+        // The root, a PDPT and five directories of 2MiB pages
+        assert_eq!(pages.allocations.len(), 7);
+        assert_eq!(tables.access(0), Some(Access::ReadWrite));
+        assert_eq!(tables.access(0x1_3fff_ffff), Some(Access::ReadWrite));
+        assert_eq!(tables.access(0x1_4000_0000), None);
+
+        let mut pages = TestPages::new(16);
+        let tables = PageTables::new(&mut pages, 1 << 40, true, true).unwrap();
+        // The root and two PDPTs of 1GiB pages
+        assert_eq!(pages.allocations.len(), 3);
+        assert_eq!(tables.access(0xff_ffff_f000), Some(Access::ReadWrite));
+        assert_eq!(tables.access(1 << 40), None);
+    }
+
+    #[test]
+    fn test_set_access() {
+        let mut pages = TestPages::new(16);
+        let mut tables = PageTables::new(&mut pages, 0x1_0000_0000, true, true).unwrap();
+
+        // A whole 1GiB page takes no new table
+        assert_eq!(tables.set_access(&mut pages, 0x4000_0000, SIZE_1G, Access::ReadOnly), Status::SUCCESS);
+        assert_eq!(pages.allocations.len(), 2);
+        assert_eq!(tables.access(0x7fff_ffff), Some(Access::ReadOnly));
+        assert_eq!(tables.access(0x8000_0000), Some(Access::ReadWrite));
+
+        // The null guard splits down to 4KiB pages
+        assert_eq!(tables.set_access(&mut pages, 0, PAGE_SIZE, Access::NoAccess), Status::SUCCESS);
+        assert_eq!(pages.allocations.len(), 4);
+        assert_eq!(tables.access(0), Some(Access::NoAccess));
+        assert_eq!(tables.access(0xfff), Some(Access::NoAccess));
+        assert_eq!(tables.access(0x1000), Some(Access::ReadWrite));
+        assert_eq!(tables.access(0x20_0000), Some(Access::ReadWrite));
+
+        // Across a 2MiB boundary, the next 2MiB page is split too
+        assert_eq!(tables.set_access(&mut pages, 0x1f_f000, 0x3000, Access::ReadExecute), Status::SUCCESS);
+        assert_eq!(pages.allocations.len(), 5);
+        assert_eq!(tables.access(0x1f_e000), Some(Access::ReadWrite));
+        assert_eq!(tables.access(0x1f_f000), Some(Access::ReadExecute));
+        assert_eq!(tables.access(0x20_1000), Some(Access::ReadExecute));
+        assert_eq!(tables.access(0x20_2000), Some(Access::ReadWrite));
+
+        // Pages set back do not merge, and keep working
+        assert_eq!(tables.set_access(&mut pages, 0, 0x40_0000, Access::ReadWriteExecute), Status::SUCCESS);
+        assert_eq!(pages.allocations.len(), 5);
+        assert_eq!(tables.access(0), Some(Access::ReadWriteExecute));
+        assert_eq!(tables.access(0x3f_f000), Some(Access::ReadWriteExecute));
+
+        assert_eq!(tables.set_access(&mut pages, 0x800, PAGE_SIZE, Access::ReadOnly), Status::INVALID_PARAMETER);
+        assert_eq!(tables.set_access(&mut pages, 0xffff_f000, 0x2000, Access::ReadOnly), Status::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_split_keeps_caching() {
+        let mut pages = TestPages::new(16);
+        let mut tables = PageTables::new(&mut pages, SIZE_1G, true, false).unwrap();
+        let directory = table(table(tables.root)[0] & ADDRESS_MASK)[0] & ADDRESS_MASK;
+        table(directory)[1] |= PAT_LARGE | (1 << 4);
+
+        assert_eq!(tables.set_access(&mut pages, SIZE_2M, PAGE_SIZE, Access::ReadOnly), Status::SUCCESS);
+        let page_table = table(directory)[1] & ADDRESS_MASK;
+        assert_eq!(table(page_table)[0], SIZE_2M | PAT_4K | (1 << 4) | PRESENT | NO_EXECUTE);
+        assert_eq!(table(page_table)[1], (SIZE_2M + PAGE_SIZE) | PAT_4K | (1 << 4) | PRESENT | WRITABLE | NO_EXECUTE);
+    }
+
+    #[test]
+    fn test_no_nx() {
+        let mut pages = TestPages::new(16);
+        let mut tables = PageTables::new(&mut pages, SIZE_1G, false, true).unwrap();
+        assert_eq!(tables.access(0), Some(Access::ReadWriteExecute));
+        tables.set_access(&mut pages, 0, PAGE_SIZE, Access::ReadOnly);
+        assert_eq!(tables.access(0), Some(Access::ReadExecute));
+        assert_eq!(table(table(table(tables.root)[0] & ADDRESS_MASK)[0] & ADDRESS_MASK)[1] & NO_EXECUTE, 0);
+    }
+
+    #[test]
+    fn test_memory_type_access() {
+        assert_eq!(memory_type_access(MemoryType::LoaderCode as u32), Access::ReadWriteExecute);
+        assert_eq!(memory_type_access(MemoryType::LoaderData as u32), Access::ReadWrite);
+        assert_eq!(memory_type_access(MemoryType::BootServicesCode as u32), Access::ReadWrite);
+        assert_eq!(memory_type_access(MemoryType::RuntimeServicesCode as u32), Access::ReadWrite);
+        assert_eq!(memory_type_access(MemoryType::BootServicesData as u32), Access::ReadWrite);
+        assert_eq!(memory_type_access(MemoryType::ConventionalMemory as u32), Access::ReadWrite);
+    }
+}
@@ -67,6 +67,10 @@ pub struct ImageFileHeader {
     pub characteristics: u16,
 }
 
+pub const IMAGE_SUBSYSTEM_EFI_APPLICATION:          u16 = 10;
+pub const IMAGE_SUBSYSTEM_EFI_BOOT_SERVICE_DRIVER:  u16 = 11;
+pub const IMAGE_SUBSYSTEM_EFI_RUNTIME_DRIVER:       u16 = 12;
+
 pub const IMAGE_NUMBER_OF_DIRECTORY_ENTRIES: usize = 16;
 
 pub const IMAGE_DIRECTORY_ENTRY_EXPORT:      usize = 0;
@@ -207,6 +211,10 @@ pub struct ImageSectionHeader {
     pub characteristics: u32,
 }
 
+pub const IMAGE_SCN_MEM_EXECUTE: u32 = 0x20000000;
+pub const IMAGE_SCN_MEM_READ: u32    = 0x40000000;
+pub const IMAGE_SCN_MEM_WRITE: u32   = 0x80000000;
+
 pub const IMAGE_SIZEOF_RELOCATION: usize = 10;
 
 pub const IMAGE_REL_I386_ABSOLUTE: u16 = 0x0000;
@@ -450,6 +458,32 @@ pub fn peloader_load_image (
     (dest_buffer as usize + nt_header.optional_header.address_of_entry_point as usize)
 }
 
+// The headers are part of the loaded image, so this works on image_base.
+pub fn peloader_get_subsystem (
+    image_base: *mut c_void,
+    ) -> u16 {
+    let dos_header = unsafe {transmute::<*mut c_void, &mut ImageDosHeader>(image_base)};
+    let nt_header = unsafe {transmute::<usize, &mut ImageNtHeader64>(image_base as usize + dos_header.e_lfanew as usize)};
+    nt_header.optional_header.subsystem
+}
+
+// Give the section alignment of a loaded image, and each of its section headers
+pub fn peloader_for_each_section (
+    image_base: *mut c_void,
+    f: &mut dyn FnMut(&ImageSectionHeader),
+    ) -> u32 {
+    let dos_header = unsafe {transmute::<*mut c_void, &mut ImageDosHeader>(image_base)};
+    let nt_header_offset = image_base as usize + dos_header.e_lfanew as usize;
+    let nt_header = unsafe {transmute::<usize, &mut ImageNtHeader64>(nt_header_offset)};
+    let first_section_offset = nt_header_offset + offset_of!(ImageNtHeader64, optional_header) as usize + nt_header.file_header.size_of_optional_header as usize;
+    for section_index in 0..nt_header.file_header.number_of_sections {
+      let section = unsafe {transmute::<usize, &ImageSectionHeader>(
+                              first_section_offset + section_index as usize * size_of::<ImageSectionHeader>())};
+      f(section);
+    }
+    nt_header.optional_header.section_alignment
+}
+
 pub fn pe_dumper(
   buffer: *mut c_void, size: usize){
     pe_dumper_header(buffer);
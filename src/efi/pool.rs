@@ -0,0 +1,371 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use r_efi::efi::{AllocateType, MemoryType, Status};
+
+use super::alloc::Allocator;
+
+const PAGE_SIZE: u64 = 4096;
+
+// Every block starts with a header, the data after it is 16 bytes aligned
+const HEADER_SIZE: u64 = 16;
+const POOL_SIGNATURE: u32 = 0x6c6f_6f70; // 'p','o','o','l'
+const FREE_SIGNATURE: u32 = 0x6565_7266; // 'f','r','e','e'
+
+// Blocks of 32 bytes to 2KiB, header included. Larger requests get pages of
+// their own.
+const BUCKET_COUNT: usize = 7;
+const MIN_BLOCK_SIZE: u64 = 32;
+const MAX_BLOCK_SIZE: u64 = MIN_BLOCK_SIZE << (BUCKET_COUNT - 1);
+
+const MEMORY_TYPE_COUNT: usize = MemoryType::PersistentMemory as usize + 1;
+
+/// Where a pool gets its pages from
+pub trait PageAllocator {
+    fn allocate_pages(&mut self, memory_type: MemoryType, page_count: u64) -> Option<u64>;
+    fn free_pages(&mut self, address: u64, page_count: u64);
+    /// The type of the memory an address is in, if it is allocated
+    fn memory_type(&self, address: u64) -> Option<u32>;
+}
+
+impl PageAllocator for Allocator {
+    fn allocate_pages(&mut self, memory_type: MemoryType, page_count: u64) -> Option<u64> {
+        match Allocator::allocate_pages(self, AllocateType::AllocateAnyPages, memory_type, page_count, 0) {
+            (Status::SUCCESS, address) => {
+                #[cfg(not(test))]
+                super::paging::set_memory_type_access(self, address, page_count, memory_type as u32);
+                Some(address)
+            }
+            _ => None,
+        }
+    }
+
+    fn free_pages(&mut self, address: u64, page_count: u64) {
+        if Allocator::free_pages(self, address, page_count) == Status::SUCCESS {
+            #[cfg(not(test))]
+            super::paging::set_memory_type_access(self, address, page_count, MemoryType::ConventionalMemory as u32);
+        }
+    }
+
+    fn memory_type(&self, address: u64) -> Option<u32> {
+        match self.get_memory_type(address) {
+            Some(memory_type) if memory_type != MemoryType::ConventionalMemory as u32 => Some(memory_type),
+            _ => None,
+        }
+    }
+}
+
+#[repr(C)]
+struct Header {
+    signature: u32,
+    memory_type: u32,
+    // The size of the block, header included, or of the pages of a large one
+    size: u64,
+}
+
+fn header(address: u64) -> &'static mut Header {
+    unsafe { &mut *(address as *mut Header) }
+}
+
+// A free block links to the next free one of its size after its header
+fn next_free(address: u64) -> &'static mut u64 {
+    unsafe { &mut *((address + HEADER_SIZE) as *mut u64) }
+}
+
+// Whether none of the blocks of a page is in use
+fn is_free_page(page: u64, block_size: u64) -> bool {
+    (0 .. PAGE_SIZE / block_size).all(|index| header(page + index * block_size).signature == FREE_SIGNATURE)
+}
+
+fn bucket(size: u64) -> Option<usize> {
+    (0 .. BUCKET_COUNT).find(|bucket| size <= MIN_BLOCK_SIZE << bucket)
+}
+
+fn is_pool_type(memory_type: MemoryType) -> bool {
+    match memory_type {
+        MemoryType::ConventionalMemory | MemoryType::PersistentMemory => false,
+        _ => true,
+    }
+}
+
+/// AllocatePool and FreePool. Small allocations are blocks of a few sizes,
+/// carved out of pages of their memory type and kept for the same size once
+/// freed, until all the blocks of their page are free and it is given back.
+/// The headers let FreePool check it is given a pool allocation.
+pub struct Pool {
+    // The first free block of every size and memory type, 0 for none
+    free: [[u64; BUCKET_COUNT]; MEMORY_TYPE_COUNT],
+}
+
+impl Pool {
+    pub fn new() -> Pool {
+        Pool {
+            free: [[0; BUCKET_COUNT]; MEMORY_TYPE_COUNT],
+        }
+    }
+
+    // Split a new page into free blocks of a bucket
+    fn refill<A: PageAllocator>(&mut self, pages: &mut A, memory_type: MemoryType, bucket: usize) -> bool {
+        let page = match pages.allocate_pages(memory_type, 1) {
+            Some(page) => page,
+            None => return false,
+        };
+        let block_size = MIN_BLOCK_SIZE << bucket;
+        for index in (0 .. PAGE_SIZE / block_size).rev() {
+            let block = page + index * block_size;
+            *header(block) = Header {
+                signature: FREE_SIGNATURE,
+                memory_type: memory_type as u32,
+                size: block_size,
+            };
+            *next_free(block) = self.free[memory_type as usize][bucket];
+            self.free[memory_type as usize][bucket] = block;
+        }
+        true
+    }
+
+    pub fn allocate<A: PageAllocator>(&mut self, pages: &mut A, memory_type: MemoryType, size: usize) -> (Status, u64) {
+        if !is_pool_type(memory_type) {
+            return (Status::INVALID_PARAMETER, 0);
+        }
+        let size = match (size as u64).checked_add(HEADER_SIZE) {
+            Some(size) => size,
+            None => return (Status::OUT_OF_RESOURCES, 0),
+        };
+
+        let block = match bucket(size) {
+            Some(bucket) => {
+                if self.free[memory_type as usize][bucket] == 0 && !self.refill(pages, memory_type, bucket) {
+                    log!("{}:{} out of resource\n", file!(), line!());
+                    return (Status::OUT_OF_RESOURCES, 0);
+                }
+                let block = self.free[memory_type as usize][bucket];
+                self.free[memory_type as usize][bucket] = *next_free(block);
+                block
+            },
+            None => {
+                let page_count = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+                match pages.allocate_pages(memory_type, page_count) {
+                    Some(address) => {
+                        header(address).size = page_count * PAGE_SIZE;
+                        address
+                    },
+                    None => {
+                        log!("{}:{} out of resource\n", file!(), line!());
+                        return (Status::OUT_OF_RESOURCES, 0);
+                    },
+                }
+            },
+        };
+
+        let header = header(block);
+        header.signature = POOL_SIGNATURE;
+        header.memory_type = memory_type as u32;
+        (Status::SUCCESS, block + HEADER_SIZE)
+    }
+
+    pub fn free<A: PageAllocator>(&mut self, pages: &mut A, address: u64) -> Status {
+        if address < HEADER_SIZE || address % HEADER_SIZE != 0 {
+            return Status::INVALID_PARAMETER;
+        }
+        // The header is only read once it is known to be in allocated memory
+        let block = address - HEADER_SIZE;
+        let memory_type = match pages.memory_type(block) {
+            Some(memory_type) => memory_type,
+            None => {
+                log!("free_pool: 0x{:x} is not allocated\n", address);
+                return Status::INVALID_PARAMETER;
+            },
+        };
+        let header = header(block);
+        if header.signature == FREE_SIGNATURE && header.memory_type == memory_type {
+            log!("free_pool: 0x{:x} is already free\n", address);
+            return Status::INVALID_PARAMETER;
+        }
+        if header.signature != POOL_SIGNATURE || header.memory_type != memory_type {
+            log!("free_pool: 0x{:x} is not a pool allocation\n", address);
+            return Status::INVALID_PARAMETER;
+        }
+
+        let size = header.size;
+        if size > MAX_BLOCK_SIZE {
+            if block % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+                return Status::INVALID_PARAMETER;
+            }
+            header.signature = FREE_SIGNATURE;
+            pages.free_pages(block, size / PAGE_SIZE);
+            return Status::SUCCESS;
+        }
+
+        let bucket = match bucket(size) {
+            Some(bucket) if size == MIN_BLOCK_SIZE << bucket && block % size == 0 => bucket,
+            _ => return Status::INVALID_PARAMETER,
+        };
+        header.signature = FREE_SIGNATURE;
+        *next_free(block) = self.free[memory_type as usize][bucket];
+        self.free[memory_type as usize][bucket] = block;
+
+        // Give the page back once none of its blocks is in use
+        let page = block - block % PAGE_SIZE;
+        if is_free_page(page, size) {
+            let mut link: &mut u64 = &mut self.free[memory_type as usize][bucket];
+            while *link != 0 {
+                if *link - *link % PAGE_SIZE == page {
+                    *link = *next_free(*link);
+                } else {
+                    link = next_free(*link);
+                }
+            }
+            pages.free_pages(page, 1);
+        }
+        Status::SUCCESS
+    }
+}
+
+/// Pages out of a buffer for the tests, remembering what they were allocated for
+#[cfg(test)]
+pub struct TestPages {
+    pub memory: Vec<u64>,
+    pub next: u64,
+    pub allocations: Vec<(u64, u64, u32)>,
+}
+
+#[cfg(test)]
+impl TestPages {
+    pub fn new(page_count: u64) -> TestPages {
+        let memory = vec![0u64; ((page_count + 1) * PAGE_SIZE / 8) as usize];
+        let next = (memory.as_ptr() as u64 + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        TestPages { memory, next, allocations: Vec::new() }
+    }
+}
+
+#[cfg(test)]
+impl PageAllocator for TestPages {
+    fn allocate_pages(&mut self, memory_type: MemoryType, page_count: u64) -> Option<u64> {
+        let end = self.memory.as_ptr() as u64 + 8 * self.memory.len() as u64;
+        if self.next + page_count * PAGE_SIZE > end {
+            return None;
+        }
+        let address = self.next;
+        self.next += page_count * PAGE_SIZE;
+        self.allocations.push((address, page_count, memory_type as u32));
+        Some(address)
+    }
+
+    fn free_pages(&mut self, address: u64, page_count: u64) {
+        let index = self.allocations.iter().position(|a| a.0 == address).unwrap();
+        assert_eq!(self.allocations.remove(index).1, page_count);
+    }
+
+    fn memory_type(&self, address: u64) -> Option<u32> {
+        self.allocations.iter()
+            .find(|(start, page_count, _)| address >= *start && address < start + page_count * PAGE_SIZE)
+            .map(|allocation| allocation.2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate() {
+        let mut pages = TestPages::new(16);
+        let mut pool = Pool::new();
+
+        // Small allocations share a page of their type
+        let (status, a) = pool.allocate(&mut pages, MemoryType::BootServicesData, 24);
+        assert_eq!(status, Status::SUCCESS);
+        let (_, b) = pool.allocate(&mut pages, MemoryType::BootServicesData, 40);
+        let (_, c) = pool.allocate(&mut pages, MemoryType::BootServicesData, 200);
+        let (_, d) = pool.allocate(&mut pages, MemoryType::RuntimeServicesData, 16);
+        assert_eq!(pages.allocations.len(), 3);
+        assert_eq!(a % 16, 0);
+        assert_eq!(a / PAGE_SIZE, b / PAGE_SIZE);
+        assert_ne!(a / PAGE_SIZE, c / PAGE_SIZE);
+        assert_eq!(pages.memory_type(d), Some(MemoryType::RuntimeServicesData as u32));
+        unsafe { core::ptr::write_bytes(a as *mut u8, 0xff, 24) };
+        assert_eq!(header(b - HEADER_SIZE).signature, POOL_SIGNATURE);
+
+        // Freed blocks are used again
+        assert_eq!(pool.free(&mut pages, a), Status::SUCCESS);
+        assert_eq!(pool.allocate(&mut pages, MemoryType::BootServicesData, 20), (Status::SUCCESS, a));
+
+        // Large allocations get their own pages, given back when freed
+        let (status, e) = pool.allocate(&mut pages, MemoryType::LoaderData, 5000);
+        assert_eq!(status, Status::SUCCESS);
+        assert_eq!(pages.allocations.len(), 4);
+        assert_eq!(pages.allocations[3].1, 2);
+        assert_eq!(pool.free(&mut pages, e), Status::SUCCESS);
+        assert_eq!(pages.allocations.len(), 3);
+
+        assert_eq!(pool.allocate(&mut pages, MemoryType::ConventionalMemory, 16).0, Status::INVALID_PARAMETER);
+        assert_eq!(pool.allocate(&mut pages, MemoryType::LoaderData, 1 << 20).0, Status::OUT_OF_RESOURCES);
+    }
+
+    #[test]
+    fn test_free() {
+        let mut pages = TestPages::new(4);
+        let mut pool = Pool::new();
+
+        let (_, a) = pool.allocate(&mut pages, MemoryType::BootServicesData, 100);
+        let (_, b) = pool.allocate(&mut pages, MemoryType::BootServicesData, 100);
+        let other = pages.allocate_pages(MemoryType::BootServicesData, 1).unwrap();
+
+        // Not pool allocations
+        assert_eq!(pool.free(&mut pages, 0), Status::INVALID_PARAMETER);
+        assert_eq!(pool.free(&mut pages, a + 8), Status::INVALID_PARAMETER);
+        assert_eq!(pool.free(&mut pages, a + 16), Status::INVALID_PARAMETER);
+        assert_eq!(pool.free(&mut pages, other + HEADER_SIZE), Status::INVALID_PARAMETER);
+        let unallocated = pages.next;
+        assert_eq!(pool.free(&mut pages, unallocated + HEADER_SIZE), Status::INVALID_PARAMETER);
+
+        // Double free
+        assert_eq!(pool.free(&mut pages, a), Status::SUCCESS);
+        assert_eq!(pool.free(&mut pages, a), Status::INVALID_PARAMETER);
+        assert_eq!(pool.free(&mut pages, b), Status::SUCCESS);
+        assert_eq!(pool.free(&mut pages, b), Status::INVALID_PARAMETER);
+    }
+
+    #[test]
+    fn test_free_pages() {
+        let mut pages = TestPages::new(4);
+        let mut pool = Pool::new();
+
+        // Two blocks of 2KiB per page
+        let size = (MAX_BLOCK_SIZE - HEADER_SIZE) as usize;
+        let (_, a) = pool.allocate(&mut pages, MemoryType::BootServicesData, size);
+        let (_, b) = pool.allocate(&mut pages, MemoryType::BootServicesData, size);
+        let (_, c) = pool.allocate(&mut pages, MemoryType::BootServicesData, size);
+        assert_eq!(pages.allocations.len(), 2);
+        assert_eq!(a / PAGE_SIZE, b / PAGE_SIZE);
+
+        // The page of c is free once c is, and its other block is not used anymore
+        assert_eq!(pool.free(&mut pages, c), Status::SUCCESS);
+        assert_eq!(pages.allocations.len(), 1);
+        assert_eq!(pool.free(&mut pages, a), Status::SUCCESS);
+        assert_eq!(pages.allocations.len(), 1);
+        assert_eq!(pool.allocate(&mut pages, MemoryType::BootServicesData, size), (Status::SUCCESS, a));
+        let (_, d) = pool.allocate(&mut pages, MemoryType::BootServicesData, size);
+        assert_eq!(pages.allocations.len(), 2);
+        assert_ne!(d / PAGE_SIZE, a / PAGE_SIZE);
+
+        assert_eq!(pool.free(&mut pages, a), Status::SUCCESS);
+        assert_eq!(pool.free(&mut pages, b), Status::SUCCESS);
+        assert_eq!(pool.free(&mut pages, d), Status::SUCCESS);
+        assert!(pages.allocations.is_empty());
+    }
+}
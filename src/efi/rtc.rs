@@ -0,0 +1,520 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use r_efi::efi;
+use r_efi::efi::{Status, Time, TimeCapabilities};
+
+use cpuio::Port;
+
+const RTC_INDEX_PORT: u16 = 0x70;
+const RTC_DATA_PORT: u16 = 0x71;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_SECONDS_ALARM: u8 = 0x01;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_MINUTES_ALARM: u8 = 0x03;
+const RTC_HOURS: u8 = 0x04;
+const RTC_HOURS_ALARM: u8 = 0x05;
+const RTC_DAY_OF_MONTH: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_REGISTER_A: u8 = 0x0a;
+const RTC_REGISTER_B: u8 = 0x0b;
+const RTC_REGISTER_C: u8 = 0x0c;
+// The century and date alarm registers are wherever the FADT says
+
+const RTC_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+const RTC_B_SET: u8 = 0x80;
+const RTC_B_ALARM_INTERRUPT_ENABLE: u8 = 0x20;
+const RTC_B_BINARY: u8 = 0x04;
+const RTC_B_24_HOUR: u8 = 0x02;
+const RTC_C_ALARM_FLAG: u8 = 0x20;
+const RTC_HOUR_PM: u8 = 0x80;
+
+// An update cycle takes at most 2ms, poll for 10ms to be safe.
+const RTC_UPDATE_TIMEOUT_US: u64 = 10_000;
+const RTC_UPDATE_POLL_US: u64 = 10;
+
+// The RTC counts seconds, with the usual 50ppm crystal.
+const RTC_RESOLUTION: u32 = 1;
+const RTC_ACCURACY: u32 = 50_000_000;
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+fn binary_to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn is_leap_year(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        _ => 0,
+    }
+}
+
+fn next_day(year: u16, month: u8, day: u8) -> (u16, u8, u8) {
+    if day < days_in_month(year, month) {
+        (year, month, day + 1)
+    } else if month < 12 {
+        (year, month + 1, 1)
+    } else {
+        (year + 1, 1, 1)
+    }
+}
+
+fn time_of_day(time: &Time) -> (u8, u8, u8) {
+    (time.hour, time.minute, time.second)
+}
+
+/// Whether a time is after another by at most a day, as the alarm matches
+/// the time of day and at best the day of the month.
+pub fn is_within_one_day(from: &Time, to: &Time) -> bool {
+    let date = |time: &Time| (time.year, time.month, time.day);
+    if date(to) == date(from) {
+        time_of_day(to) > time_of_day(from)
+    } else {
+        date(to) == next_day(from.year, from.month, from.day) && time_of_day(to) <= time_of_day(from)
+    }
+}
+
+// The date of an alarm at most a day after now, from the day of month alarm
+// register if there is one, else from its time of day.
+fn wakeup_date(now: &Time, alarm_day: Option<u8>, alarm: (u8, u8, u8)) -> (u16, u8, u8) {
+    let today = (now.year, now.month, now.day);
+    let tomorrow = next_day(now.year, now.month, now.day);
+    match alarm_day {
+        Some(day) if day == today.2 => today,
+        Some(day) if day == tomorrow.2 => tomorrow,
+        _ if alarm > time_of_day(now) => today,
+        _ => tomorrow,
+    }
+}
+
+/// Check the fields of a Time as required by SetTime and SetWakeupTime.
+pub fn is_valid_time(time: &Time) -> bool {
+    if time.year < 1900 || time.year > 9999 {
+        return false;
+    }
+    if time.month < 1 || time.month > 12 {
+        return false;
+    }
+    if time.day < 1 || time.day > days_in_month(time.year, time.month) {
+        return false;
+    }
+    if time.hour > 23 || time.minute > 59 || time.second > 59 || time.nanosecond > 999_999_999 {
+        return false;
+    }
+    if time.timezone != efi::UNSPECIFIED_TIMEZONE && (time.timezone < -1440 || time.timezone > 1440) {
+        return false;
+    }
+    if (time.daylight & !(efi::TIME_ADJUST_DAYLIGHT | efi::TIME_IN_DAYLIGHT)) != 0 {
+        return false;
+    }
+    true
+}
+
+fn empty_time() -> Time {
+    Time {
+        year: 0,
+        month: 0,
+        day: 0,
+        hour: 0,
+        minute: 0,
+        second: 0,
+        pad1: 0,
+        nanosecond: 0,
+        timezone: efi::UNSPECIFIED_TIMEZONE,
+        daylight: 0,
+        pad2: 0,
+    }
+}
+
+pub struct Rtc {
+    index_port: Port<u8>,
+    data_port: Port<u8>,
+    // The RTC keeps neither the time zone nor the daylight flags. They are
+    // remembered here.
+    timezone: i16,
+    daylight: u8,
+}
+
+impl Rtc {
+    fn read_register(&mut self, index: u8) -> u8 {
+        self.index_port.write(index);
+        self.data_port.read()
+    }
+
+    fn write_register(&mut self, index: u8, value: u8) {
+        self.index_port.write(index);
+        self.data_port.write(value);
+    }
+
+    #[cfg(not(test))]
+    fn wait_for_update(&mut self) -> Status {
+        let mut elapsed = 0;
+        while (self.read_register(RTC_REGISTER_A) & RTC_A_UPDATE_IN_PROGRESS) != 0 {
+            if elapsed >= RTC_UPDATE_TIMEOUT_US {
+                return Status::DEVICE_ERROR;
+            }
+            crate::timer::stall(RTC_UPDATE_POLL_US);
+            elapsed += RTC_UPDATE_POLL_US;
+        }
+        Status::SUCCESS
+    }
+
+    // Decode a register according to the BCD/binary mode.
+    fn decode(&self, register_b: u8, value: u8) -> u8 {
+        if (register_b & RTC_B_BINARY) != 0 {
+            value
+        } else {
+            bcd_to_binary(value)
+        }
+    }
+
+    fn encode(&self, register_b: u8, value: u8) -> u8 {
+        if (register_b & RTC_B_BINARY) != 0 {
+            value
+        } else {
+            binary_to_bcd(value)
+        }
+    }
+
+    // Decode an hour register according to the 12/24-hour mode.
+    fn decode_hour(&self, register_b: u8, value: u8) -> u8 {
+        if (register_b & RTC_B_24_HOUR) != 0 {
+            return self.decode(register_b, value);
+        }
+        let hour = self.decode(register_b, value & !RTC_HOUR_PM) % 12;
+        if (value & RTC_HOUR_PM) != 0 {
+            hour + 12
+        } else {
+            hour
+        }
+    }
+
+    fn encode_hour(&self, register_b: u8, hour: u8) -> u8 {
+        if (register_b & RTC_B_24_HOUR) != 0 {
+            return self.encode(register_b, hour);
+        }
+        let hour_12 = if hour % 12 == 0 { 12 } else { hour % 12 };
+        let value = self.encode(register_b, hour_12);
+        if hour >= 12 {
+            value | RTC_HOUR_PM
+        } else {
+            value
+        }
+    }
+
+    #[cfg(not(test))]
+    pub fn get_time(&mut self, time: &mut Time, capabilities: Option<&mut TimeCapabilities>) -> Status {
+        let status = self.wait_for_update();
+        if status != Status::SUCCESS {
+            return status;
+        }
+
+        let register_b = self.read_register(RTC_REGISTER_B);
+        let second = self.read_register(RTC_SECONDS);
+        let minute = self.read_register(RTC_MINUTES);
+        let hour = self.read_register(RTC_HOURS);
+        let day = self.read_register(RTC_DAY_OF_MONTH);
+        let month = self.read_register(RTC_MONTH);
+        let year = self.read_register(RTC_YEAR);
+        let century_register = crate::acpi::rtc_century();
+        let century = if century_register != 0 { self.read_register(century_register) } else { 0 };
+
+        time.second = self.decode(register_b, second);
+        time.minute = self.decode(register_b, minute);
+        time.hour = self.decode_hour(register_b, hour);
+        time.day = self.decode(register_b, day);
+        time.month = self.decode(register_b, month);
+        let century = self.decode(register_b, century);
+        let year = self.decode(register_b, year) as u16;
+        // Without a sane century register, assume the 21st century
+        time.year = if century >= 19 && century <= 99 {
+            century as u16 * 100 + year
+        } else {
+            2000 + year
+        };
+        time.pad1 = 0;
+        time.nanosecond = 0;
+        time.timezone = self.timezone;
+        time.daylight = self.daylight;
+        time.pad2 = 0;
+
+        if let Some(capabilities) = capabilities {
+            capabilities.resolution = RTC_RESOLUTION;
+            capabilities.accuracy = RTC_ACCURACY;
+            capabilities.sets_to_zero = efi::Boolean::FALSE;
+        }
+
+        if !is_valid_time(time) {
+            return Status::DEVICE_ERROR;
+        }
+        Status::SUCCESS
+    }
+
+    #[cfg(not(test))]
+    pub fn set_time(&mut self, time: &Time) -> Status {
+        if !is_valid_time(time) {
+            return Status::INVALID_PARAMETER;
+        }
+
+        let status = self.wait_for_update();
+        if status != Status::SUCCESS {
+            return status;
+        }
+
+        // Stop the update cycle while the registers are written
+        let register_b = self.read_register(RTC_REGISTER_B);
+        self.write_register(RTC_REGISTER_B, register_b | RTC_B_SET);
+
+        let value = self.encode(register_b, time.second);
+        self.write_register(RTC_SECONDS, value);
+        let value = self.encode(register_b, time.minute);
+        self.write_register(RTC_MINUTES, value);
+        let value = self.encode_hour(register_b, time.hour);
+        self.write_register(RTC_HOURS, value);
+        let value = self.encode(register_b, time.day);
+        self.write_register(RTC_DAY_OF_MONTH, value);
+        let value = self.encode(register_b, time.month);
+        self.write_register(RTC_MONTH, value);
+        let value = self.encode(register_b, (time.year % 100) as u8);
+        self.write_register(RTC_YEAR, value);
+        let century_register = crate::acpi::rtc_century();
+        if century_register != 0 {
+            let value = self.encode(register_b, (time.year / 100) as u8);
+            self.write_register(century_register, value);
+        }
+
+        self.write_register(RTC_REGISTER_B, register_b & !RTC_B_SET);
+
+        self.timezone = time.timezone;
+        self.daylight = time.daylight;
+        Status::SUCCESS
+    }
+
+    #[cfg(not(test))]
+    pub fn get_wakeup_time(&mut self, time: &mut Time) -> (Status, bool, bool) {
+        // The alarm is at most a day ahead, its date follows from the current one
+        let mut now = empty_time();
+        let status = self.get_time(&mut now, None);
+        if status != Status::SUCCESS {
+            return (status, false, false);
+        }
+
+        let register_b = self.read_register(RTC_REGISTER_B);
+        let enabled = (register_b & RTC_B_ALARM_INTERRUPT_ENABLE) != 0;
+        // Reading register C acknowledges the flags, like EDKII does
+        let pending = (self.read_register(RTC_REGISTER_C) & RTC_C_ALARM_FLAG) != 0;
+
+        let second = self.read_register(RTC_SECONDS_ALARM);
+        let minute = self.read_register(RTC_MINUTES_ALARM);
+        let hour = self.read_register(RTC_HOURS_ALARM);
+        let day_alarm = crate::acpi::rtc_day_alarm();
+        let alarm_day = if day_alarm != 0 {
+            let day = self.read_register(day_alarm);
+            Some(self.decode(register_b, day))
+        } else {
+            None
+        };
+
+        *time = empty_time();
+        time.second = self.decode(register_b, second);
+        time.minute = self.decode(register_b, minute);
+        time.hour = self.decode_hour(register_b, hour);
+        let (year, month, day) = wakeup_date(&now, alarm_day, time_of_day(time));
+        time.year = year;
+        time.month = month;
+        time.day = day;
+        time.timezone = self.timezone;
+        time.daylight = self.daylight;
+
+        (Status::SUCCESS, enabled, pending)
+    }
+
+    #[cfg(not(test))]
+    pub fn set_wakeup_time(&mut self, enable: bool, time: Option<&Time>) -> Status {
+        if enable {
+            match time {
+                Some(time) if is_valid_time(time) => {},
+                _ => { return Status::INVALID_PARAMETER; },
+            }
+        }
+
+        // The alarm matches the time of day, and the day of the month when
+        // there is a register for it, so it can be at most a day ahead
+        if let Some(time) = time.filter(|_| enable) {
+            let mut now = empty_time();
+            let status = self.get_time(&mut now, None);
+            if status != Status::SUCCESS {
+                return status;
+            }
+            if !is_within_one_day(&now, time) {
+                return Status::INVALID_PARAMETER;
+            }
+        }
+
+        let status = self.wait_for_update();
+        if status != Status::SUCCESS {
+            return status;
+        }
+
+        let register_b = self.read_register(RTC_REGISTER_B);
+        if !enable {
+            self.write_register(RTC_REGISTER_B, register_b & !RTC_B_ALARM_INTERRUPT_ENABLE);
+            return Status::SUCCESS;
+        }
+
+        let time = time.unwrap();
+        self.write_register(RTC_REGISTER_B, (register_b | RTC_B_SET) & !RTC_B_ALARM_INTERRUPT_ENABLE);
+        let value = self.encode(register_b, time.second);
+        self.write_register(RTC_SECONDS_ALARM, value);
+        let value = self.encode(register_b, time.minute);
+        self.write_register(RTC_MINUTES_ALARM, value);
+        let value = self.encode_hour(register_b, time.hour);
+        self.write_register(RTC_HOURS_ALARM, value);
+        let day_alarm = crate::acpi::rtc_day_alarm();
+        if day_alarm != 0 {
+            let value = self.encode(register_b, time.day);
+            self.write_register(day_alarm, value);
+        }
+        let month_alarm = crate::acpi::rtc_month_alarm();
+        if month_alarm != 0 {
+            let value = self.encode(register_b, time.month);
+            self.write_register(month_alarm, value);
+        }
+        // Clear a stale alarm flag before arming the alarm
+        self.read_register(RTC_REGISTER_C);
+        self.write_register(RTC_REGISTER_B, (register_b & !RTC_B_SET) | RTC_B_ALARM_INTERRUPT_ENABLE);
+        Status::SUCCESS
+    }
+
+    pub fn new() -> Rtc {
+        Rtc {
+            index_port: unsafe { Port::new(RTC_INDEX_PORT) },
+            data_port: unsafe { Port::new(RTC_DATA_PORT) },
+            timezone: efi::UNSPECIFIED_TIMEZONE,
+            daylight: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{binary_to_bcd, bcd_to_binary, days_in_month, empty_time, is_valid_time, is_within_one_day, wakeup_date, Rtc};
+    use super::{RTC_B_24_HOUR, RTC_B_BINARY, RTC_HOUR_PM};
+    use r_efi::efi;
+
+    #[test]
+    fn test_bcd() {
+        assert_eq!(bcd_to_binary(0x59), 59);
+        assert_eq!(binary_to_bcd(59), 0x59);
+        for value in 0..100 {
+            assert_eq!(bcd_to_binary(binary_to_bcd(value)), value);
+        }
+    }
+
+    #[test]
+    fn test_hour_modes() {
+        let rtc = Rtc::new();
+
+        // 12-hour BCD: 12 AM is midnight, 12 PM is noon
+        assert_eq!(rtc.decode_hour(0, 0x12), 0);
+        assert_eq!(rtc.decode_hour(0, RTC_HOUR_PM | 0x12), 12);
+        assert_eq!(rtc.decode_hour(0, RTC_HOUR_PM | 0x11), 23);
+        for hour in 0..24 {
+            assert_eq!(rtc.decode_hour(0, rtc.encode_hour(0, hour)), hour);
+            assert_eq!(rtc.decode_hour(RTC_B_24_HOUR, rtc.encode_hour(RTC_B_24_HOUR, hour)), hour);
+            assert_eq!(rtc.decode_hour(RTC_B_BINARY, rtc.encode_hour(RTC_B_BINARY, hour)), hour);
+        }
+        assert_eq!(rtc.encode_hour(RTC_B_24_HOUR | RTC_B_BINARY, 23), 23);
+        assert_eq!(rtc.encode_hour(RTC_B_24_HOUR, 23), 0x23);
+    }
+
+    #[test]
+    fn test_valid_time() {
+        assert_eq!(days_in_month(2000, 2), 29);
+        assert_eq!(days_in_month(1900, 2), 28);
+        assert_eq!(days_in_month(2024, 2), 29);
+
+        let mut time = empty_time();
+        time.year = 2020;
+        time.month = 2;
+        time.day = 29;
+        time.hour = 23;
+        time.minute = 59;
+        time.second = 59;
+        assert!(is_valid_time(&time));
+
+        time.day = 30;
+        assert!(!is_valid_time(&time));
+        time.day = 1;
+        time.timezone = 1441;
+        assert!(!is_valid_time(&time));
+        time.timezone = -60;
+        assert!(is_valid_time(&time));
+        time.daylight = 0x04;
+        assert!(!is_valid_time(&time));
+        time.daylight = efi::TIME_ADJUST_DAYLIGHT;
+        time.year = 1899;
+        assert!(!is_valid_time(&time));
+    }
+
+    #[test]
+    fn test_wakeup_within_one_day() {
+        let mut now = empty_time();
+        now.year = 2020;
+        now.month = 12;
+        now.day = 31;
+        now.hour = 12;
+
+        let mut wakeup = empty_time();
+        wakeup.year = 2020;
+        wakeup.month = 12;
+        wakeup.day = 31;
+        wakeup.hour = 13;
+        assert!(is_within_one_day(&now, &wakeup));
+        wakeup.hour = 11;
+        assert!(!is_within_one_day(&now, &wakeup));
+        wakeup.hour = 12;
+        assert!(!is_within_one_day(&now, &wakeup));
+
+        // Across the end of the year, up to the same time
+        wakeup.year = 2021;
+        wakeup.month = 1;
+        wakeup.day = 1;
+        assert!(is_within_one_day(&now, &wakeup));
+        wakeup.second = 1;
+        assert!(!is_within_one_day(&now, &wakeup));
+        wakeup.second = 0;
+        wakeup.day = 2;
+        assert!(!is_within_one_day(&now, &wakeup));
+
+        assert_eq!(wakeup_date(&now, None, (13, 0, 0)), (2020, 12, 31));
+        assert_eq!(wakeup_date(&now, None, (11, 0, 0)), (2021, 1, 1));
+        assert_eq!(wakeup_date(&now, Some(1), (13, 0, 0)), (2021, 1, 1));
+        assert_eq!(wakeup_date(&now, Some(31), (11, 0, 0)), (2020, 12, 31));
+    }
+}
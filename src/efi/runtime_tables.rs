@@ -0,0 +1,273 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use r_efi::efi;
+use r_efi::efi::MemoryType;
+
+use core::ffi::c_void;
+use core::mem::size_of;
+
+use super::alloc::MemoryDescriptor;
+
+const PAGE_SIZE: u64 = 4096;
+
+const MAX_ENTRIES: usize = 64;
+
+/// The runtime services that work. There are no capsules, and without flash
+/// SetVariable() cannot keep non-volatile variables once the block devices
+/// are gone.
+pub fn runtime_services_supported(variables_in_flash: bool) -> u32 {
+    let mut supported = efi::RT_SUPPORTED_GET_TIME
+        | efi::RT_SUPPORTED_SET_TIME
+        | efi::RT_SUPPORTED_GET_WAKEUP_TIME
+        | efi::RT_SUPPORTED_SET_WAKEUP_TIME
+        | efi::RT_SUPPORTED_GET_VARIABLE
+        | efi::RT_SUPPORTED_GET_NEXT_VARIABLE_NAME
+        | efi::RT_SUPPORTED_SET_VIRTUAL_ADDRESS_MAP
+        | efi::RT_SUPPORTED_CONVERT_POINTER
+        | efi::RT_SUPPORTED_GET_NEXT_HIGH_MONOTONIC_COUNT
+        | efi::RT_SUPPORTED_RESET_SYSTEM
+        | efi::RT_SUPPORTED_QUERY_VARIABLE_INFO;
+    if variables_in_flash {
+        supported |= efi::RT_SUPPORTED_SET_VARIABLE;
+    }
+    supported
+}
+
+/// A part of a runtime region with its own attributes, such as a section of
+/// the firmware image
+#[derive(Clone, Copy, Debug)]
+pub struct Section {
+    pub start: u64,
+    pub end: u64,
+    pub attribute: u64,
+}
+
+// Code is at least read-only, data not executable
+fn default_attribute(memory_type: u32) -> u64 {
+    if memory_type == MemoryType::RuntimeServicesData as u32 {
+        efi::MEMORY_XP
+    } else {
+        efi::MEMORY_RO
+    }
+}
+
+/// Fill out with the entries of the memory attributes table: the runtime code
+/// and data regions of the memory map, split at the sections. The parts that
+/// are not executable are data, the others read-only code, unless a section
+/// tells otherwise. None when out is too small.
+pub fn memory_attributes(
+    descriptors: &[MemoryDescriptor],
+    sections: &[Section],
+    out: &mut [MemoryDescriptor],
+) -> Option<usize> {
+    let mut count = 0;
+    let mut add = |start: u64, end: u64, attribute: u64| -> Option<()> {
+        if start >= end {
+            return Some(());
+        }
+        let memory_type = if (attribute & efi::MEMORY_XP) != 0 {
+            MemoryType::RuntimeServicesData
+        } else {
+            MemoryType::RuntimeServicesCode
+        };
+        *out.get_mut(count)? = MemoryDescriptor {
+            r#type: memory_type as u32,
+            physical_start: start,
+            virtual_start: 0,
+            number_of_pages: (end - start) / PAGE_SIZE,
+            attribute: attribute | efi::MEMORY_RUNTIME,
+        };
+        count += 1;
+        Some(())
+    };
+
+    for descriptor in descriptors {
+        if descriptor.r#type != MemoryType::RuntimeServicesCode as u32
+            && descriptor.r#type != MemoryType::RuntimeServicesData as u32
+        {
+            continue;
+        }
+        let end = descriptor.physical_start + descriptor.number_of_pages * PAGE_SIZE;
+        let default = default_attribute(descriptor.r#type);
+
+        let mut current = descriptor.physical_start;
+        for section in sections {
+            let start = core::cmp::max(section.start, current);
+            let section_end = core::cmp::min(section.end, end);
+            if start >= section_end {
+                continue;
+            }
+            add(current, start, default)?;
+            add(start, section_end, section.attribute)?;
+            current = section_end;
+        }
+        add(current, end, default)?;
+    }
+
+    Some(count)
+}
+
+#[repr(C)]
+struct MemoryAttributesTable {
+    version: u32,
+    number_of_entries: u32,
+    descriptor_size: u32,
+    reserved: u32,
+    entries: [MemoryDescriptor; MAX_ENTRIES],
+}
+
+// Both tables are in the firmware image, which stays mapped for the OS.
+#[cfg(not(test))]
+static mut MEMORY_ATTRIBUTES_TABLE: MemoryAttributesTable = MemoryAttributesTable {
+    version: efi::MEMORY_ATTRIBUTES_TABLE_VERSION,
+    number_of_entries: 0,
+    descriptor_size: size_of::<MemoryDescriptor>() as u32,
+    reserved: 0,
+    entries: [MemoryDescriptor {
+        r#type: 0,
+        physical_start: 0,
+        virtual_start: 0,
+        number_of_pages: 0,
+        attribute: 0,
+    }; MAX_ENTRIES],
+};
+
+#[cfg(not(test))]
+static mut RT_PROPERTIES_TABLE: efi::RtPropertiesTable = efi::RtPropertiesTable {
+    version: efi::RT_PROPERTIES_TABLE_VERSION,
+    length: size_of::<efi::RtPropertiesTable>() as u16,
+    runtime_services_supported: 0,
+};
+
+#[cfg(not(test))]
+extern "C" {
+    static start_of_text: u8;
+    static end_of_text: u8;
+    static end_of_rodata: u8;
+    static end_of_bss: u8;
+}
+
+/// Describe the runtime regions of the memory map as it is now
+#[cfg(not(test))]
+pub fn update_memory_attributes() {
+    let sections = unsafe {
+        let text = &start_of_text as *const u8 as u64;
+        let rodata = &end_of_text as *const u8 as u64;
+        let data = &end_of_rodata as *const u8 as u64;
+        let end = &end_of_bss as *const u8 as u64;
+        [
+            Section { start: text, end: rodata, attribute: efi::MEMORY_RO },
+            Section { start: rodata, end: data, attribute: efi::MEMORY_RO | efi::MEMORY_XP },
+            Section { start: data, end, attribute: efi::MEMORY_XP },
+        ]
+    };
+
+    let mut descriptors = [MemoryDescriptor::default(); MAX_ENTRIES];
+    let table = unsafe { &mut MEMORY_ATTRIBUTES_TABLE };
+    let count = match crate::efi::ALLOCATOR.lock().get_runtime_descriptors(&mut descriptors) {
+        Some(count) => memory_attributes(&descriptors[..count], &sections, &mut table.entries),
+        None => None,
+    };
+    match count {
+        Some(count) => table.number_of_entries = count as u32,
+        None => {
+            log!("update_memory_attributes - more than {} runtime regions\n", MAX_ENTRIES);
+            table.number_of_entries = 0;
+        }
+    }
+}
+
+#[cfg(not(test))]
+pub fn install() {
+    update_memory_attributes();
+    unsafe {
+        RT_PROPERTIES_TABLE.runtime_services_supported =
+            runtime_services_supported(crate::efi::init::variables_in_flash());
+        crate::efi::install_configuration_table(
+            &mut efi::MEMORY_ATTRIBUTES_TABLE_GUID.clone(),
+            &mut MEMORY_ATTRIBUTES_TABLE as *mut MemoryAttributesTable as *mut c_void,
+        );
+        crate::efi::install_configuration_table(
+            &mut efi::RT_PROPERTIES_TABLE_GUID.clone(),
+            &mut RT_PROPERTIES_TABLE as *mut efi::RtPropertiesTable as *mut c_void,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(memory_type: MemoryType, start: u64, number_of_pages: u64) -> MemoryDescriptor {
+        MemoryDescriptor {
+            r#type: memory_type as u32,
+            physical_start: start,
+            virtual_start: 0,
+            number_of_pages,
+            attribute: 0,
+        }
+    }
+
+    #[test]
+    fn test_runtime_services_supported() {
+        let supported = runtime_services_supported(false);
+        assert_eq!(supported & efi::RT_SUPPORTED_SET_VARIABLE, 0);
+        assert_eq!(supported & efi::RT_SUPPORTED_UPDATE_CAPSULE, 0);
+        assert_eq!(supported & efi::RT_SUPPORTED_QUERY_CAPSULE_CAPABILITIES, 0);
+        assert_ne!(supported & efi::RT_SUPPORTED_GET_VARIABLE, 0);
+        assert_eq!(runtime_services_supported(true), supported | efi::RT_SUPPORTED_SET_VARIABLE);
+    }
+
+    #[test]
+    fn test_memory_attributes() {
+        let descriptors = [
+            descriptor(MemoryType::ConventionalMemory, 0, 0x100),
+            // The firmware image, its code then its data
+            descriptor(MemoryType::RuntimeServicesCode, 0x10_0000, 0xb),
+            descriptor(MemoryType::RuntimeServicesData, 0x10_b000, 0x5),
+            descriptor(MemoryType::BootServicesData, 0x11_0000, 0x10),
+            descriptor(MemoryType::RuntimeServicesData, 0x12_0000, 2),
+            descriptor(MemoryType::RuntimeServicesCode, 0x13_0000, 1),
+        ];
+        let sections = [
+            Section { start: 0x10_0000, end: 0x10_8000, attribute: efi::MEMORY_RO },
+            Section { start: 0x10_8000, end: 0x10_a000, attribute: efi::MEMORY_RO | efi::MEMORY_XP },
+            Section { start: 0x10_b000, end: 0x10_f000, attribute: efi::MEMORY_XP },
+        ];
+        let mut out = [MemoryDescriptor::default(); 8];
+        assert_eq!(memory_attributes(&descriptors, &sections, &mut out), Some(7));
+
+        let expected = [
+            (MemoryType::RuntimeServicesCode, 0x10_0000, 8, efi::MEMORY_RO),
+            (MemoryType::RuntimeServicesData, 0x10_8000, 2, efi::MEMORY_RO | efi::MEMORY_XP),
+            (MemoryType::RuntimeServicesCode, 0x10_a000, 1, efi::MEMORY_RO),
+            (MemoryType::RuntimeServicesData, 0x10_b000, 4, efi::MEMORY_XP),
+            (MemoryType::RuntimeServicesData, 0x10_f000, 1, efi::MEMORY_XP),
+            (MemoryType::RuntimeServicesData, 0x12_0000, 2, efi::MEMORY_XP),
+            (MemoryType::RuntimeServicesCode, 0x13_0000, 1, efi::MEMORY_RO),
+        ];
+        for (entry, (memory_type, start, pages, attribute)) in out.iter().zip(expected.iter()) {
+            assert_eq!(entry.r#type, *memory_type as u32);
+            assert_eq!(entry.physical_start, *start);
+            assert_eq!(entry.number_of_pages, *pages);
+            assert_eq!(entry.attribute, *attribute | efi::MEMORY_RUNTIME);
+        }
+
+        let mut out = [MemoryDescriptor::default(); 6];
+        assert_eq!(memory_attributes(&descriptors, &sections, &mut out), None);
+    }
+}
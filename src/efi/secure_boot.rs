@@ -0,0 +1,240 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use r_efi::efi::{Guid, Status};
+
+use core::ffi::c_void;
+
+use crate::crypto::sha256;
+use crate::crypto::x509::Certificate;
+
+use super::auth::{self, Mode, CERT_SHA256_GUID, CERT_X509_SHA256_GUID, IMAGE_SECURITY_DATABASE_GUID, SIGNATURE_LIST_SIZE, SIGNATURE_OWNER_SIZE};
+use super::authenticode::{Image, Signature};
+
+// EFI_IMAGE_EXECUTION_ACTION
+pub const IMAGE_EXECUTION_AUTH_UNTESTED: u32 = 0x00;
+pub const IMAGE_EXECUTION_AUTH_SIG_FAILED: u32 = 0x01;
+pub const IMAGE_EXECUTION_AUTH_SIG_PASSED: u32 = 0x02;
+pub const IMAGE_EXECUTION_AUTH_SIG_NOT_FOUND: u32 = 0x03;
+pub const IMAGE_EXECUTION_AUTH_SIG_FOUND: u32 = 0x04;
+pub const IMAGE_EXECUTION_POLICY_FAILED: u32 = 0x05;
+pub const IMAGE_EXECUTION_INITIALIZED: u32 = 0x08;
+
+// The image execution information table is installed with the GUID of the signature databases
+const IMAGE_EXECUTION_INFO_TABLE_GUID: Guid = IMAGE_SECURITY_DATABASE_GUID;
+const IMAGE_EXECUTION_INFO_TABLE_SIZE: usize = 16 * 1024;
+
+// NumberOfImages, then the EFI_IMAGE_EXECUTION_INFO of every image
+static mut IMAGE_EXECUTION_INFO_TABLE: [u8; IMAGE_EXECUTION_INFO_TABLE_SIZE] = [0; IMAGE_EXECUTION_INFO_TABLE_SIZE];
+static mut IMAGE_EXECUTION_INFO_TABLE_USED: usize = 0;
+
+// Whether the lists have a signature of the given type starting with the hash
+fn is_listed_as(lists: &[u8], signature_type: &Guid, hash: &[u8; sha256::DIGEST_SIZE]) -> bool {
+    let mut listed = false;
+    auth::for_each_signature_list(lists, &mut |list_type, signature_size, signatures| {
+        if *list_type == *signature_type.as_bytes() {
+            listed |= signatures.chunks(signature_size).any(|signature| signature[SIGNATURE_OWNER_SIZE ..].starts_with(hash));
+        }
+    });
+    listed
+}
+
+fn is_hash_listed(lists: &[u8], hash: &[u8; sha256::DIGEST_SIZE]) -> bool {
+    is_listed_as(lists, &CERT_SHA256_GUID, hash)
+}
+
+// A certificate of dbx, one issued by it, or one whose TBSCertificate hash it has.
+// The time of revocation is not checked, there are no timestamp signatures.
+fn is_revoked(certificate: &Certificate, dbx: &[u8]) -> bool {
+    auth::is_trusted_by(certificate, dbx) || is_listed_as(dbx, &CERT_X509_SHA256_GUID, &certificate.tbs_digest())
+}
+
+/// Check an image against the allowed (db) and forbidden (dbx) signature
+/// databases. Returns the EFI_IMAGE_EXECUTION_ACTION, and the Authenticode
+/// hash of the image if it could be computed.
+pub fn check_image(data: &[u8], db: &[u8], dbx: &[u8]) -> (u32, Option<[u8; sha256::DIGEST_SIZE]>) {
+    let image = match Image::parse(data) {
+        Some(image) => image,
+        None => return (IMAGE_EXECUTION_AUTH_SIG_FAILED, None),
+    };
+    let hash = *image.hash();
+    if is_hash_listed(dbx, &hash) {
+        return (IMAGE_EXECUTION_AUTH_SIG_FOUND, Some(hash));
+    }
+
+    // Any signature by a forbidden certificate forbids the image, whatever the others are
+    let mut signed = false;
+    let mut forbidden = false;
+    let mut allowed = false;
+    image.for_each_signature(&mut |data| {
+        signed = true;
+        if let Some(signature) = Signature::parse(data) {
+            forbidden |= signature.verify(&hash, &|certificate| is_revoked(certificate, dbx)).is_some();
+            allowed |= signature.verify(&hash, &|certificate| auth::is_trusted_by(certificate, db)).is_some();
+        }
+    });
+
+    let action = if forbidden {
+        IMAGE_EXECUTION_AUTH_SIG_FOUND
+    } else if allowed || is_hash_listed(db, &hash) {
+        IMAGE_EXECUTION_AUTH_SIG_PASSED
+    } else if signed {
+        IMAGE_EXECUTION_AUTH_SIG_FAILED
+    } else {
+        IMAGE_EXECUTION_AUTH_SIG_NOT_FOUND
+    };
+    (action, Some(hash))
+}
+
+// Add an EFI_IMAGE_EXECUTION_INFO to the table, installing it on first use
+#[cfg(not(test))]
+fn record_image(action: u32, device_path: *mut c_void, hash: Option<&[u8; sha256::DIGEST_SIZE]>) {
+    let end_device_path = [0x7fu8, 0xff, 0x04, 0x00];
+    let device_path: &[u8] = if device_path.is_null() {
+        &end_device_path
+    } else {
+        let size = crate::efi::device_path::get_device_path_size(device_path as *mut r_efi::protocols::device_path::Protocol);
+        unsafe { core::slice::from_raw_parts(device_path as *const u8, size) }
+    };
+    // Action, InfoSize, an empty Name, the DevicePath, and the hash as an EFI_SIGNATURE_LIST
+    let signature_size = SIGNATURE_OWNER_SIZE + sha256::DIGEST_SIZE;
+    let signature_list_size = if hash.is_some() { SIGNATURE_LIST_SIZE + signature_size } else { 0 };
+    let info_size = 4 + 4 + 2 + device_path.len() + signature_list_size;
+
+    let table = unsafe { &mut IMAGE_EXECUTION_INFO_TABLE };
+    let used = unsafe { &mut IMAGE_EXECUTION_INFO_TABLE_USED };
+    if *used == 0 {
+        *used = 8;
+        let status = crate::efi::install_configuration_table(
+                       &mut IMAGE_EXECUTION_INFO_TABLE_GUID.clone(),
+                       table as *mut [u8; IMAGE_EXECUTION_INFO_TABLE_SIZE] as *mut c_void);
+        if status != Status::SUCCESS {
+            log!("secure_boot: cannot install the image execution information table\n");
+        }
+    }
+    if *used + info_size > IMAGE_EXECUTION_INFO_TABLE_SIZE {
+        log!("secure_boot: image execution information table full\n");
+        return;
+    }
+
+    let info = &mut table[*used .. *used + info_size];
+    info[0 .. 4].copy_from_slice(&action.to_le_bytes());
+    info[4 .. 8].copy_from_slice(&(info_size as u32).to_le_bytes());
+    info[8 .. 10].copy_from_slice(&[0, 0]);
+    info[10 .. 10 + device_path.len()].copy_from_slice(device_path);
+    if let Some(hash) = hash {
+        let list = &mut info[10 + device_path.len() ..];
+        list[0 .. 16].copy_from_slice(CERT_SHA256_GUID.as_bytes());
+        list[16 .. 20].copy_from_slice(&(signature_list_size as u32).to_le_bytes());
+        list[20 .. 24].copy_from_slice(&0u32.to_le_bytes());
+        list[24 .. 28].copy_from_slice(&(signature_size as u32).to_le_bytes());
+        list[28 .. 28 + SIGNATURE_OWNER_SIZE].copy_from_slice(&[0; SIGNATURE_OWNER_SIZE]);
+        list[28 + SIGNATURE_OWNER_SIZE ..].copy_from_slice(hash);
+    }
+    *used += info_size;
+
+    let count = u64::from_le_bytes([table[0], table[1], table[2], table[3], table[4], table[5], table[6], table[7]]) + 1;
+    table[0 .. 8].copy_from_slice(&count.to_le_bytes());
+}
+
+/// Decide whether an image may be loaded. Nothing is checked in setup mode,
+/// untrusted images are recorded in the image execution information table and
+/// only loaded in audit mode.
+#[cfg(not(test))]
+pub fn verify_image(source_buffer: *mut c_void, source_size: usize, device_path: *mut c_void) -> Status {
+    let variable = crate::efi::VARIABLE.lock();
+    let mode = auth::get_mode(&variable);
+    if mode == Mode::Setup {
+        return Status::SUCCESS;
+    }
+
+    let db = auth::get_data(&variable, "db", &IMAGE_SECURITY_DATABASE_GUID).unwrap_or(&[]);
+    let dbx = auth::get_data(&variable, "dbx", &IMAGE_SECURITY_DATABASE_GUID).unwrap_or(&[]);
+    let data = unsafe { core::slice::from_raw_parts(source_buffer as *const u8, source_size) };
+    let (action, hash) = check_image(data, db, dbx);
+    drop(variable);
+    if action == IMAGE_EXECUTION_AUTH_SIG_PASSED {
+        return Status::SUCCESS;
+    }
+
+    log!("secure_boot: image not trusted, action 0x{:x}\n", action);
+    record_image(action, device_path, hash.as_ref());
+    if mode == Mode::Audit {
+        Status::SUCCESS
+    } else {
+        Status::SECURITY_VIOLATION
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::auth::CERT_X509_GUID;
+
+    fn signature_list(signature_type: &Guid, signature: &[u8]) -> Vec<u8> {
+        let signature_size = SIGNATURE_OWNER_SIZE + signature.len();
+        let mut list = signature_type.as_bytes().to_vec();
+        list.extend_from_slice(&((SIGNATURE_LIST_SIZE + signature_size) as u32).to_le_bytes());
+        list.extend_from_slice(&0u32.to_le_bytes());
+        list.extend_from_slice(&(signature_size as u32).to_le_bytes());
+        list.extend_from_slice(&[0; SIGNATURE_OWNER_SIZE]);
+        list.extend_from_slice(signature);
+        list
+    }
+
+    // See testdata/make_signed_images.py
+    #[test]
+    fn test_check_image() {
+        let signed = &include_bytes!("testdata/signed.efi")[..];
+        let unsigned = &include_bytes!("testdata/unsigned.efi")[..];
+        let hash = *Image::parse(signed).unwrap().hash();
+        let root = signature_list(&CERT_X509_GUID, include_bytes!("testdata/image_root.der"));
+        let signer = signature_list(&CERT_X509_GUID, include_bytes!("testdata/image_signer.der"));
+        let other = signature_list(&CERT_X509_GUID, include_bytes!("testdata/image_other.der"));
+        let image_hash = signature_list(&CERT_SHA256_GUID, &hash);
+
+        // Signed by a certificate issued by one in db
+        assert_eq!(check_image(signed, &root, &[]), (IMAGE_EXECUTION_AUTH_SIG_PASSED, Some(hash)));
+        assert_eq!(check_image(signed, &signer, &[]).0, IMAGE_EXECUTION_AUTH_SIG_PASSED);
+        assert_eq!(check_image(signed, &other, &[]).0, IMAGE_EXECUTION_AUTH_SIG_FAILED);
+        assert_eq!(check_image(signed, &[], &[]).0, IMAGE_EXECUTION_AUTH_SIG_FAILED);
+
+        // Unsigned, but its hash is allowed
+        assert_eq!(check_image(unsigned, &root, &[]).0, IMAGE_EXECUTION_AUTH_SIG_NOT_FOUND);
+        assert_eq!(check_image(unsigned, &image_hash, &[]).0, IMAGE_EXECUTION_AUTH_SIG_PASSED);
+
+        // dbx wins over db, for the hash and for the certificates
+        assert_eq!(check_image(signed, &root, &image_hash).0, IMAGE_EXECUTION_AUTH_SIG_FOUND);
+        assert_eq!(check_image(signed, &root, &signer).0, IMAGE_EXECUTION_AUTH_SIG_FOUND);
+        assert_eq!(check_image(signed, &root, &other).0, IMAGE_EXECUTION_AUTH_SIG_PASSED);
+
+        // dbx revokes the signer by the hash of its TBSCertificate
+        let revoked = |der: &[u8]| {
+            let mut signature = Certificate::parse(der).unwrap().tbs_digest().to_vec();
+            signature.extend_from_slice(&[0; 16]);
+            signature_list(&CERT_X509_SHA256_GUID, &signature)
+        };
+        let revoked_signer = revoked(include_bytes!("testdata/image_signer.der"));
+        let revoked_other = revoked(include_bytes!("testdata/image_other.der"));
+        assert_eq!(check_image(signed, &root, &revoked_signer).0, IMAGE_EXECUTION_AUTH_SIG_FOUND);
+        assert_eq!(check_image(signed, &root, &revoked_other).0, IMAGE_EXECUTION_AUTH_SIG_PASSED);
+
+        let mut tampered = signed.to_vec();
+        tampered[0x300] ^= 1;
+        assert_eq!(check_image(&tampered, &root, &[]).0, IMAGE_EXECUTION_AUTH_SIG_FAILED);
+        assert_eq!(check_image(&signed[.. 100], &root, &[]), (IMAGE_EXECUTION_AUTH_SIG_FAILED, None));
+    }
+}
@@ -0,0 +1,408 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use r_efi::efi::{Guid, Status};
+use r_efi::system::{VARIABLE_NON_VOLATILE, VARIABLE_BOOTSERVICE_ACCESS, VARIABLE_RUNTIME_ACCESS};
+
+use core::fmt;
+
+use crate::logger::{LOG_LEVEL_ERROR, LOG_LEVEL_INFO, LOG_LEVEL_NONE, LOG_LEVEL_VERBOSE, LOG_LEVEL_WARN};
+
+use super::auth::{self, variable_name, Mode};
+use super::boot_manager::{self, LoadOption};
+use super::variable::GLOBAL_VARIABLE_GUID;
+
+/// The vendor GUID of the variables of the firmware setup
+pub const SETUP_GUID: Guid = Guid::from_fields(
+    0x3c9a6b2e, 0x51d4, 0x4f0b, 0x9a, 0x7e, &[0x21, 0x6d, 0x8c, 0x40, 0xe5, 0x13]
+);
+
+// LogLevel, CommandLine and Initrd are kept across boots, BootOrder and Timeout
+// are the global variables
+const SETUP_ATTRIBUTES: u32 = VARIABLE_NON_VOLATILE | VARIABLE_BOOTSERVICE_ACCESS;
+const BOOT_ATTRIBUTES: u32 = VARIABLE_NON_VOLATILE | VARIABLE_BOOTSERVICE_ACCESS | VARIABLE_RUNTIME_ACCESS;
+
+const LOG_LEVELS: [(&str, usize); 5] = [
+    ("none", LOG_LEVEL_NONE),
+    ("error", LOG_LEVEL_ERROR),
+    ("warn", LOG_LEVEL_WARN),
+    ("info", LOG_LEVEL_INFO),
+    ("verbose", LOG_LEVEL_VERBOSE),
+];
+
+const MODES: [Mode; 4] = [Mode::Setup, Mode::User, Mode::Audit, Mode::Deployed];
+
+// The most boot options listed, or in BootOrder
+const MAX_BOOT_OPTIONS: usize = 64;
+const MAX_LINE_LENGTH: usize = 5 * MAX_BOOT_OPTIONS;
+
+/// The longest firmware command line, which is in ASCII
+pub const MAX_COMMAND_LINE_LENGTH: usize = 1024;
+
+/// The name of the level a log level is at
+pub fn log_level_name(level: usize) -> &'static str {
+    LOG_LEVELS.iter().rev().find(|(_, value)| *value <= level).map(|(name, _)| *name).unwrap_or("none")
+}
+
+/// The level after a log level, going round
+pub fn next_log_level(level: usize) -> usize {
+    LOG_LEVELS.iter().find(|(_, value)| *value > level).map(|(_, value)| *value).unwrap_or(LOG_LEVEL_NONE)
+}
+
+/// Parse option numbers, in hexadecimal and separated by spaces, into order.
+/// Returns how many there are.
+pub fn parse_boot_order(line: &str, order: &mut [u16]) -> Option<usize> {
+    let mut count = 0;
+    for number in line.split_whitespace() {
+        if number.len() > 4 || count == order.len() {
+            return None;
+        }
+        order[count] = u16::from_str_radix(number, 16).ok()?;
+        count += 1;
+    }
+    Some(count)
+}
+
+// The serial console, with the line endings it needs
+struct Screen;
+
+#[cfg(not(test))]
+impl fmt::Write for Screen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut conout = super::CONOUT.lock();
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                conout.write_byte(b'\r');
+            }
+            conout.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Write to the console, whatever the log level
+#[cfg(not(test))]
+pub fn print(args: fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut Screen, args);
+}
+
+#[cfg(not(test))]
+fn read_key() -> u8 {
+    loop {
+        let byte = super::CONIN.lock().read_byte();
+        if byte != 0 {
+            return byte;
+        }
+        super::stall(1000);
+    }
+}
+
+// Read a line, echoing it. Escape gives an empty line.
+#[cfg(not(test))]
+fn read_line(line: &mut [u8]) -> &str {
+    let mut length = 0;
+    loop {
+        match read_key() {
+            b'\r' | b'\n' => break,
+            0x1b => {
+                length = 0;
+                break;
+            },
+            0x08 | 0x7f => {
+                if length > 0 {
+                    length -= 1;
+                    print(format_args!("\x08 \x08"));
+                }
+            },
+            byte @ 0x20 ..= 0x7e if length < line.len() => {
+                line[length] = byte;
+                length += 1;
+                print(format_args!("{}", byte as char));
+            },
+            _ => {},
+        }
+    }
+    print(format_args!("\n"));
+    core::str::from_utf8(&line[.. length]).unwrap_or("").trim()
+}
+
+#[cfg(not(test))]
+fn get_u16(name: &str) -> Option<u16> {
+    let variable = super::VARIABLE.lock();
+    match auth::get_data(&variable, name, &GLOBAL_VARIABLE_GUID) {
+        Some(data) if data.len() == 2 => Some(u16::from_le_bytes([data[0], data[1]])),
+        _ => None,
+    }
+}
+
+#[cfg(not(test))]
+fn set_variable(name: &str, guid: &Guid, attributes: u32, data: &[u8]) -> Status {
+    super::VARIABLE.lock().set_variable(variable_name(name).as_bytes(), guid.as_bytes(), attributes, data)
+}
+
+#[cfg(not(test))]
+fn get_boot_order(order: &mut [u16]) -> usize {
+    let variable = super::VARIABLE.lock();
+    let data = auth::get_data(&variable, "BootOrder", &GLOBAL_VARIABLE_GUID).unwrap_or(&[]);
+    let mut count = 0;
+    for number in boot_manager::boot_order(data).take(order.len()) {
+        order[count] = number;
+        count += 1;
+    }
+    count
+}
+
+// The options of BootOrder, then the other Boot#### variables
+#[cfg(not(test))]
+fn list_boot_options(options: &mut [u16]) -> usize {
+    let mut count = get_boot_order(options);
+    let variable = super::VARIABLE.lock();
+    let (mut status, mut name, mut guid) = variable.get_next_variable_name(&[], &[0; 16], false);
+    while status == Status::SUCCESS && count < options.len() {
+        if guid == *GLOBAL_VARIABLE_GUID.as_bytes() {
+            match boot_manager::boot_option_number(name) {
+                Some(number) if !options[.. count].contains(&number) => {
+                    options[count] = number;
+                    count += 1;
+                },
+                _ => {},
+            }
+        }
+        let next = variable.get_next_variable_name(name, &guid, false);
+        status = next.0;
+        name = next.1;
+        guid = next.2;
+    }
+    count
+}
+
+#[cfg(not(test))]
+fn print_boot_option(index: usize, number: u16) {
+    let variable = super::VARIABLE.lock();
+    let name = boot_manager::boot_option_name(number);
+    let option = match variable.get_variable(&name, GLOBAL_VARIABLE_GUID.as_bytes()) {
+        (Status::SUCCESS, _, data) => LoadOption::parse(data),
+        _ => None,
+    };
+    print(format_args!("  {}. Boot{:04X} ", index, number));
+    match option {
+        Some(option) => {
+            for c in option.description.chunks_exact(2) {
+                let c = u16::from_le_bytes([c[0], c[1]]);
+                print(format_args!("{}", if c < 0x80 { c as u8 as char } else { '?' }));
+            }
+            if !option.is_bootable() {
+                print(format_args!(" (inactive)"));
+            }
+        },
+        None => print(format_args!("(invalid)")),
+    }
+    print(format_args!("\n"));
+}
+
+#[cfg(not(test))]
+fn edit_boot_order() {
+    print(format_args!("New boot order, option numbers separated by spaces: "));
+    let mut line = [0u8; MAX_LINE_LENGTH];
+    let line = read_line(&mut line);
+    if line.is_empty() {
+        return;
+    }
+    let mut order = [0u16; MAX_BOOT_OPTIONS];
+    let count = match parse_boot_order(line, &mut order) {
+        Some(count) => count,
+        None => {
+            print(format_args!("Invalid boot order\n"));
+            return;
+        },
+    };
+    let mut data = [0u8; 2 * MAX_BOOT_OPTIONS];
+    for (index, number) in order[.. count].iter().enumerate() {
+        if super::VARIABLE.lock().get_variable(&boot_manager::boot_option_name(*number), GLOBAL_VARIABLE_GUID.as_bytes()).0 != Status::SUCCESS {
+            print(format_args!("There is no Boot{:04X}\n", number));
+            return;
+        }
+        data[2 * index .. 2 * index + 2].copy_from_slice(&number.to_le_bytes());
+    }
+    let status = set_variable("BootOrder", &GLOBAL_VARIABLE_GUID, BOOT_ATTRIBUTES, &data[.. 2 * count]);
+    if status != Status::SUCCESS {
+        print(format_args!("Cannot set BootOrder: {:?}\n", status));
+    }
+}
+
+#[cfg(not(test))]
+fn edit_timeout() {
+    print(format_args!("Seconds to wait for a key before booting, 65535 to wait for ever: "));
+    let mut line = [0u8; MAX_LINE_LENGTH];
+    let line = read_line(&mut line);
+    if line.is_empty() {
+        return;
+    }
+    let status = match line.parse::<u16>() {
+        Ok(timeout) => set_variable("Timeout", &GLOBAL_VARIABLE_GUID, BOOT_ATTRIBUTES, &timeout.to_le_bytes()),
+        Err(_) => Status::INVALID_PARAMETER,
+    };
+    if status != Status::SUCCESS {
+        print(format_args!("Cannot set Timeout: {:?}\n", status));
+    }
+}
+
+#[cfg(not(test))]
+fn print_setting(name: &str) {
+    let variable = super::VARIABLE.lock();
+    match auth::get_data(&variable, name, &SETUP_GUID) {
+        Some(value) => print(format_args!("{}\n", core::str::from_utf8(value).unwrap_or("(invalid)"))),
+        None => print(format_args!("none\n")),
+    }
+}
+
+// A text setting, kept until set to -
+#[cfg(not(test))]
+fn edit_setting(name: &str, prompt: &str) {
+    print(format_args!("{}, - to remove it: ", prompt));
+    let mut line = [0u8; MAX_COMMAND_LINE_LENGTH];
+    let status = match read_line(&mut line) {
+        "" => return,
+        "-" => set_variable(name, &SETUP_GUID, 0, &[]),
+        value => set_variable(name, &SETUP_GUID, SETUP_ATTRIBUTES, value.as_bytes()),
+    };
+    if status != Status::SUCCESS && status != Status::NOT_FOUND {
+        print(format_args!("Cannot set {}: {:?}\n", name, status));
+    }
+}
+
+#[cfg(not(test))]
+fn set_log_level(level: usize) {
+    crate::logger::set_level(level);
+    let status = set_variable("LogLevel", &SETUP_GUID, SETUP_ATTRIBUTES, &(level as u32).to_le_bytes());
+    if status != Status::SUCCESS {
+        print(format_args!("Cannot set LogLevel: {:?}\n", status));
+    }
+}
+
+#[cfg(not(test))]
+fn edit_secure_boot_mode() {
+    for (index, mode) in MODES.iter().enumerate() {
+        print(format_args!("  {}. {:?}\n", index + 1, mode));
+    }
+    print(format_args!("Secure Boot mode, Setup and Audit remove PK: "));
+    let mut line = [0u8; MAX_LINE_LENGTH];
+    let mode = match read_line(&mut line).parse::<usize>() {
+        Ok(index) if index >= 1 && index <= MODES.len() => MODES[index - 1],
+        _ => return,
+    };
+    match auth::set_user_mode(&mut super::VARIABLE.lock(), mode) {
+        Status::SUCCESS => {},
+        Status::NOT_FOUND => print(format_args!("{:?} mode needs a PK, enroll one first\n", mode)),
+        status => print(format_args!("Cannot move to {:?} mode: {:?}\n", mode, status)),
+    }
+}
+
+/// Apply the settings kept in the setup variables, once the non-volatile
+/// variables are loaded
+#[cfg(not(test))]
+pub fn initialize() {
+    let variable = super::VARIABLE.lock();
+    if let Some(data) = auth::get_data(&variable, "LogLevel", &SETUP_GUID) {
+        if data.len() == 4 {
+            crate::logger::set_level(u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize);
+        }
+    }
+}
+
+/// The setup screen on the serial console: boot an option, or change the
+/// boot order, the timeout, the command line, the log level or the Secure
+/// Boot mode. Returns to go on booting.
+#[cfg(not(test))]
+pub fn run() {
+    loop {
+        let mut options = [0u16; MAX_BOOT_OPTIONS];
+        let count = list_boot_options(&mut options);
+        let mut order = [0u16; MAX_BOOT_OPTIONS];
+        let order_count = get_boot_order(&mut order);
+
+        print(format_args!("\npayload-efi setup\n\n"));
+        for (index, number) in options[.. count].iter().enumerate() {
+            print_boot_option(index + 1, *number);
+        }
+        print(format_args!("\n  o. Boot order:"));
+        for number in order[.. order_count].iter() {
+            print(format_args!(" {:04X}", number));
+        }
+        print(format_args!("\n  t. Timeout: "));
+        match get_u16("Timeout") {
+            Some(0xffff) => print(format_args!("wait for ever\n")),
+            Some(timeout) => print(format_args!("{} seconds\n", timeout)),
+            None => print(format_args!("none\n")),
+        }
+        print(format_args!("  k. Command line: "));
+        print_setting("CommandLine");
+        print(format_args!("  i. Initrd: "));
+        print_setting("Initrd");
+        print(format_args!("  l. Log level: {}\n", log_level_name(crate::logger::get_level())));
+        print(format_args!("  s. Secure Boot mode: {:?}\n", auth::get_mode(&super::VARIABLE.lock())));
+        print(format_args!("  c. Continue booting\n\n> "));
+
+        let mut line = [0u8; MAX_LINE_LENGTH];
+        match read_line(&mut line) {
+            "" | "c" => return,
+            "o" => edit_boot_order(),
+            "t" => edit_timeout(),
+            "k" => edit_setting("CommandLine", "Load options of the images booted without their own"),
+            "i" => edit_setting("Initrd", "Initrd file of Linux kernels booted directly"),
+            "l" => set_log_level(next_log_level(crate::logger::get_level())),
+            "s" => edit_secure_boot_mode(),
+            choice => match choice.parse::<usize>() {
+                Ok(index) if index >= 1 && index <= count => {
+                    // Booted as BootNext would be, whatever its attributes
+                    boot_manager::boot_option(options[index - 1], true);
+                },
+                _ => print(format_args!("Unknown choice {}\n", choice)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(log_level_name(LOG_LEVEL_VERBOSE), "verbose");
+        assert_eq!(log_level_name(LOG_LEVEL_WARN), "warn");
+        assert_eq!(log_level_name(LOG_LEVEL_WARN + 1), "warn");
+        assert_eq!(log_level_name(LOG_LEVEL_NONE), "none");
+
+        let mut level = LOG_LEVEL_NONE;
+        for name in ["error", "warn", "info", "verbose", "none"].iter() {
+            level = next_log_level(level);
+            assert_eq!(log_level_name(level), *name);
+        }
+        assert_eq!(next_log_level(LOG_LEVEL_VERBOSE + 1), LOG_LEVEL_NONE);
+    }
+
+    #[test]
+    fn test_parse_boot_order() {
+        let mut order = [0u16; 3];
+        assert_eq!(parse_boot_order(" 0001 3  BEEF", &mut order), Some(3));
+        assert_eq!(order, [0x0001, 0x0003, 0xbeef]);
+        assert_eq!(parse_boot_order("", &mut order), Some(0));
+        assert_eq!(parse_boot_order("0001 10000", &mut order), None);
+        assert_eq!(parse_boot_order("0001 zz", &mut order), None);
+        assert_eq!(parse_boot_order("1 2 3 4", &mut order), None);
+    }
+}
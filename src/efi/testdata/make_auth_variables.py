@@ -0,0 +1,95 @@
+#!/usr/bin/env python3
+# Generate the authenticated variable writes used by the tests of auth.rs:
+# EFI_VARIABLE_AUTHENTICATION_2 descriptors followed by their payloads.
+#
+# PK is a self-signed certificate, KEK a certificate it issued, and other a
+# self-signed certificate PK knows nothing about.
+
+import datetime
+import struct
+import uuid
+
+from cryptography import x509
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import rsa
+from cryptography.hazmat.primitives.serialization import pkcs7
+from cryptography.x509.oid import NameOID
+
+GLOBAL_VARIABLE = uuid.UUID("8be4df61-93ca-11d2-aa0d-00e098032b8c")
+IMAGE_SECURITY_DATABASE = uuid.UUID("d719b2cb-3d3a-4596-a3bc-dad00e67656f")
+PRIVATE = uuid.UUID("12345678-1234-1234-1234-123456789abc")
+CERT_X509 = uuid.UUID("a5c059a1-94e4-4aa7-87b5-ab155c2bf072")
+CERT_SHA256 = uuid.UUID("c1c41626-504c-4092-aca9-41f936934328")
+CERT_TYPE_PKCS7 = uuid.UUID("4aafd29d-68df-49ee-8aa9-347d375665a7")
+OWNER = uuid.UUID("11111111-2222-3333-4444-555555555555")
+
+# NV | BS | RT | TIME_BASED_AUTHENTICATED_WRITE_ACCESS, and APPEND_WRITE
+ATTRIBUTES = 0x27
+APPEND = 0x40
+
+
+def certificate(name, key, issuer_name, issuer_key):
+    now = datetime.datetime(2020, 1, 1)
+    return (x509.CertificateBuilder()
+            .subject_name(x509.Name([x509.NameAttribute(NameOID.COMMON_NAME, name)]))
+            .issuer_name(x509.Name([x509.NameAttribute(NameOID.COMMON_NAME, issuer_name)]))
+            .public_key(key.public_key())
+            .serial_number(x509.random_serial_number())
+            .not_valid_before(now)
+            .not_valid_after(now + datetime.timedelta(days=36500))
+            .sign(issuer_key, hashes.SHA256()))
+
+
+def signature_list(signature_type, signatures):
+    size = 16 + len(signatures[0])
+    data = b"".join(OWNER.bytes_le + signature for signature in signatures)
+    return signature_type.bytes_le + struct.pack("<III", 28 + len(data), 0, size) + data
+
+
+def authenticated(name, guid, attributes, day, payload, cert, key):
+    timestamp = struct.pack("<HBBBBBBIhBB", 2020, 1, day, 0, 0, 0, 0, 0, 0, 0, 0)
+    signed = (name.encode("utf-16-le") + guid.bytes_le + struct.pack("<I", attributes)
+              + timestamp + payload)
+    signature = (pkcs7.PKCS7SignatureBuilder().set_data(signed)
+                 .add_signer(cert, key, hashes.SHA256())
+                 .sign(serialization.Encoding.DER, [pkcs7.PKCS7Options.DetachedSignature,
+                                                    pkcs7.PKCS7Options.NoAttributes,
+                                                    pkcs7.PKCS7Options.Binary]))
+    win_certificate = struct.pack("<IHH", 24 + len(signature), 0x0200, 0x0ef1) + CERT_TYPE_PKCS7.bytes_le
+    return timestamp + win_certificate + signature + payload
+
+
+def main():
+    pk_key = rsa.generate_private_key(public_exponent=65537, key_size=2048)
+    kek_key = rsa.generate_private_key(public_exponent=65537, key_size=2048)
+    other_key = rsa.generate_private_key(public_exponent=65537, key_size=2048)
+    pk = certificate("PK", pk_key, "PK", pk_key)
+    kek = certificate("KEK", kek_key, "PK", pk_key)
+    other = certificate("Other", other_key, "Other", other_key)
+    der = lambda cert: cert.public_bytes(serialization.Encoding.DER)
+
+    pk_list = signature_list(CERT_X509, [der(pk)])
+    kek_list = signature_list(CERT_X509, [der(kek)])
+    hash_a = hashes.Hash(hashes.SHA256()); hash_a.update(b"a"); hash_a = hash_a.finalize()
+    hash_b = hashes.Hash(hashes.SHA256()); hash_b.update(b"b"); hash_b = hash_b.finalize()
+
+    writes = {
+        "pk": ("PK", GLOBAL_VARIABLE, ATTRIBUTES, 1, pk_list, pk, pk_key),
+        "kek": ("KEK", GLOBAL_VARIABLE, ATTRIBUTES, 2, kek_list, pk, pk_key),
+        "kek_by_other": ("KEK", GLOBAL_VARIABLE, ATTRIBUTES, 2, kek_list, other, other_key),
+        "db": ("db", IMAGE_SECURITY_DATABASE, ATTRIBUTES, 3, signature_list(CERT_SHA256, [hash_a]), kek, kek_key),
+        "db_append": ("db", IMAGE_SECURITY_DATABASE, ATTRIBUTES | APPEND, 4,
+                      signature_list(CERT_SHA256, [hash_a, hash_b]), kek, kek_key),
+        "pk_delete": ("PK", GLOBAL_VARIABLE, ATTRIBUTES, 5, b"", pk, pk_key),
+        "pk_later": ("PK", GLOBAL_VARIABLE, ATTRIBUTES, 6, pk_list, pk, pk_key),
+        "private": ("Private", PRIVATE, ATTRIBUTES, 1, b"created", kek, kek_key),
+        "private_by_pk": ("Private", PRIVATE, ATTRIBUTES, 2, b"by pk", pk, pk_key),
+        "private_update": ("Private", PRIVATE, ATTRIBUTES, 3, b"updated", kek, kek_key),
+    }
+    for file, write in writes.items():
+        with open(file + ".auth", "wb") as f:
+            f.write(authenticated(*write))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,137 @@
+#!/usr/bin/env python3
+# Generate the images used by the tests of authenticode.rs and secure_boot.rs:
+# a small PE32+ image, unsigned and with an Authenticode signature.
+#
+# The signer certificate is issued by root.der, other.der is unrelated.
+
+import datetime
+import hashlib
+import struct
+
+from cryptography import x509
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import padding, rsa
+from cryptography.x509.oid import NameOID
+
+OID_SHA256 = bytes([0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01])
+OID_RSA_ENCRYPTION = bytes([0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01])
+OID_SIGNED_DATA = bytes([0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02])
+OID_CONTENT_TYPE = bytes([0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x03])
+OID_MESSAGE_DIGEST = bytes([0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x04])
+OID_SPC_INDIRECT_DATA = bytes([0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x01, 0x04])
+OID_SPC_PE_IMAGE_DATA = bytes([0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x01, 0x0f])
+
+
+def tlv(tag, content):
+    if len(content) < 0x80:
+        length = bytes([len(content)])
+    else:
+        encoded = len(content).to_bytes((len(content).bit_length() + 7) // 8, "big")
+        length = bytes([0x80 | len(encoded)]) + encoded
+    return bytes([tag]) + length + content
+
+
+def sequence(*items):
+    return tlv(0x30, b"".join(items))
+
+
+def integer(value):
+    return tlv(0x02, value.to_bytes(value.bit_length() // 8 + 1, "big"))
+
+
+def algorithm(oid):
+    return sequence(tlv(0x06, oid), tlv(0x05, b""))
+
+
+def certificate(name, key, issuer_name, issuer_key, ca):
+    now = datetime.datetime(2020, 1, 1)
+    return (x509.CertificateBuilder()
+            .subject_name(x509.Name([x509.NameAttribute(NameOID.COMMON_NAME, name)]))
+            .issuer_name(x509.Name([x509.NameAttribute(NameOID.COMMON_NAME, issuer_name)]))
+            .public_key(key.public_key())
+            .serial_number(x509.random_serial_number())
+            .not_valid_before(now)
+            .not_valid_after(now + datetime.timedelta(days=36500))
+            .add_extension(x509.BasicConstraints(ca=ca, path_length=None), critical=True)
+            .sign(issuer_key, hashes.SHA256()))
+
+
+def image():
+    nt = 0x40
+    dos = struct.pack("<H58xI", 0x5a4d, nt)
+    file_header = struct.pack("<HHIIIHH", 0x8664, 1, 0, 0, 0, 240, 0x22)
+    optional_header = struct.pack("<HBBIIIII", 0x20b, 0, 0, 0x200, 0, 0, 0x1000, 0x1000)
+    optional_header += struct.pack("<QIIHHHHHHIIIIHHQQQQII", 0, 0x1000, 0x200, 0, 0, 0, 0, 0, 0, 0,
+                                   0x2000, 0x200, 0x12345678, 10, 0, 0, 0, 0, 0, 0, 16)
+    optional_header += bytes(16 * 8)
+    section = struct.pack("<8sIIIIIIHHI", b".text", 0x200, 0x1000, 0x200, 0x200, 0, 0, 0, 0, 0x60000020)
+    headers = dos + b"PE\0\0" + file_header + optional_header + section
+    text = bytes([0xc3]) + bytes(range(256)) * 2
+    return bytearray(headers.ljust(0x200, b"\0") + text[:0x200])
+
+
+def authenticode_hash(data, certificate_size):
+    nt = struct.unpack_from("<I", data, 0x3c)[0]
+    optional = nt + 24
+    checksum = optional + 64
+    security = optional + 112 + 4 * 8
+    size_of_headers = struct.unpack_from("<I", data, optional + 60)[0]
+    sha256 = hashlib.sha256()
+    sha256.update(data[:checksum])
+    sha256.update(data[checksum + 4:security])
+    sha256.update(data[security + 8:size_of_headers])
+    # A single section, right after the headers
+    sha256.update(data[size_of_headers:len(data) - certificate_size])
+    return sha256.digest()
+
+
+def sign(data, cert, key):
+    indirect_data = sequence(
+        sequence(tlv(0x06, OID_SPC_PE_IMAGE_DATA), sequence(tlv(0x03, b"\0"))),
+        sequence(algorithm(OID_SHA256), tlv(0x04, authenticode_hash(data, 0))))
+    # The content is signed without its tag and length
+    content_digest = hashlib.sha256(indirect_data[2:] if indirect_data[1] < 0x80
+                                    else indirect_data[2 + (indirect_data[1] & 0x7f):]).digest()
+    attributes = (sequence(tlv(0x06, OID_CONTENT_TYPE), tlv(0x31, tlv(0x06, OID_SPC_INDIRECT_DATA)))
+                  + sequence(tlv(0x06, OID_MESSAGE_DIGEST), tlv(0x31, tlv(0x04, content_digest))))
+    signature = key.sign(tlv(0x31, attributes), padding.PKCS1v15(), hashes.SHA256())
+    issuer = cert.issuer.public_bytes()
+    signer_info = sequence(integer(1), sequence(issuer, integer(cert.serial_number)), algorithm(OID_SHA256),
+                           tlv(0xa0, attributes), algorithm(OID_RSA_ENCRYPTION), tlv(0x04, signature))
+    signed_data = sequence(integer(1), tlv(0x31, algorithm(OID_SHA256)),
+                           sequence(tlv(0x06, OID_SPC_INDIRECT_DATA), tlv(0xa0, indirect_data)),
+                           tlv(0xa0, cert.public_bytes(serialization.Encoding.DER)),
+                           tlv(0x31, signer_info))
+    content_info = sequence(tlv(0x06, OID_SIGNED_DATA), tlv(0xa0, signed_data))
+
+    # WIN_CERTIFICATE with WIN_CERT_TYPE_PKCS_SIGNED_DATA, padded to 8 bytes
+    win_certificate = struct.pack("<IHH", 8 + len(content_info), 0x0200, 0x0002) + content_info
+    win_certificate = win_certificate.ljust((len(win_certificate) + 7) & ~7, b"\0")
+    signed = bytearray(data)
+    struct.pack_into("<II", signed, 0x40 + 24 + 112 + 4 * 8, len(data), len(win_certificate))
+    return signed + win_certificate
+
+
+def main():
+    root_key = rsa.generate_private_key(public_exponent=65537, key_size=2048)
+    signer_key = rsa.generate_private_key(public_exponent=65537, key_size=2048)
+    other_key = rsa.generate_private_key(public_exponent=65537, key_size=2048)
+    root = certificate("Root", root_key, "Root", root_key, True)
+    signer = certificate("Signer", signer_key, "Root", root_key, False)
+    other = certificate("Other", other_key, "Other", other_key, True)
+
+    data = image()
+    files = {
+        "unsigned.efi": data,
+        "signed.efi": sign(data, signer, signer_key),
+        "image_root.der": root.public_bytes(serialization.Encoding.DER),
+        "image_signer.der": signer.public_bytes(serialization.Encoding.DER),
+        "image_other.der": other.public_bytes(serialization.Encoding.DER),
+    }
+    for name, content in files.items():
+        with open(name, "wb") as f:
+            f.write(content)
+
+
+if __name__ == "__main__":
+    main()
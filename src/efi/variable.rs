@@ -29,162 +29,506 @@ pub const GLOBAL_VARIABLE_GUID: Guid = Guid::from_fields(
     0x8BE4DF61, 0x93CA, 0x11D2, 0xAA, 0x0D, &[0x00, 0xE0, 0x98, 0x03, 0x2B, 0x8C]
 );
 
-pub const MAX_VARIABLE_NAME: usize = 32;
+// The vendor GUID of the "MTC" variable holding the high 32 bits of the monotonic count
+pub const MTC_GUID: Guid = Guid::from_fields(
+    0xEB704011, 0x1402, 0x11D3, 0x8E, 0x77, &[0x00, 0xA0, 0xC9, 0x69, 0x72, 0x3B]
+);
+
+
+// A size given at build time, in decimal or in hex after 0x. 0 when it is not a number.
+const fn build_setting(value: Option<&str>, default: usize) -> usize {
+    let digits = match value {
+      Some(value) => value.as_bytes(),
+      None => return default,
+    };
+    let (radix, mut index) = if digits.len() > 2 && digits[0] == b'0' && (digits[1] == b'x' || digits[1] == b'X') {
+      (16, 2)
+    } else {
+      (10, 0)
+    };
+    if index == digits.len() {
+      return 0;
+    }
+    let mut size = 0;
+    while index < digits.len() {
+      let digit = match digits[index] {
+        b'0' ..= b'9' => digits[index] - b'0',
+        b'a' ..= b'f' => digits[index] - b'a' + 10,
+        b'A' ..= b'F' => digits[index] - b'A' + 10,
+        _ => return 0,
+      } as usize;
+      if digit >= radix {
+        return 0;
+      }
+      size = size * radix + digit;
+      index += 1;
+    }
+    size
+}
+
+/// Limit on the size of a variable: its UCS-2 name, terminator included, and its data.
+/// 64 KiB unless set with MAX_VARIABLE_SIZE at build time.
+pub const MAX_VARIABLE_SIZE: usize = build_setting(option_env!("MAX_VARIABLE_SIZE"), 0x10000);
+
+/// Size of the pages holding all the variables, allocated once at boot.
+/// 256 KiB unless set with VARIABLE_STORE_SIZE at build time.
+pub const VARIABLE_STORE_SIZE: usize = build_setting(option_env!("VARIABLE_STORE_SIZE"), 0x40000);
+
+// The store is whole pages, and a variable of the largest size fits in it
+const _: [(); 0 - !(VARIABLE_STORE_SIZE > 0
+    && VARIABLE_STORE_SIZE % 0x1000 == 0
+    && MAX_VARIABLE_SIZE > 0
+    && MAX_VARIABLE_SIZE <= VARIABLE_STORE_SIZE) as usize] = [];
 
-pub const MAX_VARIABLE_DATA: usize = 2048;
+pub const AUTH_INFO_SIZE: usize = 48;
 
+/// What is kept with a variable written with time based authentication: the time
+/// stamp of its last write and, outside the Secure Boot key hierarchy, the SHA-256
+/// digest of the certificate allowed to write it.
 #[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Default, PartialEq)]
+pub struct AuthInfo {
+    pub timestamp: [u8; 16],
+    pub signer: [u8; 32],
+}
+
+impl AuthInfo {
+    fn to_bytes(&self) -> [u8; AUTH_INFO_SIZE] {
+        let mut bytes = [0u8; AUTH_INFO_SIZE];
+        bytes[.. 16].copy_from_slice(&self.timestamp);
+        bytes[16 ..].copy_from_slice(&self.signer);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> AuthInfo {
+        let mut auth = AuthInfo::default();
+        auth.timestamp.copy_from_slice(&bytes[.. 16]);
+        auth.signer.copy_from_slice(&bytes[16 .. AUTH_INFO_SIZE]);
+        auth
+    }
+}
+
+// Variables are kept one after the other in the storage, in insertion order:
+// this header, then the name (UCS-2, without its terminator) and the data.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
 struct VariableItem {
-    name: [u8; MAX_VARIABLE_NAME],
-    guid: [u8; 16],
     attributes: u32,
-    data_size: usize,
-    data: [u8; MAX_VARIABLE_DATA],
+    name_size: u32,
+    data_size: u32,
+    reserved: u32,
+    guid: [u8; 16],
+    auth: AuthInfo,
+}
+
+const VARIABLE_ITEM_SIZE: usize = size_of::<VariableItem>();
+
+const VARIABLE_ITEM_ALIGNMENT: usize = 8;
+
+fn item_size(name_size: usize, data_size: usize) -> usize {
+    (VARIABLE_ITEM_SIZE + name_size + data_size + VARIABLE_ITEM_ALIGNMENT - 1) & !(VARIABLE_ITEM_ALIGNMENT - 1)
+}
+
+// What counts against the maximum variable size
+fn variable_size(name_size: usize, data_size: usize) -> usize {
+    name_size + size_of::<Char16>() + data_size
+}
+
+/// Storage keeping the non-volatile variables across reboots
+pub trait NvStore {
+    /// Pass every variable in the store to restore, in the order they were written.
+    /// Variables are read into buffer, those whose name and data do not fit are skipped.
+    fn load(&mut self, buffer: &mut [u8], restore: &mut dyn FnMut(&[u8], &[u8; 16], u32, &[u8]));
+    /// Save a variable, whose data is given in pieces, or delete it when data is empty
+    fn write_variable(&mut self, name: &[u8], guid: &[u8; 16], attributes: u32, data: &[&[u8]]) -> Status;
+    /// Size of the storage, and how much of it is left for new variables
+    fn size(&self) -> u64;
+    fn free_space(&self) -> u64;
+    /// Update the addresses used by the store, once the OS has mapped the runtime regions
+    fn relocate(&mut self, convert: &dyn Fn(u64) -> Option<u64>) {}
 }
 
-impl Default for VariableItem {
-  fn default() -> VariableItem {
-    VariableItem {
-      name: [0; MAX_VARIABLE_NAME],
-      guid: [0; 16],
-      attributes: 0,
-      data_size: 0,
-      data: [0; MAX_VARIABLE_DATA],
+/// Convert a trait object pointer, both the address of the object and the
+/// address of its vtable. It is left alone unless both are converted.
+pub fn relocate_trait_object<T: ?Sized>(pointer: &mut *mut T, convert: &dyn Fn(u64) -> Option<u64>) {
+    assert_eq!(size_of::<*mut T>(), 2 * size_of::<u64>());
+    let parts = unsafe { &mut *(pointer as *mut *mut T as *mut [u64; 2]) };
+    if let (Some(object), Some(vtable)) = (convert(parts[0]), convert(parts[1])) {
+        *parts = [object, vtable];
     }
-  }
 }
 
-const MAX_VARIABLE_ITEM: usize = 64;
+// Save a variable to the store, time based authenticated ones behind their AuthInfo
+fn write_nv_variable (
+    nv_store: &mut Option<&'static mut (dyn NvStore + Send)>,
+    name: &[u8],
+    guid: &[u8; 16],
+    attributes: u32,
+    data: &[u8],
+    auth: &AuthInfo,
+) -> Status {
+    let nv_store = match nv_store.as_mut() {
+      Some(nv_store) => nv_store,
+      None => return Status::SUCCESS,
+    };
+    if data.is_empty() || (attributes & efi::VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS) == 0 {
+      return nv_store.write_variable(name, guid, attributes, &[data]);
+    }
+    nv_store.write_variable(name, guid, attributes, &[&auth.to_bytes(), data])
+}
 
-#[repr(C)]
-#[derive(Copy, Clone)]
 pub struct Variable {
-    variable_count: usize,
-    varialbe_item : [VariableItem; MAX_VARIABLE_ITEM],
+    storage: &'static mut [u8],
+    // How much of the storage the variables take
+    used: usize,
+    max_variable_size: usize,
+    nv_store: Option<&'static mut (dyn NvStore + Send)>,
 }
 
 impl Default for Variable {
   fn default() -> Variable {
     Variable {
-      variable_count: 0,
-      varialbe_item: [VariableItem{
-                        name: [0; MAX_VARIABLE_NAME],
-                        guid: [0; 16],
-                        attributes: 0,
-                        data_size: 0,
-                        data: [0; MAX_VARIABLE_DATA],
-                      } ; MAX_VARIABLE_ITEM],
+      storage: &mut [],
+      used: 0,
+      max_variable_size: MAX_VARIABLE_SIZE,
+      nv_store: None,
     }
   }
 }
 
 impl Variable {
-    pub fn get_variable (
-        &mut self,
-        var_name: *mut [u8; MAX_VARIABLE_NAME],
-        var_guid: *mut [u8; 16],
-    ) -> (Status, u32, usize, *mut [u8; MAX_VARIABLE_DATA]) {
+    /// Give the variables their storage, and the largest size allowed for one of them
+    pub fn set_storage (&mut self, storage: &'static mut [u8], max_variable_size: usize) {
+        self.storage = storage;
+        self.used = 0;
+        self.max_variable_size = max_variable_size;
+    }
 
+    pub fn get_variable (
+        &self,
+        var_name: &[u8],
+        var_guid: &[u8; 16],
+    ) -> (Status, u32, &[u8]) {
         match self.find_variable (var_name, var_guid) {
-          Some(var_item) => {
-            unsafe {
-              return (Status::SUCCESS, (*var_item).attributes, (*var_item).data_size, &mut (*var_item).data as *mut [u8; MAX_VARIABLE_DATA]);
-            }
+          Some(offset) => {
+            let item = self.item(offset);
+            (Status::SUCCESS, item.attributes, self.data(offset, &item))
           },
-          None => { return (Status::NOT_FOUND, 0, 0, core::ptr::null_mut()) },
+          None => (Status::NOT_FOUND, 0, &[]),
         }
     }
 
     pub fn set_variable (
         &mut self,
-        var_name: *mut [u8; MAX_VARIABLE_NAME],
-        var_guid: *mut [u8; 16],
+        var_name: &[u8],
+        var_guid: &[u8; 16],
         attributes: u32,
+        data: &[u8],
+    ) -> Status {
+        self.update_variable (var_name, var_guid, attributes, data, &AuthInfo::default())
+    }
+
+    /// Set a variable along with its AuthInfo, once the write has been authenticated
+    pub fn set_authenticated_variable (
+        &mut self,
+        var_name: &[u8],
+        var_guid: &[u8; 16],
+        attributes: u32,
+        data: &[u8],
+        auth: &AuthInfo,
+    ) -> Status {
+        self.update_variable (var_name, var_guid, attributes, data, auth)
+    }
+
+    /// Append to the data of a variable, once the write has been authenticated. fill
+    /// is given the existing data and room for size more bytes, and returns how many
+    /// of them it used. Nothing is written when it used none and auth is unchanged.
+    pub fn append_variable (
+        &mut self,
+        var_name: &[u8],
+        var_guid: &[u8; 16],
         size: usize,
-        data: *mut [u8; MAX_VARIABLE_DATA],
-    ) -> (Status) {
-        assert!(size <= MAX_VARIABLE_DATA);
+        auth: &AuthInfo,
+        fill: &mut dyn FnMut(&[u8], &mut [u8]) -> usize,
+    ) -> Status {
+        let offset = match self.find_variable (var_name, var_guid) {
+          Some(offset) => offset,
+          None => return Status::NOT_FOUND,
+        };
+        let item = self.item(offset);
+        let name_size = item.name_size as usize;
+        let data_size = item.data_size as usize;
+        let old_size = item_size(name_size, data_size);
+        let reserved_size = item_size(name_size, data_size + size);
+        if reserved_size - old_size > self.storage.len() - self.used {
+          return Status::OUT_OF_RESOURCES;
+        }
+
+        // Make room after the existing data, then give back what fill did not use
+        self.resize(offset, old_size, reserved_size);
+        let start = offset + VARIABLE_ITEM_SIZE + name_size;
+        let (existing, new) = self.storage[start .. start + data_size + size].split_at_mut(data_size);
+        let added = core::cmp::min(fill(existing, new), size);
+        let new_size = item_size(name_size, data_size + added);
+        self.resize(offset, reserved_size, new_size);
+        if added == 0 && item.auth == *auth {
+          return Status::SUCCESS;
+        }
+        if variable_size(name_size, data_size + added) > self.max_variable_size {
+          self.resize(offset, new_size, old_size);
+          return Status::INVALID_PARAMETER;
+        }
+
+        if (item.attributes & efi::VARIABLE_NON_VOLATILE) != 0 {
+          let status = write_nv_variable(&mut self.nv_store, var_name, var_guid, item.attributes,
+                                         &self.storage[start .. start + data_size + added], auth);
+          if status != Status::SUCCESS {
+            self.resize(offset, new_size, old_size);
+            return status;
+          }
+        }
+        self.set_item(offset, &VariableItem { data_size: (data_size + added) as u32, auth: *auth, ..item });
+        Status::SUCCESS
+    }
+
+    pub fn get_auth_info (
+        &self,
+        var_name: &[u8],
+        var_guid: &[u8; 16],
+    ) -> Option<AuthInfo> {
         match self.find_variable (var_name, var_guid) {
-          Some(var_item) => {
-            if attributes == 0 || size == 0 || data == core::ptr::null_mut() {
-              // delete the variable
-              match self.find_last_variable () {
-                Some(last_var_item) => {
-                  unsafe { *var_item = *last_var_item; }
-                },
-                None => {},
+          Some(offset) => Some(self.item(offset).auth),
+          None => None,
+        }
+    }
+
+    fn update_variable (
+        &mut self,
+        var_name: &[u8],
+        var_guid: &[u8; 16],
+        attributes: u32,
+        data: &[u8],
+        auth: &AuthInfo,
+    ) -> Status {
+        let delete = attributes == 0 || data.is_empty();
+        if !delete && variable_size(var_name.len(), data.len()) > self.max_variable_size {
+          return Status::INVALID_PARAMETER;
+        }
+        let new_size = item_size(var_name.len(), data.len());
+        let free = self.storage.len() - self.used;
+
+        match self.find_variable (var_name, var_guid) {
+          Some(offset) => {
+            let item = self.item(offset);
+            if !delete && attributes != item.attributes {
+              return Status::INVALID_PARAMETER;
+            }
+            if !delete && self.data(offset, &item) == data && item.auth == *auth {
+              return Status::SUCCESS;
+            }
+            let old_size = item_size(item.name_size as usize, item.data_size as usize);
+            if !delete && new_size > old_size + free {
+              return Status::OUT_OF_RESOURCES;
+            }
+            if (item.attributes & efi::VARIABLE_NON_VOLATILE) != 0 {
+              let status = write_nv_variable(&mut self.nv_store, var_name, var_guid, attributes, if delete { &[] } else { data }, auth);
+              if status != Status::SUCCESS {
+                return status;
               }
-              self.variable_count = self.variable_count - 1;
-              return (Status::SUCCESS);
+            }
+
+            if delete {
+              // delete the variable, keeping the others in insertion order
+              self.resize(offset, old_size, 0);
             } else {
               // update this variable.
-              unsafe {
-                (*var_item).attributes = attributes;
-                (*var_item).data_size = size;
-                (*var_item).data = *data;
-              }
-              return (Status::SUCCESS);
+              self.resize(offset, old_size, new_size);
+              self.write_variable_item(offset, var_name, var_guid, attributes, data, auth);
             }
+            Status::SUCCESS
           },
           None => {
-            if attributes == 0 || size == 0 || data == core::ptr::null_mut() {
-              return (Status::SUCCESS);
-            } else {
-              // add this variable.
-              match self.find_new_variable () {
-                Some(new_var_item) => {
-                  unsafe {
-                    (*new_var_item).name = *var_name;
-                    (*new_var_item).guid = *var_guid;
-                    (*new_var_item).attributes = attributes;
-                    (*new_var_item).data_size = size;
-                    (*new_var_item).data = *data;
-                  }
-                },
-                None => {},
+            if delete {
+              return Status::SUCCESS;
+            }
+            if new_size > free {
+              return Status::OUT_OF_RESOURCES;
+            }
+            if (attributes & efi::VARIABLE_NON_VOLATILE) != 0 {
+              let status = write_nv_variable(&mut self.nv_store, var_name, var_guid, attributes, data, auth);
+              if status != Status::SUCCESS {
+                return status;
               }
-              return (Status::SUCCESS);
             }
+
+            // add this variable.
+            let offset = self.used;
+            self.used += new_size;
+            self.write_variable_item(offset, var_name, var_guid, attributes, data, auth);
+            Status::SUCCESS
           },
         }
+    }
+
+    /// Attach the storage of the non-volatile variables, and load them from it
+    pub fn set_nv_store (&mut self, nv_store: &'static mut (dyn NvStore + Send)) {
+        self.nv_store = None;
+
+        // Stored variables are read at the end of the storage, and added at its start
+        let size = self.storage.len();
+        let buffer_size = core::cmp::min(self.max_variable_size + AUTH_INFO_SIZE, size - self.used);
+        let storage = self.storage.as_mut_ptr();
+        let buffer = unsafe { core::slice::from_raw_parts_mut(storage.add(size - buffer_size), buffer_size) };
+        self.storage = unsafe { core::slice::from_raw_parts_mut(storage, size - buffer_size) };
+
+        nv_store.load(buffer, &mut |name, guid, attributes, data| {
+          let authenticated = (attributes & efi::VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS) != 0;
+          if (attributes & efi::VARIABLE_NON_VOLATILE) == 0 || (authenticated && data.len() < AUTH_INFO_SIZE) {
+            log!("variable: skip a stored variable\n");
+            return;
+          }
+          let (auth, data) = if authenticated {
+            (AuthInfo::from_bytes(data), &data[AUTH_INFO_SIZE ..])
+          } else {
+            (AuthInfo::default(), data)
+          };
+          // A variable written again replaces the one loaded before
+          self.set_variable(name, guid, 0, &[]);
+          if self.update_variable(name, guid, attributes, data, &auth) != Status::SUCCESS {
+            log!("variable: skip a stored variable\n");
+          }
+        });
+
+        self.storage = unsafe { core::slice::from_raw_parts_mut(storage, size) };
+        self.nv_store = Some(nv_store);
+    }
 
-        (Status::NOT_FOUND)
+    pub fn relocate (&mut self, convert: &dyn Fn(u64) -> Option<u64>) {
+        let size = self.storage.len();
+        if let Some(storage) = convert(self.storage.as_mut_ptr() as u64) {
+          self.storage = unsafe { core::slice::from_raw_parts_mut(storage as *mut u8, size) };
+        }
+        if let Some(nv_store) = self.nv_store.take() {
+          nv_store.relocate(convert);
+          let mut nv_store = nv_store as *mut (dyn NvStore + Send);
+          relocate_trait_object(&mut nv_store, convert);
+          self.nv_store = Some(unsafe { &mut *nv_store });
+        }
+    }
+
+    /// Get the variable following the named one, an empty name starting the enumeration
+    pub fn get_next_variable_name (
+        &self,
+        var_name: &[u8],
+        var_guid: &[u8; 16],
+        runtime: bool,
+    ) -> (Status, &[u8], [u8; 16]) {
+        let mut offset = if var_name.is_empty() {
+          0
+        } else {
+          match self.find_variable (var_name, var_guid) {
+            Some(offset) => self.next_offset(offset),
+            None => { return (Status::INVALID_PARAMETER, &[], [0; 16]); },
+          }
+        };
+
+        while offset < self.used {
+          let item = self.item(offset);
+          // Only runtime variables are visible after ExitBootServices
+          if !runtime || (item.attributes & efi::VARIABLE_RUNTIME_ACCESS) != 0 {
+            return (Status::SUCCESS, self.name(offset, &item), item.guid);
+          }
+          offset = self.next_offset(offset);
+        }
+
+        (Status::NOT_FOUND, &[], [0; 16])
+    }
+
+    /// Get the maximum storage, the remaining storage and the maximum size of a variable.
+    /// Non-volatile variables are also limited by the space left in the store.
+    pub fn query_variable_info (&self, non_volatile: bool) -> (u64, u64, u64) {
+        let max_storage = self.storage.len() as u64;
+        let remaining_storage = (self.storage.len() - self.used) as u64;
+        let max_variable_size = self.max_variable_size as u64;
+        match self.nv_store.as_ref() {
+          Some(nv_store) if non_volatile => (
+            core::cmp::min(max_storage, nv_store.size()),
+            core::cmp::min(remaining_storage, nv_store.free_space()),
+            core::cmp::min(max_variable_size, nv_store.size()),
+          ),
+          _ => (max_storage, remaining_storage, max_variable_size),
+        }
+    }
+
+    fn item (&self, offset: usize) -> VariableItem {
+        let bytes = &self.storage[offset .. offset + VARIABLE_ITEM_SIZE];
+        unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const VariableItem) }
+    }
+
+    fn set_item (&mut self, offset: usize, item: &VariableItem) {
+        let bytes = &mut self.storage[offset .. offset + VARIABLE_ITEM_SIZE];
+        unsafe { core::ptr::write_unaligned(bytes.as_mut_ptr() as *mut VariableItem, *item) }
+    }
+
+    fn name (&self, offset: usize, item: &VariableItem) -> &[u8] {
+        let start = offset + VARIABLE_ITEM_SIZE;
+        &self.storage[start .. start + item.name_size as usize]
+    }
+
+    fn data (&self, offset: usize, item: &VariableItem) -> &[u8] {
+        let start = offset + VARIABLE_ITEM_SIZE + item.name_size as usize;
+        &self.storage[start .. start + item.data_size as usize]
+    }
+
+    fn next_offset (&self, offset: usize) -> usize {
+        let item = self.item(offset);
+        offset + item_size(item.name_size as usize, item.data_size as usize)
     }
 
     fn find_variable (
-        &mut self,
-        var_name: *mut [u8; MAX_VARIABLE_NAME],
-        var_guid: *mut [u8; 16],
-    ) -> Option<*mut VariableItem> {
-        for var_index in 0 .. self.variable_count {
-          let var_item : &mut VariableItem = &mut self.varialbe_item[var_index] as &mut VariableItem;
-          if unsafe {*var_name} == var_item.name && unsafe {*var_guid} == var_item.guid {
-            return Some(var_item as *mut VariableItem);
+        &self,
+        var_name: &[u8],
+        var_guid: &[u8; 16],
+    ) -> Option<usize> {
+        let mut offset = 0;
+        while offset < self.used {
+          let item = self.item(offset);
+          if item.guid == *var_guid && self.name(offset, &item) == var_name {
+            return Some(offset);
           }
+          offset = self.next_offset(offset);
         }
         None
     }
-    fn find_last_variable (
-        &mut self,
-    ) -> Option<*mut VariableItem> {
-        if self.variable_count == 0 {
-          let var_item : &mut VariableItem = &mut self.varialbe_item[self.variable_count - 1] as &mut VariableItem;
-          Some(var_item as *mut VariableItem)
-        } else {
-          None
-        }
+
+    // Change the size of the item at offset, moving the ones after it
+    fn resize (&mut self, offset: usize, old_size: usize, new_size: usize) {
+        self.storage.copy_within(offset + old_size .. self.used, offset + new_size);
+        self.used = self.used + new_size - old_size;
     }
-    fn find_new_variable (
+
+    fn write_variable_item (
         &mut self,
-    ) -> Option<*mut VariableItem> {
-        if self.variable_count < MAX_VARIABLE_ITEM {
-          let var_item : &mut VariableItem = &mut self.varialbe_item[self.variable_count] as &mut VariableItem;
-          self.variable_count = self.variable_count + 1;
-          Some(var_item as *mut VariableItem)
-        } else {
-          None
-        }
+        offset: usize,
+        var_name: &[u8],
+        var_guid: &[u8; 16],
+        attributes: u32,
+        data: &[u8],
+        auth: &AuthInfo,
+    ) {
+        let item = VariableItem {
+          attributes,
+          name_size: var_name.len() as u32,
+          data_size: data.len() as u32,
+          reserved: 0,
+          guid: *var_guid,
+          auth: *auth,
+        };
+        self.set_item(offset, &item);
+        let start = offset + VARIABLE_ITEM_SIZE;
+        self.storage[start .. start + var_name.len()].copy_from_slice(var_name);
+        let start = start + var_name.len();
+        self.storage[start .. start + data.len()].copy_from_slice(data);
     }
 
     pub fn new() -> Variable {
@@ -194,3 +538,135 @@ impl Variable {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::{build_setting, item_size, relocate_trait_object, Variable, MAX_VARIABLE_SIZE};
+    use r_efi::efi::{self, Status};
+
+    fn new_variable(storage_size: usize, max_variable_size: usize) -> Variable {
+        let mut variable = Variable::new();
+        variable.set_storage(Box::leak(vec![0u8; storage_size].into_boxed_slice()), max_variable_size);
+        variable
+    }
+
+    // Names are kept in UCS-2
+    fn name(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(|c| c.to_le_bytes().to_vec()).collect()
+    }
+
+    fn set(variable: &mut Variable, s: &str, attributes: u32, size: usize) -> Status {
+        variable.set_variable(&name(s), &[1; 16], attributes, &vec![0xa5; size])
+    }
+
+    #[test]
+    fn test_build_setting() {
+        assert_eq!(build_setting(None, 0x10000), 0x10000);
+        assert_eq!(build_setting(Some("524288"), 0x10000), 0x80000);
+        assert_eq!(build_setting(Some("0x80000"), 0x10000), 0x80000);
+        assert_eq!(build_setting(Some("0X7fFf"), 0x10000), 0x7fff);
+        assert_eq!(build_setting(Some(""), 0x10000), 0);
+        assert_eq!(build_setting(Some("0x"), 0x10000), 0);
+        assert_eq!(build_setting(Some("64k"), 0x10000), 0);
+        assert_eq!(build_setting(Some("12a"), 0x10000), 0);
+    }
+
+    #[test]
+    fn test_relocate_trait_object() {
+        let mut object = 0u32;
+        let mut pointer = &mut object as &mut dyn core::fmt::Debug as *mut dyn core::fmt::Debug;
+        let parts = unsafe { *(&pointer as *const *mut dyn core::fmt::Debug as *const [u64; 2]) };
+
+        // Both halves are converted, or none
+        let object_address = parts[0];
+        relocate_trait_object(&mut pointer, &|address| if address == object_address { Some(0x1000) } else { None });
+        assert_eq!(unsafe { *(&pointer as *const *mut dyn core::fmt::Debug as *const [u64; 2]) }, parts);
+
+        relocate_trait_object(&mut pointer, &|address| Some(address + 0x1000));
+        assert_eq!(
+            unsafe { *(&pointer as *const *mut dyn core::fmt::Debug as *const [u64; 2]) },
+            [parts[0] + 0x1000, parts[1] + 0x1000]
+        );
+    }
+
+    #[test]
+    fn test_get_next_variable_name() {
+        let mut variable = new_variable(0x1000, MAX_VARIABLE_SIZE);
+        let attributes = efi::VARIABLE_BOOTSERVICE_ACCESS | efi::VARIABLE_RUNTIME_ACCESS;
+        assert_eq!(set(&mut variable, "a", attributes, 1), Status::SUCCESS);
+        assert_eq!(set(&mut variable, "b", efi::VARIABLE_BOOTSERVICE_ACCESS, 1), Status::SUCCESS);
+        assert_eq!(set(&mut variable, "c", attributes, 1), Status::SUCCESS);
+
+        let mut var_name = Vec::new();
+        let mut var_guid = [0; 16];
+        let mut names = Vec::new();
+        for _ in 0..3 {
+            let (status, next_name, next_guid) = variable.get_next_variable_name(&var_name, &var_guid, false);
+            assert_eq!(status, Status::SUCCESS);
+            assert_eq!(next_guid, [1; 16]);
+            var_name = next_name.to_vec();
+            var_guid = next_guid;
+            names.push(var_name.clone());
+        }
+        assert_eq!(names, [name("a"), name("b"), name("c")]);
+        assert_eq!(variable.get_next_variable_name(&var_name, &var_guid, false).0, Status::NOT_FOUND);
+        assert_eq!(variable.get_next_variable_name(&name("d"), &[1; 16], false).0, Status::INVALID_PARAMETER);
+
+        // Deleting keeps the insertion order, boot service variables are hidden at runtime
+        assert_eq!(set(&mut variable, "a", 0, 0), Status::SUCCESS);
+        assert_eq!(variable.get_next_variable_name(&[], &[0; 16], false).1, &name("b")[..]);
+        assert_eq!(variable.get_next_variable_name(&[], &[0; 16], true).1, &name("c")[..]);
+    }
+
+    #[test]
+    fn test_query_variable_info() {
+        let mut variable = new_variable(0x1000, 0x400);
+        let (max_storage, remaining, max_size) = variable.query_variable_info(false);
+        assert_eq!((max_storage, remaining, max_size), (0x1000, 0x1000, 0x400));
+
+        assert_eq!(set(&mut variable, "a", efi::VARIABLE_BOOTSERVICE_ACCESS, 1), Status::SUCCESS);
+        assert_eq!(variable.query_variable_info(false).1, remaining - item_size(2, 1) as u64);
+
+        // The name, with its terminator, and the data count against the maximum size
+        assert_eq!(set(&mut variable, "b", efi::VARIABLE_BOOTSERVICE_ACCESS, 0x400 - 4), Status::SUCCESS);
+        assert_eq!(set(&mut variable, "c", efi::VARIABLE_BOOTSERVICE_ACCESS, 0x400 - 3), Status::INVALID_PARAMETER);
+
+        let mut i = 0;
+        while set(&mut variable, &format!("{}", i), efi::VARIABLE_BOOTSERVICE_ACCESS, 0x100) == Status::SUCCESS {
+            i += 1;
+        }
+        assert!(variable.query_variable_info(false).1 < item_size(2, 0x100) as u64);
+        assert_eq!(set(&mut variable, "full", efi::VARIABLE_BOOTSERVICE_ACCESS, 0x100), Status::OUT_OF_RESOURCES);
+        // Deleting gives the space back
+        assert_eq!(set(&mut variable, "b", 0, 0), Status::SUCCESS);
+        assert_eq!(set(&mut variable, "full", efi::VARIABLE_BOOTSERVICE_ACCESS, 0x100), Status::SUCCESS);
+    }
+
+    #[test]
+    fn test_large_variable() {
+        let mut variable = new_variable(0x40000, MAX_VARIABLE_SIZE);
+        let long_name = name(&"Long".repeat(100));
+        let data: Vec<u8> = (0..0x8000).map(|i| i as u8).collect();
+        assert_eq!(variable.set_variable(&long_name, &[2; 16], efi::VARIABLE_BOOTSERVICE_ACCESS, &data), Status::SUCCESS);
+        assert_eq!(set(&mut variable, "after", efi::VARIABLE_BOOTSERVICE_ACCESS, 3), Status::SUCCESS);
+        assert_eq!(variable.get_variable(&long_name, &[2; 16]), (Status::SUCCESS, efi::VARIABLE_BOOTSERVICE_ACCESS, &data[..]));
+
+        // Growing and shrinking it moves the variables after it
+        let bigger = vec![0x5a; 0xc000];
+        assert_eq!(variable.set_variable(&long_name, &[2; 16], efi::VARIABLE_BOOTSERVICE_ACCESS, &bigger), Status::SUCCESS);
+        assert_eq!(variable.get_variable(&name("after"), &[1; 16]).2, &[0xa5; 3]);
+        assert_eq!(variable.append_variable(&long_name, &[2; 16], 0x100, &Default::default(), &mut |existing, new| {
+            assert_eq!(existing, &bigger[..]);
+            new[.. 0x10].copy_from_slice(&[1; 0x10]);
+            0x10
+        }), Status::SUCCESS);
+        let (_, _, appended) = variable.get_variable(&long_name, &[2; 16]);
+        assert_eq!(appended.len(), bigger.len() + 0x10);
+        assert_eq!(&appended[bigger.len() ..], &[1; 0x10]);
+        assert_eq!(variable.get_variable(&name("after"), &[1; 16]).2, &[0xa5; 3]);
+
+        assert_eq!(variable.set_variable(&long_name, &[2; 16], efi::VARIABLE_BOOTSERVICE_ACCESS, &[7]), Status::SUCCESS);
+        assert_eq!(variable.get_variable(&name("after"), &[1; 16]).2, &[0xa5; 3]);
+        assert_eq!(variable.get_variable(&long_name, &[2; 16]).2, &[7]);
+    }
+}
@@ -14,7 +14,7 @@
 
 #![allow(unused)]
 
-use crate::block::SectorRead;
+use crate::block::{SectorRead, SectorWrite};
 use core::fmt;
 use efi_str::OsStr;
 
@@ -46,7 +46,7 @@ struct Fat32Header {
     _flags: u16,
     _version: u16,
     root_cluster: u32,
-    _fsinfo_sector: u16,
+    fsinfo_sector: u16,
     _backup_boot_sector: u16,
     _reserved: [u8; 12],
     _drive_no: u8,
@@ -106,6 +106,7 @@ pub struct Filesystem<'a> {
     pub data_sector_count: u32,
     pub data_cluster_count: u32,
     pub root_cluster: u32, // FAT32 only
+    pub fsinfo_sector: u32, // FAT32 only
 }
 
 #[derive(Clone, Copy)]
@@ -329,6 +330,50 @@ impl<'a> Directory<'a> {
         self.offset = 0;
         self.sector = 0;
     }
+
+    // Find an unused entry, returning its sector and index in the sector. The
+    // directory is not extended, so EndOfFile is returned when it is full.
+    fn find_free_entry(&mut self) -> Result<(u32, usize), Error> {
+        self.reset();
+        loop {
+            let sector = self.get_sector()?;
+            // The FAT12/16 root directory has a fixed size
+            if self.cluster.is_none() && sector >= self.filesystem.first_data_sector {
+                return Err(Error::EndOfFile);
+            }
+
+            let mut data: [u8; 512] = [0; 512];
+            match self.filesystem.read(u64::from(sector), &mut data) {
+                Ok(_) => {}
+                Err(_) => return Err(Error::BlockError),
+            };
+
+            for i in 0..512 / 32 {
+                if data[i * 32] == 0x0 || data[i * 32] == 0xe5 {
+                    return Ok((sector, i));
+                }
+            }
+            self.sector += 1;
+        }
+    }
+}
+
+impl<'a> File<'a> {
+    /// Get the sectors holding the start of the file, as sectors of the device rather
+    /// than of the partition. Returns how many of them were filled in.
+    pub fn get_sectors(&self, sectors: &mut [u64]) -> Result<usize, Error> {
+        let sectors_per_cluster = self.filesystem.sectors_per_cluster;
+        let count = core::cmp::min((self.size as usize + 511) / 512, sectors.len());
+        let mut cluster = self.start_cluster;
+        for i in 0..count {
+            if i > 0 && i as u32 % sectors_per_cluster == 0 {
+                cluster = self.filesystem.next_cluster(cluster)?;
+            }
+            let sector = self.filesystem.first_sector_of_cluster(cluster) + i as u32 % sectors_per_cluster;
+            sectors[i] = self.filesystem.start + u64::from(sector);
+        }
+        Ok(count)
+    }
 }
 
 pub trait Read {
@@ -471,6 +516,7 @@ impl<'a> Filesystem<'a> {
             data_sector_count: 0,
             data_cluster_count: 0,
             root_cluster: 0,
+            fsinfo_sector: 0,
         }
     }
 
@@ -510,6 +556,7 @@ impl<'a> Filesystem<'a> {
             let h32 = unsafe { &*(data.as_ptr() as *const Fat32Header) };
             self.sectors_per_fat = h32.sectors_per_fat;
             self.root_cluster = h32.root_cluster;
+            self.fsinfo_sector = u32::from(h32.fsinfo_sector);
         } else {
             self.sectors_per_fat = u32::from(h.legacy_sectors_per_fat);
         }
@@ -794,6 +841,185 @@ impl<'a> Filesystem<'a> {
             }
         }
     }
+
+    fn write(&self, writer: &dyn SectorWrite, sector: u64, data: &mut [u8]) -> Result<(), Error> {
+        if self.start + sector > self.last {
+            return Err(Error::BlockError);
+        }
+        match writer.write(self.start + sector, data) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::BlockError),
+        }
+    }
+
+    // The sector of the first FAT and the offset in it of the entry for a cluster
+    fn fat_entry_location(&self, cluster: u32) -> Result<(u32, usize), Error> {
+        let fat_offset = match self.fat_type {
+            FatType::FAT16 => cluster * 2,
+            FatType::FAT32 => cluster * 4,
+            _ => return Err(Error::Unsupported),
+        };
+        Ok((
+            self.first_fat_sector + (fat_offset / self.bytes_per_sector),
+            (fat_offset % self.bytes_per_sector) as usize,
+        ))
+    }
+
+    // Find a run of free clusters, returning the first one
+    fn find_free_clusters(&self, count: u32) -> Result<u32, Error> {
+        let last_cluster = self.data_sector_count / self.sectors_per_cluster + 1;
+        let mut data: [u8; 512] = [0; 512];
+        let mut loaded_sector = None;
+        let mut run_start = 0;
+        let mut run_length = 0;
+
+        for cluster in 2..=last_cluster {
+            let (sector, offset) = self.fat_entry_location(cluster)?;
+            if loaded_sector != Some(sector) {
+                match self.read(u64::from(sector), &mut data) {
+                    Ok(_) => {}
+                    Err(_) => return Err(Error::BlockError),
+                };
+                loaded_sector = Some(sector);
+            }
+
+            let entry = match self.fat_type {
+                FatType::FAT16 => u32::from(u16::from_le_bytes([data[offset], data[offset + 1]])),
+                _ => {
+                    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+                        & 0x0fff_ffff
+                }
+            };
+            if entry != 0 {
+                run_length = 0;
+                continue;
+            }
+            if run_length == 0 {
+                run_start = cluster;
+            }
+            run_length += 1;
+            if run_length == count {
+                return Ok(run_start);
+            }
+        }
+        Err(Error::NotFound)
+    }
+
+    // Chain a run of clusters in every copy of the FAT
+    fn write_cluster_chain(&self, writer: &dyn SectorWrite, first: u32, count: u32) -> Result<(), Error> {
+        for copy in 0..self.fat_count {
+            let mut data: [u8; 512] = [0; 512];
+            let mut loaded_sector = None;
+
+            for cluster in first..first + count {
+                let (sector, offset) = self.fat_entry_location(cluster)?;
+                let sector = sector + copy * self.sectors_per_fat;
+                if loaded_sector != Some(sector) {
+                    if let Some(loaded_sector) = loaded_sector {
+                        self.write(writer, u64::from(loaded_sector), &mut data)?;
+                    }
+                    match self.read(u64::from(sector), &mut data) {
+                        Ok(_) => {}
+                        Err(_) => return Err(Error::BlockError),
+                    };
+                    loaded_sector = Some(sector);
+                }
+
+                let last = cluster == first + count - 1;
+                match self.fat_type {
+                    FatType::FAT16 => {
+                        let next: u16 = if last { 0xffff } else { (cluster + 1) as u16 };
+                        data[offset..offset + 2].copy_from_slice(&next.to_le_bytes());
+                    }
+                    _ => {
+                        // The top 4 bits are reserved
+                        let old = u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+                        let next = if last { 0x0fff_ffff } else { cluster + 1 };
+                        data[offset..offset + 4].copy_from_slice(&((old & 0xf000_0000) | next).to_le_bytes());
+                    }
+                }
+            }
+
+            if let Some(loaded_sector) = loaded_sector {
+                self.write(writer, u64::from(loaded_sector), &mut data)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Mark the free cluster count of FAT32 as unknown, rather than keeping it up to date
+    fn invalidate_free_count(&self, writer: &dyn SectorWrite) -> Result<(), Error> {
+        const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+        const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+        const FSINFO_FREE_COUNT_OFFSET: usize = 488;
+
+        if self.fat_type != FatType::FAT32 || self.fsinfo_sector == 0 || self.fsinfo_sector == 0xffff {
+            return Ok(());
+        }
+
+        let mut data: [u8; 512] = [0; 512];
+        match self.read(u64::from(self.fsinfo_sector), &mut data) {
+            Ok(_) => {}
+            Err(_) => return Err(Error::BlockError),
+        };
+        if u32::from_le_bytes([data[0], data[1], data[2], data[3]]) != FSINFO_LEAD_SIGNATURE
+            || u32::from_le_bytes([data[484], data[485], data[486], data[487]]) != FSINFO_STRUCT_SIGNATURE
+        {
+            return Ok(());
+        }
+        data[FSINFO_FREE_COUNT_OFFSET..FSINFO_FREE_COUNT_OFFSET + 4].copy_from_slice(&0xffff_ffffu32.to_le_bytes());
+        self.write(writer, u64::from(self.fsinfo_sector), &mut data)
+    }
+
+    /// Create a file in a directory, with a short name in the format of directory entries
+    /// (such as "NVVARS     "). The clusters of the file are allocated contiguously but not
+    /// cleared. Only FAT16 and FAT32 are supported, and the directory is not extended.
+    pub fn create_file(
+        &self,
+        writer: &dyn SectorWrite,
+        directory: &OFileDirectory,
+        name: &[u8; EFI_FAT_SHORT_NAME_LEN],
+        size: u32,
+    ) -> Result<(), Error> {
+        if self.fat_type != FatType::FAT16 && self.fat_type != FatType::FAT32 {
+            return Err(Error::Unsupported);
+        }
+        if size == 0 {
+            return Err(Error::InvalidOffset);
+        }
+
+        let mut dir = match directory.dir {
+            Some(dir) => dir,
+            None => return Err(Error::NotFound),
+        };
+        let (entry_sector, entry_index) = dir.find_free_entry()?;
+
+        let cluster_size = self.sectors_per_cluster * self.bytes_per_sector;
+        let count = (size + cluster_size - 1) / cluster_size;
+        let first = self.find_free_clusters(count)?;
+        self.write_cluster_chain(writer, first, count)?;
+        self.invalidate_free_count(writer)?;
+
+        // The entry comes last: if interrupted before, only free space is lost
+        let mut data: [u8; 512] = [0; 512];
+        match self.read(u64::from(entry_sector), &mut data) {
+            Ok(_) => {}
+            Err(_) => return Err(Error::BlockError),
+        };
+        let entry = FatDirectory {
+            name: *name,
+            flags: 0x20, // Archive
+            _unused1: [0; 8],
+            cluster_high: (first >> 16) as u16,
+            _unused2: [0; 4],
+            cluster_low: first as u16,
+            size,
+        };
+        unsafe {
+            core::ptr::write_unaligned(data.as_mut_ptr().add(entry_index * 32) as *mut FatDirectory, entry);
+        }
+        self.write(writer, u64::from(entry_sector), &mut data)
+    }
 }
 
 impl fmt::Display for Filesystem<'_> {
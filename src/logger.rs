@@ -116,6 +116,22 @@ pub fn _log(args: fmt::Arguments) {
     LOGGER.lock().write_fmt(args).unwrap();
 }
 
+#[cfg(not(test))]
+pub fn get_level() -> usize {
+    LOGGER.lock().get_level()
+}
+
+#[cfg(not(test))]
+pub fn set_level(level: usize) {
+    LOGGER.lock().set_level(level);
+}
+
+// Whether the interrupted code is writing to the log.
+#[cfg(not(test))]
+pub fn is_locked() -> bool {
+    LOGGER.try_lock().is_none()
+}
+
 #[cfg(not(test))]
 pub fn _log_ex(level: usize, mask: u64, args: fmt::Arguments) {
     if level > LOGGER.lock().get_level() {
@@ -15,6 +15,7 @@
 #![allow(unused)]
 
 #![feature(llvm_asm)]
+#![feature(global_asm)]
 #![feature(abi_x86_interrupt)]
 #![cfg_attr(not(test), no_std)]
 #![cfg_attr(not(test), no_main)]
@@ -24,10 +25,13 @@
 extern crate lazy_static;
 
 extern crate x86_64;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::tss::TaskStateSegment;
 use x86_64::{
     instructions::hlt,
-    registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    registers::control::{Cr0, Cr0Flags, Cr2, Cr4, Cr4Flags},
+    VirtAddr,
 };
 
 
@@ -45,6 +49,7 @@ use cpuio::Port;
 
 mod block;
 mod bzimage;
+mod crypto;
 mod efi;
 mod pi;
 mod fat;
@@ -54,8 +59,12 @@ mod mmio;
 mod part;
 mod pci;
 mod pe;
+mod pflash;
 mod virtio;
 mod calloc;
+mod timer;
+mod acpi;
+mod reset;
 
 #[cfg(not(test))]
 #[panic_handler]
@@ -65,18 +74,62 @@ fn panic(_info: &PanicInfo) -> ! {
     loop {}
 }
 
+// Page faults switch to a stack of their own, so that running into the guard
+// page below the firmware stack can still be reported.
+const PAGE_FAULT_STACK_INDEX: u16 = 0;
+const PAGE_FAULT_STACK_SIZE: usize = 0x8000;
+
+static mut PAGE_FAULT_STACK: [u8; PAGE_FAULT_STACK_SIZE] = [0; PAGE_FAULT_STACK_SIZE];
+
+lazy_static! {
+    static ref TSS: TaskStateSegment = {
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[PAGE_FAULT_STACK_INDEX as usize] =
+            VirtAddr::from_ptr(unsafe { &PAGE_FAULT_STACK }) + PAGE_FAULT_STACK_SIZE as u64;
+        tss
+    };
+}
+
+lazy_static! {
+    static ref GDT: (GlobalDescriptorTable, SegmentSelector, SegmentSelector) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        (gdt, code, tss)
+    };
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        unsafe {
+            idt.page_fault.set_handler_fn(page_fault_handler).set_stack_index(PAGE_FAULT_STACK_INDEX);
+        }
         idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
         idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        #[cfg(not(test))]
+        idt[timer::TIMER_VECTOR as usize].set_handler_fn(timer_interrupt_handler);
+        idt[timer::PIC_SPURIOUS_VECTOR_MASTER as usize].set_handler_fn(spurious_interrupt_handler);
+        idt[timer::PIC_SPURIOUS_VECTOR_SLAVE as usize].set_handler_fn(spurious_interrupt_handler);
+        idt[timer::LAPIC_SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
 
 extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut InterruptStackFrame, error_code: PageFaultErrorCode) {
-    log!("EXCEPTION: PAGE FAULT {:#?}\n{:#?}", error_code, stack_frame);
+    let address = Cr2::read().as_u64();
+    if address < 0x1000 {
+        log!("EXCEPTION: NULL POINTER DEREFERENCE at 0x{:x}\n", address);
+    }
+    #[cfg(not(test))]
+    {
+        if efi::is_stack_overflow(address) {
+            log!("EXCEPTION: STACK OVERFLOW at 0x{:x}\n", address);
+        }
+    }
+    #[cfg(all(not(test), feature = "guard-pages"))]
+    efi::report_guard_page_fault(address);
+    log!("EXCEPTION: PAGE FAULT at 0x{:x} {:#?}\n{:#?}", address, error_code, stack_frame);
     loop {}
 }
 
@@ -91,16 +144,28 @@ extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: &mut InterruptStac
 }
 
 #[cfg(not(test))]
-/// Reset the VM via the keyboard controller
-fn i8042_reset() -> ! {
-    log!("i8042_reset...\n");
-    loop {
-        let mut good: u8 = 0x02;
-        let mut i8042_command: Port<u8> = unsafe { Port::new(0x64) };
-        while good & 0x02 > 0 {
-            good = i8042_command.read();
-        }
-        i8042_command.write(0xFE);
+extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    timer::end_of_interrupt();
+    efi::timer_tick(timer::TIMER_PERIOD);
+}
+
+// Spurious interrupts are not acknowledged at the controller.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+}
+
+#[cfg(not(test))]
+/// Load a GDT with the TSS holding the page fault stack. The stack segment is
+/// made null, which is valid in 64-bit mode, as the selector of the previous
+/// GDT means nothing in this one.
+fn load_gdt() {
+    use x86_64::instructions::segmentation::{load_ss, set_cs};
+    use x86_64::instructions::tables::load_tss;
+
+    GDT.0.load();
+    unsafe {
+        set_cs(GDT.1);
+        load_ss(SegmentSelector(0));
+        load_tss(GDT.2);
     }
 }
 
@@ -131,10 +196,11 @@ pub extern "win64" fn _start(hob: *const c_void) -> ! {
 
     log!("Starting UEFI hob - {:p}\n", hob);
 
+    load_gdt();
     IDT.load();
     enable_sse2();
 
     efi::enter_uefi(hob);
 
-    //i8042_reset();
+    //reset::i8042_reset();
 }
@@ -150,6 +150,24 @@ pub fn print_bus() {
     }
 }
 
+#[cfg(not(test))]
+/// Get the ACPI PM I/O base of the Q35 LPC or i440FX PIIX4 PM function, if it is enabled
+pub fn get_acpi_pm_base() -> Option<u16> {
+    // ICH9 LPC: PMBASE at 0x40, ACPI_EN in ACPI_CNTL (0x44) bit 7
+    let (vendor_id, device_id) = get_device_details(0, 0x1f, 0);
+    if vendor_id == 0x8086 && device_id == 0x2918 && (pci_config_read_u8(0, 0x1f, 0, 0x44) & 0x80) != 0 {
+        return Some(pci_config_read_u16(0, 0x1f, 0, 0x40) & 0xff80);
+    }
+
+    // PIIX4 PM: PMBA at 0x40, PMIOSE in PMREGMISC (0x80) bit 0
+    let (vendor_id, device_id) = get_device_details(0, 0x01, 3);
+    if vendor_id == 0x8086 && device_id == 0x7113 && (pci_config_read_u8(0, 0x01, 3, 0x80) & 0x01) != 0 {
+        return Some(pci_config_read_u16(0, 0x01, 3, 0x40) & 0xffc0);
+    }
+
+    None
+}
+
 #[cfg(not(test))]
 pub fn search_bus(target_vendor_id: u16, target_device_id: u16) -> Option<PciDevice> {
     for device in 0..MAX_DEVICES {
@@ -0,0 +1,174 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+// Intel command set of the CFI flash emulated by QEMU (pflash_cfi01)
+const CMD_WRITE_BYTE: u8 = 0x10;
+const CMD_BLOCK_ERASE: u8 = 0x20;
+const CMD_BLOCK_ERASE_CONFIRM: u8 = 0xd0;
+const CMD_CLEAR_STATUS: u8 = 0x50;
+const CMD_READ_STATUS: u8 = 0x70;
+const CMD_READ_ARRAY: u8 = 0xff;
+
+const STATUS_READY: u8 = 0x80;
+const STATUS_ERASE_ERROR: u8 = 0x20;
+const STATUS_PROGRAM_ERROR: u8 = 0x10;
+const STATUS_CLEARED: u8 = 0x00;
+
+// Both are immediate on QEMU, real parts take up to a few seconds to erase.
+const STATUS_POLL_LIMIT: u32 = 100_000_000;
+
+#[derive(Debug)]
+pub enum Error {
+    ProgramFailed,
+    EraseFailed,
+    Timeout,
+}
+
+/// A device that stores bytes in erasable blocks, where a write can only clear bits
+pub trait FlashDevice {
+    fn size(&self) -> u64;
+    fn block_size(&self) -> u64;
+    fn read(&self, offset: u64, data: &mut [u8]);
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Error>;
+    /// Erase the block containing offset, setting all of its bits
+    fn erase_block(&mut self, offset: u64) -> Result<(), Error>;
+    /// Follow the device to a new address, given a function converting addresses
+    fn relocate(&mut self, convert: &dyn Fn(u64) -> Option<u64>) {}
+}
+
+pub struct Pflash {
+    base: u64,
+    size: u64,
+    block_size: u64,
+}
+
+impl Pflash {
+    fn read_u8(&self, offset: u64) -> u8 {
+        unsafe { core::ptr::read_volatile((self.base + offset) as *const u8) }
+    }
+
+    fn write_u8(&self, offset: u64, value: u8) {
+        unsafe { core::ptr::write_volatile((self.base + offset) as *mut u8, value) }
+    }
+
+    // Poll the status register until the operation is done, then return to read array mode.
+    fn wait_for_status(&self, offset: u64, error: u8) -> Result<(), u8> {
+        let mut status = self.read_u8(offset);
+        let mut count = 0;
+        while (status & STATUS_READY) == 0 && count < STATUS_POLL_LIMIT {
+            status = self.read_u8(offset);
+            count += 1;
+        }
+        if (status & STATUS_READY) == 0 {
+            self.write_u8(offset, CMD_READ_ARRAY);
+            return Err(status);
+        }
+        if (status & error) != 0 {
+            self.write_u8(offset, CMD_CLEAR_STATUS);
+        }
+        self.write_u8(offset, CMD_READ_ARRAY);
+        if (status & error) != 0 {
+            return Err(status);
+        }
+        Ok(())
+    }
+
+    /// Check that the region behaves as a flash device, rather than as ROM or RAM,
+    /// following what OVMF does for QEMU.
+    pub fn probe(base: u64, size: u64, block_size: u64) -> Option<Pflash> {
+        if size == 0 || block_size == 0 || size % block_size != 0 {
+            return None;
+        }
+        let pflash = Pflash { base, size, block_size };
+
+        // The last byte is not part of any header, so probing is harmless
+        let offset = size - 1;
+        let original = pflash.read_u8(offset);
+        pflash.write_u8(offset, CMD_CLEAR_STATUS);
+        let probe = pflash.read_u8(offset);
+        if original != CMD_CLEAR_STATUS && probe == CMD_CLEAR_STATUS {
+            // RAM
+            pflash.write_u8(offset, original);
+            return None;
+        }
+
+        pflash.write_u8(offset, CMD_READ_STATUS);
+        let probe = pflash.read_u8(offset);
+        if probe == STATUS_CLEARED {
+            pflash.write_u8(offset, CMD_READ_ARRAY);
+            return Some(pflash);
+        }
+        if probe == CMD_READ_STATUS {
+            // RAM
+            pflash.write_u8(offset, original);
+        }
+        None
+    }
+}
+
+impl FlashDevice for Pflash {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn read(&self, offset: u64, data: &mut [u8]) {
+        assert!(offset + data.len() as u64 <= self.size);
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.read_u8(offset + i as u64);
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        assert!(offset + data.len() as u64 <= self.size);
+        for (i, byte) in data.iter().enumerate() {
+            // Nothing to clear in an erased byte
+            if *byte == 0xff {
+                continue;
+            }
+            let offset = offset + i as u64;
+            self.write_u8(offset, CMD_WRITE_BYTE);
+            self.write_u8(offset, *byte);
+            match self.wait_for_status(offset, STATUS_PROGRAM_ERROR) {
+                Ok(()) => {}
+                Err(status) if (status & STATUS_READY) == 0 => return Err(Error::Timeout),
+                Err(_) => return Err(Error::ProgramFailed),
+            }
+        }
+        Ok(())
+    }
+
+    fn erase_block(&mut self, offset: u64) -> Result<(), Error> {
+        assert!(offset < self.size);
+        let offset = offset - offset % self.block_size;
+        self.write_u8(offset, CMD_BLOCK_ERASE);
+        self.write_u8(offset, CMD_BLOCK_ERASE_CONFIRM);
+        match self.wait_for_status(offset, STATUS_ERASE_ERROR) {
+            Ok(()) => Ok(()),
+            Err(status) if (status & STATUS_READY) == 0 => Err(Error::Timeout),
+            Err(_) => Err(Error::EraseFailed),
+        }
+    }
+
+    fn relocate(&mut self, convert: &dyn Fn(u64) -> Option<u64>) {
+        if let Some(base) = convert(self.base) {
+            self.base = base;
+        }
+    }
+}
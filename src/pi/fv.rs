@@ -57,6 +57,11 @@ pub struct FirmwareVolumeExtEntry {
     pub ext_entry_type: u32,
 }
 
+// The file system GUID of the firmware volume holding the non-volatile variables
+pub const SYSTEM_NV_DATA_FV_GUID: r_efi::base::Guid = r_efi::base::Guid::from_fields(
+    0xfff12b8d, 0x7696, 0x4c8b, 0xa9, 0x85, &[0x27, 0x47, 0x07, 0x5b, 0x4f, 0x50]
+);
+
 pub const FIRMWARE_FILE_SYSTEM2_GUID: r_efi::base::Guid = r_efi::base::Guid::from_fields(
     0x8c8ce578, 0x8a3d, 0x4f1c, 0x99, 0x35, &[0x89, 0x61, 0x85, 0xc3, 0x2d, 0xd3]
 );
@@ -59,7 +59,7 @@ fn get_image_from_sections(sections_address: usize, sections_length: usize, sect
 }
 
 #[cfg(not(test))]
-fn get_image_from_fv(fv_base_address: u64, fv_length: u64, fv_file_type: FvFileType, section_type: SectionType) -> (*const c_void, usize) {
+fn get_image_from_fv(fv_base_address: u64, fv_length: u64, fv_file_type: FvFileType, fv_file_name: Option<&Guid>, section_type: SectionType) -> (*const c_void, usize) {
 
   log!("get_image_from_fv - 0x{:x} 0x{:x}\n", fv_base_address, fv_length);
 
@@ -94,6 +94,9 @@ fn get_image_from_fv(fv_base_address: u64, fv_length: u64, fv_file_type: FvFileT
     if ffs_header.r#type != fv_file_type {
       continue;
     }
+    if fv_file_name.map_or(false, |name| ffs_header.name != *name) {
+      continue;
+    }
 
     let (image, size) = get_image_from_sections (current_ptr + ffs_header_size, ffs_size - ffs_header_size, section_type);
     if image != core::ptr::null_mut() {
@@ -104,7 +107,7 @@ fn get_image_from_fv(fv_base_address: u64, fv_length: u64, fv_file_type: FvFileT
   (core::ptr::null_mut(), 0)
 }
 
-pub fn find_image_in_fv (hob: *const c_void) -> (*const c_void, usize) {
+fn find_in_fv (hob: *const c_void, fv_file_type: FvFileType, fv_file_name: Option<&Guid>, section_type: SectionType) -> (*const c_void, usize) {
   let mut hob_header : *const Header = hob as *const Header;
 
   loop {
@@ -112,7 +115,7 @@ pub fn find_image_in_fv (hob: *const c_void) -> (*const c_void, usize) {
     match header.r#type {
       HOB_TYPE_FV => {
         let fv_hob = unsafe {transmute::<*const Header, &FirmwareVolume>(hob_header)};
-        let (image, size) = get_image_from_fv (fv_hob.base_address, fv_hob.length, FV_FILETYPE_APPLICATION, SECTION_PE32);
+        let (image, size) = get_image_from_fv (fv_hob.base_address, fv_hob.length, fv_file_type, fv_file_name, section_type);
         if image != core::ptr::null_mut() {
           return (image, size);
         }        
@@ -128,3 +131,12 @@ pub fn find_image_in_fv (hob: *const c_void) -> (*const c_void, usize) {
 
   (core::ptr::null_mut(), 0)
 }
+
+pub fn find_image_in_fv (hob: *const c_void) -> (*const c_void, usize) {
+  find_in_fv (hob, FV_FILETYPE_APPLICATION, None, SECTION_PE32)
+}
+
+// The section of a freeform file, the firmware volumes carry data as well as images
+pub fn find_file_in_fv (hob: *const c_void, name: &Guid, section_type: SectionType) -> (*const c_void, usize) {
+  find_in_fv (hob, FV_FILETYPE_FREEFORM, Some(name), section_type)
+}
@@ -22,6 +22,11 @@ pub const HOB_LIST_GUID: Guid = Guid::from_fields(
     0x7739F24C, 0x93D7, 0x11D4, 0x9A, 0x3A, &[0x00, 0x90, 0x27, 0x3F, 0xC1, 0x4D]
 );
 
+// The name of the memory allocation HOB of the stack
+pub const MEMORY_ALLOCATION_STACK_GUID: Guid = Guid::from_fields(
+    0x4ED4BF27, 0x4092, 0x42E9, 0x80, 0x7D, &[0x52, 0x7B, 0x1D, 0x00, 0xC9, 0xBD]
+);
+
 pub type ResourceType = u32;
 
 pub const RESOURCE_SYSTEM_MEMORY:         u32 = 0x00;
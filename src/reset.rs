@@ -0,0 +1,106 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use cpuio::Port;
+
+// Reset Control Register of the ICH9 and PIIX3/4
+const RESET_CONTROL_PORT: u16 = 0xcf9;
+const RESET_CONTROL_SYS_RST: u8 = 1 << 1;
+const RESET_CONTROL_RST_CPU: u8 = 1 << 2;
+const RESET_CONTROL_FULL_RST: u8 = 1 << 3;
+
+const I8042_COMMAND_PORT: u16 = 0x64;
+const I8042_STATUS_INPUT_FULL: u8 = 0x02;
+const I8042_PULSE_RESET: u8 = 0xfe;
+
+// QEMU PM1a control at its default PM base of 0x600 (Q35) and 0xb000 (older i440FX),
+// with SLP_TYP 0 and SLP_EN.
+const QEMU_SHUTDOWN_PORTS: [u16; 2] = [0x604, 0xb004];
+const QEMU_SHUTDOWN_VALUE: u16 = 0x2000;
+// The Cloud Hypervisor ACPI sleep control register, SLP_TYP 5 and SLP_EN
+const CLOUD_HYPERVISOR_SHUTDOWN_PORT: u16 = 0x600;
+const CLOUD_HYPERVISOR_SHUTDOWN_VALUE: u8 = 0x34;
+
+// Time given to each mechanism before falling back to the next one
+const RESET_DELAY_US: u64 = 50_000;
+
+/// Reset the VM via the keyboard controller
+pub fn i8042_reset() -> ! {
+    log!("i8042_reset...\n");
+    loop {
+        let mut good: u8 = I8042_STATUS_INPUT_FULL;
+        let mut i8042_command: Port<u8> = unsafe { Port::new(I8042_COMMAND_PORT) };
+        while good & I8042_STATUS_INPUT_FULL > 0 {
+            good = i8042_command.read();
+        }
+        i8042_command.write(I8042_PULSE_RESET);
+    }
+}
+
+fn reset_control(value: u8) {
+    let mut reset_control: Port<u8> = unsafe { Port::new(RESET_CONTROL_PORT) };
+    reset_control.write(RESET_CONTROL_SYS_RST);
+    crate::timer::stall(1);
+    reset_control.write(value);
+    crate::timer::stall(RESET_DELAY_US);
+}
+
+/// Reset the platform, cycling power, through the reset control register
+pub fn reset_cold() -> ! {
+    reset_control(RESET_CONTROL_FULL_RST | RESET_CONTROL_RST_CPU | RESET_CONTROL_SYS_RST);
+    i8042_reset();
+}
+
+/// Reset the processors and the chipset without cycling power
+pub fn reset_warm() -> ! {
+    reset_control(RESET_CONTROL_RST_CPU | RESET_CONTROL_SYS_RST);
+    i8042_reset();
+}
+
+/// Power off through ACPI S5, or the hypervisor specific ports
+pub fn shutdown() -> ! {
+    crate::acpi::shutdown();
+    crate::timer::stall(RESET_DELAY_US);
+
+    let mut port: Port<u8> = unsafe { Port::new(CLOUD_HYPERVISOR_SHUTDOWN_PORT) };
+    port.write(CLOUD_HYPERVISOR_SHUTDOWN_VALUE);
+    for port in QEMU_SHUTDOWN_PORTS.iter() {
+        let mut port: Port<u16> = unsafe { Port::new(*port) };
+        port.write(QEMU_SHUTDOWN_VALUE);
+    }
+    crate::timer::stall(RESET_DELAY_US);
+
+    log!("Shutdown failed\n");
+    loop {
+        x86_64::instructions::interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Perform a reset described by the platform specific data: a null-terminated
+/// string, optionally followed by a GUID. No such reset is known, so a cold
+/// reset is done as required by the specification.
+pub fn reset_platform_specific(data: &[u8]) -> ! {
+    let mut string_length = 0;
+    while string_length + 1 < data.len() && (data[string_length] != 0 || data[string_length + 1] != 0) {
+        string_length += 2;
+    }
+    let guid_offset = string_length + 2;
+    if guid_offset + 16 <= data.len() {
+        log!("Platform specific reset: {:x?}\n", &data[guid_offset..guid_offset + 16]);
+    }
+    reset_cold();
+}
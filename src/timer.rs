@@ -0,0 +1,348 @@
+// Copyright © 2020 Intel Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![allow(unused)]
+
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
+use cpuio::Port;
+use x86_64::registers::model_specific::Msr;
+
+use crate::mem::MemoryRegion;
+
+/// Timer tick period in 100ns units (10ms, the same as EDKII)
+pub const TIMER_PERIOD: u64 = 100_000;
+
+/// Vector of the periodic timer interrupt, from either the LAPIC or the PIT (IRQ0)
+pub const TIMER_VECTOR: u8 = 0x20;
+/// Spurious vectors of the remapped 8259 (IRQ7 and IRQ15)
+pub const PIC_SPURIOUS_VECTOR_MASTER: u8 = 0x27;
+pub const PIC_SPURIOUS_VECTOR_SLAVE: u8 = 0x2f;
+/// Spurious vector of the local APIC
+pub const LAPIC_SPURIOUS_VECTOR: u8 = 0xff;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+const PIT_CHANNEL0_PORT: u16 = 0x40;
+const PIT_CHANNEL2_PORT: u16 = 0x42;
+const PIT_COMMAND_PORT: u16 = 0x43;
+const PIT_GATE_PORT: u16 = 0x61;
+
+const PIC_MASTER_COMMAND_PORT: u16 = 0x20;
+const PIC_MASTER_DATA_PORT: u16 = 0x21;
+const PIC_SLAVE_COMMAND_PORT: u16 = 0xa0;
+const PIC_SLAVE_DATA_PORT: u16 = 0xa1;
+const PIC_EOI: u8 = 0x20;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0xf_ffff_f000;
+
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SVR: u64 = 0xf0;
+const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: u64 = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: u64 = 0x390;
+const LAPIC_TIMER_DIVIDE: u64 = 0x3e0;
+const LAPIC_REGION_SIZE: u64 = 0x1000;
+
+const LAPIC_SVR_ENABLE: u32 = 1 << 8;
+const LAPIC_LVT_MASKED: u32 = 1 << 16;
+const LAPIC_LVT_PERIODIC: u32 = 1 << 17;
+const LAPIC_TIMER_DIVIDE_BY_16: u32 = 0x3;
+
+// The LAPIC timer and the TSC are calibrated against a 10ms reference count.
+const CALIBRATE_PERIOD_US: u64 = 10_000;
+
+const PM_TIMER_FREQUENCY: u64 = 3_579_545;
+const PM_TIMER_OFFSET: u16 = 0x08;
+const PM_TIMER_MASK: u32 = 0xff_ffff;
+
+// Anything slower means the reference counter did not tick.
+const MIN_TSC_FREQUENCY: u64 = 100_000_000;
+// Used when nothing better is known. It over-estimates, so a stall is never shorter than requested.
+const DEFAULT_TSC_FREQUENCY: u64 = 5_000_000_000;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TimerSource {
+    None,
+    Lapic,
+    Pit,
+}
+
+static TIMER_SOURCE: AtomicU8 = AtomicU8::new(TimerSource::None as u8);
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+pub fn get_timer_source() -> TimerSource {
+    match TIMER_SOURCE.load(Ordering::SeqCst) {
+        x if x == TimerSource::Lapic as u8 => TimerSource::Lapic,
+        x if x == TimerSource::Pit as u8 => TimerSource::Pit,
+        _ => TimerSource::None,
+    }
+}
+
+pub fn is_running() -> bool {
+    get_timer_source() != TimerSource::None
+}
+
+fn lapic_region() -> MemoryRegion {
+    MemoryRegion::new(LAPIC_BASE.load(Ordering::SeqCst), LAPIC_REGION_SIZE)
+}
+
+// Remap the 8259 pair to vectors 0x20-0x2f and mask every IRQ line.
+#[cfg(not(test))]
+fn pic_init() {
+    let mut master_command: Port<u8> = unsafe { Port::new(PIC_MASTER_COMMAND_PORT) };
+    let mut master_data: Port<u8> = unsafe { Port::new(PIC_MASTER_DATA_PORT) };
+    let mut slave_command: Port<u8> = unsafe { Port::new(PIC_SLAVE_COMMAND_PORT) };
+    let mut slave_data: Port<u8> = unsafe { Port::new(PIC_SLAVE_DATA_PORT) };
+
+    master_command.write(0x11); // ICW1: edge triggered, cascade, ICW4
+    slave_command.write(0x11);
+    master_data.write(TIMER_VECTOR); // ICW2: vector base
+    slave_data.write(TIMER_VECTOR + 8);
+    master_data.write(0x04); // ICW3: slave on IRQ2
+    slave_data.write(0x02);
+    master_data.write(0x01); // ICW4: 8086 mode
+    slave_data.write(0x01);
+
+    master_data.write(0xff);
+    slave_data.write(0xff);
+}
+
+#[cfg(not(test))]
+fn pic_set_irq0_mask(masked: bool) {
+    let mut master_data: Port<u8> = unsafe { Port::new(PIC_MASTER_DATA_PORT) };
+    let mask = master_data.read();
+    if masked {
+        master_data.write(mask | 0x01);
+    } else {
+        master_data.write(mask & !0x01);
+    }
+}
+
+#[cfg(not(test))]
+fn pit_set_periodic(period: u64) {
+    let mut command: Port<u8> = unsafe { Port::new(PIT_COMMAND_PORT) };
+    let mut channel0: Port<u8> = unsafe { Port::new(PIT_CHANNEL0_PORT) };
+
+    let divisor = (PIT_FREQUENCY * period / 10_000_000) as u16;
+    command.write(0x36); // channel 0, lobyte/hibyte, mode 3 (square wave)
+    channel0.write((divisor & 0xff) as u8);
+    channel0.write((divisor >> 8) as u8);
+}
+
+#[cfg(not(test))]
+fn pit_stop() {
+    let mut command: Port<u8> = unsafe { Port::new(PIT_COMMAND_PORT) };
+    command.write(0x30); // channel 0, lobyte/hibyte, mode 0 (one-shot), count never loaded
+}
+
+/// Busy wait on a one-shot count of PIT channel 2, used as the calibration reference.
+#[cfg(not(test))]
+pub fn pit_wait_us(us: u64) {
+    let mut command: Port<u8> = unsafe { Port::new(PIT_COMMAND_PORT) };
+    let mut channel2: Port<u8> = unsafe { Port::new(PIT_CHANNEL2_PORT) };
+    let mut gate: Port<u8> = unsafe { Port::new(PIT_GATE_PORT) };
+
+    let count = (PIT_FREQUENCY * us / 1_000_000) as u16;
+
+    // Gate channel 2 off and disconnect the speaker, then load the count
+    let value = gate.read();
+    gate.write(value & 0xfc);
+    command.write(0xb0); // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+    channel2.write((count & 0xff) as u8);
+    channel2.write((count >> 8) as u8);
+
+    // Raise the gate to start counting, OUT2 (bit 5) goes high on terminal count
+    let value = gate.read();
+    gate.write(value | 0x01);
+    while (gate.read() & 0x20) == 0 {
+        core::sync::atomic::spin_loop_hint();
+    }
+
+    let value = gate.read();
+    gate.write(value & 0xfe);
+}
+
+/// Busy wait on the 24-bit ACPI PM timer.
+#[cfg(not(test))]
+pub fn pm_timer_wait_us(pm_base: u16, us: u64) {
+    let mut pm_timer: Port<u32> = unsafe { Port::new(pm_base + PM_TIMER_OFFSET) };
+
+    let count = (PM_TIMER_FREQUENCY * us / 1_000_000) as u32;
+    let start = pm_timer.read();
+    while (pm_timer.read().wrapping_sub(start) & PM_TIMER_MASK) < count {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+pub fn read_tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+pub fn get_tsc_frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::SeqCst)
+}
+
+/// Calibrate the TSC against the ACPI PM timer, or PIT channel 2 if there is none.
+#[cfg(not(test))]
+pub fn calibrate_tsc() -> u64 {
+    let pm_base = crate::pci::get_acpi_pm_base();
+
+    let start = read_tsc();
+    match pm_base {
+        Some(pm_base) => pm_timer_wait_us(pm_base, CALIBRATE_PERIOD_US),
+        None => pit_wait_us(CALIBRATE_PERIOD_US),
+    }
+    let end = read_tsc();
+
+    let mut frequency = (end - start) * (1_000_000 / CALIBRATE_PERIOD_US);
+    if frequency < MIN_TSC_FREQUENCY {
+        // No reference counter, fall back to the nominal frequency from CPUID
+        frequency = DEFAULT_TSC_FREQUENCY;
+        if unsafe { core::arch::x86_64::__cpuid(0) }.eax >= 0x16 {
+            let base_mhz = unsafe { core::arch::x86_64::__cpuid(0x16) }.eax as u64;
+            if base_mhz != 0 {
+                frequency = base_mhz * 1_000_000;
+            }
+        }
+        log!("TSC calibration failed, assume {} Hz\n", frequency);
+    } else {
+        log!("TSC: {} Hz (reference: {})\n", frequency, if pm_base.is_some() { "PM timer" } else { "PIT" });
+    }
+
+    TSC_FREQUENCY.store(frequency, Ordering::SeqCst);
+    frequency
+}
+
+/// Busy wait for at least the given number of microseconds.
+#[cfg(not(test))]
+pub fn stall(us: u64) {
+    let mut frequency = get_tsc_frequency();
+    if frequency == 0 {
+        frequency = calibrate_tsc();
+    }
+
+    let count = (us as u128 * frequency as u128 / 1_000_000) as u64;
+    let start = read_tsc();
+    while read_tsc().wrapping_sub(start) < count {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+#[cfg(not(test))]
+fn lapic_probe() -> bool {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    if (cpuid.edx & (1 << 9)) == 0 {
+        return false;
+    }
+
+    let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    if (apic_base & APIC_BASE_ENABLE) == 0 {
+        return false;
+    }
+    LAPIC_BASE.store(apic_base & APIC_BASE_ADDRESS_MASK, Ordering::SeqCst);
+    true
+}
+
+// Count how many LAPIC timer ticks (divided by 16) elapse in one timer period.
+#[cfg(not(test))]
+fn lapic_calibrate() -> u32 {
+    let lapic = lapic_region();
+
+    lapic.io_write_u32(LAPIC_TIMER_DIVIDE, LAPIC_TIMER_DIVIDE_BY_16);
+    lapic.io_write_u32(LAPIC_LVT_TIMER, LAPIC_LVT_MASKED | TIMER_VECTOR as u32);
+    lapic.io_write_u32(LAPIC_TIMER_INITIAL_COUNT, 0xffff_ffff);
+
+    pit_wait_us(CALIBRATE_PERIOD_US);
+
+    let elapsed = 0xffff_ffff - lapic.io_read_u32(LAPIC_TIMER_CURRENT_COUNT);
+    lapic.io_write_u32(LAPIC_TIMER_INITIAL_COUNT, 0);
+
+    (elapsed as u64 * (TIMER_PERIOD / 10) / CALIBRATE_PERIOD_US) as u32
+}
+
+#[cfg(not(test))]
+fn lapic_start() -> bool {
+    let lapic = lapic_region();
+
+    lapic.io_write_u32(LAPIC_SVR, LAPIC_SVR_ENABLE | LAPIC_SPURIOUS_VECTOR as u32);
+
+    let count = lapic_calibrate();
+    if count == 0 {
+        log!("LAPIC timer calibration failed\n");
+        return false;
+    }
+    log!("LAPIC timer: base 0x{:x}, {} ticks per period\n", LAPIC_BASE.load(Ordering::SeqCst), count);
+
+    lapic.io_write_u32(LAPIC_TIMER_DIVIDE, LAPIC_TIMER_DIVIDE_BY_16);
+    lapic.io_write_u32(LAPIC_LVT_TIMER, LAPIC_LVT_PERIODIC | TIMER_VECTOR as u32);
+    lapic.io_write_u32(LAPIC_TIMER_INITIAL_COUNT, count);
+    true
+}
+
+/// Start the periodic timer interrupt. The LAPIC timer is preferred and the
+/// PIT on IRQ0 is the fallback. Interrupts are left to the caller to enable.
+#[cfg(not(test))]
+pub fn start() -> TimerSource {
+    if get_tsc_frequency() == 0 {
+        calibrate_tsc();
+    }
+    pic_init();
+
+    if lapic_probe() && lapic_start() {
+        TIMER_SOURCE.store(TimerSource::Lapic as u8, Ordering::SeqCst);
+    } else {
+        pit_set_periodic(TIMER_PERIOD);
+        pic_set_irq0_mask(false);
+        TIMER_SOURCE.store(TimerSource::Pit as u8, Ordering::SeqCst);
+    }
+
+    log!("Timer started: {:?}\n", get_timer_source());
+    get_timer_source()
+}
+
+/// Stop the periodic timer interrupt.
+#[cfg(not(test))]
+pub fn stop() {
+    match get_timer_source() {
+        TimerSource::Lapic => {
+            let lapic = lapic_region();
+            lapic.io_write_u32(LAPIC_LVT_TIMER, LAPIC_LVT_MASKED | TIMER_VECTOR as u32);
+            lapic.io_write_u32(LAPIC_TIMER_INITIAL_COUNT, 0);
+        }
+        TimerSource::Pit => {
+            pic_set_irq0_mask(true);
+            pit_stop();
+        }
+        TimerSource::None => {}
+    }
+    TIMER_SOURCE.store(TimerSource::None as u8, Ordering::SeqCst);
+}
+
+/// Acknowledge the timer interrupt at its source.
+pub fn end_of_interrupt() {
+    match get_timer_source() {
+        TimerSource::Lapic => {
+            lapic_region().io_write_u32(LAPIC_EOI, 0);
+        }
+        TimerSource::Pit => {
+            let mut master_command: Port<u8> = unsafe { Port::new(PIC_MASTER_COMMAND_PORT) };
+            master_command.write(PIC_EOI);
+        }
+        TimerSource::None => {}
+    }
+}